@@ -0,0 +1,164 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use statsrelay::backends::Backends;
+use statsrelay::config::{processor, Route, RouteType};
+use statsrelay::error::ErrorCounters;
+use statsrelay::processors::regex_filter::RegexFilter;
+use statsrelay::processors::sampler::Sampler;
+use statsrelay::processors::Processor;
+use statsrelay::shard::{statsrelay_compat_hash, Ring};
+use statsrelay::stats::Collector;
+use statsrelay::statsd_proto::{Event, Pdu};
+use statsrelay::statsd_server::process_buffer_newlines;
+
+/// A mix of line shapes seen in production: plain counters and timers, sample
+/// rates, DogStatsD tags, CRLF endings and the occasional malformed line.
+const MIXED_LINES: &[&[u8]] = &[
+    b"service.requests:1|c\n",
+    b"service.latency:12.5|ms|@0.1\n",
+    b"service.queue_depth:42|g|#host:web-12,region:us-east-1\n",
+    b"service.users:8812|s\n",
+    b"service.requests.by_route:1|c|@0.5|#route:/api/v1/items,method:get\r\n",
+    b"not a statsd line\n",
+];
+
+fn mixed_buffer(lines: usize) -> Bytes {
+    let mut buf = BytesMut::new();
+    for line in MIXED_LINES.iter().cycle().take(lines) {
+        buf.put_slice(line);
+    }
+    buf.freeze()
+}
+
+fn events(count: usize) -> Vec<Event> {
+    (0..count)
+        .map(|i| {
+            let line = format!("service.metric.{}:{}|c|#host:web-{}", i % 500, i, i % 20);
+            Event::Pdu(Pdu::parse(Bytes::from(line)).unwrap())
+        })
+        .collect()
+}
+
+fn route_to(route_type: RouteType, route_to: &str) -> Vec<Route> {
+    vec![Route {
+        route_type,
+        route_to: route_to.to_owned(),
+    }]
+}
+
+fn framing(c: &mut Criterion) {
+    let errors = ErrorCounters::new(&Collector::default().scope("bench"), "bench");
+    let mut group = c.benchmark_group("process_buffer_newlines");
+    for lines in [16_usize, 256] {
+        let input = mixed_buffer(lines);
+        group.bench_with_input(BenchmarkId::from_parameter(lines), &input, |b, input| {
+            b.iter(|| {
+                let mut buf = BytesMut::from(input.as_ref());
+                process_buffer_newlines(black_box(&mut buf), &errors, None)
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Route events through a chain of pass-through processors, isolating the
+/// routing overhead from the work done by any particular processor.
+fn routing(c: &mut Criterion) {
+    let input = events(256);
+    let mut group = c.benchmark_group("backends_routing");
+    for processors in [1_usize, 4, 16] {
+        let scope = Collector::default().scope("bench");
+        let backends = Backends::new(scope.clone());
+        for i in 0..processors {
+            let next = if i + 1 < processors {
+                route_to(RouteType::Processor, format!("p{}", i + 1).as_str())
+            } else {
+                vec![]
+            };
+            let filter = RegexFilter::new(
+                scope.scope(format!("p{}", i).as_str()),
+                &processor::RegexFilter {
+                    remove: Some(vec!["^never_matches".to_owned()]),
+                    allow: None,
+                    allow_filter: None,
+                    remove_filter: None,
+                    route: next,
+                },
+            )
+            .unwrap();
+            backends
+                .replace_processor(format!("p{}", i).as_str(), Box::new(filter))
+                .unwrap();
+        }
+        let route = route_to(RouteType::Processor, "p0");
+        group.bench_with_input(
+            BenchmarkId::from_parameter(processors),
+            &input,
+            |b, input| b.iter(|| backends.provide_statsd_slice(black_box(input), &route)),
+        );
+    }
+    group.finish();
+}
+
+fn sampler(c: &mut Criterion) {
+    let config = processor::Sampler {
+        window: 10,
+        align_to_window: false,
+        flush_jitter_ms: None,
+        timer_reservoir_size: Some(100),
+        timer_output: processor::TimerOutput::Reservoir,
+        timer_percentiles: vec![],
+        sets: None,
+        reemit_direct_gauges: false,
+        max_keys: None,
+        key_overflow: processor::KeyOverflow::PassThrough,
+        shards: None,
+        sub_windows: None,
+        overwrite_gauges: false,
+        debug_timers: None,
+        route: vec![],
+    };
+    let input = events(256);
+    let backends = Backends::new(Collector::default().scope("bench"));
+
+    let sampler = Sampler::new(Collector::default().scope("bench"), &config).unwrap();
+    c.bench_function("sampler record", |b| {
+        b.iter(|| {
+            for event in input.iter() {
+                sampler.provide_statsd(black_box(event));
+            }
+        })
+    });
+
+    c.bench_function("sampler record and flush", |b| {
+        let sampler = Sampler::new(Collector::default().scope("bench"), &config).unwrap();
+        b.iter(|| {
+            for event in input.iter() {
+                sampler.provide_statsd(black_box(event));
+            }
+            sampler.flush(std::time::SystemTime::now(), &backends);
+        })
+    });
+}
+
+fn sharding(c: &mut Criterion) {
+    let pdu = Pdu::parse(Bytes::from_static(
+        b"hello_world.worldworld_i_am_a_pumpkin:3|c|@1.0|#tags:tags",
+    ))
+    .unwrap();
+    c.bench_function("shard hash", |b| {
+        b.iter(|| statsrelay_compat_hash(black_box(&pdu)))
+    });
+
+    let mut ring: Ring<usize> = Ring::new();
+    for i in 0..64 {
+        ring.push(i);
+    }
+    c.bench_function("shard hash and pick", |b| {
+        b.iter(|| *ring.pick_from(statsrelay_compat_hash(black_box(&pdu))))
+    });
+}
+
+criterion_group!(benches, framing, routing, sampler, sharding);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+#![no_main]
+use bytes::{BufMut, BytesMut};
+use libfuzzer_sys::fuzz_target;
+use statsrelay::error::ErrorCounters;
+use statsrelay::statsd_proto::ParseLimits;
+use statsrelay::statsd_server::process_buffer_commands;
+
+// Frame input as a stream connection does, arriving in two reads split at
+// the offset given by the first byte, and parse each line it holds.
+fuzz_target!(|data: &[u8]| {
+    let (split, data) = match data.split_first() {
+        Some((split, data)) => (*split as usize % (data.len() + 1), data),
+        None => return,
+    };
+    let errors = ErrorCounters::new(
+        &statsrelay::stats::Collector::default().scope("fuzz"),
+        "fuzz",
+    );
+    for limits in [None, Some(ParseLimits::default())].iter() {
+        let mut buf = BytesMut::new();
+        let mut commands = Vec::new();
+        for read in [&data[..split], &data[split..]].iter() {
+            buf.put_slice(read);
+            process_buffer_commands(&mut buf, &errors, limits.as_ref(), 64, &mut commands);
+        }
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+use std::convert::TryFrom;
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use statsrelay::statsd_proto::{Owned, ParseLimits, Pdu};
+
+fn exercise(pdu: Pdu) {
+    let _ = Owned::try_from(&pdu);
+    let rewritten = pdu.with_prefix_suffix(b"prefix.", b".suffix");
+    let _ = (
+        rewritten.name(),
+        rewritten.value(),
+        rewritten.pdu_type(),
+        rewritten.sample_rate(),
+        rewritten.tags(),
+    );
+    let _ = Owned::try_from(&rewritten);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let line = Bytes::copy_from_slice(data);
+    if let Ok(pdu) = Pdu::parse(line.clone()) {
+        exercise(pdu);
+    }
+    if let Ok(pdu) = Pdu::parse_strict(line, &ParseLimits::default()) {
+        exercise(pdu);
+    }
+});
@@ -1,17 +1,31 @@
 use log::info;
+use serde::{Deserialize, Serialize};
 
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server};
 use tokio::runtime;
+use tokio::sync::oneshot;
 
 use std::boxed::Box;
 use std::convert::Infallible;
 
+use crate::backends::Backends;
+use crate::config::{AdminConfig, IpFamily};
+use crate::discovery::Exclusions;
+use crate::net;
 use crate::stats::Collector;
 
 #[derive(Clone)]
 struct AdminState {
     collector: Collector,
+    backends: Backends,
+    exclusions: Exclusions,
+    write_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExcludedHosts {
+    hosts: Option<Vec<String>>,
 }
 
 async fn metric_response(
@@ -25,6 +39,94 @@ async fn metric_response(
         .unwrap())
 }
 
+async fn processors_response(state: AdminState) -> Result<Response<Body>, Infallible> {
+    let reports = serde_json::Value::Object(state.backends.processor_reports());
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(reports.to_string()))
+        .unwrap())
+}
+
+/// Check a request changing state carries the configured bearer token,
+/// returning the response refusing it if not
+fn refuse_write(write_token: Option<&str>, req: &Request<Body>) -> Option<Response<Body>> {
+    let write_token = match write_token {
+        Some(token) => token,
+        None => {
+            return Some(
+                Response::builder()
+                    .status(403)
+                    .body(Body::from("admin writes are disabled"))
+                    .unwrap(),
+            )
+        }
+    };
+    let given = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Compare every byte, so the time taken doesn't tell how much matched
+    let matches = given.is_some_and(|given| {
+        given.len() == write_token.len()
+            && given
+                .bytes()
+                .zip(write_token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    });
+    if matches {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(401)
+            .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::from("unauthorized"))
+            .unwrap(),
+    )
+}
+
+/// Get, override or reset the hosts excluded from discovery updates
+async fn exclusions_response(
+    state: AdminState,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET {
+        if let Some(response) = refuse_write(state.write_token.as_deref(), &req) {
+            return Ok(response);
+        }
+    }
+    match *req.method() {
+        Method::PUT => {
+            let body = hyper::body::to_bytes(req.into_body()).await;
+            let excluded = body
+                .ok()
+                .and_then(|body| serde_json::from_slice::<ExcludedHosts>(&body).ok());
+            match excluded {
+                Some(excluded) => state
+                    .exclusions
+                    .set(Some(excluded.hosts.unwrap_or_default())),
+                None => {
+                    return Ok(Response::builder()
+                        .status(400)
+                        .body(Body::from("expected {\"hosts\": [...]}"))
+                        .unwrap())
+                }
+            }
+        }
+        Method::DELETE => state.exclusions.set(None),
+        _ => (),
+    }
+    let excluded = ExcludedHosts {
+        hosts: state.exclusions.get(),
+    };
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&excluded).unwrap()))
+        .unwrap())
+}
+
 async fn request_handler(
     state: AdminState,
     req: Request<Body>,
@@ -35,6 +137,10 @@ async fn request_handler(
             .unwrap()),
         (&Method::GET, "/healthcheck") => Ok(Response::builder().body(Body::from("OK")).unwrap()),
         (&Method::GET, "/metrics") => metric_response(state, req).await,
+        (&Method::GET, "/processors") => processors_response(state).await,
+        (&Method::GET | &Method::PUT | &Method::DELETE, "/discovery/exclusions") => {
+            exclusions_response(state, req).await
+        }
         _ => Ok(Response::builder()
             .status(404)
             .body(Body::from("not found"))
@@ -42,9 +148,29 @@ async fn request_handler(
     }
 }
 
-async fn hyper_server(port: u16, collector: Collector) -> Result<(), Box<dyn std::error::Error>> {
-    let addr = format!("[::]:{}", port).parse().unwrap();
-    let admin_state = AdminState { collector };
+async fn hyper_server(
+    config: AdminConfig,
+    collector: Collector,
+    backends: Backends,
+    exclusions: Exclusions,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let port = config.port;
+    let wildcard = match config.ip_family {
+        Some(IpFamily::V4) => "0.0.0.0",
+        _ => "[::]",
+    };
+    let bind = format!("{}:{}", wildcard, port);
+    let builder = match config.ip_family {
+        Some(_) => Server::from_tcp(net::bind_tcp(&bind, config.ip_family)?)?,
+        None => Server::bind(&bind.parse().unwrap()),
+    };
+    let admin_state = AdminState {
+        collector,
+        backends,
+        exclusions,
+        write_token: config.write_token.clone(),
+    };
     let make_svc = make_service_fn(move |_conn| {
         let service_capture = admin_state.clone();
         async {
@@ -54,14 +180,78 @@ async fn hyper_server(port: u16, collector: Collector) -> Result<(), Box<dyn std
         }
     });
     info!("admin server starting on port {}", port);
-    Server::bind(&addr).serve(make_svc).await?;
+    builder
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            let _ = shutdown.await;
+        })
+        .await?;
+    info!("admin server stopped");
     Ok(())
 }
 
-pub fn spawn_admin_server(port: u16, collector: Collector) {
+/// Handle to a running admin server, used to stop it on shutdown.
+pub struct AdminServer {
+    shutdown: oneshot::Sender<()>,
+    stopped: oneshot::Receiver<()>,
+}
+
+impl AdminServer {
+    /// Gracefully stop the admin server, resolving once it has exited.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.stopped.await;
+    }
+}
+
+pub fn spawn_admin_server(
+    config: AdminConfig,
+    collector: Collector,
+    backends: Backends,
+    exclusions: Exclusions,
+) -> AdminServer {
     let rt = runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
-    std::thread::spawn(move || rt.block_on(hyper_server(port, collector)).unwrap());
+    let (shutdown_sender, shutdown) = oneshot::channel();
+    let (stopped_sender, stopped) = oneshot::channel();
+    std::thread::spawn(move || {
+        rt.block_on(hyper_server(
+            config, collector, backends, exclusions, shutdown,
+        ))
+        .unwrap();
+        let _ = stopped_sender.send(());
+    });
+    AdminServer {
+        shutdown: shutdown_sender,
+        stopped,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(Method::DELETE)
+            .uri("/discovery/exclusions");
+        if let Some(authorization) = authorization {
+            builder = builder.header(hyper::header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_refuse_write() {
+        let status = |token, authorization| {
+            refuse_write(token, &request(authorization)).map(|response| response.status().as_u16())
+        };
+        assert_eq!(status(None, Some("Bearer secret")), Some(403));
+        assert_eq!(status(Some("secret"), None), Some(401));
+        assert_eq!(status(Some("secret"), Some("Bearer secre")), Some(401));
+        assert_eq!(status(Some("secret"), Some("secret")), Some(401));
+        assert_eq!(status(Some("secret"), Some("Bearer secret")), None);
+    }
 }
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use stream_cancel::Tripwire;
+use tokio::select;
+use tokio::time::sleep;
+
+use crate::config::{AlertLevel, AlertMode, AlertRule, AlertsConfig};
+use crate::stats;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A change in state of a single rule against a single metric.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transition {
+    Firing { value: f64 },
+    Resolved { value: f64 },
+}
+
+/// Periodically compares internal stats against configured thresholds,
+/// logging when a metric starts and stops breaching its threshold. This gives
+/// operators actionable signals from the relay's own logs without requiring
+/// an external alerting stack scraping the admin endpoint.
+pub struct Evaluator {
+    rules: Vec<AlertRule>,
+    collector: stats::Collector,
+    breaches: stats::Counter,
+    previous: HashMap<String, f64>,
+    firing: HashSet<(usize, String)>,
+}
+
+/// Whether a rule watches a metric, ignoring its scope and any labels
+fn matches(rule: &AlertRule, name: &str) -> bool {
+    let name = name.split('{').next().unwrap_or(name);
+    name == rule.metric
+        || (name.ends_with(rule.metric.as_str())
+            && name[..name.len() - rule.metric.len()].ends_with(stats::SEP))
+}
+
+impl Evaluator {
+    pub fn new(scope: stats::Scope, rules: &[AlertRule]) -> Self {
+        Evaluator {
+            rules: rules.to_vec(),
+            collector: scope.collector().clone(),
+            breaches: scope.counter("breaches").unwrap(),
+            previous: HashMap::new(),
+            firing: HashSet::new(),
+        }
+    }
+
+    /// Evaluate every rule against the current stats, given the time since
+    /// the last evaluation for computing rates. Returns the rule name, metric
+    /// name and transition for every rule which changed state.
+    pub fn evaluate(&mut self, elapsed: Duration) -> Vec<(String, String, Transition)> {
+        let snapshot = self.collector.snapshot();
+        let seconds = elapsed.as_secs_f64();
+        let mut transitions = Vec::new();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            for (name, current) in snapshot.iter().filter(|(n, _)| matches(rule, n)) {
+                let value = match rule.mode {
+                    AlertMode::Value => *current,
+                    AlertMode::Rate => match self.previous.get(name) {
+                        Some(previous) if seconds > 0_f64 => (current - previous) / seconds,
+                        // Rates need two samples
+                        _ => continue,
+                    },
+                };
+                let key = (index, name.clone());
+                let breached = value > rule.threshold;
+                let transition = match (breached, self.firing.contains(&key)) {
+                    (true, false) => {
+                        self.breaches.inc();
+                        self.firing.insert(key);
+                        Transition::Firing { value }
+                    }
+                    (false, true) => {
+                        self.firing.remove(&key);
+                        Transition::Resolved { value }
+                    }
+                    _ => continue,
+                };
+                log_transition(rule, name, &transition);
+                transitions.push((rule.name.clone(), name.clone(), transition));
+            }
+        }
+
+        self.previous = snapshot;
+        transitions
+    }
+}
+
+fn log_transition(rule: &AlertRule, metric: &str, transition: &Transition) {
+    match (transition, rule.level) {
+        (Transition::Firing { value }, AlertLevel::Warn) => warn!(
+            "alert={} state=firing metric={} value={} threshold={}",
+            rule.name, metric, value, rule.threshold
+        ),
+        (Transition::Firing { value }, AlertLevel::Error) => error!(
+            "alert={} state=firing metric={} value={} threshold={}",
+            rule.name, metric, value, rule.threshold
+        ),
+        (Transition::Resolved { value }, _) => info!(
+            "alert={} state=resolved metric={} value={} threshold={}",
+            rule.name, metric, value, rule.threshold
+        ),
+    }
+}
+
+/// Run an evaluator on the configured interval until the tripwire is set.
+pub async fn run(scope: stats::Scope, config: AlertsConfig, mut tripwire: Tripwire) {
+    let interval = config
+        .interval_seconds
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let mut evaluator = Evaluator::new(scope, &config.rules);
+    let mut last = Instant::now();
+    loop {
+        select! {
+            _ = sleep(interval) => {},
+            _ = &mut tripwire => return,
+        }
+        let now = Instant::now();
+        evaluator.evaluate(now - last);
+        last = now;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn rule(metric: &str, mode: AlertMode, threshold: f64) -> AlertRule {
+        AlertRule {
+            name: "test".to_owned(),
+            metric: metric.to_owned(),
+            mode,
+            threshold,
+            level: AlertLevel::Warn,
+        }
+    }
+
+    #[test]
+    fn match_scoped_names() {
+        let r = rule("backend_fails", AlertMode::Value, 0_f64);
+        assert!(matches(&r, "backend_fails"));
+        assert!(matches(&r, "statsrelay:backends:foo:backend_fails"));
+        assert!(!matches(&r, "statsrelay:backends:foo:other_backend_fails"));
+        assert!(!matches(&r, "statsrelay:backends:foo:backend_fails_total"));
+        assert!(matches(&r, "statsrelay:backend_fails{backend=\"foo\"}"));
+    }
+
+    #[test]
+    fn labeled_threshold() {
+        let scope = stats::Collector::default().scope("prefix");
+        let flagged = scope
+            .scope("cardinality")
+            .counter_vec("prefix_flagged_metrics", &["prefix"])
+            .unwrap();
+        let mut evaluator = Evaluator::new(
+            scope.scope("alerts"),
+            &[rule("prefix_flagged_metrics", AlertMode::Value, 10_f64)],
+        );
+
+        flagged.inc_by(&["api"], 20_f64);
+        flagged.inc_by(&["web"], 5_f64);
+        // Each series is evaluated separately
+        let t = evaluator.evaluate(Duration::from_secs(1));
+        assert_eq!(
+            t,
+            vec![(
+                "test".to_owned(),
+                "prefix:cardinality:prefix_flagged_metrics{prefix=\"api\"}".to_owned(),
+                Transition::Firing { value: 20_f64 }
+            )]
+        );
+    }
+
+    #[test]
+    fn value_threshold() {
+        let scope = stats::Collector::default().scope("prefix");
+        let depth = scope.scope("client").gauge("queue_depth").unwrap();
+        let mut evaluator = Evaluator::new(
+            scope.scope("alerts"),
+            &[rule("queue_depth", AlertMode::Value, 10_f64)],
+        );
+
+        depth.set(5_f64);
+        assert!(evaluator.evaluate(Duration::from_secs(1)).is_empty());
+        depth.set(11_f64);
+        let t = evaluator.evaluate(Duration::from_secs(1));
+        assert_eq!(
+            t,
+            vec![(
+                "test".to_owned(),
+                "prefix:client:queue_depth".to_owned(),
+                Transition::Firing { value: 11_f64 }
+            )]
+        );
+        // Continued breaches only fire once
+        depth.set(12_f64);
+        assert!(evaluator.evaluate(Duration::from_secs(1)).is_empty());
+        depth.set(0_f64);
+        let t = evaluator.evaluate(Duration::from_secs(1));
+        assert_eq!(t[0].2, Transition::Resolved { value: 0_f64 });
+        assert_eq!(evaluator.breaches.get(), 1_f64);
+    }
+
+    #[test]
+    fn rate_threshold() {
+        let scope = stats::Collector::default().scope("prefix");
+        let fails = scope.counter("backend_fails").unwrap();
+        let mut evaluator = Evaluator::new(
+            scope.scope("alerts"),
+            &[rule("backend_fails", AlertMode::Rate, 5_f64)],
+        );
+
+        fails.inc_by(100_f64);
+        // The first evaluation only records a baseline
+        assert!(evaluator.evaluate(Duration::from_secs(2)).is_empty());
+        fails.inc_by(20_f64);
+        let t = evaluator.evaluate(Duration::from_secs(2));
+        assert_eq!(t[0].2, Transition::Firing { value: 10_f64 });
+        fails.inc_by(2_f64);
+        let t = evaluator.evaluate(Duration::from_secs(2));
+        assert_eq!(t[0].2, Transition::Resolved { value: 1_f64 });
+    }
+}
@@ -1,13 +1,25 @@
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{info, warn};
 use parking_lot::RwLock;
 use stream_cancel::Tripwire;
 use thiserror::Error;
 
 use crate::discovery;
+use crate::error::{Categorized, Category};
+use crate::file_backend::FileBackend;
+use crate::influx_backend::InfluxBackend;
+use crate::null_backend::NullBackend;
+use crate::prometheus_backend::PrometheusBackend;
 use crate::stats;
 use crate::statsd_backend::StatsdBackend;
+use crate::statsd_client::ClientOptions;
 use crate::statsd_proto::Event;
 use crate::{config, processors};
 
@@ -17,9 +29,19 @@ pub enum BackendError {
     InvalidIndex(usize),
 }
 
+impl Categorized for BackendError {
+    fn category(&self) -> Category {
+        Category::Internal
+    }
+}
+
 struct BackendsInner {
     statsd: HashMap<String, StatsdBackend>,
-    processors: HashMap<String, Box<dyn processors::Processor + Send + Sync>>,
+    prometheus: HashMap<String, PrometheusBackend>,
+    influx: HashMap<String, InfluxBackend>,
+    file: HashMap<String, FileBackend>,
+    null: HashMap<String, NullBackend>,
+    processors: HashMap<String, Arc<dyn processors::Processor + Send + Sync>>,
     stats: stats::Scope,
 }
 
@@ -27,6 +49,10 @@ impl BackendsInner {
     fn new(stats: stats::Scope) -> Self {
         BackendsInner {
             statsd: HashMap::new(),
+            prometheus: HashMap::new(),
+            influx: HashMap::new(),
+            file: HashMap::new(),
+            null: HashMap::new(),
             processors: HashMap::new(),
             stats,
         }
@@ -37,7 +63,8 @@ impl BackendsInner {
         name: &str,
         processor: Box<dyn processors::Processor + Send + Sync>,
     ) -> anyhow::Result<()> {
-        self.processors.insert(name.to_owned(), processor);
+        self.processors
+            .insert(name.to_owned(), Arc::from(processor));
         Ok(())
     }
 
@@ -46,19 +73,146 @@ impl BackendsInner {
         name: &str,
         c: &config::StatsdBackendConfig,
         discovery_update: Option<&discovery::Update>,
+        migration_update: Option<&discovery::Update>,
     ) -> anyhow::Result<()> {
         let previous = self.statsd.get(name);
-        let backend = StatsdBackend::new(self.stats.scope(name), c, previous, discovery_update)?;
+        let backend = StatsdBackend::new(
+            self.stats.scope(name),
+            name,
+            c,
+            previous,
+            discovery_update,
+            migration_update,
+        )?;
         self.statsd.insert(name.to_owned(), backend);
         Ok(())
     }
 
+    /// Replace a prometheus backend, unless its configuration is unchanged.
+    /// Replacing a backend resets its series.
+    fn replace_prometheus_backend(
+        &mut self,
+        name: &str,
+        c: &config::PrometheusBackendConfig,
+    ) -> anyhow::Result<()> {
+        if self.prometheus.get(name).map(|b| b.conf()) == Some(c) {
+            return Ok(());
+        }
+        let backend = PrometheusBackend::new(self.stats.scope(name), c)?;
+        self.prometheus.insert(name.to_owned(), backend);
+        Ok(())
+    }
+
+    fn remove_prometheus_backend(&mut self, name: &str) -> anyhow::Result<()> {
+        self.prometheus.remove(name);
+        Ok(())
+    }
+
+    fn prometheus_backend_names(&self) -> HashSet<&String> {
+        self.prometheus.keys().collect()
+    }
+
+    /// Replace an influx backend, unless its configuration is unchanged.
+    fn replace_influx_backend(
+        &mut self,
+        name: &str,
+        c: &config::InfluxBackendConfig,
+    ) -> anyhow::Result<()> {
+        if self.influx.get(name).map(|b| b.conf()) == Some(c) {
+            return Ok(());
+        }
+        let backend = InfluxBackend::new(self.stats.scope(name), c)?;
+        self.influx.insert(name.to_owned(), backend);
+        Ok(())
+    }
+
+    fn remove_influx_backend(&mut self, name: &str) -> anyhow::Result<()> {
+        self.influx.remove(name);
+        Ok(())
+    }
+
+    fn influx_backend_names(&self) -> HashSet<&String> {
+        self.influx.keys().collect()
+    }
+
+    /// Replace a file backend, unless its configuration is unchanged.
+    fn replace_file_backend(
+        &mut self,
+        name: &str,
+        c: &config::FileBackendConfig,
+    ) -> anyhow::Result<()> {
+        if self.file.get(name).map(|b| b.conf()) == Some(c) {
+            return Ok(());
+        }
+        let backend = FileBackend::new(self.stats.scope(name), c)?;
+        self.file.insert(name.to_owned(), backend);
+        Ok(())
+    }
+
+    fn remove_file_backend(&mut self, name: &str) -> anyhow::Result<()> {
+        self.file.remove(name);
+        Ok(())
+    }
+
+    fn file_backend_names(&self) -> HashSet<&String> {
+        self.file.keys().collect()
+    }
+
+    /// Replace a null backend, unless its configuration is unchanged.
+    fn replace_null_backend(
+        &mut self,
+        name: &str,
+        c: &config::NullBackendConfig,
+    ) -> anyhow::Result<()> {
+        if self.null.get(name).map(|b| b.conf()) == Some(c) {
+            return Ok(());
+        }
+        let backend = NullBackend::new(self.stats.scope(name), c)?;
+        self.null.insert(name.to_owned(), backend);
+        Ok(())
+    }
+
+    fn remove_null_backend(&mut self, name: &str) -> anyhow::Result<()> {
+        self.null.remove(name);
+        Ok(())
+    }
+
+    fn null_backend_names(&self) -> HashSet<&String> {
+        self.null.keys().collect()
+    }
+
     fn len(&self) -> usize {
         self.statsd.len()
     }
 
     fn remove_statsd_backend(&mut self, name: &str) -> anyhow::Result<()> {
-        self.statsd.remove(name);
+        // Removed backends take no more events, but their clients keep
+        // writing out their queues until the drain timeout
+        let backend = match self.statsd.remove(name) {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return Ok(()),
+        };
+        let name = name.to_owned();
+        let drain_timeout = backend
+            .conf()
+            .drain_timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| ClientOptions::default().drain_timeout);
+        let finished = futures::future::join_all(backend.finished());
+        drop(backend);
+        runtime.spawn(async move {
+            match tokio::time::timeout(drain_timeout, finished).await {
+                Ok(_) => info!("removed statsd backend {} drained its queues", name),
+                Err(_) => warn!(
+                    "removed statsd backend {} did not drain its queues within {:?}",
+                    name, drain_timeout
+                ),
+            }
+        });
         Ok(())
     }
 
@@ -74,6 +228,26 @@ impl BackendsInner {
                         backend.provide_statsd(pdu)
                     }
                 }
+                config::RouteType::Prometheus => {
+                    if let Some(backend) = self.prometheus.get(dest.route_to.as_str()) {
+                        backend.provide_statsd(pdu)
+                    }
+                }
+                config::RouteType::Influx => {
+                    if let Some(backend) = self.influx.get(dest.route_to.as_str()) {
+                        backend.provide_statsd(pdu)
+                    }
+                }
+                config::RouteType::File => {
+                    if let Some(backend) = self.file.get(dest.route_to.as_str()) {
+                        backend.provide_statsd(pdu)
+                    }
+                }
+                config::RouteType::Null => {
+                    if let Some(backend) = self.null.get(dest.route_to.as_str()) {
+                        backend.provide_statsd(pdu)
+                    }
+                }
                 config::RouteType::Processor => {
                     if let Some(chain) = self
                         .processors
@@ -91,6 +265,8 @@ impl BackendsInner {
                         }
                     }
                 }
+                // Expanded into processor routes as the config is loaded
+                config::RouteType::Pipeline => {}
             }
         }
     }
@@ -111,12 +287,15 @@ impl BackendsInner {
 #[derive(Clone)]
 pub struct Backends {
     inner: Arc<RwLock<BackendsInner>>,
+    /// Bits of the queue occupancy as of the last tick
+    occupancy: Arc<AtomicU64>,
 }
 
 impl Backends {
     pub fn new(stats: stats::Scope) -> Self {
         Backends {
             inner: Arc::new(RwLock::new(BackendsInner::new(stats))),
+            occupancy: Arc::new(AtomicU64::new(0_f64.to_bits())),
         }
     }
 
@@ -133,16 +312,101 @@ impl Backends {
         name: &str,
         c: &config::StatsdBackendConfig,
         discovery_update: Option<&discovery::Update>,
+        migration_update: Option<&discovery::Update>,
     ) -> anyhow::Result<()> {
         self.inner
             .write()
-            .replace_statsd_backend(name, c, discovery_update)
+            .replace_statsd_backend(name, c, discovery_update, migration_update)
     }
 
     pub fn remove_statsd_backend(&self, name: &str) -> anyhow::Result<()> {
         self.inner.write().remove_statsd_backend(name)
     }
 
+    pub fn replace_prometheus_backend(
+        &self,
+        name: &str,
+        c: &config::PrometheusBackendConfig,
+    ) -> anyhow::Result<()> {
+        self.inner.write().replace_prometheus_backend(name, c)
+    }
+
+    pub fn remove_prometheus_backend(&self, name: &str) -> anyhow::Result<()> {
+        self.inner.write().remove_prometheus_backend(name)
+    }
+
+    pub fn prometheus_backend_names(&self) -> HashSet<String> {
+        self.inner
+            .read()
+            .prometheus_backend_names()
+            .iter()
+            .map(|s| (*s).clone())
+            .collect()
+    }
+
+    pub fn replace_influx_backend(
+        &self,
+        name: &str,
+        c: &config::InfluxBackendConfig,
+    ) -> anyhow::Result<()> {
+        self.inner.write().replace_influx_backend(name, c)
+    }
+
+    pub fn remove_influx_backend(&self, name: &str) -> anyhow::Result<()> {
+        self.inner.write().remove_influx_backend(name)
+    }
+
+    pub fn influx_backend_names(&self) -> HashSet<String> {
+        self.inner
+            .read()
+            .influx_backend_names()
+            .iter()
+            .map(|s| (*s).clone())
+            .collect()
+    }
+
+    pub fn replace_file_backend(
+        &self,
+        name: &str,
+        c: &config::FileBackendConfig,
+    ) -> anyhow::Result<()> {
+        self.inner.write().replace_file_backend(name, c)
+    }
+
+    pub fn remove_file_backend(&self, name: &str) -> anyhow::Result<()> {
+        self.inner.write().remove_file_backend(name)
+    }
+
+    pub fn file_backend_names(&self) -> HashSet<String> {
+        self.inner
+            .read()
+            .file_backend_names()
+            .iter()
+            .map(|s| (*s).clone())
+            .collect()
+    }
+
+    pub fn replace_null_backend(
+        &self,
+        name: &str,
+        c: &config::NullBackendConfig,
+    ) -> anyhow::Result<()> {
+        self.inner.write().replace_null_backend(name, c)
+    }
+
+    pub fn remove_null_backend(&self, name: &str) -> anyhow::Result<()> {
+        self.inner.write().remove_null_backend(name)
+    }
+
+    pub fn null_backend_names(&self) -> HashSet<String> {
+        self.inner
+            .read()
+            .null_backend_names()
+            .iter()
+            .map(|s| (*s).clone())
+            .collect()
+    }
+
     pub fn backend_names(&self) -> HashSet<String> {
         self.inner
             .read()
@@ -174,6 +438,86 @@ impl Backends {
     pub fn processor_tick(&self, now: std::time::SystemTime) {
         self.inner.read().processor_tick(now, self);
     }
+
+    /// Force all processors to emit any buffered state, regardless of their
+    /// flush windows.
+    pub fn processor_flush(&self, now: std::time::SystemTime) {
+        let processors: Vec<_> = self.inner.read().processors.values().cloned().collect();
+        // Flushed without holding the lock, as a processor may wait on lines
+        // being sent on by other threads, which need it
+        for proc in processors {
+            proc.flush(now, self);
+        }
+    }
+
+    /// The highest send queue occupancy, from 0 to 1, of any statsd backend
+    /// client, as of the last tick. Used by servers to detect when
+    /// downstream queues are filling, so cheap enough to check every read.
+    pub fn queue_occupancy(&self) -> f64 {
+        f64::from_bits(self.occupancy.load(Ordering::Relaxed))
+    }
+
+    /// Measure the queue occupancy returned until the next update
+    pub fn update_queue_occupancy(&self) {
+        let occupancy = self
+            .inner
+            .read()
+            .statsd
+            .values()
+            .map(|b| b.queue_occupancy())
+            .fold(0_f64, f64::max);
+        self.occupancy.store(occupancy.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Number of lines queued to send by each statsd backend, by name
+    pub fn queue_depths(&self) -> Vec<(String, usize)> {
+        self.inner
+            .read()
+            .statsd
+            .iter()
+            .map(|(name, b)| (name.clone(), b.queue_depth()))
+            .collect()
+    }
+
+    /// Reports of every processor which has one, by name
+    pub fn processor_reports(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.inner
+            .read()
+            .processors
+            .iter()
+            .filter_map(|(name, proc)| Some((name.clone(), proc.report()?)))
+            .collect()
+    }
+
+    /// Remove all backends and processors, returning a future which resolves
+    /// once every backend has written out its queue and exited. Events
+    /// provided after this call are not sent anywhere.
+    pub fn drain_backends(&self) -> impl Future<Output = ()> {
+        let (statsd, prometheus, influx, file, processors) = {
+            let mut inner = self.inner.write();
+            // Null backends have nothing to write out
+            inner.null.clear();
+            (
+                std::mem::take(&mut inner.statsd),
+                std::mem::take(&mut inner.prometheus),
+                std::mem::take(&mut inner.influx),
+                std::mem::take(&mut inner.file),
+                std::mem::take(&mut inner.processors),
+            )
+        };
+        // Dropped outside the lock, which stops the threads of any processor
+        // with workers once they finish their queue
+        drop(processors);
+        let mut finished: Vec<BoxFuture<'static, ()>> = statsd
+            .values()
+            .flat_map(|b| b.finished())
+            .map(FutureExt::boxed)
+            .collect();
+        finished.extend(prometheus.values().map(|b| b.finished().boxed()));
+        finished.extend(influx.values().map(|b| b.finished().boxed()));
+        finished.extend(file.values().map(|b| b.finished().boxed()));
+        futures::future::join_all(finished).map(|_| ())
+    }
 }
 
 pub async fn ticker(tripwire: Tripwire, backends: Backends) {
@@ -187,6 +531,7 @@ pub async fn ticker(tripwire: Tripwire, backends: Backends) {
             _ = ticker.tick() => {
                 let back = backends.clone();
                 tokio::task::spawn_blocking(move || {
+                    back.update_queue_occupancy();
                     back.processor_tick(std::time::SystemTime::now())
                 }).await.unwrap();
             }
@@ -254,7 +599,7 @@ pub mod test {
             .inner
             .write()
             .processors
-            .insert(name.to_owned(), proc);
+            .insert(name.to_owned(), Arc::from(proc));
     }
 
     #[test]
@@ -277,7 +622,10 @@ pub mod test {
         insert_proc(&backend, "final", proc);
 
         // Create the processor under test
-        let tn = processors::tag::Normalizer::new(&route_final);
+        let tn = processors::tag::Normalizer::new(
+            crate::stats::Collector::default().scope("tag"),
+            &route_final,
+        );
         insert_proc(&backend, "tag", Box::new(tn));
 
         let pdu =
@@ -319,7 +667,10 @@ pub mod test {
         insert_proc(&backend, "final2", proc2);
 
         // Create the processor under test
-        let tn = processors::tag::Normalizer::new(&route_final);
+        let tn = processors::tag::Normalizer::new(
+            crate::stats::Collector::default().scope("tag"),
+            &route_final,
+        );
         insert_proc(&backend, "tag", Box::new(tn));
 
         let pdu =
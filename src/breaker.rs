@@ -0,0 +1,191 @@
+//! A circuit breaker for a backend endpoint. After enough consecutive send
+//! failures the circuit opens, and lines for the endpoint fail fast rather
+//! than being queued behind a dead endpoint. Once the circuit has been open
+//! for a while it goes half-open, letting lines through again as a probe: the
+//! next success closes the circuit, and the next failure opens it again.
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::stats;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreakerSettings {
+    /// Consecutive failures which open the circuit
+    pub failures: u32,
+    /// How long the circuit stays open before probing the endpoint
+    pub open_for: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum State {
+    Closed = 0,
+    Open = 1,
+    HalfOpen = 2,
+}
+
+impl State {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => State::Open,
+            2 => State::HalfOpen,
+            _ => State::Closed,
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    settings: Option<BreakerSettings>,
+    endpoint: String,
+    state: AtomicU8,
+    failures: AtomicU32,
+    opened_at: Mutex<Instant>,
+    state_gauge: stats::GaugeVec,
+}
+
+impl CircuitBreaker {
+    /// Create a closed breaker for an endpoint. Without settings the breaker
+    /// never opens. The state is exported in the `breaker_state` gauge,
+    /// labeled by endpoint, as 0 for closed, 1 for open and 2 for half-open.
+    pub fn new(settings: Option<BreakerSettings>, stats: &stats::Scope, endpoint: &str) -> Self {
+        let state_gauge = stats.gauge_vec("breaker_state", &["endpoint"]).unwrap();
+        if settings.is_some() {
+            state_gauge.set(&[endpoint], State::Closed as u8 as f64);
+        }
+        CircuitBreaker {
+            settings,
+            endpoint: endpoint.to_owned(),
+            state: AtomicU8::new(State::Closed as u8),
+            failures: AtomicU32::new(0),
+            opened_at: Mutex::new(Instant::now()),
+            state_gauge,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn transition(&self, from: State, to: State) -> bool {
+        let moved = self
+            .state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if moved {
+            self.state_gauge.set(&[&self.endpoint], to as u8 as f64);
+        }
+        moved
+    }
+
+    /// Whether lines should be queued for the endpoint. An open circuit
+    /// which has waited long enough goes half-open and lets lines through.
+    pub fn allow(&self, now: Instant) -> bool {
+        let settings = match (&self.settings, self.state()) {
+            (Some(settings), State::Open) => settings,
+            _ => return true,
+        };
+        let opened_at = *self.opened_at.lock();
+        if now.saturating_duration_since(opened_at) < settings.open_for {
+            return false;
+        }
+        if self.transition(State::Open, State::HalfOpen) {
+            info!("circuit for {} half-open, probing", self.endpoint);
+        }
+        true
+    }
+
+    /// Record a successful send, closing the circuit.
+    pub fn success(&self) {
+        if self.settings.is_none() || self.failures.swap(0, Ordering::AcqRel) == 0 {
+            return;
+        }
+        // Lines queued before the circuit opened can still get through, so
+        // an open circuit is closed as well as a half-open one
+        if self.transition(State::HalfOpen, State::Closed)
+            || self.transition(State::Open, State::Closed)
+        {
+            info!("circuit for {} closed", self.endpoint);
+        }
+    }
+
+    /// Record a failed send, opening the circuit once there have been
+    /// enough consecutive failures or if a half-open probe failed.
+    pub fn failure(&self, now: Instant) {
+        let settings = match &self.settings {
+            Some(settings) => settings,
+            None => return,
+        };
+        let failures = self
+            .failures
+            .fetch_add(1, Ordering::AcqRel)
+            .saturating_add(1);
+        let from = match self.state() {
+            State::Closed if failures >= settings.failures => State::Closed,
+            State::HalfOpen => State::HalfOpen,
+            _ => return,
+        };
+        *self.opened_at.lock() = now;
+        if self.transition(from, State::Open) {
+            warn!(
+                "circuit for {} open after {} consecutive failures",
+                self.endpoint, failures
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_breaker() {
+        let scope = crate::stats::Collector::default().scope("test");
+        let settings = BreakerSettings {
+            failures: 3,
+            open_for: Duration::from_secs(10),
+        };
+        let breaker = CircuitBreaker::new(Some(settings), &scope, "a:1");
+        let gauge = scope.gauge_vec("breaker_state", &["endpoint"]).unwrap();
+        let start = Instant::now();
+
+        breaker.failure(start);
+        breaker.failure(start);
+        breaker.success();
+        breaker.failure(start);
+        breaker.failure(start);
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.allow(start));
+        breaker.failure(start);
+        assert_eq!(breaker.state(), State::Open);
+        assert_eq!(gauge.get(&["a:1"]), 1_f64);
+        assert!(!breaker.allow(start + Duration::from_secs(5)));
+
+        // A failed probe opens the circuit again, for another full period
+        assert!(breaker.allow(start + Duration::from_secs(10)));
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.failure(start + Duration::from_secs(10));
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.allow(start + Duration::from_secs(15)));
+
+        assert!(breaker.allow(start + Duration::from_secs(20)));
+        breaker.success();
+        assert_eq!(breaker.state(), State::Closed);
+        assert_eq!(gauge.get(&["a:1"]), 0_f64);
+    }
+
+    #[test]
+    fn test_breaker_disabled() {
+        let scope = crate::stats::Collector::default().scope("test");
+        let breaker = CircuitBreaker::new(None, &scope, "a:1");
+        let start = Instant::now();
+        for _ in 0..100 {
+            breaker.failure(start);
+        }
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.allow(start));
+    }
+}
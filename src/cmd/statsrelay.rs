@@ -19,9 +19,17 @@ use tokio::signal::unix::{signal, SignalKind};
 use env_logger::Env;
 use log::{debug, error, info};
 
+use statsrelay::alerts;
 use statsrelay::config;
 use statsrelay::discovery;
+use statsrelay::error::{Categorized, Category};
+#[cfg(feature = "kafka")]
+use statsrelay::kafka_server;
+#[cfg(feature = "otlp")]
+use statsrelay::otlp_server;
 use statsrelay::processors;
+use statsrelay::shard;
+use statsrelay::shutdown;
 use statsrelay::stats;
 use statsrelay::statsd_server;
 use statsrelay::{admin, config::Config};
@@ -42,13 +50,48 @@ struct Options {
     pub version: bool,
 }
 
+/// Failure from any of the listeners run by the server.
+#[derive(thiserror::Error, Debug)]
+enum ServerError {
+    #[error(transparent)]
+    Statsd(#[from] statsd_server::Error),
+    #[cfg(feature = "otlp")]
+    #[error(transparent)]
+    Otlp(#[from] otlp_server::Error),
+    #[cfg(feature = "kafka")]
+    #[error(transparent)]
+    Kafka(#[from] kafka_server::Error),
+}
+
+impl Categorized for ServerError {
+    fn category(&self) -> Category {
+        match self {
+            ServerError::Statsd(e) => e.category(),
+            #[cfg(feature = "otlp")]
+            ServerError::Otlp(e) => e.category(),
+            #[cfg(feature = "kafka")]
+            ServerError::Kafka(e) => e.category(),
+        }
+    }
+}
+
 /// The main server invocation, for a given configuration, options and stats
 /// scope. The server will spawn any listeners, initialize a backend
 /// configuration update loop, as well as register signal handlers.
-async fn server(scope: stats::Scope, config: Config, opts: Options) {
+///
+/// On shutdown, teardown happens in stages: listeners stop accepting and
+/// drain their connections, processors are flushed, backend queues are
+/// drained, and finally the admin server is stopped.
+async fn server(
+    scope: stats::Scope,
+    config: Config,
+    opts: Options,
+    backends: backends::Backends,
+    exclusions: discovery::Exclusions,
+    admin: Option<admin::AdminServer>,
+) -> anyhow::Result<()> {
     let backend_reloads = scope.counter("backend_reloads").unwrap();
     let config_load_failures = scope.counter("backend_reloads_failure").unwrap();
-    let backends = backends::Backends::new(scope.scope("backends"));
 
     // Load processors
     if let Some(processors) = config.processors.as_ref() {
@@ -58,6 +101,16 @@ async fn server(scope: stats::Scope, config: Config, opts: Options) {
     }
 
     let (sender, tripwire) = Tripwire::new();
+    let shutdown_config = config.shutdown.clone().unwrap_or_default();
+
+    if let Some(alerts) = config.alerts.as_ref() {
+        info!("evaluating {} alert rules", alerts.rules.len());
+        tokio::spawn(alerts::run(
+            scope.scope("alerts"),
+            alerts.clone(),
+            tripwire.clone(),
+        ));
+    }
     let mut run: FuturesUnordered<_> = config
         .statsd
         .servers
@@ -71,11 +124,58 @@ async fn server(scope: stats::Scope, config: Config, opts: Options) {
                     server_config.clone(),
                     backends.clone(),
                 )
-                .map(|_| name)
+                .map(|result| (name, result.map_err(ServerError::from)))
+                .boxed()
             }
         })
         .collect();
 
+    if let Some(otlp) = config.otlp.as_ref() {
+        #[cfg(feature = "otlp")]
+        for (server_name, server_config) in otlp.servers.iter() {
+            let name = server_name.clone();
+            run.push(
+                otlp_server::run(
+                    scope.scope("otlp_server").scope(server_name),
+                    tripwire.clone(),
+                    server_config.clone(),
+                    backends.clone(),
+                )
+                .map(|result| (name, result.map_err(ServerError::from)))
+                .boxed(),
+            );
+        }
+        #[cfg(not(feature = "otlp"))]
+        if !otlp.servers.is_empty() {
+            anyhow::bail!(
+                "otlp servers are configured, but statsrelay was built without the otlp feature"
+            );
+        }
+    }
+
+    if let Some(kafka) = config.kafka.as_ref() {
+        #[cfg(feature = "kafka")]
+        for (server_name, server_config) in kafka.servers.iter() {
+            let name = server_name.clone();
+            run.push(
+                kafka_server::run(
+                    scope.scope("kafka_server").scope(server_name),
+                    tripwire.clone(),
+                    server_config.clone(),
+                    backends.clone(),
+                )
+                .map(|result| (name, result.map_err(ServerError::from)))
+                .boxed(),
+            );
+        }
+        #[cfg(not(feature = "kafka"))]
+        if !kafka.servers.is_empty() {
+            anyhow::bail!(
+                "kafka servers are configured, but statsrelay was built without the kafka feature"
+            );
+        }
+    }
+
     // Trap ctrl+c and sigterm messages and perform a clean shutdown
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
@@ -99,18 +199,27 @@ async fn server(scope: stats::Scope, config: Config, opts: Options) {
     // SIGHUP will attempt to reload backend configurations as well as any
     // discovery changes.
     let discovery_backends = backends.clone();
+    let discovery_scope = scope.scope("discovery");
+    let update_failures = discovery_scope
+        .counter_vec("update_failures", &["source"])
+        .unwrap();
+    let mut reload_tripwire = tripwire.clone();
     tokio::spawn(async move {
         let mut last_config = config.clone();
         let dconfig = config.discovery.unwrap_or_default();
         let discovery_cache = discovery::Cache::new();
-        let mut discovery_stream =
-            discovery::reflector(discovery_cache.clone(), discovery::as_stream(&dconfig));
+        let mut discovery_stream = discovery::reflector(
+            discovery_cache.clone(),
+            &discovery_scope,
+            discovery::as_stream(&discovery_scope, &dconfig, &exclusions),
+        );
         loop {
             info!("loading configuration and updating backends");
             backend_reloads.inc();
             let config = match load_backend_configs(
                 &discovery_cache,
                 &discovery_backends,
+                &update_failures,
                 opts.config.as_ref(),
             )
             .await
@@ -130,12 +239,16 @@ async fn server(scope: stats::Scope, config: Config, opts: Options) {
             tokio::select! {
                 _ = sighup.recv() => {
                     info!("received sighup");
-                    discovery_stream = discovery::reflector(discovery_cache.clone(), discovery::as_stream(&dconfig));
+                    discovery_stream = discovery::reflector(discovery_cache.clone(), &discovery_scope, discovery::as_stream(&discovery_scope, &dconfig, &exclusions));
                     info!("reloaded discovery stream");
                 }
                 Some(event) = discovery_stream.next() => {
                     info!("updating discovery for map {}", event.0);
                 }
+                _ = &mut reload_tripwire => {
+                    info!("stopping configuration reloads for shutdown");
+                    return;
+                }
             };
         }
     });
@@ -144,12 +257,72 @@ async fn server(scope: stats::Scope, config: Config, opts: Options) {
     let ticker_backends = backends.clone();
     tokio::spawn(backends::ticker(tripwire.clone(), ticker_backends));
 
-    // Wait for the server to finish
-    while let Some(name) = run.next().await {
-        debug!("server {} exited", name)
+    // Serve until shutdown is signalled, or a server fails
+    let mut shutdown_tripwire = tripwire.clone();
+    loop {
+        select! {
+            _ = &mut shutdown_tripwire => break,
+            next = run.next() => match next {
+                None => break,
+                Some((name, Ok(()))) => debug!("server {} exited", name),
+                Some((name, Err(e))) => {
+                    error!("server {} {} error: {}", name, e.category(), e);
+                    return Err(e).with_context(|| format!("server {} failed", name));
+                }
+            }
+        }
+    }
+
+    // Listeners have stopped accepting, wait for their connections to finish
+    shutdown::stage(
+        "draining listeners",
+        shutdown::deadline(
+            shutdown_config.listener_drain_seconds,
+            shutdown::LISTENER_DRAIN_TIMEOUT,
+        ),
+        async {
+            while let Some((name, result)) = run.next().await {
+                if let Err(e) = result {
+                    error!("server {} {} error: {}", name, e.category(), e);
+                }
+                debug!("server {} exited", name);
+            }
+        },
+    )
+    .await;
+
+    let flush_backends = backends.clone();
+    shutdown::stage(
+        "flushing processors",
+        shutdown::deadline(
+            shutdown_config.processor_flush_seconds,
+            shutdown::PROCESSOR_FLUSH_TIMEOUT,
+        ),
+        tokio::task::spawn_blocking(move || {
+            flush_backends.processor_flush(std::time::SystemTime::now())
+        }),
+    )
+    .await;
+
+    shutdown::stage(
+        "draining backend queues",
+        shutdown::deadline(
+            shutdown_config.backend_drain_seconds,
+            shutdown::BACKEND_DRAIN_TIMEOUT,
+        ),
+        backends.drain_backends(),
+    )
+    .await;
+
+    if let Some(admin) = admin {
+        shutdown::stage(
+            "stopping admin server",
+            shutdown::ADMIN_STOP_TIMEOUT,
+            admin.shutdown(),
+        )
+        .await;
     }
-    debug!("forcing processor tick to flush");
-    backends.processor_tick(std::time::SystemTime::now());
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
@@ -175,16 +348,26 @@ fn main() -> anyhow::Result<()> {
     info!("loaded config file {}", opts.config);
     debug!("servers defined: {:?}", config.statsd.servers);
     if opts.config_check {
+        log_key_distribution(&config);
         info!("--config-check-and-exit set, exiting");
         return Ok(());
     }
 
     let collector = stats::Collector::default();
-
-    if let Some(admin) = &config.admin {
-        admin::spawn_admin_server(admin.port, collector.clone());
+    let scope = collector.scope("statsrelay");
+    let backends = backends::Backends::new(scope.scope("backends"));
+    let exclusions = discovery::Exclusions::new();
+
+    let admin = config.admin.as_ref().map(|admin| {
+        let server = admin::spawn_admin_server(
+            admin.clone(),
+            collector.clone(),
+            backends.clone(),
+            exclusions.clone(),
+        );
         info!("spawned admin server on port {}", admin.port);
-    }
+        server
+    });
     debug!("installed metrics receiver");
 
     let mut builder = match opts.threaded {
@@ -195,13 +378,29 @@ fn main() -> anyhow::Result<()> {
     let runtime = builder.enable_all().build().unwrap();
     info!("tokio runtime built, threaded: {}", opts.threaded);
 
-    let scope = collector.scope("statsrelay");
-
-    runtime.block_on(server(scope, config, opts));
+    let result = runtime.block_on(server(scope, config, opts, backends, exclusions, admin));
 
     drop(runtime);
     info!("runtime terminated");
-    Ok(())
+    result
+}
+
+/// Log the share of keys each endpoint of each statsd backend's shard map is
+/// expected to receive
+fn log_key_distribution(config: &Config) {
+    let mut names: Vec<&String> = config.statsd.backends.keys().collect();
+    names.sort();
+    for name in names {
+        let backend = &config.statsd.backends[name];
+        for (endpoint, share) in shard::expected_shares(&backend.shard_map, backend.vnodes) {
+            info!(
+                "backend {} endpoint {} expected key share {:.2}%",
+                name,
+                endpoint,
+                share * 100_f64
+            );
+        }
+    }
 }
 
 /// Load processors from a given config structure and pack them into the given
@@ -209,24 +408,30 @@ fn main() -> anyhow::Result<()> {
 async fn load_processors(
     scope: Scope,
     backends: &backends::Backends,
-    processors: &HashMap<String, config::Processor>,
+    processors: &HashMap<String, config::ProcessorConfig>,
 ) -> anyhow::Result<()> {
     for (name, cp) in processors.iter() {
-        let proc: Box<dyn processors::Processor + Send + Sync> = match cp {
+        let proc: Box<dyn processors::Processor + Send + Sync> = match &cp.processor {
             config::Processor::TagConverter(tc) => {
                 info!("processor tag_converter: {:?}", tc);
-                Box::new(processors::tag::Normalizer::new(tc.route.as_ref()))
+                Box::new(processors::tag::Normalizer::new(
+                    scope.scope(name),
+                    tc.route.as_ref(),
+                ))
             }
             config::Processor::Sampler(sampler) => {
                 info!("processor sampler: {:?}", sampler);
-                Box::new(processors::sampler::Sampler::new(sampler)?)
+                Box::new(processors::sampler::Sampler::new(
+                    scope.scope(name),
+                    sampler,
+                )?)
             }
             config::Processor::Cardinality(cardinality) => {
                 info!("processor cardinality: {:?}", cardinality);
                 Box::new(processors::cardinality::Cardinality::new(
                     scope.scope(name),
                     cardinality,
-                ))
+                )?)
             }
             config::Processor::RegexFilter(regex) => {
                 info!("processor regex_filter: {:?}", regex);
@@ -235,6 +440,147 @@ async fn load_processors(
                     regex,
                 )?)
             }
+            config::Processor::TagFilter(filter) => {
+                info!("processor tag_filter: {:?}", filter);
+                Box::new(processors::tag_filter::TagFilter::new(
+                    scope.scope(name),
+                    filter,
+                ))
+            }
+            config::Processor::TagObfuscator(obfuscator) => {
+                info!("processor tag_obfuscator: {:?}", obfuscator);
+                Box::new(processors::tag_obfuscator::TagObfuscator::new(
+                    scope.scope(name),
+                    obfuscator,
+                ))
+            }
+            #[cfg(feature = "wasm")]
+            config::Processor::Wasm(wasm) => {
+                info!("processor wasm: {:?}", wasm);
+                Box::new(processors::wasm::Wasm::new(scope.scope(name), wasm)?)
+            }
+            #[cfg(not(feature = "wasm"))]
+            config::Processor::Wasm(_) => {
+                anyhow::bail!(
+                    "wasm processor {} is configured, but statsrelay was built without the wasm feature",
+                    name
+                );
+            }
+            config::Processor::Anomaly(anomaly) => {
+                info!("processor anomaly: {:?}", anomaly);
+                Box::new(processors::anomaly::Anomaly::new(
+                    scope.scope(name),
+                    anomaly,
+                )?)
+            }
+            config::Processor::SampleRate(rate) => {
+                info!("processor sample_rate: {:?}", rate);
+                Box::new(processors::sample_rate::SampleRate::new(
+                    scope.scope(name),
+                    rate,
+                )?)
+            }
+            config::Processor::Throttle(throttle) => {
+                info!("processor throttle: {:?}", throttle);
+                Box::new(processors::throttle::Throttle::new(
+                    scope.scope(name),
+                    throttle,
+                )?)
+            }
+            config::Processor::TagValueLimit(limit) => {
+                info!("processor tag_value_limit: {:?}", limit);
+                Box::new(processors::tag_value_limit::TagValueLimit::new(
+                    scope.scope(name),
+                    limit,
+                )?)
+            }
+            config::Processor::NormalizeName(normalize) => {
+                info!("processor normalize_name: {:?}", normalize);
+                Box::new(processors::normalize_name::NormalizeName::new(
+                    scope.scope(name),
+                    normalize,
+                )?)
+            }
+            config::Processor::SetUnion(union) => {
+                info!("processor set_union: {:?}", union);
+                Box::new(processors::set_union::SetUnion::new(
+                    scope.scope(name),
+                    union,
+                )?)
+            }
+            config::Processor::DeadMetric(dead) => {
+                info!("processor dead_metric: {:?}", dead);
+                Box::new(processors::dead_metric::DeadMetric::new(
+                    scope.scope(name),
+                    dead,
+                )?)
+            }
+            #[cfg(feature = "lua")]
+            config::Processor::Lua(lua) => {
+                info!("processor lua: {:?}", lua);
+                Box::new(processors::lua::Lua::new(scope.scope(name), lua)?)
+            }
+            #[cfg(not(feature = "lua"))]
+            config::Processor::Lua(_) => {
+                anyhow::bail!(
+                    "lua processor {} is configured, but statsrelay was built without the lua feature",
+                    name
+                );
+            }
+            config::Processor::Rollup(rollup) => {
+                info!("processor rollup: {:?}", rollup);
+                Box::new(processors::rollup::Rollup::new(scope.scope(name), rollup)?)
+            }
+            config::Processor::Scale(scale) => {
+                info!("processor scale: {:?}", scale);
+                Box::new(processors::scale::Scale::new(scope.scope(name), scale)?)
+            }
+            config::Processor::Clamp(clamp) => {
+                info!("processor clamp: {:?}", clamp);
+                Box::new(processors::clamp::Clamp::new(scope.scope(name), clamp)?)
+            }
+            config::Processor::Delta(delta) => {
+                info!("processor delta: {:?}", delta);
+                Box::new(processors::delta::Delta::new(scope.scope(name), delta)?)
+            }
+            config::Processor::GaugeDedup(dedup) => {
+                info!("processor gauge_dedup: {:?}", dedup);
+                Box::new(processors::gauge_dedup::GaugeDedup::new(
+                    scope.scope(name),
+                    dedup,
+                ))
+            }
+            config::Processor::Splitter(splitter) => {
+                info!("processor splitter: {:?}", splitter);
+                Box::new(processors::splitter::Splitter::new(
+                    scope.scope(name),
+                    splitter,
+                ))
+            }
+            config::Processor::Router(router) => {
+                info!("processor router: {:?}", router);
+                Box::new(processors::router::Router::new(scope.scope(name), router)?)
+            }
+            config::Processor::Rewrite(rewrite) => {
+                info!("processor rewrite: {:?}", rewrite);
+                Box::new(processors::rewrite::Rewrite::new(
+                    scope.scope(name),
+                    rewrite,
+                )?)
+            }
+        };
+        let proc = Box::new(processors::Instrumented::new(&scope.scope(name), proc));
+        let proc: Box<dyn processors::Processor + Send + Sync> = match &cp.workers {
+            Some(workers) => {
+                info!("processor {} workers: {:?}", name, workers);
+                Box::new(processors::worker_pool::WorkerPool::new(
+                    &scope.scope(name),
+                    workers,
+                    backends,
+                    proc,
+                ))
+            }
+            None => proc,
         };
         backends.replace_processor(name.as_str(), proc)?;
     }
@@ -244,6 +590,7 @@ async fn load_processors(
 async fn load_backend_configs(
     discovery_cache: &discovery::Cache,
     backends: &backends::Backends,
+    update_failures: &stats::CounterVec,
     path: &str,
 ) -> anyhow::Result<config::Config> {
     // Check if we have to load the configuration file
@@ -264,8 +611,25 @@ async fn load_backend_configs(
         } else {
             None
         };
-        if let Err(e) = backends.replace_statsd_backend(name, dp, discovery_data.as_ref()) {
+        let migration_data = dp
+            .migration
+            .as_ref()
+            .and_then(|m| m.shard_map_source.as_ref())
+            .and_then(|discovery_name| discovery_cache.get(discovery_name));
+        if let Err(e) = backends.replace_statsd_backend(
+            name,
+            dp,
+            discovery_data.as_ref(),
+            migration_data.as_ref(),
+        ) {
             error!("failed to replace backend index {} error {}", name, e);
+            let sources = dp
+                .shard_map_source
+                .iter()
+                .chain(dp.migration.iter().flat_map(|m| m.shard_map_source.iter()));
+            for source in sources {
+                update_failures.inc(&[source.as_str()]);
+            }
             continue;
         }
     }
@@ -278,6 +642,90 @@ async fn load_backend_configs(
         }
     }
 
+    let prometheus = config
+        .prometheus
+        .as_ref()
+        .map(|p| p.backends.clone())
+        .unwrap_or_default();
+    for (name, pc) in prometheus.iter() {
+        if let Err(e) = backends.replace_prometheus_backend(name, pc) {
+            error!("failed to replace prometheus backend {} error {}", name, e);
+        }
+    }
+    let existing_backends = backends.prometheus_backend_names();
+    let config_backends: HashSet<String> = prometheus.keys().cloned().collect();
+    for remove in existing_backends.difference(&config_backends) {
+        if let Err(e) = backends.remove_prometheus_backend(remove) {
+            error!(
+                "failed to remove prometheus backend {} with error {:?}",
+                remove, e
+            );
+        }
+    }
+
+    let influx = config
+        .influx
+        .as_ref()
+        .map(|i| i.backends.clone())
+        .unwrap_or_default();
+    for (name, ic) in influx.iter() {
+        if let Err(e) = backends.replace_influx_backend(name, ic) {
+            error!("failed to replace influx backend {} error {}", name, e);
+        }
+    }
+    let existing_backends = backends.influx_backend_names();
+    let config_backends: HashSet<String> = influx.keys().cloned().collect();
+    for remove in existing_backends.difference(&config_backends) {
+        if let Err(e) = backends.remove_influx_backend(remove) {
+            error!(
+                "failed to remove influx backend {} with error {:?}",
+                remove, e
+            );
+        }
+    }
+
+    let file = config
+        .file
+        .as_ref()
+        .map(|f| f.backends.clone())
+        .unwrap_or_default();
+    for (name, fc) in file.iter() {
+        if let Err(e) = backends.replace_file_backend(name, fc) {
+            error!("failed to replace file backend {} error {}", name, e);
+        }
+    }
+    let existing_backends = backends.file_backend_names();
+    let config_backends: HashSet<String> = file.keys().cloned().collect();
+    for remove in existing_backends.difference(&config_backends) {
+        if let Err(e) = backends.remove_file_backend(remove) {
+            error!(
+                "failed to remove file backend {} with error {:?}",
+                remove, e
+            );
+        }
+    }
+
+    let null = config
+        .null
+        .as_ref()
+        .map(|n| n.backends.clone())
+        .unwrap_or_default();
+    for (name, nc) in null.iter() {
+        if let Err(e) = backends.replace_null_backend(name, nc) {
+            error!("failed to replace null backend {} error {}", name, e);
+        }
+    }
+    let existing_backends = backends.null_backend_names();
+    let config_backends: HashSet<String> = null.keys().cloned().collect();
+    for remove in existing_backends.difference(&config_backends) {
+        if let Err(e) = backends.remove_null_backend(remove) {
+            error!(
+                "failed to remove null backend {} with error {:?}",
+                remove, e
+            );
+        }
+    }
+
     info!("backends reloaded");
     Ok(config)
 }
@@ -0,0 +1,127 @@
+//! Stream compression for statsd traffic relayed between statsrelay tiers.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+use crate::config::Compression;
+
+/// A stream whose reads are decompressed according to the configured
+/// compression, and whose writes pass through to the underlying stream
+/// uncompressed.
+pub enum Decompress<T> {
+    Plain(T),
+    Gzip(GzipDecoder<BufReader<T>>),
+    Zstd(ZstdDecoder<BufReader<T>>),
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Decompress<T> {
+    pub fn new(inner: T, compression: Option<Compression>) -> Self {
+        match compression {
+            None => Decompress::Plain(inner),
+            Some(Compression::Gzip) => {
+                let mut decoder = GzipDecoder::new(BufReader::new(inner));
+                // Senders may start a new gzip member on each flush
+                decoder.multiple_members(true);
+                Decompress::Gzip(decoder)
+            }
+            Some(Compression::Zstd) => {
+                let mut decoder = ZstdDecoder::new(BufReader::new(inner));
+                decoder.multiple_members(true);
+                Decompress::Zstd(decoder)
+            }
+        }
+    }
+
+    fn inner(&mut self) -> Pin<&mut (dyn AsyncWrite + Unpin)> {
+        match self {
+            Decompress::Plain(inner) => Pin::new(inner),
+            Decompress::Gzip(decoder) => Pin::new(decoder.get_mut()),
+            Decompress::Zstd(decoder) => Pin::new(decoder.get_mut()),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for Decompress<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Decompress::Plain(inner) => Pin::new(inner).poll_read(cx, buf),
+            Decompress::Gzip(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Decompress::Zstd(decoder) => Pin::new(decoder).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Decompress<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().inner().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner().poll_shutdown(cx)
+    }
+}
+
+/// Wrap a stream so everything written to it is compressed, for a
+/// `Decompress` on the other end. Each flush writes out everything compressed
+/// so far, so the stream can be decompressed as it arrives.
+pub fn compress<'a, T: AsyncWrite + Send + Unpin + 'a>(
+    inner: T,
+    compression: Compression,
+) -> Box<dyn AsyncWrite + Send + Unpin + 'a> {
+    match compression {
+        Compression::Gzip => Box::new(GzipEncoder::new(inner)),
+        Compression::Zstd => Box::new(ZstdEncoder::new(inner)),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn roundtrip(compression: Compression) {
+        let (client, server) = tokio::io::duplex(4096);
+        let send = async move {
+            let mut client = client;
+            let mut encoder = compress(&mut client, compression);
+            encoder.write_all(b"a:1|c\n").await.unwrap();
+            encoder.flush().await.unwrap();
+            encoder.write_all(b"b:1|c\n").await.unwrap();
+            encoder.shutdown().await.unwrap();
+            drop(encoder);
+            client.read_to_end(&mut Vec::new()).await.unwrap();
+        };
+        let receive = async move {
+            let mut stream = Decompress::new(server, Some(compression));
+            let mut lines = vec![0_u8; 12];
+            stream.read_exact(&mut lines).await.unwrap();
+            stream.write_all(b"ok\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+            lines
+        };
+        let (_, lines) = tokio::join!(send, receive);
+        assert_eq!(lines, b"a:1|c\nb:1|c\n");
+    }
+
+    #[tokio::test]
+    async fn test_decompress() {
+        roundtrip(Compression::Gzip).await;
+        roundtrip(Compression::Zstd).await;
+    }
+}
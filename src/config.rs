@@ -1,13 +1,23 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::{AsRef, TryFrom, TryInto};
 use std::fmt;
 use thiserror::Error;
 
+use crate::error::{Categorized, Category};
+use crate::statsd_proto::Type;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RouteType {
     Statsd,
     Processor,
+    Prometheus,
+    Influx,
+    File,
+    Null,
+    /// A pipeline of processors, as their names separated by commas. Only
+    /// found in a loaded config before it is expanded into processor routes.
+    Pipeline,
 }
 
 impl TryFrom<&str> for RouteType {
@@ -17,6 +27,11 @@ impl TryFrom<&str> for RouteType {
         match value {
             "statsd" => Ok(RouteType::Statsd),
             "processor" => Ok(RouteType::Processor),
+            "prometheus" => Ok(RouteType::Prometheus),
+            "influx" => Ok(RouteType::Influx),
+            "file" => Ok(RouteType::File),
+            "null" => Ok(RouteType::Null),
+            "pipeline" => Ok(RouteType::Pipeline),
             _ => Err(Error::UnknownRouteType(value.to_string())),
         }
     }
@@ -27,6 +42,11 @@ impl From<&RouteType> for &str {
         match t {
             RouteType::Statsd => "statsd",
             RouteType::Processor => "processor",
+            RouteType::Prometheus => "prometheus",
+            RouteType::Influx => "influx",
+            RouteType::File => "file",
+            RouteType::Null => "null",
+            RouteType::Pipeline => "pipeline",
         }
     }
 }
@@ -77,27 +97,251 @@ impl Serialize for Route {
     }
 }
 
+/// What part of each line is hashed to choose its shard
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ShardKey {
+    #[default]
+    Name,
+    NameAndTags,
+    /// The value of a DogStatsD tag, such as `host`
+    Tag(String),
+}
+
+impl TryFrom<&str> for ShardKey {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.split_once(':') {
+            None if value == "name" => Ok(ShardKey::Name),
+            None if value == "name_and_tags" => Ok(ShardKey::NameAndTags),
+            Some(("tag", key)) if !key.is_empty() => Ok(ShardKey::Tag(key.to_owned())),
+            _ => Err(Error::MalformedShardKey(value.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for ShardKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardKey::Name => write!(f, "name"),
+            ShardKey::NameAndTags => write!(f, "name_and_tags"),
+            ShardKey::Tag(key) => write!(f, "tag:{}", key),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.as_str().try_into().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for ShardKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
 pub mod processor {
     use super::*;
 
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum TimerOutput {
+        /// Re-emit a reservoir sample of each timer's values
+        #[default]
+        Reservoir,
+        /// Emit `.count`, `.sum`, `.upper`, `.lower` and `.mean` metrics
+        /// for each timer, as a statsd server would
+        Aggregates,
+        /// Both the reservoir sample and the aggregates
+        Both,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum KeyOverflow {
+        /// Pass through lines of metrics which are not already tracked
+        #[default]
+        PassThrough,
+        /// Emit and forget the least recently seen metric to track another
+        Evict,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SamplerSets {
+        /// Members held exactly for each set, after which its count of
+        /// distinct members is estimated
+        pub exact_limit: Option<usize>,
+        /// Most sets aggregated each window, after which further sets are
+        /// passed through
+        pub max_sets: Option<usize>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SamplerDebugTimers {
+        /// Regex of the names of timers whose raw samples are emitted
+        pub name: String,
+        /// Routes the raw samples are sent to, as well as the sampler's
+        /// usual output
+        pub route: Vec<Route>,
+    }
+
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct Sampler {
         pub window: u32,
+        /// Flush at multiples of the window since the Unix epoch, such as
+        /// :00, :10 and :20 for a 10 second window
+        #[serde(default)]
+        pub align_to_window: bool,
+        /// Most time each aligned flush is delayed by, a random offset fixed
+        /// for each instance so instances do not all flush at once
+        pub flush_jitter_ms: Option<u64>,
         pub timer_reservoir_size: Option<u32>,
+        /// What is emitted for timers each window
+        #[serde(default)]
+        pub timer_output: TimerOutput,
+        /// Percentiles of each timer's values emitted each window, as
+        /// `.p50` or `.p99_9` metrics
+        #[serde(default)]
+        pub timer_percentiles: Vec<f64>,
+        /// Aggregate sets into a gauge of their distinct members each window,
+        /// instead of passing them through
+        pub sets: Option<SamplerSets>,
+        /// Re-emit the last value of each direct gauge each window, as well
+        /// as passing them through as they arrive
+        #[serde(default)]
+        pub reemit_direct_gauges: bool,
+        /// Most metrics of each type aggregated each window
+        pub max_keys: Option<usize>,
+        /// What happens to lines of further metrics once `max_keys` are
+        /// aggregated
+        #[serde(default)]
+        pub key_overflow: KeyOverflow,
+        /// Number of separately locked maps each type's metrics are spread
+        /// over, so lines can be recorded concurrently
+        pub shards: Option<usize>,
+        /// Number of sub-windows each window is aggregated in, 1 by default.
+        /// Lines are aggregated into the sub-window they arrive in, and the
+        /// sub-windows of a window merged as it is flushed, so lines arriving
+        /// once a window has ended wait for the next flush. `max_keys` caps
+        /// the aggregates held across all sub-windows yet to be flushed.
+        pub sub_windows: Option<u32>,
+        /// Set gauges to the values of lines written with a leading `+` or
+        /// `-`, as earlier versions did, rather than changing the gauge by
+        /// them as statsd does
+        #[serde(default)]
+        pub overwrite_gauges: bool,
+        /// Also emit the raw reservoir samples of some timers each window,
+        /// to inspect the shape of their distributions
+        pub debug_timers: Option<SamplerDebugTimers>,
 
+        #[serde(default)]
         pub route: Vec<Route>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct TagConverter {
+        #[serde(default)]
         pub route: Vec<Route>,
     }
 
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct CardinalityTagKeys {
+        /// Maximum number of distinct tag keys to track
+        pub max_keys: usize,
+        /// Number of tag keys with the most distinct values to export
+        pub top: Option<usize>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct CardinalityTopOffenders {
+        /// Number of leading `.` separated components of a metric name
+        /// offenders are grouped by, 2 by default
+        pub prefix_depth: Option<usize>,
+        /// Most prefixes counted at once. Counts are approximate once more
+        /// prefixes than this are seen in a window.
+        pub capacity: Option<usize>,
+        /// Number of prefixes with the most new metrics logged and reported
+        pub top: Option<usize>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct CardinalityTagValues {
+        /// Tag keys whose distinct values are limited, such as `user_id`
+        pub keys: Vec<String>,
+        /// Most distinct values of each key passed each window
+        pub max_values: usize,
+        /// Value further distinct values are rewritten to, `__overflow` by
+        /// default
+        pub overflow_value: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum CardinalityAction {
+        /// Drop metrics past the limit
+        #[default]
+        Drop,
+        /// Pass metrics past the limit on with their tags removed
+        StripTags,
+        /// Send metrics past the limit to these routes instead, such as a
+        /// quarantine backend for inspection
+        RouteTo(Vec<Route>),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct CardinalityLimit {
+        /// Metric name prefix the limit applies to, such as `app1.`
+        pub prefix: String,
+        /// Most distinct metrics with the prefix passed each window
+        pub limit: usize,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct CardinalityAllow {
+        /// Regexes of metric names which are never limited
+        #[serde(default)]
+        pub names: Vec<String>,
+        /// Metric name prefixes which are never limited
+        #[serde(default)]
+        pub prefixes: Vec<String>,
+        /// Combinations of `name:value` tags. Metrics carrying every tag of
+        /// any one combination are never limited.
+        #[serde(default)]
+        pub tags: Vec<Vec<String>>,
+    }
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Cardinality {
         pub size_limit: usize,
         pub rotate_after_seconds: u64,
         pub buckets: usize,
+        pub tag_keys: Option<CardinalityTagKeys>,
+        /// Limit the distinct values of tag keys, rewriting tags past the
+        /// limit rather than dropping their metric
+        pub tag_values: Option<CardinalityTagValues>,
+        /// Track the name prefixes adding the most new metrics each window,
+        /// logged when the window ends and served on the admin server
+        pub top_offenders: Option<CardinalityTopOffenders>,
+        /// Limits for metrics by name prefix. A metric is counted against
+        /// the first limit its name matches, and against `size_limit` when
+        /// it matches none.
+        #[serde(default)]
+        pub limits: Vec<CardinalityLimit>,
+        /// Metrics passed without being counted against any limit
+        pub allow: Option<CardinalityAllow>,
+        /// What happens to new metrics past the limit
+        #[serde(default)]
+        pub action: CardinalityAction,
+        #[serde(default)]
         pub route: Vec<Route>,
     }
 
@@ -105,8 +349,474 @@ pub mod processor {
     pub struct RegexFilter {
         pub remove: Option<Vec<String>>,
         pub allow: Option<Vec<String>>,
+        /// Filter expression metrics must match to be kept, such as
+        /// `name =~ "^api\." && tags.env == "prod"`
+        pub allow_filter: Option<String>,
+        /// Filter expression of metrics removed
+        pub remove_filter: Option<String>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct TagFilter {
+        /// Tag keys kept, removing all others
+        pub keep: Option<Vec<String>>,
+        /// Tag keys removed
+        pub remove: Option<Vec<String>>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ObfuscationMethod {
+        /// Replace values with a hex encoded hash of the value and `hash_key`
+        #[default]
+        Hash,
+        /// Cut values to their first `length` bytes
+        Truncate,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct TagObfuscator {
+        /// Tag keys whose values are obfuscated
+        pub keys: Vec<String>,
+        #[serde(default)]
+        pub method: ObfuscationMethod,
+        /// Secret hashed with each value, so hashes can not be matched by
+        /// hashing known identifiers. Required to hash values.
+        pub hash_key: Option<String>,
+        /// Length values are truncated to, or hashes shortened to
+        pub length: Option<usize>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum OutOfBounds {
+        /// Replace values outside the bounds with the nearest bound
+        #[default]
+        Clamp,
+        /// Drop lines with values outside the bounds
+        Drop,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Wasm {
+        /// Path of the WASM module transforming each metric
+        pub module: String,
+        /// Fuel, roughly a number of instructions, each call of the module
+        /// may use before it is stopped, 1,000,000 by default
+        pub fuel: Option<u64>,
+        /// Alternative routes a module can send metrics to, by name
+        #[serde(default)]
+        pub routes: HashMap<String, Vec<Route>>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Lua {
+        /// Path of the Lua script transforming each metric
+        pub script: String,
+        /// Instructions each call of the script may run before it is
+        /// stopped, 1,000,000 by default
+        pub max_instructions: Option<u32>,
+        /// Alternative routes a script can send metrics to, by name
+        #[serde(default)]
+        pub routes: HashMap<String, Vec<Route>>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Rollup {
+        /// Tag keys removed from metrics before they are aggregated
+        pub strip_tags: Vec<String>,
+        /// How metrics are aggregated, as for a sampler processor. Rollups
+        /// are sent to the sampler's route.
+        pub sampler: Sampler,
+        /// Routes for the original metrics, with all their tags
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ScaleRule {
+        /// Regex of the names of metrics the rule applies to. The rule
+        /// applies to all names when not set.
+        pub name: Option<String>,
+        /// Metric types the rule applies to, by name such as `timer`. The
+        /// rule applies to all types when empty.
+        #[serde(default)]
+        pub types: Vec<String>,
+        /// Factor values are multiplied by, such as 0.000001 to convert
+        /// nanoseconds to milliseconds
+        pub factor: f64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Scale {
+        /// Rules tried in order, scaling values by the first which applies
+        pub rules: Vec<ScaleRule>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ClampRule {
+        /// Regex of the names of metrics the rule applies to. The rule
+        /// applies to all names when not set.
+        pub name: Option<String>,
+        /// Metric types the rule applies to, by name such as `timer`. The
+        /// rule applies to all types when empty.
+        #[serde(default)]
+        pub types: Vec<String>,
+        pub min: Option<f64>,
+        pub max: Option<f64>,
+        #[serde(default)]
+        pub action: OutOfBounds,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Clamp {
+        /// Rules tried in order, bounding values by the first which applies
+        pub rules: Vec<ClampRule>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DeltaOutput {
+        /// The increase since the previous value
+        #[default]
+        Delta,
+        /// The increase since the previous value per second
+        Rate,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Delta {
+        /// Regex of the names of gauges holding ever increasing totals. All
+        /// gauges are converted when not set.
+        pub filter: Option<String>,
+        #[serde(default)]
+        pub output: DeltaOutput,
+        /// Time after which the last value of a total no longer seen is
+        /// forgotten, 600 seconds by default
+        pub expire_seconds: Option<u64>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct GaugeDedup {
+        /// Longest time a gauge repeating the same value is suppressed
+        /// before it is passed on again
+        pub heartbeat_seconds: u64,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    #[serde(rename_all = "snake_case")]
+    pub enum AnomalyAction {
+        /// Add a tag to anomalous metrics, such as `anomaly:true`
+        Tag(String),
+        /// Send anomalous metrics to these routes instead
+        RouteTo(Vec<Route>),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Anomaly {
+        /// Regex of the names of metrics checked. All names are checked when
+        /// not set.
+        pub name: Option<String>,
+        /// Metric types checked, by name such as `timer`. All types but sets
+        /// are checked when empty.
+        #[serde(default)]
+        pub types: Vec<String>,
+        /// Weight of each value in the moving average and variance, between
+        /// 0 and 1. Higher weights follow recent values more closely.
+        pub alpha: f64,
+        /// Standard deviations from the moving average past which a value is
+        /// anomalous
+        pub z_score: f64,
+        /// Values of a metric seen before its values are checked, 10 by
+        /// default
+        pub min_samples: Option<u64>,
+        /// Most metrics tracked at once
+        pub capacity: usize,
+        /// Seconds after which metrics which have not been seen are
+        /// forgotten, 600 seconds by default
+        pub expire_seconds: Option<u64>,
+        pub action: AnomalyAction,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct DeadMetric {
+        /// Regex of the names of metrics watched for. All names are watched
+        /// when not set.
+        pub name: Option<String>,
+        /// Metric types watched for, by name such as `gauge`. All types are
+        /// watched when empty.
+        #[serde(default)]
+        pub types: Vec<String>,
+        /// Length of a window in seconds
+        pub window: u32,
+        /// Whole windows a metric must be missing for before it is reported
+        pub missing_windows: u32,
+        /// Most metrics watched at once. Metrics first seen while this many
+        /// are watched are not watched.
+        pub capacity: usize,
+        /// Name of the counter reporting a missing metric, `metric.missing`
+        /// by default. It is tagged with `name` and the missing metric's
+        /// tags, less any `name` tag of its own.
+        pub absence_name: Option<String>,
+        /// Routes for the counters reporting missing metrics
+        pub absence_route: Vec<Route>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Split {
+        /// Percentage of metrics sent to the routes of this split
+        pub percent: f64,
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Splitter {
+        /// Splits of metrics, each sent to its own routes. Metrics are
+        /// assigned to splits by a hash of their name, type and tags, so
+        /// each metric is always sent to the same split.
+        pub splits: Vec<Split>,
+        /// Routes for metrics in no split, when splits total less than 100
+        /// percent
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    /// Most rules of a router in `all_matches` mode, as the routes of each
+    /// combination of rules are kept
+    pub const MAX_ALL_MATCHES_RULES: usize = 12;
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum RouterMode {
+        /// Route metrics by the first rule they match
+        #[default]
+        FirstMatch,
+        /// Route metrics to the routes of every rule they match
+        AllMatches,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct RouterRule {
+        /// Regex matched against metric names
+        pub name: Option<String>,
+        /// Metric types matched, by name such as `counter` or `timer`
+        #[serde(default)]
+        pub types: Vec<String>,
+        /// Tags metrics must carry, as `name` for any value or `name:value`
+        #[serde(default)]
+        pub tags: Vec<String>,
+        /// Filter expression metrics must match, such as
+        /// `name =~ "^api\." && tags.env == "prod" && type == counter`
+        pub filter: Option<String>,
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Router {
+        /// Rules tried in order. A metric matches a rule when it matches all
+        /// of the rule's conditions.
+        pub rules: Vec<RouterRule>,
+        #[serde(default)]
+        pub mode: RouterMode,
+        /// Routes for metrics matching no rule
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct RewriteTag {
+        pub name: String,
+        /// Tag value, which may refer to captures of the rule's regex as
+        /// `$1` or `${name}`
+        pub value: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct RewriteRule {
+        /// Regex matched against metric names
+        #[serde(rename = "match")]
+        pub pattern: String,
+        /// Replacement for the matched part of the name, which may refer to
+        /// captures as `$1` or `${name}`
+        pub replace: String,
+        /// Tags added to rewritten metrics, replacing existing tags of the
+        /// same name
+        #[serde(default)]
+        pub tags: Vec<RewriteTag>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Rewrite {
+        /// Rules tried in order, rewriting names with the first rule which
+        /// matches
+        pub rules: Vec<RewriteRule>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SampleRateAction {
+        /// Divide the values of counters by their rate and remove it, so
+        /// they are sent on as if unsampled. Other lines keep their rate.
+        #[default]
+        Scale,
+        /// Remove the rate, keeping values as they are
+        Strip,
+        /// Keep valid rates as they are
+        Keep,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum InvalidSampleRate {
+        /// Drop lines with invalid rates
+        #[default]
+        Drop,
+        /// Remove invalid rates, keeping values as they are
+        Strip,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SampleRate {
+        /// Regex of the names of metrics whose rates are normalized. All
+        /// names are normalized when not set.
+        pub name: Option<String>,
+        /// Metric types normalized, by name such as `counter`. All types
+        /// are normalized when empty.
+        #[serde(default)]
+        pub types: Vec<String>,
+        #[serde(default)]
+        pub action: SampleRateAction,
+        /// What happens to lines whose rate is not a number above 0 and at
+        /// most 1
+        #[serde(default)]
+        pub invalid: InvalidSampleRate,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SetUnion {
+        /// Seconds members of sets are accumulated for before the union of
+        /// each set is emitted
+        pub window: u32,
+        /// Regex of the names of sets accumulated. All sets are accumulated
+        /// when not set.
+        pub name: Option<String>,
+        /// Most members held across all sets each window, after which lines
+        /// of further members are passed through
+        pub max_members: usize,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct NormalizeName {
+        /// Lowercase names, true by default
+        #[serde(default = "default_true")]
+        pub lowercase: bool,
+        /// Character replacing those not allowed in names, `_` by default.
+        /// Names may hold ASCII letters and digits, `_`, `-` and `.`.
+        pub replacement: Option<char>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct TagValueLimit {
+        /// Tag keys whose distinct values are limited, such as `user_id`
+        pub keys: Vec<String>,
+        /// Most distinct values of each key passed
+        pub max_values: usize,
+        /// Value further distinct values are rewritten to, `other` by
+        /// default
+        pub overflow_value: Option<String>,
+        /// Seconds after which the values seen are forgotten. Values are
+        /// kept until restarted when not set.
+        pub window_seconds: Option<u64>,
+        #[serde(default)]
+        pub route: Vec<Route>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ThrottleAction {
+        /// Drop lines past a tenant's budget
+        #[default]
+        Drop,
+        /// Send lines past a tenant's budget to these routes instead
+        RouteTo(Vec<Route>),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Throttle {
+        /// Filter expression of the lines throttled. Other lines are passed
+        /// on without counting against any budget.
+        pub filter: Option<String>,
+        /// Tag whose value is the tenant of a line, such as `team`
+        pub tag: Option<String>,
+        /// Number of leading `.` separated components of a metric name used
+        /// as its tenant when it has no tenant tag, 1 by default
+        pub prefix_depth: Option<usize>,
+        /// Lines passed per second for each tenant
+        pub lines_per_second: f64,
+        /// Most lines a tenant can send at once after being idle, a second
+        /// of lines by default
+        pub burst: Option<f64>,
+        /// Lines passed per second for particular tenants, overriding
+        /// `lines_per_second`
+        #[serde(default)]
+        pub tenants: HashMap<String, f64>,
+        /// Most tenants given their own budget. Further tenants share one
+        /// budget, and are counted as `__overflow`.
+        pub max_tenants: usize,
+        #[serde(default)]
+        pub action: ThrottleAction,
+        #[serde(default)]
         pub route: Vec<Route>,
     }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct WorkerPool {
+        /// Number of worker threads, 1 by default
+        pub threads: Option<usize>,
+        /// Most lines queued for the workers. Lines arriving while the queue
+        /// is full are dropped.
+        pub queue_size: usize,
+    }
+}
+
+/// A processor, and how it is run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessorConfig {
+    #[serde(flatten)]
+    pub processor: Processor,
+    /// Run the processor on its own worker threads, fed by a queue, rather
+    /// than on the thread each line arrives on
+    pub workers: Option<processor::WorkerPool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -116,6 +826,64 @@ pub enum Processor {
     TagConverter(processor::TagConverter),
     Cardinality(processor::Cardinality),
     RegexFilter(processor::RegexFilter),
+    Rewrite(processor::Rewrite),
+    TagFilter(processor::TagFilter),
+    TagObfuscator(processor::TagObfuscator),
+    Router(processor::Router),
+    Splitter(processor::Splitter),
+    GaugeDedup(processor::GaugeDedup),
+    Delta(processor::Delta),
+    Clamp(processor::Clamp),
+    Scale(processor::Scale),
+    Rollup(processor::Rollup),
+    Wasm(processor::Wasm),
+    Lua(processor::Lua),
+    DeadMetric(processor::DeadMetric),
+    Anomaly(processor::Anomaly),
+    SampleRate(processor::SampleRate),
+    Throttle(processor::Throttle),
+    TagValueLimit(processor::TagValueLimit),
+    NormalizeName(processor::NormalizeName),
+    SetUnion(processor::SetUnion),
+}
+
+impl Processor {
+    /// Routes the processor sends the metrics it is given on to
+    pub fn route_mut(&mut self) -> &mut Vec<Route> {
+        match self {
+            Processor::Sampler(p) => &mut p.route,
+            Processor::TagConverter(p) => &mut p.route,
+            Processor::Cardinality(p) => &mut p.route,
+            Processor::RegexFilter(p) => &mut p.route,
+            Processor::Rewrite(p) => &mut p.route,
+            Processor::TagFilter(p) => &mut p.route,
+            Processor::TagObfuscator(p) => &mut p.route,
+            Processor::Router(p) => &mut p.route,
+            Processor::Splitter(p) => &mut p.route,
+            Processor::GaugeDedup(p) => &mut p.route,
+            Processor::Delta(p) => &mut p.route,
+            Processor::Clamp(p) => &mut p.route,
+            Processor::Scale(p) => &mut p.route,
+            Processor::Rollup(p) => &mut p.route,
+            Processor::Wasm(p) => &mut p.route,
+            Processor::Lua(p) => &mut p.route,
+            Processor::DeadMetric(p) => &mut p.route,
+            Processor::Anomaly(p) => &mut p.route,
+            Processor::SampleRate(p) => &mut p.route,
+            Processor::Throttle(p) => &mut p.route,
+            Processor::TagValueLimit(p) => &mut p.route,
+            Processor::NormalizeName(p) => &mut p.route,
+            Processor::SetUnion(p) => &mut p.route,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendProtocol {
+    #[default]
+    Tcp,
+    Udp,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -128,99 +896,799 @@ pub struct StatsdBackendConfig {
     pub input_blocklist: Option<String>,
     pub input_filter: Option<String>,
     pub max_queue: Option<u32>,
+    #[serde(default)]
+    pub protocol: BackendProtocol,
+    /// Largest datagram a UDP backend sends
+    pub max_datagram_bytes: Option<usize>,
+    /// Size in bytes at which queued lines are written out
+    pub max_batch_bytes: Option<usize>,
+    /// Longest time lines are held waiting for a batch to fill
+    pub flush_interval_ms: Option<u64>,
+    /// Complete batches held waiting to be written to each endpoint
+    pub max_in_flight: Option<usize>,
+    /// Waiting batches written to a stream before it is flushed
+    pub pipeline_batches: Option<usize>,
+    /// Delay before the first attempt to reconnect to a failed endpoint
+    pub reconnect_initial_ms: Option<u64>,
+    /// Longest delay between attempts to reconnect
+    pub reconnect_max_ms: Option<u64>,
+    /// How long endpoints removed by a reload keep sending their queue
+    pub drain_timeout_ms: Option<u64>,
+    /// Connect to TCP and unix stream endpoints over TLS
+    pub tls: Option<TlsClientConfig>,
+    /// Spill lines to disk while an endpoint's queue is full
+    pub spill: Option<SpillConfig>,
+    /// Consecutive send failures which open the circuit for an endpoint
+    pub breaker_failures: Option<u32>,
+    /// How long a circuit stays open before probing the endpoint again
+    pub breaker_open_ms: Option<u64>,
+    /// Actively check endpoints, ejecting unhealthy ones from the ring
+    pub health_check: Option<HealthCheckConfig>,
+    /// Cap on the rate lines are sent to each endpoint
+    pub rate_limit: Option<BackendRateLimit>,
+    /// Compress streams to endpoints, which must be statsrelay servers with
+    /// the same compression
+    pub compression: Option<Compression>,
+    /// Protocol lines are sent to endpoints in
+    #[serde(default)]
+    pub format: BackendFormat,
+    /// Options for the graphite format
+    pub graphite: Option<GraphiteConfig>,
+    /// How tags are written, instead of as they were received
+    pub tag_format: Option<TagFormat>,
+    /// Convert tags as a `tag_converter` processor would, before any other
+    /// handling by the backend
+    pub convert_tags: Option<ConvertTags>,
+    /// Points each endpoint is placed at on a consistent hash ring, instead
+    /// of choosing endpoints by hash modulo their number
+    pub vnodes: Option<u32>,
+    /// Number of distinct endpoints each line is sent to
+    pub replication: Option<u32>,
+    /// Time over which endpoints added to the shard map ramp up to their
+    /// full share of lines
+    pub slow_start_ms: Option<u64>,
+    /// What part of each line is hashed to choose its endpoint
+    #[serde(default)]
+    pub shard_key: ShardKey,
+    /// Percentage of metric names, chosen by hash, which are sent on to
+    /// this backend
+    pub shadow_percent: Option<f64>,
+    /// Percentage of metrics, chosen by hash of their name, type and tags,
+    /// which are sent on to this backend with their sample rate scaled down
+    pub sample_percent: Option<f64>,
+    /// A shard map being migrated to, which a share of keys are cut over to
+    pub migration: Option<MigrationConfig>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct StatsdServerConfig {
-    pub bind: String,
-    pub socket: Option<String>,
-    pub read_buffer: Option<usize>,
-    pub route: Vec<Route>,
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MigrationConfig {
+    /// Endpoints of the shard map being migrated to
+    #[serde(default)]
+    pub shard_map: Vec<String>,
+    /// Discovery source for the shard map being migrated to
+    pub shard_map_source: Option<String>,
+    /// Percentage of keys, chosen by hash of the shard key, sent to the next
+    /// shard map instead of the current one
+    pub cutover_percent: f64,
+    /// Keep sending keys which have been cut over to the current shard map
+    /// as well
+    #[serde(default)]
+    pub dual_write: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct StatsdConfig {
-    pub servers: HashMap<String, StatsdServerConfig>,
-    pub backends: HashMap<String, StatsdBackendConfig>,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagFormat {
+    /// DogStatsD `|#key:value` tags
+    Datadog,
+    /// `.__key=value` components of the name
+    Inline,
+    /// No tags at all
+    Strip,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum DiscoveryTransform {
-    Format { pattern: String },
-    Repeat { count: u32 },
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConvertTags {
+    /// DogStatsD tags become `.__key=value` components of the name
+    Inline,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct S3DiscoverySource {
-    pub bucket: String,
-    pub key: String,
-    pub interval: u32,
-    pub transforms: Option<Vec<DiscoveryTransform>>,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendFormat {
+    #[default]
+    Statsd,
+    /// Graphite plaintext, for carbon and carbon-relay
+    Graphite,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PathDiscoverySource {
-    pub path: String,
-    pub interval: u32,
-    pub transforms: Option<Vec<DiscoveryTransform>>,
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum GraphiteTimestamp {
+    /// The time statsrelay received the line
+    #[default]
+    Received,
+    /// Seconds since the epoch from a tag on the line, which is not sent on
+    /// as a tag. Lines without the tag use the time they were received.
+    Tag { name: String },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum DiscoverySource {
-    StaticFile(PathDiscoverySource),
-    S3(S3DiscoverySource),
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GraphiteConfig {
+    #[serde(default)]
+    pub timestamp: GraphiteTimestamp,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Discovery {
-    pub sources: HashMap<String, DiscoverySource>,
+fn default_true() -> bool {
+    true
 }
 
-impl Default for Discovery {
-    fn default() -> Self {
-        Discovery {
-            sources: HashMap::new(),
-        }
-    }
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct AdminConfig {
-    pub port: u16,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    /// Bind an IPv4 address only
+    V4,
+    /// Bind an IPv6 address, accepting IPv6 traffic only
+    V6,
+    /// Bind an IPv6 address, also accepting IPv4 traffic as mapped addresses
+    Dual,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Config {
-    pub admin: Option<AdminConfig>,
-    pub statsd: StatsdConfig,
-    pub discovery: Option<Discovery>,
-    pub processors: Option<HashMap<String, Processor>>,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAction {
+    /// Stop reading from the connection until it is back under the limit,
+    /// pushing back on the client
+    #[default]
+    Delay,
+    /// Keep reading, but drop lines over the limit
+    Drop,
 }
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("could not locate discovery source {0}")]
-    UnknownDiscoverySource(String),
-    #[error("malformed route {0}")]
-    MalformedRoute(String),
-    #[error("invalid route type {0}")]
-    UnknownRouteType(String),
-    #[error("invalid routing destination {0}")]
-    UnknownRoutingDestination(Route),
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendRateLimitAction {
+    /// Hold lines over the limit in the endpoint's queue
+    #[default]
+    Queue,
+    /// Drop lines over the limit
+    Drop,
 }
 
-fn check_routes(config: &Config, routes: &[Route]) -> Result<(), Error> {
-    let result: Result<Vec<_>, Error> = routes
-        .iter()
-        .map(|route| match route.route_type {
-            RouteType::Statsd => config
-                .statsd
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackendRateLimit {
+    pub lines_per_second: Option<f64>,
+    pub bytes_per_second: Option<f64>,
+    #[serde(default)]
+    pub action: BackendRateLimitAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionRateLimit {
+    pub lines_per_second: Option<f64>,
+    pub bytes_per_second: Option<f64>,
+    #[serde(default)]
+    pub action: RateLimitAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+    /// Drop incoming lines at the server
+    Shed,
+    /// Hold lines which have been read until queues drain, and stop reading
+    Block,
+    /// Pass on lines which have been read, but stop reading until queues
+    /// drain
+    PauseReads,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Backpressure {
+    pub policy: BackpressurePolicy,
+    /// Fraction of a backend queue in use above which the policy applies
+    pub watermark: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsdServerConfig {
+    pub bind: String,
+    /// Address for the UDP listener, defaulting to the same address as `bind`
+    pub udp_bind: Option<String>,
+    /// Address family the TCP and UDP listeners bind, instead of the first
+    /// address the bind address resolves to
+    pub ip_family: Option<IpFamily>,
+    #[serde(default = "default_true")]
+    pub udp_enabled: bool,
+    /// Requested SO_RCVBUF size for the UDP socket
+    pub udp_recv_buffer_bytes: Option<usize>,
+    /// Number of threads reading from the UDP listener
+    pub udp_workers: Option<usize>,
+    /// Give each UDP worker its own SO_REUSEPORT socket rather than sharing
+    /// one socket
+    #[serde(default)]
+    pub udp_reuseport: bool,
+    /// Path of a unix stream socket, or an abstract socket name prefixed by `@`
+    pub socket: Option<String>,
+    /// Path of a unix datagram socket, or an abstract socket name prefixed
+    /// by `@`
+    pub datagram_socket: Option<String>,
+    /// Compression of the streams accepted by the TCP and unix stream
+    /// listeners
+    pub compression: Option<Compression>,
+    pub read_buffer: Option<usize>,
+    pub read_timeout_seconds: Option<u64>,
+    /// Longest line accepted on a stream connection before the partial line
+    /// is discarded
+    pub max_line_length: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Limit applied independently to each stream connection
+    pub connection_rate_limit: Option<ConnectionRateLimit>,
+    pub backpressure: Option<Backpressure>,
+    /// Enforce strict bounds when parsing lines from untrusted clients
+    #[serde(default)]
+    pub strict_parsing: bool,
+    /// Accept TLS rather than plaintext TCP connections
+    pub tls: Option<TlsServerConfig>,
+    /// Tag key under which the sender's IP address is attached to every line
+    pub source_ip_tag: Option<String>,
+    /// Prepended to the name of every line received
+    pub prefix: Option<String>,
+    /// Appended to the name of every line received
+    pub suffix: Option<String>,
+    /// Tags in `key:value` form attached to every line received
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub route: Vec<Route>,
+    /// Processors every line received is passed through in order, instead
+    /// of a route. Lines are sent on to the route of the last processor.
+    #[serde(default)]
+    pub pipeline: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsServerConfig {
+    /// Path to the PEM encoded certificate chain presented to clients
+    pub cert: String,
+    /// Path to the PEM encoded private key for the certificate
+    pub key: String,
+    /// Path to a PEM bundle of CAs which sign client certificates. When set,
+    /// clients must present a certificate signed by one of them
+    pub client_ca: Option<String>,
+    /// Client certificate names allowed to connect
+    pub client_san_allowlist: Option<Vec<String>>,
+    /// Tag key under which the client identity is attached to every line
+    pub identity_tag: Option<String>,
+    pub handshake_timeout_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsClientConfig {
+    /// Path to a PEM bundle of CAs which sign the backend certificates
+    pub ca: String,
+    /// Path to the PEM encoded certificate chain presented to backends
+    pub cert: Option<String>,
+    /// Path to the PEM encoded private key for the client certificate
+    pub key: Option<String>,
+    /// Name to send as SNI and verify backend certificates against, instead
+    /// of the host of each endpoint
+    pub server_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckMode {
+    /// An endpoint is healthy if it accepts a connection
+    #[default]
+    Connect,
+    /// An endpoint is healthy if it answers `ok` to an inline `health`
+    /// command, for endpoints which are themselves statsrelay
+    Ping,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Ejection {
+    /// Spread the lines of unhealthy endpoints over the healthy ones
+    #[default]
+    Rehash,
+    /// Drop the lines of unhealthy endpoints, so no other lines move
+    Hole,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthCheckConfig {
+    #[serde(default)]
+    pub mode: HealthCheckMode,
+    pub interval_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    /// Consecutive failed checks which eject an endpoint
+    pub unhealthy_threshold: Option<u32>,
+    /// Consecutive passed checks which reinstate an endpoint
+    pub healthy_threshold: Option<u32>,
+    #[serde(default)]
+    pub ejection: Ejection,
+    /// Hold new endpoints out of the ring until a check passes, so a stale
+    /// shard map can't send keys to endpoints which were never reachable
+    #[serde(default)]
+    pub verify_new_endpoints: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpillConfig {
+    /// Directory holding a subdirectory of spilled lines per endpoint
+    pub dir: String,
+    /// Most bytes spilled per endpoint before the oldest lines are dropped
+    pub max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OtlpServerConfig {
+    /// Socket address for the OTLP/gRPC listener
+    pub bind: String,
+    #[serde(default)]
+    pub route: Vec<Route>,
+    /// Processors every metric received is passed through in order, as for
+    /// a statsd server
+    #[serde(default)]
+    pub pipeline: Vec<String>,
+    /// Most cumulative series whose last value is kept, 100,000 by default
+    pub max_series: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OtlpConfig {
+    pub servers: HashMap<String, OtlpServerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KafkaServerConfig {
+    /// Comma separated list of bootstrap brokers
+    pub brokers: String,
+    pub group_id: String,
+    pub topics: Vec<String>,
+    /// Seconds between consumer lag updates
+    pub lag_interval_seconds: Option<u64>,
+    /// Additional librdkafka consumer properties
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    #[serde(default)]
+    pub route: Vec<Route>,
+    /// Processors every line consumed is passed through in order, as for a
+    /// statsd server
+    #[serde(default)]
+    pub pipeline: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KafkaConfig {
+    pub servers: HashMap<String, KafkaServerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsdConfig {
+    pub servers: HashMap<String, StatsdServerConfig>,
+    pub backends: HashMap<String, StatsdBackendConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrometheusBackendConfig {
+    /// URL of the remote_write endpoint, such as
+    /// `http://prometheus:9090/api/v1/write`
+    pub url: String,
+    /// Milliseconds between snapshots of the aggregated series
+    pub flush_interval_ms: Option<u64>,
+    /// Number of queues series are split over, each sending one request at a
+    /// time
+    pub shards: Option<usize>,
+    /// Requests held per shard while waiting to be sent
+    pub max_queue: Option<usize>,
+    pub max_samples_per_send: Option<usize>,
+    /// Times a failed request is retried before its samples are dropped
+    pub max_retries: Option<u32>,
+    pub retry_initial_ms: Option<u64>,
+    pub retry_max_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    /// Additional headers sent with each request, such as for authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub input_filter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PrometheusConfig {
+    pub backends: HashMap<String, PrometheusBackendConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InfluxBackendConfig {
+    /// Write URL, including the database or bucket to write to, such as
+    /// `http://influxdb:8086/api/v2/write?org=org&bucket=metrics`
+    pub url: String,
+    /// API token, sent as `Authorization: Token <token>`
+    pub token: Option<String>,
+    /// Username and password sent as HTTP basic authentication
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// TLS settings for `https` URLs
+    pub tls: Option<TlsClientConfig>,
+    /// Most lines sent in a request
+    pub batch_lines: Option<usize>,
+    /// Most bytes of lines sent in a request
+    pub batch_bytes: Option<usize>,
+    /// Milliseconds a partial batch is held before it is sent
+    pub flush_interval_ms: Option<u64>,
+    /// Lines held while waiting to be sent
+    pub max_queue: Option<usize>,
+    /// Times a failed request is retried before its lines are dropped
+    pub max_retries: Option<u32>,
+    pub retry_initial_ms: Option<u64>,
+    pub retry_max_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub input_filter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InfluxConfig {
+    pub backends: HashMap<String, InfluxBackendConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// Leave writing files out to disk to the operating system
+    #[default]
+    Never,
+    /// Sync each file once it is rotated
+    Rotate,
+    /// Sync the current file each time buffered lines are flushed to it
+    Flush,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileBackendConfig {
+    /// Directory files are written to
+    pub dir: String,
+    /// Start of each file name, followed by the time it was opened
+    pub prefix: Option<String>,
+    /// Bytes of lines written to a file before it is rotated
+    pub rotate_bytes: Option<u64>,
+    /// Seconds a file is written to before it is rotated
+    pub rotate_seconds: Option<u64>,
+    /// Number of rotated files kept, deleting the oldest
+    pub retain_files: Option<usize>,
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    /// Milliseconds between flushes of buffered lines to the current file
+    pub flush_interval_ms: Option<u64>,
+    /// Lines held while waiting to be written
+    pub max_queue: Option<usize>,
+    pub input_filter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileConfig {
+    pub backends: HashMap<String, FileBackendConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct NullBackendConfig {
+    pub input_filter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NullConfig {
+    pub backends: HashMap<String, NullBackendConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscoveryTransform {
+    Format {
+        pattern: String,
+    },
+    Repeat {
+        count: u32,
+    },
+    Sort,
+    Dedup,
+    Shuffle {
+        seed: u64,
+    },
+    /// Keep only hosts matching a regex
+    Filter {
+        pattern: String,
+    },
+    /// Drop hosts which are listed or match a regex, such as hosts under
+    /// maintenance. The list can be overridden through the admin server.
+    Exclude {
+        hosts: Option<Vec<String>>,
+        pattern: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct S3DiscoverySource {
+    pub bucket: String,
+    pub key: String,
+    pub interval: u32,
+    /// AWS region, instead of the region of the environment
+    pub region: Option<String>,
+    /// URL of an S3 compatible service such as MinIO, instead of AWS
+    pub endpoint: Option<String>,
+    /// Profile of the shared credentials file to read the object with
+    pub profile: Option<String>,
+    /// ARN of a role assumed to read the object
+    pub role_arn: Option<String>,
+    /// External ID the role requires to be assumed
+    pub external_id: Option<String>,
+    pub transforms: Option<Vec<DiscoveryTransform>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PathDiscoverySource {
+    pub path: String,
+    pub interval: u32,
+    pub transforms: Option<Vec<DiscoveryTransform>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ec2AsgDiscoverySource {
+    /// Name of the auto scaling group whose instances are listed
+    pub asg_name: Option<String>,
+    /// Tags instances must have, instead of or as well as a group name
+    pub tags: Option<HashMap<String, String>>,
+    /// Port of each instance's endpoint
+    pub port: u16,
+    /// AWS region, instead of the region of the environment
+    pub region: Option<String>,
+    pub interval: u32,
+    pub transforms: Option<Vec<DiscoveryTransform>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpDiscoverySource {
+    /// URL of the discovery document, polled with conditional requests so an
+    /// unchanged document isn't sent again
+    pub url: String,
+    pub interval: u32,
+    /// Bearer token, sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// Username and password sent as HTTP basic authentication
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Additional headers sent with each request
+    pub headers: Option<HashMap<String, String>>,
+    /// TLS settings for `https` URLs
+    pub tls: Option<TlsClientConfig>,
+    pub timeout_ms: Option<u64>,
+    pub transforms: Option<Vec<DiscoveryTransform>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsRecordType {
+    #[default]
+    A,
+    Aaaa,
+    /// Service records, giving the port of each endpoint
+    Srv,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DnsDiscoverySource {
+    pub name: String,
+    #[serde(default)]
+    pub record_type: DnsRecordType,
+    /// Port of each endpoint, required for address records
+    pub port: Option<u16>,
+    /// Longest time between resolutions, which is shortened to the TTL of
+    /// the records
+    pub interval: u32,
+    /// Nameserver address, instead of the first of /etc/resolv.conf
+    pub nameserver: Option<String>,
+    pub transforms: Option<Vec<DiscoveryTransform>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscoverySource {
+    StaticFile(PathDiscoverySource),
+    S3(S3DiscoverySource),
+    Dns(DnsDiscoverySource),
+    Http(HttpDiscoverySource),
+    Ec2Asg(Ec2AsgDiscoverySource),
+}
+
+impl DiscoverySource {
+    pub fn transforms(&self) -> &[DiscoveryTransform] {
+        let transforms = match self {
+            DiscoverySource::StaticFile(source) => &source.transforms,
+            DiscoverySource::S3(source) => &source.transforms,
+            DiscoverySource::Dns(source) => &source.transforms,
+            DiscoverySource::Http(source) => &source.transforms,
+            DiscoverySource::Ec2Asg(source) => &source.transforms,
+        };
+        transforms.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Discovery {
+    pub sources: HashMap<String, DiscoverySource>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            sources: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    pub port: u16,
+    /// Address family to listen on, instead of the system default for an
+    /// IPv6 wildcard address
+    pub ip_family: Option<IpFamily>,
+    /// Bearer token required by requests changing state, such as overriding
+    /// discovery exclusions. Such requests are refused when not set.
+    pub write_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMode {
+    /// Compare the current value of a counter or gauge
+    #[default]
+    Value,
+    /// Compare the per-second rate of change of a counter
+    Rate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertLevel {
+    #[default]
+    Warn,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    /// Internal metric name, matched either exactly or as the last components
+    /// of a scoped name, such as `backend_fails`
+    pub metric: String,
+    #[serde(default)]
+    pub mode: AlertMode,
+    pub threshold: f64,
+    #[serde(default)]
+    pub level: AlertLevel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertsConfig {
+    pub interval_seconds: Option<u64>,
+    pub rules: Vec<AlertRule>,
+}
+
+/// Deadlines for each stage of a graceful shutdown. A stage which does not
+/// finish in time is abandoned, and shutdown moves on to the next.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShutdownConfig {
+    pub listener_drain_seconds: Option<u64>,
+    pub processor_flush_seconds: Option<u64>,
+    pub backend_drain_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub admin: Option<AdminConfig>,
+    pub statsd: StatsdConfig,
+    pub otlp: Option<OtlpConfig>,
+    pub kafka: Option<KafkaConfig>,
+    pub prometheus: Option<PrometheusConfig>,
+    pub influx: Option<InfluxConfig>,
+    pub file: Option<FileConfig>,
+    pub null: Option<NullConfig>,
+    pub discovery: Option<Discovery>,
+    pub processors: Option<HashMap<String, ProcessorConfig>>,
+    pub alerts: Option<AlertsConfig>,
+    pub shutdown: Option<ShutdownConfig>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not locate discovery source {0}")]
+    UnknownDiscoverySource(String),
+    #[error("malformed route {0}")]
+    MalformedRoute(String),
+    #[error("invalid route type {0}")]
+    UnknownRouteType(String),
+    #[error("malformed shard key {0}")]
+    MalformedShardKey(String),
+    #[error("invalid routing destination {0}")]
+    UnknownRoutingDestination(Route),
+    #[error("invalid value for server {server} option {option}")]
+    InvalidServerOption {
+        server: String,
+        option: &'static str,
+    },
+    #[error("invalid value for backend {backend} option {option}")]
+    InvalidBackendOption {
+        backend: String,
+        option: &'static str,
+    },
+    #[error("invalid value for processor {processor} option {option}")]
+    InvalidProcessorOption {
+        processor: String,
+        option: &'static str,
+    },
+    #[error("invalid value for discovery source {source_name} option {option}")]
+    InvalidDiscoveryOption {
+        source_name: String,
+        option: &'static str,
+    },
+    #[error("invalid value for alerts option {0}")]
+    InvalidAlertsOption(&'static str),
+    #[error("invalid value for shutdown option {0}")]
+    InvalidShutdownOption(&'static str),
+    #[error("invalid pipeline of {owner}: {reason}")]
+    InvalidPipeline { owner: String, reason: String },
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        Category::Config
+    }
+}
+
+fn check_routes(config: &Config, routes: &[Route]) -> Result<(), Error> {
+    let result: Result<Vec<_>, Error> = routes
+        .iter()
+        .map(|route| match route.route_type {
+            RouteType::Statsd => config
+                .statsd
                 .backends
                 .get(route.route_to.as_str())
                 .ok_or_else(|| Error::UnknownRoutingDestination(route.clone()))
                 .map(|_| ()),
+            RouteType::Prometheus => config
+                .prometheus
+                .as_ref()
+                .and_then(|p| p.backends.get(route.route_to.as_str()))
+                .ok_or_else(|| Error::UnknownRoutingDestination(route.clone()))
+                .map(|_| ()),
+            RouteType::Influx => config
+                .influx
+                .as_ref()
+                .and_then(|i| i.backends.get(route.route_to.as_str()))
+                .ok_or_else(|| Error::UnknownRoutingDestination(route.clone()))
+                .map(|_| ()),
+            RouteType::File => config
+                .file
+                .as_ref()
+                .and_then(|f| f.backends.get(route.route_to.as_str()))
+                .ok_or_else(|| Error::UnknownRoutingDestination(route.clone()))
+                .map(|_| ()),
+            RouteType::Null => config
+                .null
+                .as_ref()
+                .and_then(|n| n.backends.get(route.route_to.as_str()))
+                .ok_or_else(|| Error::UnknownRoutingDestination(route.clone()))
+                .map(|_| ()),
             RouteType::Processor => {
                 if let Some(procs) = &config.processors {
                     return procs
@@ -231,6 +1699,8 @@ fn check_routes(config: &Config, routes: &[Route]) -> Result<(), Error> {
                     Err(Error::UnknownRoutingDestination(route.clone()))
                 }
             }
+            // Pipelines are expanded before routes are checked
+            RouteType::Pipeline => Err(Error::UnknownRoutingDestination(route.clone())),
         })
         .collect();
     result.map(|_| ())
@@ -240,16 +1710,90 @@ fn check_config_route(config: &Config) -> Result<(), Error> {
     for (_, statsd) in config.statsd.servers.iter() {
         check_routes(config, statsd.route.as_ref())?;
     }
+    for (_, otlp) in config.otlp.iter().flat_map(|o| o.servers.iter()) {
+        check_routes(config, otlp.route.as_ref())?;
+    }
+    for (_, kafka) in config.kafka.iter().flat_map(|k| k.servers.iter()) {
+        check_routes(config, kafka.route.as_ref())?;
+    }
     let routes: Result<Vec<_>, Error> = config
         .clone()
         .processors
         .unwrap_or_default()
         .iter()
-        .map(|(_, proc)| match proc {
-            Processor::Sampler(sampler) => check_routes(config, sampler.route.as_ref()),
+        .map(|(_, proc)| match &proc.processor {
+            Processor::Sampler(sampler) => {
+                if let Some(debug) = &sampler.debug_timers {
+                    check_routes(config, debug.route.as_ref())?;
+                }
+                check_routes(config, sampler.route.as_ref())
+            }
             Processor::TagConverter(tc) => check_routes(config, tc.route.as_ref()),
-            Processor::Cardinality(c) => check_routes(config, c.route.as_ref()),
+            Processor::Cardinality(c) => {
+                if let processor::CardinalityAction::RouteTo(route) = &c.action {
+                    check_routes(config, route.as_ref())?;
+                }
+                check_routes(config, c.route.as_ref())
+            }
             Processor::RegexFilter(filter) => check_routes(config, filter.route.as_ref()),
+            Processor::Rewrite(rewrite) => check_routes(config, rewrite.route.as_ref()),
+            Processor::TagFilter(filter) => check_routes(config, filter.route.as_ref()),
+            Processor::TagObfuscator(obfuscator) => check_routes(config, obfuscator.route.as_ref()),
+            Processor::GaugeDedup(dedup) => check_routes(config, dedup.route.as_ref()),
+            Processor::Delta(delta) => check_routes(config, delta.route.as_ref()),
+            Processor::Clamp(clamp) => check_routes(config, clamp.route.as_ref()),
+            Processor::Scale(scale) => check_routes(config, scale.route.as_ref()),
+            Processor::SampleRate(rate) => check_routes(config, rate.route.as_ref()),
+            Processor::TagValueLimit(limit) => check_routes(config, limit.route.as_ref()),
+            Processor::NormalizeName(normalize) => check_routes(config, normalize.route.as_ref()),
+            Processor::SetUnion(union) => check_routes(config, union.route.as_ref()),
+            Processor::Wasm(wasm) => {
+                for route in wasm.routes.values() {
+                    check_routes(config, route.as_ref())?;
+                }
+                check_routes(config, wasm.route.as_ref())
+            }
+            Processor::Lua(lua) => {
+                for route in lua.routes.values() {
+                    check_routes(config, route.as_ref())?;
+                }
+                check_routes(config, lua.route.as_ref())
+            }
+            Processor::Anomaly(anomaly) => {
+                if let processor::AnomalyAction::RouteTo(route) = &anomaly.action {
+                    check_routes(config, route.as_ref())?;
+                }
+                check_routes(config, anomaly.route.as_ref())
+            }
+            Processor::Throttle(throttle) => {
+                if let processor::ThrottleAction::RouteTo(route) = &throttle.action {
+                    check_routes(config, route.as_ref())?;
+                }
+                check_routes(config, throttle.route.as_ref())
+            }
+            Processor::DeadMetric(dead) => {
+                check_routes(config, dead.absence_route.as_ref())?;
+                check_routes(config, dead.route.as_ref())
+            }
+            Processor::Rollup(rollup) => {
+                if let Some(debug) = &rollup.sampler.debug_timers {
+                    check_routes(config, debug.route.as_ref())?;
+                }
+                check_routes(config, rollup.sampler.route.as_ref())?;
+                check_routes(config, rollup.route.as_ref())
+            }
+            Processor::Splitter(splitter) => {
+                for split in splitter.splits.iter() {
+                    check_routes(config, split.route.as_ref())?;
+                }
+                check_routes(config, splitter.route.as_ref())
+            }
+            Processor::Router(router) => {
+                for rule in router.rules.iter() {
+                    check_routes(config, rule.route.as_ref())?;
+                }
+                check_routes(config, router.route.as_ref())
+            }
         })
         .collect();
     routes.map(|_| ())
@@ -257,126 +1801,2009 @@ fn check_config_route(config: &Config) -> Result<(), Error> {
 
 fn check_config_discovery(config: &Config, discovery: &Discovery) -> anyhow::Result<()> {
     for (_, statsd_dupl) in config.statsd.backends.iter() {
-        if let Some(source) = &statsd_dupl.shard_map_source {
+        let migration_source = statsd_dupl
+            .migration
+            .as_ref()
+            .and_then(|m| m.shard_map_source.as_ref());
+        for source in statsd_dupl.shard_map_source.iter().chain(migration_source) {
             if discovery.sources.get(source).is_none() {
                 return Err(Error::UnknownDiscoverySource(source.clone()).into());
             }
         }
     }
-    Ok(())
-}
-
-fn check_config(config: &Config) -> anyhow::Result<()> {
-    let default = Discovery::default();
-    let discovery = &config.discovery.as_ref().unwrap_or(&default);
-    // Every reference to a shard_map needs a reference to a valid discovery block
-    check_config_discovery(config, discovery)?;
-    check_config_route(config)?;
-    Ok(())
-}
-
-pub fn load(path: &str) -> anyhow::Result<Config> {
-    let input = std::fs::read_to_string(path)?;
-    let config: Config = serde_json::from_str(input.as_ref())?;
-    // Perform some high level validation
-    check_config(&config)?;
-    Ok(config)
-}
+    for (name, source) in discovery.sources.iter() {
+        let invalid = |option| Error::InvalidDiscoveryOption {
+            source_name: name.clone(),
+            option,
+        };
+        let valid_transform = |transform: &DiscoveryTransform| match transform {
+            DiscoveryTransform::Filter { pattern } => regex::Regex::new(pattern).is_ok(),
+            DiscoveryTransform::Exclude {
+                pattern: Some(pattern),
+                ..
+            } => regex::Regex::new(pattern).is_ok(),
+            _ => true,
+        };
+        if !source.transforms().iter().all(valid_transform) {
+            return Err(invalid("transforms").into());
+        }
+        match source {
+            DiscoverySource::Dns(dns) => {
+                if dns.name.is_empty() {
+                    return Err(invalid("name").into());
+                }
+                if dns.interval == 0 {
+                    return Err(invalid("interval").into());
+                }
+                if dns.record_type != DnsRecordType::Srv && dns.port.is_none() {
+                    return Err(invalid("port").into());
+                }
+                if dns
+                    .nameserver
+                    .as_ref()
+                    .is_some_and(|ns| parse_nameserver(ns).is_none())
+                {
+                    return Err(invalid("nameserver").into());
+                }
+            }
+            DiscoverySource::Http(http) => {
+                let scheme = http
+                    .url
+                    .parse::<hyper::Uri>()
+                    .ok()
+                    .filter(|uri| uri.host().is_some())
+                    .and_then(|uri| uri.scheme_str().map(str::to_owned));
+                match scheme.as_deref() {
+                    Some("http") => (),
+                    // https needs the CA to verify the server against
+                    Some("https") if http.tls.is_some() => (),
+                    _ => return Err(invalid("url").into()),
+                }
+                if http.interval == 0 {
+                    return Err(invalid("interval").into());
+                }
+                if let Some(tls) = &http.tls {
+                    if tls.cert.is_some() != tls.key.is_some() {
+                        return Err(invalid("tls.cert").into());
+                    }
+                }
+                if http.token.is_some() && http.username.is_some() {
+                    return Err(invalid("token").into());
+                }
+                if http.password.is_some() && http.username.is_none() {
+                    return Err(invalid("password").into());
+                }
+                let valid_header = |(name, value): (&String, &String)| {
+                    hyper::header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+                        && hyper::header::HeaderValue::from_str(value).is_ok()
+                };
+                if !http.headers.iter().flatten().all(valid_header) {
+                    return Err(invalid("headers").into());
+                }
+                if http.timeout_ms == Some(0) {
+                    return Err(invalid("timeout_ms").into());
+                }
+            }
+            DiscoverySource::Ec2Asg(asg) => {
+                // Without a group or tags, every instance would be listed
+                if asg.asg_name.is_none() && asg.tags.as_ref().is_none_or(HashMap::is_empty) {
+                    return Err(invalid("asg_name").into());
+                }
+                if asg.interval == 0 {
+                    return Err(invalid("interval").into());
+                }
+                if asg
+                    .region
+                    .as_ref()
+                    .is_some_and(|region| region.parse::<rusoto_core::Region>().is_err())
+                {
+                    return Err(invalid("region").into());
+                }
+            }
+            DiscoverySource::S3(s3) => {
+                let valid_endpoint = |endpoint: &String| {
+                    endpoint
+                        .parse::<hyper::Uri>()
+                        .ok()
+                        .filter(|uri| uri.host().is_some())
+                        .is_some_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")))
+                };
+                if !s3.endpoint.iter().all(valid_endpoint) {
+                    return Err(invalid("endpoint").into());
+                }
+                // Services at other endpoints may name their regions freely
+                if s3.endpoint.is_none()
+                    && s3
+                        .region
+                        .as_ref()
+                        .is_some_and(|region| region.parse::<rusoto_core::Region>().is_err())
+                {
+                    return Err(invalid("region").into());
+                }
+                if s3.external_id.is_some() && s3.role_arn.is_none() {
+                    return Err(invalid("external_id").into());
+                }
+            }
+            DiscoverySource::StaticFile(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Parse a nameserver address, with the DNS port if none is given
+pub fn parse_nameserver(nameserver: &str) -> Option<std::net::SocketAddr> {
+    nameserver.parse().ok().or_else(|| {
+        nameserver
+            .parse::<std::net::IpAddr>()
+            .ok()
+            .map(|ip| std::net::SocketAddr::new(ip, 53))
+    })
+}
+
+/// Check that a bind address given as a literal IP address is of the
+/// requested family. Hostnames are only resolved when binding.
+fn bind_matches_family(bind: &str, family: Option<IpFamily>) -> bool {
+    match (bind.parse::<std::net::SocketAddr>(), family) {
+        (Ok(addr), Some(IpFamily::V4)) => addr.is_ipv4(),
+        (Ok(addr), Some(IpFamily::V6 | IpFamily::Dual)) => addr.is_ipv6(),
+        _ => true,
+    }
+}
+
+fn check_config_servers(config: &Config) -> Result<(), Error> {
+    for (name, server) in config.statsd.servers.iter() {
+        let invalid = |option| Error::InvalidServerOption {
+            server: name.clone(),
+            option,
+        };
+        if server.read_buffer == Some(0) {
+            return Err(invalid("read_buffer"));
+        }
+        if server.read_timeout_seconds == Some(0) {
+            return Err(invalid("read_timeout_seconds"));
+        }
+        if server.source_ip_tag.as_deref() == Some("") {
+            return Err(invalid("source_ip_tag"));
+        }
+        let invalid_tag = |tag: &String| tag.is_empty() || tag.contains(&[',', '|', '#', '\n'][..]);
+        if server.tags.iter().flatten().any(invalid_tag) {
+            return Err(invalid("tags"));
+        }
+        if server.udp_recv_buffer_bytes == Some(0) {
+            return Err(invalid("udp_recv_buffer_bytes"));
+        }
+        let mut binds = std::iter::once(&server.bind).chain(server.udp_bind.iter());
+        if !binds.all(|b| bind_matches_family(b, server.ip_family)) {
+            return Err(invalid("ip_family"));
+        }
+        if server.udp_workers == Some(0) {
+            return Err(invalid("udp_workers"));
+        }
+        if server.max_line_length == Some(0) {
+            return Err(invalid("max_line_length"));
+        }
+        if let Some(backpressure) = &server.backpressure {
+            if !backpressure
+                .watermark
+                .is_none_or(|w| w > 0_f64 && w <= 1_f64)
+            {
+                return Err(invalid("backpressure.watermark"));
+            }
+        }
+        if let Some(tls) = &server.tls {
+            if tls.client_ca.is_none() {
+                if tls.client_san_allowlist.is_some() {
+                    return Err(invalid("tls.client_san_allowlist"));
+                }
+                if tls.identity_tag.is_some() {
+                    return Err(invalid("tls.identity_tag"));
+                }
+            }
+            if tls.handshake_timeout_seconds == Some(0) {
+                return Err(invalid("tls.handshake_timeout_seconds"));
+            }
+        }
+        if let Some(limit) = &server.connection_rate_limit {
+            let positive = |rate: Option<f64>| rate.is_none_or(|r| r > 0_f64);
+            if !positive(limit.lines_per_second) {
+                return Err(invalid("connection_rate_limit.lines_per_second"));
+            }
+            if !positive(limit.bytes_per_second) {
+                return Err(invalid("connection_rate_limit.bytes_per_second"));
+            }
+        }
+    }
+    for (name, server) in config.kafka.iter().flat_map(|k| k.servers.iter()) {
+        let invalid = |option| Error::InvalidServerOption {
+            server: name.clone(),
+            option,
+        };
+        if server.topics.is_empty() {
+            return Err(invalid("topics"));
+        }
+        if server.lag_interval_seconds == Some(0) {
+            return Err(invalid("lag_interval_seconds"));
+        }
+    }
+    Ok(())
+}
+
+fn check_config_backends(config: &Config) -> Result<(), Error> {
+    for (name, backend) in config.statsd.backends.iter() {
+        let invalid = |option| Error::InvalidBackendOption {
+            backend: name.clone(),
+            option,
+        };
+        // Only the {shard} and {endpoint_host} template variables exist
+        let unknown_variable = |affix: &Option<String>| {
+            affix.as_deref().is_some_and(|affix| {
+                affix
+                    .replace("{shard}", "")
+                    .replace("{endpoint_host}", "")
+                    .contains(['{', '}'])
+            })
+        };
+        if unknown_variable(&backend.prefix) {
+            return Err(invalid("prefix"));
+        }
+        if unknown_variable(&backend.suffix) {
+            return Err(invalid("suffix"));
+        }
+        if backend.max_datagram_bytes == Some(0) {
+            return Err(invalid("max_datagram_bytes"));
+        }
+        if backend.max_batch_bytes == Some(0) {
+            return Err(invalid("max_batch_bytes"));
+        }
+        if backend.flush_interval_ms == Some(0) {
+            return Err(invalid("flush_interval_ms"));
+        }
+        if backend.max_in_flight == Some(0) {
+            return Err(invalid("max_in_flight"));
+        }
+        if backend.pipeline_batches.is_some_and(|p| {
+            p == 0
+                || backend.protocol == BackendProtocol::Udp
+                || backend.max_in_flight.is_some_and(|in_flight| p > in_flight)
+        }) {
+            return Err(invalid("pipeline_batches"));
+        }
+        if backend.reconnect_initial_ms == Some(0) {
+            return Err(invalid("reconnect_initial_ms"));
+        }
+        if backend.reconnect_max_ms.is_some_and(|max| {
+            max == 0
+                || backend
+                    .reconnect_initial_ms
+                    .is_some_and(|initial| initial > max)
+        }) {
+            return Err(invalid("reconnect_max_ms"));
+        }
+        if let Some(tls) = &backend.tls {
+            if backend.protocol == BackendProtocol::Udp {
+                return Err(invalid("tls"));
+            }
+            if tls.cert.is_some() != tls.key.is_some() {
+                return Err(invalid("tls.cert"));
+            }
+            // A unix socket path has no host to verify a certificate against
+            let unix = backend
+                .shard_map
+                .iter()
+                .chain(backend.migration.iter().flat_map(|m| m.shard_map.iter()))
+                .any(|endpoint| endpoint.starts_with("unix://"));
+            if unix && tls.server_name.is_none() {
+                return Err(invalid("tls.server_name"));
+            }
+        }
+        if backend.breaker_failures == Some(0) {
+            return Err(invalid("breaker_failures"));
+        }
+        if backend.breaker_open_ms == Some(0) {
+            return Err(invalid("breaker_open_ms"));
+        }
+        if backend.compression.is_some()
+            && (backend.protocol == BackendProtocol::Udp
+                || backend.format == BackendFormat::Graphite)
+        {
+            return Err(invalid("compression"));
+        }
+        if backend.replication == Some(0) {
+            return Err(invalid("replication"));
+        }
+        // Without a ring, a warming endpoint would move the keys of every
+        // other endpoint as well as its own
+        if backend.slow_start_ms == Some(0)
+            || (backend.slow_start_ms.is_some() && backend.vnodes.is_none())
+        {
+            return Err(invalid("slow_start_ms"));
+        }
+        if backend.drain_timeout_ms == Some(0) {
+            return Err(invalid("drain_timeout_ms"));
+        }
+        if backend.vnodes == Some(0) {
+            return Err(invalid("vnodes"));
+        }
+        if backend.convert_tags.is_some() && backend.tag_format.is_some() {
+            return Err(invalid("convert_tags"));
+        }
+        if backend
+            .shadow_percent
+            .is_some_and(|p| !(p > 0_f64 && p <= 100_f64))
+        {
+            return Err(invalid("shadow_percent"));
+        }
+        if backend
+            .sample_percent
+            .is_some_and(|p| !(p > 0_f64 && p <= 100_f64))
+        {
+            return Err(invalid("sample_percent"));
+        }
+        if let Some(migration) = &backend.migration {
+            if !(0_f64..=100_f64).contains(&migration.cutover_percent) {
+                return Err(invalid("migration.cutover_percent"));
+            }
+            if migration.shard_map.is_empty() && migration.shard_map_source.is_none() {
+                return Err(invalid("migration.shard_map"));
+            }
+        }
+        if let Some(check) = &backend.health_check {
+            if backend.protocol == BackendProtocol::Udp {
+                return Err(invalid("health_check"));
+            }
+            if check.interval_ms == Some(0) {
+                return Err(invalid("health_check.interval_ms"));
+            }
+            if check.timeout_ms == Some(0) {
+                return Err(invalid("health_check.timeout_ms"));
+            }
+            if check.unhealthy_threshold == Some(0) {
+                return Err(invalid("health_check.unhealthy_threshold"));
+            }
+            if check.healthy_threshold == Some(0) {
+                return Err(invalid("health_check.healthy_threshold"));
+            }
+        }
+        if let Some(limit) = &backend.rate_limit {
+            let positive = |rate: Option<f64>| rate.is_none_or(|r| r > 0_f64);
+            if !positive(limit.lines_per_second) {
+                return Err(invalid("rate_limit.lines_per_second"));
+            }
+            if !positive(limit.bytes_per_second) {
+                return Err(invalid("rate_limit.bytes_per_second"));
+            }
+        }
+        if let Some(spill) = &backend.spill {
+            if spill.dir.is_empty() {
+                return Err(invalid("spill.dir"));
+            }
+            if spill.max_bytes == 0 {
+                return Err(invalid("spill.max_bytes"));
+            }
+        }
+    }
+    for (name, backend) in config.prometheus.iter().flat_map(|p| p.backends.iter()) {
+        let invalid = |option| Error::InvalidBackendOption {
+            backend: name.clone(),
+            option,
+        };
+        // Only plain HTTP endpoints are supported
+        if !backend
+            .url
+            .parse::<hyper::Uri>()
+            .is_ok_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some())
+        {
+            return Err(invalid("url"));
+        }
+        if backend.flush_interval_ms == Some(0) {
+            return Err(invalid("flush_interval_ms"));
+        }
+        if backend.shards == Some(0) {
+            return Err(invalid("shards"));
+        }
+        if backend.max_queue == Some(0) {
+            return Err(invalid("max_queue"));
+        }
+        if backend.max_samples_per_send == Some(0) {
+            return Err(invalid("max_samples_per_send"));
+        }
+        if backend.retry_initial_ms == Some(0) {
+            return Err(invalid("retry_initial_ms"));
+        }
+        if backend.retry_max_ms.is_some_and(|max| {
+            max == 0
+                || backend
+                    .retry_initial_ms
+                    .is_some_and(|initial| initial > max)
+        }) {
+            return Err(invalid("retry_max_ms"));
+        }
+        if backend.timeout_ms == Some(0) {
+            return Err(invalid("timeout_ms"));
+        }
+        if !backend.headers.iter().all(|(k, v)| {
+            hyper::header::HeaderName::from_bytes(k.as_bytes()).is_ok()
+                && hyper::header::HeaderValue::from_str(v).is_ok()
+        }) {
+            return Err(invalid("headers"));
+        }
+        if let Some(filter) = &backend.input_filter {
+            if regex::bytes::Regex::new(filter).is_err() {
+                return Err(invalid("input_filter"));
+            }
+        }
+    }
+    for (name, backend) in config.influx.iter().flat_map(|i| i.backends.iter()) {
+        let invalid = |option| Error::InvalidBackendOption {
+            backend: name.clone(),
+            option,
+        };
+        let scheme = backend
+            .url
+            .parse::<hyper::Uri>()
+            .ok()
+            .filter(|uri| uri.host().is_some())
+            .and_then(|uri| uri.scheme_str().map(str::to_owned));
+        match scheme.as_deref() {
+            Some("http") => (),
+            // https needs the CA to verify the server against
+            Some("https") if backend.tls.is_some() => (),
+            _ => return Err(invalid("url")),
+        }
+        if let Some(tls) = &backend.tls {
+            if tls.cert.is_some() != tls.key.is_some() {
+                return Err(invalid("tls.cert"));
+            }
+        }
+        if backend.token.is_some() && backend.username.is_some() {
+            return Err(invalid("token"));
+        }
+        if backend.password.is_some() && backend.username.is_none() {
+            return Err(invalid("password"));
+        }
+        if backend.batch_lines == Some(0) {
+            return Err(invalid("batch_lines"));
+        }
+        if backend.batch_bytes == Some(0) {
+            return Err(invalid("batch_bytes"));
+        }
+        if backend.flush_interval_ms == Some(0) {
+            return Err(invalid("flush_interval_ms"));
+        }
+        if backend.max_queue == Some(0) {
+            return Err(invalid("max_queue"));
+        }
+        if backend.retry_initial_ms == Some(0) {
+            return Err(invalid("retry_initial_ms"));
+        }
+        if backend.retry_max_ms.is_some_and(|max| {
+            max == 0
+                || backend
+                    .retry_initial_ms
+                    .is_some_and(|initial| initial > max)
+        }) {
+            return Err(invalid("retry_max_ms"));
+        }
+        if backend.timeout_ms == Some(0) {
+            return Err(invalid("timeout_ms"));
+        }
+        if let Some(filter) = &backend.input_filter {
+            if regex::bytes::Regex::new(filter).is_err() {
+                return Err(invalid("input_filter"));
+            }
+        }
+    }
+    for (name, backend) in config.file.iter().flat_map(|f| f.backends.iter()) {
+        let invalid = |option| Error::InvalidBackendOption {
+            backend: name.clone(),
+            option,
+        };
+        if backend.dir.is_empty() {
+            return Err(invalid("dir"));
+        }
+        if backend
+            .prefix
+            .as_deref()
+            .is_some_and(|p| p.is_empty() || p.contains(std::path::is_separator))
+        {
+            return Err(invalid("prefix"));
+        }
+        if backend.rotate_bytes == Some(0) {
+            return Err(invalid("rotate_bytes"));
+        }
+        if backend.rotate_seconds == Some(0) {
+            return Err(invalid("rotate_seconds"));
+        }
+        if backend.retain_files == Some(0) {
+            return Err(invalid("retain_files"));
+        }
+        if backend.flush_interval_ms == Some(0) {
+            return Err(invalid("flush_interval_ms"));
+        }
+        if backend.max_queue == Some(0) {
+            return Err(invalid("max_queue"));
+        }
+        if let Some(filter) = &backend.input_filter {
+            if regex::bytes::Regex::new(filter).is_err() {
+                return Err(invalid("input_filter"));
+            }
+        }
+    }
+    for (name, backend) in config.null.iter().flat_map(|n| n.backends.iter()) {
+        if let Some(filter) = &backend.input_filter {
+            if regex::bytes::Regex::new(filter).is_err() {
+                return Err(Error::InvalidBackendOption {
+                    backend: name.clone(),
+                    option: "input_filter",
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the options of a sampler, or of the sampler of another processor
+fn check_sampler(name: &str, sampler: &processor::Sampler) -> Result<(), Error> {
+    if sampler
+        .timer_percentiles
+        .iter()
+        .any(|p| !(*p > 0_f64 && *p <= 100_f64))
+    {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "timer_percentiles",
+        });
+    }
+    if sampler
+        .flush_jitter_ms
+        .is_some_and(|jitter| jitter >= sampler.window as u64 * 1000)
+    {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "flush_jitter_ms",
+        });
+    }
+    // Percentiles are taken from the reservoir, so need values in it
+    if sampler.timer_reservoir_size == Some(0) && !sampler.timer_percentiles.is_empty() {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "timer_reservoir_size",
+        });
+    }
+    if sampler.shards == Some(0) {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "shards",
+        });
+    }
+    if sampler.sub_windows == Some(0) {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "sub_windows",
+        });
+    }
+    if sampler.max_keys == Some(0) {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "max_keys",
+        });
+    }
+    if sampler.sets.as_ref().is_some_and(|s| s.max_sets == Some(0)) {
+        return Err(Error::InvalidProcessorOption {
+            processor: name.to_owned(),
+            option: "sets.max_sets",
+        });
+    }
+    Ok(())
+}
+
+fn check_config_processors(config: &Config) -> Result<(), Error> {
+    for (name, processor) in config.processors.iter().flat_map(|p| p.iter()) {
+        if let Some(workers) = &processor.workers {
+            let option = if workers.threads == Some(0) {
+                Some("workers.threads")
+            } else if workers.queue_size == 0 {
+                Some("workers.queue_size")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        let processor = &processor.processor;
+        if let Processor::Sampler(sampler) = processor {
+            check_sampler(name, sampler)?;
+        }
+        if let Processor::Rollup(rollup) = processor {
+            check_sampler(name, &rollup.sampler)?;
+        }
+        if let Processor::TagFilter(filter) = processor {
+            if filter.keep.is_none() && filter.remove.is_none() {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "keep",
+                });
+            }
+        }
+        if let Processor::Scale(scale) = processor {
+            for rule in scale.rules.iter() {
+                if rule
+                    .types
+                    .iter()
+                    .any(|mtype| Type::from_name(mtype).is_none())
+                {
+                    return Err(Error::InvalidProcessorOption {
+                        processor: name.clone(),
+                        option: "rules.types",
+                    });
+                }
+                if !rule.factor.is_finite() {
+                    return Err(Error::InvalidProcessorOption {
+                        processor: name.clone(),
+                        option: "rules.factor",
+                    });
+                }
+            }
+        }
+        if let Processor::SampleRate(rate) = processor {
+            if rate
+                .types
+                .iter()
+                .any(|mtype| Type::from_name(mtype).is_none())
+            {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "types",
+                });
+            }
+        }
+        if let Processor::Throttle(throttle) = processor {
+            let positive = |rate: f64| rate.is_finite() && rate > 0_f64;
+            let option = if !positive(throttle.lines_per_second) {
+                Some("lines_per_second")
+            } else if !throttle.tenants.values().all(|rate| positive(*rate)) {
+                Some("tenants")
+            } else if throttle
+                .burst
+                .is_some_and(|burst| !(burst.is_finite() && burst >= 1_f64))
+            {
+                Some("burst")
+            } else if throttle.prefix_depth == Some(0) {
+                Some("prefix_depth")
+            } else if throttle.max_tenants == 0 {
+                Some("max_tenants")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        if let Processor::TagValueLimit(limit) = processor {
+            let option = if limit.keys.is_empty() {
+                Some("keys")
+            } else if limit.max_values == 0 {
+                Some("max_values")
+            } else if limit.window_seconds == Some(0) {
+                Some("window_seconds")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        if let Processor::NormalizeName(normalize) = processor {
+            if normalize
+                .replacement
+                .is_some_and(|c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "replacement",
+                });
+            }
+        }
+        if let Processor::SetUnion(union) = processor {
+            let option = if union.window == 0 {
+                Some("window")
+            } else if union.max_members == 0 {
+                Some("max_members")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        if let Processor::Clamp(clamp) = processor {
+            for rule in clamp.rules.iter() {
+                if rule
+                    .types
+                    .iter()
+                    .any(|mtype| Type::from_name(mtype).is_none())
+                {
+                    return Err(Error::InvalidProcessorOption {
+                        processor: name.clone(),
+                        option: "rules.types",
+                    });
+                }
+                if let (Some(min), Some(max)) = (rule.min, rule.max) {
+                    if min > max {
+                        return Err(Error::InvalidProcessorOption {
+                            processor: name.clone(),
+                            option: "rules.min",
+                        });
+                    }
+                }
+            }
+        }
+        if let Processor::Delta(delta) = processor {
+            if delta.expire_seconds == Some(0) {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "expire_seconds",
+                });
+            }
+        }
+        if let Processor::GaugeDedup(dedup) = processor {
+            if dedup.heartbeat_seconds == 0 {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "heartbeat_seconds",
+                });
+            }
+        }
+        if let Processor::Anomaly(anomaly) = processor {
+            let option = if !(anomaly.alpha > 0_f64 && anomaly.alpha <= 1_f64) {
+                Some("alpha")
+            } else if !(anomaly.z_score.is_finite() && anomaly.z_score > 0_f64) {
+                Some("z_score")
+            } else if anomaly.expire_seconds == Some(0) {
+                Some("expire_seconds")
+            } else if anomaly
+                .types
+                .iter()
+                .any(|mtype| Type::from_name(mtype).is_none())
+            {
+                Some("types")
+            } else if matches!(&anomaly.action, processor::AnomalyAction::Tag(tag) if !tag.contains(':'))
+            {
+                Some("action.tag")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        if let Processor::DeadMetric(dead) = processor {
+            let option = if dead.window == 0 {
+                Some("window")
+            } else if dead.missing_windows == 0 {
+                Some("missing_windows")
+            } else if dead
+                .types
+                .iter()
+                .any(|mtype| Type::from_name(mtype).is_none())
+            {
+                Some("types")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        if let Processor::Splitter(splitter) = processor {
+            let percents = splitter.splits.iter().map(|split| split.percent);
+            if percents.clone().any(|p| !(0_f64..=100_f64).contains(&p))
+                || percents.sum::<f64>() > 100_f64
+            {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "splits.percent",
+                });
+            }
+        }
+        if let Processor::Router(router) = processor {
+            if router.mode == processor::RouterMode::AllMatches
+                && router.rules.len() > processor::MAX_ALL_MATCHES_RULES
+            {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "rules",
+                });
+            }
+            if router
+                .rules
+                .iter()
+                .flat_map(|rule| rule.types.iter())
+                .any(|mtype| Type::from_name(mtype).is_none())
+            {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "rules.types",
+                });
+            }
+        }
+        if let Processor::TagObfuscator(obfuscator) = processor {
+            let option = match obfuscator.method {
+                processor::ObfuscationMethod::Hash if obfuscator.hash_key.is_none() => {
+                    Some("hash_key")
+                }
+                processor::ObfuscationMethod::Truncate if obfuscator.length.is_none() => {
+                    Some("length")
+                }
+                _ if obfuscator.length == Some(0) => Some("length"),
+                _ => None,
+            };
+            if let Some(option) = option {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option,
+                });
+            }
+        }
+        if let Processor::Cardinality(cardinality) = processor {
+            let mut prefixes = HashSet::new();
+            if !cardinality
+                .limits
+                .iter()
+                .all(|limit| prefixes.insert(limit.prefix.as_str()))
+            {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "limits.prefix",
+                });
+            }
+            if cardinality.allow.as_ref().is_some_and(|allow| {
+                allow
+                    .tags
+                    .iter()
+                    .any(|tags| tags.is_empty() || tags.iter().any(|tag| !tag.contains(':')))
+            }) {
+                return Err(Error::InvalidProcessorOption {
+                    processor: name.clone(),
+                    option: "allow.tags",
+                });
+            }
+            if let Some(top_offenders) = &cardinality.top_offenders {
+                for (option, value) in [
+                    ("top_offenders.prefix_depth", top_offenders.prefix_depth),
+                    ("top_offenders.capacity", top_offenders.capacity),
+                    ("top_offenders.top", top_offenders.top),
+                ] {
+                    if value == Some(0) {
+                        return Err(Error::InvalidProcessorOption {
+                            processor: name.clone(),
+                            option,
+                        });
+                    }
+                }
+            }
+            if let Some(tag_values) = &cardinality.tag_values {
+                if tag_values.keys.is_empty() {
+                    return Err(Error::InvalidProcessorOption {
+                        processor: name.clone(),
+                        option: "tag_values.keys",
+                    });
+                }
+                if tag_values.max_values == 0 {
+                    return Err(Error::InvalidProcessorOption {
+                        processor: name.clone(),
+                        option: "tag_values.max_values",
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_config_alerts(config: &Config) -> Result<(), Error> {
+    if let Some(alerts) = &config.alerts {
+        if alerts.interval_seconds == Some(0) {
+            return Err(Error::InvalidAlertsOption("interval_seconds"));
+        }
+    }
+    Ok(())
+}
+
+fn check_config_shutdown(config: &Config) -> Result<(), Error> {
+    if let Some(shutdown) = &config.shutdown {
+        if shutdown.listener_drain_seconds == Some(0) {
+            return Err(Error::InvalidShutdownOption("listener_drain_seconds"));
+        }
+        if shutdown.processor_flush_seconds == Some(0) {
+            return Err(Error::InvalidShutdownOption("processor_flush_seconds"));
+        }
+        if shutdown.backend_drain_seconds == Some(0) {
+            return Err(Error::InvalidShutdownOption("backend_drain_seconds"));
+        }
+    }
+    Ok(())
+}
+
+fn check_config(config: &Config) -> anyhow::Result<()> {
+    let default = Discovery::default();
+    let discovery = &config.discovery.as_ref().unwrap_or(&default);
+    // Every reference to a shard_map needs a reference to a valid discovery block
+    check_config_discovery(config, discovery)?;
+    check_config_route(config)?;
+    check_config_servers(config)?;
+    check_config_backends(config)?;
+    check_config_processors(config)?;
+    check_config_alerts(config)?;
+    check_config_shutdown(config)?;
+    Ok(())
+}
+
+/// Check a pipeline of processors, recording the processor each of them is
+/// followed by in `next`, along with the owner of the pipeline.
+fn add_pipeline(
+    known: &HashSet<String>,
+    next: &mut HashMap<String, (String, String)>,
+    owner: &str,
+    pipeline: &[String],
+) -> Result<(), Error> {
+    let invalid = |reason: String| Error::InvalidPipeline {
+        owner: owner.to_owned(),
+        reason,
+    };
+    let mut seen = HashSet::new();
+    for processor in pipeline.iter() {
+        if !seen.insert(processor) {
+            return Err(invalid(format!("processor {} is repeated", processor)));
+        }
+        if !known.contains(processor) {
+            return Err(invalid(format!("unknown processor {}", processor)));
+        }
+    }
+    for stages in pipeline.windows(2) {
+        let (from, to) = (&stages[0], &stages[1]);
+        match next.get(from) {
+            Some((other, other_owner)) if other != to => {
+                return Err(invalid(format!(
+                    "processor {} is followed by {} in the pipeline of {}",
+                    from, other, other_owner
+                )));
+            }
+            Some(_) => {}
+            None => {
+                next.insert(from.clone(), (to.clone(), owner.to_owned()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the pipelines of servers, and pipeline routes of processors, with
+/// the routes they stand for. A pipeline is routed to as its first
+/// processor, and each processor of the pipeline but the last is routed to
+/// the next. Pipelines can share processors, so long as each is always
+/// followed by the same processor, and processors routed to by a pipeline
+/// must have no route of their own.
+fn expand_pipelines(config: &mut Config) -> Result<(), Error> {
+    let known: HashSet<String> = config
+        .processors
+        .iter()
+        .flat_map(|processors| processors.keys().cloned())
+        .collect();
+    let first = |pipeline: &[String]| Route {
+        route_type: RouteType::Processor,
+        route_to: pipeline[0].clone(),
+    };
+    // The processor each processor of a pipeline is followed by, and the
+    // owner of the pipeline which set it
+    let mut next: HashMap<String, (String, String)> = HashMap::new();
+
+    let statsd = config
+        .statsd
+        .servers
+        .iter_mut()
+        .map(|(name, server)| (name, &mut server.route, &server.pipeline));
+    let otlp = config
+        .otlp
+        .iter_mut()
+        .flat_map(|otlp| otlp.servers.iter_mut())
+        .map(|(name, server)| (name, &mut server.route, &server.pipeline));
+    let kafka = config
+        .kafka
+        .iter_mut()
+        .flat_map(|kafka| kafka.servers.iter_mut())
+        .map(|(name, server)| (name, &mut server.route, &server.pipeline));
+    for (server, route, pipeline) in statsd.chain(otlp).chain(kafka) {
+        if pipeline.is_empty() {
+            continue;
+        }
+        let owner = format!("server {}", server);
+        if !route.is_empty() {
+            return Err(Error::InvalidPipeline {
+                owner,
+                reason: "a server can't have both a route and a pipeline".into(),
+            });
+        }
+        add_pipeline(&known, &mut next, &owner, pipeline)?;
+        *route = vec![first(pipeline)];
+    }
+
+    let processors = match config.processors.as_mut() {
+        Some(processors) => processors,
+        None => return Ok(()),
+    };
+    for (name, processor) in processors.iter_mut() {
+        let owner = format!("processor {}", name);
+        for route in processor.processor.route_mut().iter_mut() {
+            if route.route_type != RouteType::Pipeline {
+                continue;
+            }
+            let pipeline: Vec<String> = route.route_to.split(',').map(str::to_owned).collect();
+            if pipeline.contains(name) {
+                return Err(Error::InvalidPipeline {
+                    owner,
+                    reason: "a processor can't route to a pipeline it is in".into(),
+                });
+            }
+            add_pipeline(&known, &mut next, &owner, &pipeline)?;
+            *route = first(&pipeline);
+        }
+    }
+
+    // Pipelines joined through shared processors must not loop
+    for start in next.keys() {
+        let mut processor = start;
+        for _ in 0..next.len() {
+            match next.get(processor) {
+                Some((to, owner)) if to == start => {
+                    return Err(Error::InvalidPipeline {
+                        owner: owner.clone(),
+                        reason: format!("processor {} is routed back to", start),
+                    });
+                }
+                Some((to, _)) => processor = to,
+                None => break,
+            }
+        }
+    }
+
+    for (from, (to, owner)) in next {
+        let route = processors.get_mut(&from).unwrap().processor.route_mut();
+        if !route.is_empty() {
+            return Err(Error::InvalidPipeline {
+                owner,
+                reason: format!("processor {} already has a route", from),
+            });
+        }
+        *route = vec![Route {
+            route_type: RouteType::Processor,
+            route_to: to,
+        }];
+    }
+    Ok(())
+}
+
+pub fn load(path: &str) -> anyhow::Result<Config> {
+    let input = std::fs::read_to_string(path)?;
+    let mut config: Config = serde_json::from_str(input.as_ref())?;
+    expand_pipelines(&mut config)?;
+    // Perform some high level validation
+    check_config(&config)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn load_example_config() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default":
+                        {
+                            "bind": "127.0.0.1:BIND_STATSD_PORT",
+                            "route": ["statsd:test1"],
+                            "read_buffer": 65535
+                        }
+                },
+                "backends": {
+                    "test1":
+                       {
+                            "prefix": "test-1.",
+                            "shard_map": [
+                                "127.0.0.1:SEND_STATSD_PORT"
+                            ],
+                            "suffix": ".suffix"
+                        },
+                "mapsource":
+                        {
+                            "input_filter": "^(?=dontmatchme)",
+                            "prefix": "test-2.",
+                            "shard_map_source": "my_s3"
+                        }
+                }
+            },
+            "processors": {
+                "tag1": {
+                    "type": "tag_converter",
+                    "route": ["statsd:test1"]
+                },
+                "regex": {
+                    "type": "regex_filter",
+                    "allow": [".*"],
+                    "route": ["statsd:test1"]
+                }
+            },
+            "discovery": {
+                "sources": {
+                    "file": {
+                        "type":"static_file",
+                        "path":"/tmp/file",
+                        "interval":5
+                    },
+                    "my_s3": {
+                        "type": "s3",
+                        "bucket": "foo",
+                        "key": "bar",
+                        "interval": 3,
+                        "transforms": [
+                            {
+                                "type": "repeat",
+                                "count": 3
+                            },
+                            {
+                                "type": "format",
+                                "pattern": "{}:123"
+                            }
+                        ]
+                    }
+                }
+            }
+        }
+        "#;
+        let mut tf = NamedTempFile::new().unwrap();
+        tf.write_all(config.as_bytes()).unwrap();
+        let config = load(tf.path().to_str().unwrap()).unwrap();
+        // Check servers
+        let default_server = config.statsd.servers.get("default").unwrap();
+        assert_eq!(
+            default_server.bind,
+            "127.0.0.1:BIND_STATSD_PORT".to_string()
+        );
+        assert!(default_server.udp_enabled);
+        assert!(default_server.udp_bind.is_none());
+        // Check processors
+        assert_eq!(2, config.clone().processors.unwrap_or_default().len());
+        // Check discovery
+        let discovery = config.discovery.unwrap();
+        assert_eq!(2, discovery.sources.len());
+        let s3_source = discovery.sources.get("my_s3").unwrap();
+        match s3_source {
+            DiscoverySource::S3(source) => {
+                assert!(source.bucket == "foo");
+            }
+            _ => panic!("not an s3 source"),
+        };
+    }
+
+    fn load_str(config: &str) -> anyhow::Result<Config> {
+        let mut tf = NamedTempFile::new().unwrap();
+        tf.write_all(config.as_bytes()).unwrap();
+        load(tf.path().to_str().unwrap())
+    }
+
+    #[test]
+    fn reject_zero_read_timeout() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "127.0.0.1:BIND_STATSD_PORT",
+                        "route": [],
+                        "read_timeout_seconds": 0
+                    }
+                },
+                "backends": {}
+            }
+        }
+        "#;
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidServerOption {
+                option: "read_timeout_seconds",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn discovery_options() {
+        let config = |source: &str| {
+            format!(
+                r#"
+                {{
+                    "statsd": {{
+                        "servers": {{}},
+                        "backends": {{}}
+                    }},
+                    "discovery": {{
+                        "sources": {{
+                            "statsd": {}
+                        }}
+                    }}
+                }}
+                "#,
+                source
+            )
+        };
+        let loaded = load_str(&config(
+            r#"{"type": "dns", "name": "_statsd._udp.local", "record_type": "srv", "interval": 30}"#,
+        ))
+        .unwrap();
+        match loaded.discovery.unwrap().sources.get("statsd").unwrap() {
+            DiscoverySource::Dns(source) => assert_eq!(source.record_type, DnsRecordType::Srv),
+            _ => panic!("not a dns source"),
+        }
+
+        for (source, expected) in [
+            (
+                r#"{"type": "dns", "name": "statsd.local", "interval": 30}"#,
+                "port",
+            ),
+            (
+                r#"{"type": "dns", "name": "statsd.local", "port": 8125, "interval": 30, "nameserver": "dns"}"#,
+                "nameserver",
+            ),
+            (
+                r#"{"type": "s3", "bucket": "b", "key": "k", "interval": 30, "endpoint": "minio:9000"}"#,
+                "endpoint",
+            ),
+            (
+                r#"{"type": "s3", "bucket": "b", "key": "k", "interval": 30, "region": "mars-1"}"#,
+                "region",
+            ),
+            (
+                r#"{"type": "s3", "bucket": "b", "key": "k", "interval": 30, "external_id": "x"}"#,
+                "external_id",
+            ),
+        ] {
+            let err = load_str(&config(source)).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::InvalidDiscoveryOption { option, .. }) if *option == expected
+            ));
+        }
+        // Regions are only checked against AWS's without an endpoint
+        load_str(&config(
+            r#"{"type": "s3", "bucket": "b", "key": "k", "interval": 30, "region": "minio", "endpoint": "http://minio:9000"}"#,
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn load_alerts() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {}
+            },
+            "alerts": {
+                "rules": [
+                    {
+                        "name": "backend_failing",
+                        "metric": "backend_fails",
+                        "mode": "rate",
+                        "threshold": 10,
+                        "level": "error"
+                    },
+                    {
+                        "name": "queue_backlog",
+                        "metric": "queue_depth",
+                        "threshold": 50000
+                    }
+                ]
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        let rules = config.alerts.unwrap().rules;
+        assert_eq!(rules[0].mode, AlertMode::Rate);
+        assert_eq!(rules[0].level, AlertLevel::Error);
+        assert_eq!(rules[1].mode, AlertMode::Value);
+        assert_eq!(rules[1].level, AlertLevel::Warn);
+    }
+
+    #[test]
+    fn load_shutdown() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {}
+            },
+            "shutdown": {
+                "backend_drain_seconds": 30
+            }
+        }
+        "#;
+        let shutdown = load_str(config).unwrap().shutdown.unwrap();
+        assert_eq!(shutdown.backend_drain_seconds, Some(30));
+        assert_eq!(shutdown.listener_drain_seconds, None);
+
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {}
+            },
+            "shutdown": {
+                "processor_flush_seconds": 0
+            }
+        }
+        "#;
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidShutdownOption("processor_flush_seconds"))
+        ));
+    }
+
+    #[test]
+    fn load_prometheus() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "127.0.0.1:8125",
+                        "route": ["prometheus:remote"]
+                    }
+                },
+                "backends": {}
+            },
+            "prometheus": {
+                "backends": {
+                    "remote": {
+                        "url": "http://localhost:9090/api/v1/write",
+                        "shards": 4
+                    }
+                }
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        assert_eq!(
+            config.statsd.servers["default"].route[0].route_type,
+            RouteType::Prometheus
+        );
+        assert_eq!(
+            config.prometheus.unwrap().backends["remote"].shards,
+            Some(4)
+        );
+
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {}
+            },
+            "prometheus": {
+                "backends": {
+                    "remote": {
+                        "url": "https://localhost:9090/api/v1/write"
+                    }
+                }
+            }
+        }
+        "#;
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBackendOption { option: "url", .. })
+        ));
+    }
+
+    #[test]
+    fn load_influx() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "127.0.0.1:8125",
+                        "route": ["influx:metrics"]
+                    }
+                },
+                "backends": {}
+            },
+            "influx": {
+                "backends": {
+                    "metrics": {
+                        "url": "http://localhost:8086/write?db=metrics",
+                        "username": "statsrelay",
+                        "password": "secret"
+                    }
+                }
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        let backend = &config.influx.unwrap().backends["metrics"];
+        assert_eq!(backend.username.as_deref(), Some("statsrelay"));
+
+        // https needs TLS settings
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {}
+            },
+            "influx": {
+                "backends": {
+                    "metrics": {
+                        "url": "https://localhost:8086/write?db=metrics"
+                    }
+                }
+            }
+        }
+        "#;
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBackendOption { option: "url", .. })
+        ));
+    }
 
-#[cfg(test)]
-pub mod test {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+    #[test]
+    fn load_backend_shard_key() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {
+                    "default": {
+                        "shard_map": ["127.0.0.1:8126"]
+                    },
+                    "hosts": {
+                        "shard_map": ["127.0.0.1:8126"],
+                        "shard_key": "tag:host"
+                    }
+                }
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        assert_eq!(config.statsd.backends["default"].shard_key, ShardKey::Name);
+        assert_eq!(
+            config.statsd.backends["hosts"].shard_key,
+            ShardKey::Tag("host".to_owned())
+        );
+
+        for key in ["tag:", "host", "name:host"] {
+            assert!(matches!(
+                ShardKey::try_from(key),
+                Err(Error::MalformedShardKey(_))
+            ));
+        }
+    }
 
     #[test]
-    fn load_example_config() {
+    fn load_backend_migration() {
         let config = r#"
         {
             "statsd": {
-                "servers": {
-                    "default":
-                        {
-                            "bind": "127.0.0.1:BIND_STATSD_PORT",
-                            "route": ["statsd:test1"],
-                            "read_buffer": 65535
-                        }
-                },
+                "servers": {},
                 "backends": {
-                    "test1":
-                       {
-                            "prefix": "test-1.",
-                            "shard_map": [
-                                "127.0.0.1:SEND_STATSD_PORT"
-                            ],
-                            "suffix": ".suffix"
-                        },
-                "mapsource":
-                        {
-                            "input_filter": "^(?=dontmatchme)",
-                            "prefix": "test-2.",
-                            "shard_map_source": "my_s3"
+                    "default": {
+                        "shard_map": ["127.0.0.1:8126"],
+                        "migration": {
+                            "shard_map": ["127.0.0.1:8127", "127.0.0.1:8128"],
+                            "cutover_percent": 10
                         }
+                    }
                 }
-            },
-            "processors": {
-                "tag1": {
-                    "type": "tag_converter",
-                    "route": ["statsd:test1"]
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        let migration = config.statsd.backends["default"].migration.as_ref();
+        assert_eq!(
+            migration,
+            Some(&MigrationConfig {
+                shard_map: vec!["127.0.0.1:8127".to_owned(), "127.0.0.1:8128".to_owned()],
+                shard_map_source: None,
+                cutover_percent: 10_f64,
+                dual_write: false,
+            })
+        );
+
+        for migration in [
+            r#"{"shard_map": ["127.0.0.1:8127"], "cutover_percent": 101}"#,
+            r#"{"cutover_percent": 50}"#,
+        ] {
+            let config = format!(
+                r#"{{"statsd": {{"servers": {{}}, "backends": {{"default": {{
+                    "shard_map": ["127.0.0.1:8126"], "migration": {}}}}}}}}}"#,
+                migration
+            );
+            assert!(load_str(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn load_backend_convert_tags() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {
+                    "graphite": {
+                        "shard_map": ["127.0.0.1:2003"],
+                        "format": "graphite",
+                        "convert_tags": "inline"
+                    }
+                }
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        assert_eq!(
+            config.statsd.backends["graphite"].convert_tags,
+            Some(ConvertTags::Inline)
+        );
+
+        // Tags are either converted up front, or formatted on the way out
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {
+                    "graphite": {
+                        "shard_map": ["127.0.0.1:2003"],
+                        "convert_tags": "inline",
+                        "tag_format": "strip"
+                    }
+                }
+            }
+        }
+        "#;
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBackendOption {
+                option: "convert_tags",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn load_backend_unix_tls() {
+        let config = |server_name: &str| {
+            format!(
+                r#"
+        {{
+            "statsd": {{
+                "servers": {{}},
+                "backends": {{
+                    "local": {{
+                        "shard_map": ["unix:///run/statsd.sock"],
+                        "tls": {{"ca": "/etc/ssl/ca.pem"{}}}
+                    }}
+                }}
+            }}
+        }}
+        "#,
+                server_name
+            )
+        };
+        load_str(&config(r#", "server_name": "relay.local""#)).unwrap();
+        let err = load_str(&config("")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBackendOption {
+                option: "tls.server_name",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn load_backend_slow_start() {
+        let config = |vnodes: &str| {
+            format!(
+                r#"
+        {{
+            "statsd": {{
+                "servers": {{}},
+                "backends": {{
+                    "ring": {{
+                        "shard_map": ["127.0.0.1:8125", "127.0.0.2:8125"],
+                        "slow_start_ms": 60000{}
+                    }}
+                }}
+            }}
+        }}
+        "#,
+                vnodes
+            )
+        };
+        load_str(&config(r#", "vnodes": 100"#)).unwrap();
+        let err = load_str(&config("")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBackendOption {
+                option: "slow_start_ms",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn load_file() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "127.0.0.1:8125",
+                        "route": ["file:audit"]
+                    }
                 },
-                "regex": {
-                    "type": "regex_filter",
-                    "allow": [".*"],
-                    "route": ["statsd:test1"]
+                "backends": {}
+            },
+            "file": {
+                "backends": {
+                    "audit": {
+                        "dir": "/var/lib/statsrelay",
+                        "rotate_seconds": 3600,
+                        "compression": "zstd",
+                        "fsync": "rotate"
+                    }
                 }
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        let backend = &config.file.unwrap().backends["audit"];
+        assert_eq!(backend.fsync, FsyncPolicy::Rotate);
+        assert_eq!(backend.compression, Some(Compression::Zstd));
+
+        // File names can't be placed in another directory
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {},
+                "backends": {}
             },
-            "discovery": {
-                "sources": {
-                    "file": {
-                        "type":"static_file",
-                        "path":"/tmp/file",
-                        "interval":5
-                    },
-                    "my_s3": {
-                        "type": "s3",
-                        "bucket": "foo",
-                        "key": "bar",
-                        "interval": 3,
-                        "transforms": [
-                            {
-                                "type": "repeat",
-                                "count": 3
-                            },
-                            {
-                                "type": "format",
-                                "pattern": "{}:123"
-                            }
-                        ]
+            "file": {
+                "backends": {
+                    "audit": {
+                        "dir": "/var/lib/statsrelay",
+                        "prefix": "../audit"
                     }
                 }
             }
         }
         "#;
-        let mut tf = NamedTempFile::new().unwrap();
-        tf.write_all(config.as_bytes()).unwrap();
-        let config = load(tf.path().to_str().unwrap()).unwrap();
-        // Check servers
-        let default_server = config.statsd.servers.get("default").unwrap();
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBackendOption {
+                option: "prefix",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn load_null() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "127.0.0.1:8125",
+                        "route": ["null:staging"]
+                    }
+                },
+                "backends": {}
+            },
+            "null": {
+                "backends": {
+                    "staging": {}
+                }
+            }
+        }
+        "#;
+        let config = load_str(config).unwrap();
         assert_eq!(
-            default_server.bind,
-            "127.0.0.1:BIND_STATSD_PORT".to_string()
+            config.null.unwrap().backends["staging"],
+            NullBackendConfig::default()
         );
-        // Check processors
-        assert_eq!(2, config.clone().processors.unwrap_or_default().len());
-        // Check discovery
-        let discovery = config.discovery.unwrap();
-        assert_eq!(2, discovery.sources.len());
-        let s3_source = discovery.sources.get("my_s3").unwrap();
-        match s3_source {
-            DiscoverySource::S3(source) => {
-                assert!(source.bucket == "foo");
+    }
+
+    #[test]
+    fn load_sampler_reservoir() {
+        let config = |percentiles: &str| {
+            format!(
+                r#"
+        {{
+            "statsd": {{
+                "servers": {{}},
+                "backends": {{}}
+            }},
+            "processors": {{
+                "sample": {{
+                    "type": "sampler",
+                    "window": 10,
+                    "timer_reservoir_size": 0,
+                    "timer_percentiles": [{}]
+                }}
+            }}
+        }}
+        "#,
+                percentiles
+            )
+        };
+        load_str(&config("")).unwrap();
+        let err = load_str(&config("99")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidProcessorOption {
+                option: "timer_reservoir_size",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn load_workers() {
+        let config = |threads: usize| {
+            format!(
+                r#"
+        {{
+            "statsd": {{
+                "servers": {{
+                    "default": {{
+                        "bind": "127.0.0.1:8125",
+                        "route": ["processor:rewrite"]
+                    }}
+                }},
+                "backends": {{}}
+            }},
+            "processors": {{
+                "rewrite": {{
+                    "type": "tag_converter",
+                    "workers": {{ "threads": {}, "queue_size": 1000 }}
+                }}
+            }}
+        }}
+        "#,
+                threads
+            )
+        };
+        let processors = load_str(&config(2)).unwrap().processors.unwrap();
+        let rewrite = &processors["rewrite"];
+        assert!(matches!(rewrite.processor, Processor::TagConverter(_)));
+        let workers = rewrite.workers.as_ref().unwrap();
+        assert_eq!(workers.threads, Some(2));
+        assert_eq!(workers.queue_size, 1000);
+
+        let err = load_str(&config(0)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidProcessorOption {
+                option: "workers.threads",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn load_ip_family() {
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "[::]:8125",
+                        "ip_family": "dual",
+                        "route": []
+                    }
+                },
+                "backends": {}
             }
-            _ => panic!("not an s3 source"),
+        }
+        "#;
+        let config = load_str(config).unwrap();
+        assert_eq!(
+            config.statsd.servers["default"].ip_family,
+            Some(IpFamily::Dual)
+        );
+
+        let config = r#"
+        {
+            "statsd": {
+                "servers": {
+                    "default": {
+                        "bind": "127.0.0.1:8125",
+                        "ip_family": "v6",
+                        "route": []
+                    }
+                },
+                "backends": {}
+            }
+        }
+        "#;
+        let err = load_str(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidServerOption {
+                option: "ip_family",
+                ..
+            })
+        ));
+    }
+
+    fn pipeline_config(first: &str, second: &str) -> String {
+        r#"
+        {
+            "statsd": {
+                "servers": {
+                    "first": {"bind": "127.0.0.1:8125", "pipeline": FIRST},
+                    "second": {"bind": "127.0.0.1:8126", "pipeline": SECOND}
+                },
+                "backends": {
+                    "test1": {"shard_map": ["127.0.0.1:1"]}
+                }
+            },
+            "processors": {
+                "tags": {"type": "tag_converter"},
+                "filter": {"type": "regex_filter", "allow": [".*"]},
+                "keep": {"type": "tag_filter", "keep": ["env"], "route": ["statsd:test1"]}
+            }
+        }
+        "#
+        .replace("FIRST", first)
+        .replace("SECOND", second)
+    }
+
+    #[test]
+    fn load_pipeline() {
+        let config = load_str(&pipeline_config(
+            r#"["tags", "filter", "keep"]"#,
+            r#"["filter", "keep"]"#,
+        ))
+        .unwrap();
+        let route = |to: &str| {
+            vec![Route {
+                route_type: RouteType::Processor,
+                route_to: to.to_owned(),
+            }]
+        };
+        assert_eq!(config.statsd.servers["first"].route, route("tags"));
+        assert_eq!(config.statsd.servers["second"].route, route("filter"));
+        let processors = config.processors.unwrap();
+        match &processors["tags"].processor {
+            Processor::TagConverter(tags) => assert_eq!(tags.route, route("filter")),
+            _ => unreachable!(),
+        }
+        match &processors["filter"].processor {
+            Processor::RegexFilter(filter) => assert_eq!(filter.route, route("keep")),
+            _ => unreachable!(),
+        }
+        match &processors["keep"].processor {
+            Processor::TagFilter(keep) => assert_eq!(keep.route[0].route_to, "test1"),
+            _ => unreachable!(),
+        }
+
+        for (first, second) in [
+            // A processor followed by different processors
+            (r#"["tags", "filter"]"#, r#"["tags", "keep"]"#),
+            // Processors routed to each other
+            (r#"["tags", "filter"]"#, r#"["filter", "tags"]"#),
+            // A processor with a route of its own
+            (r#"["keep", "tags"]"#, r#"[]"#),
+            (r#"["tags", "missing"]"#, r#"[]"#),
+            (r#"["tags", "tags"]"#, r#"[]"#),
+        ] {
+            let err = load_str(&pipeline_config(first, second)).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::InvalidPipeline { .. })
+            ));
+        }
+    }
+
+    fn processor_pipeline_config(route: &str) -> String {
+        r#"
+        {
+            "statsd": {
+                "servers": {
+                    "first": {"bind": "127.0.0.1:8125", "route": ["processor:split"]}
+                },
+                "backends": {
+                    "test1": {"shard_map": ["127.0.0.1:1"]}
+                }
+            },
+            "processors": {
+                "split": {"type": "tag_converter", "route": ROUTE},
+                "tags": {"type": "tag_converter"},
+                "filter": {"type": "regex_filter", "allow": [".*"]},
+                "keep": {"type": "tag_filter", "keep": ["env"], "route": ["statsd:test1"]}
+            }
+        }
+        "#
+        .replace("ROUTE", route)
+    }
+
+    #[test]
+    fn load_processor_pipeline() {
+        let config = load_str(&processor_pipeline_config(
+            r#"["statsd:test1", "pipeline:tags,filter,keep"]"#,
+        ))
+        .unwrap();
+        let route = |to: &str| Route {
+            route_type: RouteType::Processor,
+            route_to: to.to_owned(),
         };
+        let processors = config.processors.unwrap();
+        match &processors["split"].processor {
+            Processor::TagConverter(split) => {
+                assert_eq!(split.route[0].route_type, RouteType::Statsd);
+                assert_eq!(split.route[1], route("tags"));
+            }
+            _ => unreachable!(),
+        }
+        match &processors["tags"].processor {
+            Processor::TagConverter(tags) => assert_eq!(tags.route, vec![route("filter")]),
+            _ => unreachable!(),
+        }
+        match &processors["filter"].processor {
+            Processor::RegexFilter(filter) => assert_eq!(filter.route, vec![route("keep")]),
+            _ => unreachable!(),
+        }
+
+        for invalid in [
+            // A pipeline back through the processor routing to it
+            r#"["pipeline:tags,split"]"#,
+            r#"["pipeline:tags,missing"]"#,
+            // A processor with a route of its own
+            r#"["pipeline:keep,tags"]"#,
+        ] {
+            let err = load_str(&processor_pipeline_config(invalid)).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::InvalidPipeline { .. })
+            ));
+        }
     }
 }
@@ -1,19 +1,39 @@
 use crate::config::{
-    Discovery, DiscoverySource, DiscoveryTransform, PathDiscoverySource, S3DiscoverySource,
+    Discovery, DiscoverySource, DiscoveryTransform, DnsDiscoverySource, DnsRecordType,
+    Ec2AsgDiscoverySource, HttpDiscoverySource, S3DiscoverySource,
 };
+use crate::dns::{self, RecordData, RecordType};
+use crate::error::{Categorized, Category, ErrorCounters};
+use crate::http_client::{self, HttpClient};
+use crate::stats;
+use crate::statsd_client::Backoff;
+use crate::tls::{self, ClientTls};
 
+use std::fs::File;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{fs::File, ops::Add};
 use std::{io::BufReader, pin::Pin};
 
+use async_compression::tokio::bufread::GzipDecoder;
 use async_stream::stream;
 use dashmap::DashMap;
 use futures::{stream::Stream, StreamExt};
-use log::warn;
-use rusoto_s3::S3;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED, USER_AGENT,
+};
+use hyper::StatusCode;
+use rusoto_core::credential::{
+    CredentialsError, DefaultCredentialsProvider, ProfileProvider, ProvideAwsCredentials,
+};
+use rusoto_core::request::TlsError;
+use rusoto_core::{Region, RusotoError};
+use rusoto_ec2::{DescribeInstancesError, DescribeInstancesRequest, DescribeInstancesResult, Ec2};
+use rusoto_s3::{GetObjectError, S3Client, S3};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
 use tokio::time::Instant;
 use tokio_stream::StreamMap;
 
@@ -53,11 +73,164 @@ fn transform_repeat(count: u32, input: &Update) -> Option<Update> {
     }
 }
 
+/// A transformer which orders hosts, so the shard map doesn't depend on the
+/// order of the source
+fn transform_sort(input: &Update) -> Option<Update> {
+    let mut hosts = input.hosts.clone();
+    hosts.sort();
+    Some(Update { hosts })
+}
+
+/// A transformer which drops repeated hosts, keeping the first of each
+fn transform_dedup(input: &Update) -> Option<Update> {
+    let mut seen = std::collections::HashSet::new();
+    Some(Update {
+        hosts: input
+            .hosts
+            .iter()
+            .filter(|host| seen.insert(*host))
+            .cloned()
+            .collect(),
+    })
+}
+
+/// A transformer which shuffles hosts in an order fixed by the seed, so every
+/// relay with the same seed builds the same shard map
+fn transform_shuffle(seed: u64, input: &Update) -> Option<Update> {
+    let mut hosts = input.hosts.clone();
+    fastrand::Rng::with_seed(seed).shuffle(&mut hosts);
+    Some(Update { hosts })
+}
+
+/// A transformer which keeps only hosts matching a regex
+fn transform_filter(pattern: &str, input: &Update) -> Option<Update> {
+    let regex = regex::Regex::new(pattern).ok()?;
+    Some(Update {
+        hosts: input
+            .hosts
+            .iter()
+            .filter(|host| regex.is_match(host))
+            .cloned()
+            .collect(),
+    })
+}
+
+/// A transformer which drops listed hosts, and hosts matching a regex
+fn transform_exclude(hosts: &[String], pattern: Option<&str>, input: &Update) -> Option<Update> {
+    let regex = match pattern {
+        Some(pattern) => Some(regex::Regex::new(pattern).ok()?),
+        None => None,
+    };
+    Some(Update {
+        hosts: input
+            .hosts
+            .iter()
+            .filter(|host| {
+                !hosts.contains(host) && !regex.as_ref().is_some_and(|regex| regex.is_match(host))
+            })
+            .cloned()
+            .collect(),
+    })
+}
+
 impl Transformer for DiscoveryTransform {
     fn transform(&self, input: &Update) -> Option<Update> {
         match self {
             DiscoveryTransform::Format { pattern } => transform_format(pattern, input),
             DiscoveryTransform::Repeat { count } => transform_repeat(*count, input),
+            DiscoveryTransform::Sort => transform_sort(input),
+            DiscoveryTransform::Dedup => transform_dedup(input),
+            DiscoveryTransform::Shuffle { seed } => transform_shuffle(*seed, input),
+            DiscoveryTransform::Filter { pattern } => transform_filter(pattern, input),
+            DiscoveryTransform::Exclude { hosts, pattern } => transform_exclude(
+                hosts.as_deref().unwrap_or_default(),
+                pattern.as_deref(),
+                input,
+            ),
+        }
+    }
+}
+
+/// Hosts excluded at runtime, such as through the admin server. When set,
+/// they replace the listed hosts of every `exclude` transform, and sources
+/// with one emit their updates again.
+#[derive(Clone)]
+pub struct Exclusions {
+    hosts: Arc<watch::Sender<Option<Vec<String>>>>,
+}
+
+impl Exclusions {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(None);
+        Exclusions {
+            hosts: Arc::new(sender),
+        }
+    }
+
+    pub fn get(&self) -> Option<Vec<String>> {
+        self.hosts.borrow().clone()
+    }
+
+    /// Override the excluded hosts, or go back to those of the configuration
+    /// with None
+    pub fn set(&self, hosts: Option<Vec<String>>) {
+        self.hosts.send_replace(hosts);
+    }
+}
+
+impl Default for Exclusions {
+    fn default() -> Self {
+        Exclusions::new()
+    }
+}
+
+/// Apply transforms in order, skipping any which don't apply to the update.
+/// Excluded hosts replace those listed by `exclude` transforms.
+fn apply_transforms(
+    transforms: &[DiscoveryTransform],
+    excluded: Option<&[String]>,
+    mut update: Update,
+) -> Update {
+    for trans in transforms {
+        let new_update = match (trans, excluded) {
+            (DiscoveryTransform::Exclude { pattern, .. }, Some(excluded)) => {
+                transform_exclude(excluded, pattern.as_deref(), &update)
+            }
+            _ => trans.transform(&update),
+        };
+        if let Some(new_update) = new_update {
+            update = new_update;
+        }
+    }
+    update
+}
+
+/// Apply transforms to the updates of a source. Sources with an `exclude`
+/// transform emit their last update again when the excluded hosts change.
+fn transformed_stream(
+    mut source: Pin<Box<dyn Stream<Item = Update> + Send>>,
+    transforms: Vec<DiscoveryTransform>,
+    exclusions: Exclusions,
+) -> impl Stream<Item = Update> {
+    let excludes = transforms
+        .iter()
+        .any(|trans| matches!(trans, DiscoveryTransform::Exclude { .. }));
+    stream! {
+        let mut changes = exclusions.hosts.subscribe();
+        let mut last_update: Option<Update> = None;
+        loop {
+            tokio::select! {
+                update = source.next() => match update {
+                    Some(update) => last_update = Some(update),
+                    None => return,
+                },
+                // The sender is held by exclusions, so is never dropped
+                _ = changes.changed(), if excludes => (),
+            }
+            if let Some(update) = &last_update {
+                let excluded = exclusions.get();
+                yield apply_transforms(&transforms, excluded.as_deref(), update.clone());
+            }
         }
     }
 }
@@ -81,115 +254,586 @@ impl Default for Update {
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("reading a discovery source had no data")]
-    EmptyObjectError,
+    #[error("reading discovery source {0} had no data")]
+    EmptyObjectError(String),
+    #[error("fetching discovery object from s3 failed: {0}")]
+    S3(Box<RusotoError<GetObjectError>>),
+    #[error("reading discovery object body failed: {0}")]
+    ReadBody(std::io::Error),
+    #[error("could not read discovery file {path}: {source}")]
+    File {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("malformed discovery document: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("discovery task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("resolving discovery name failed: {0}")]
+    Dns(#[from] dns::Error),
+    #[error("fetching discovery document failed: {0}")]
+    Http(#[from] http_client::Error),
+    #[error("invalid discovery tls settings: {0}")]
+    Tls(#[from] tls::Error),
+    #[error("invalid discovery request header {0}")]
+    Header(String),
+    #[error("listing discovery instances from ec2 failed: {0}")]
+    Ec2(Box<RusotoError<DescribeInstancesError>>),
+    #[error("invalid discovery region: {0}")]
+    Region(#[from] rusoto_core::region::ParseRegionError),
+    #[error("loading discovery credentials failed: {0}")]
+    Credentials(#[from] CredentialsError),
+    #[error("creating discovery client failed: {0}")]
+    Client(#[from] TlsError),
+    #[error("decompressing discovery object failed: {0}")]
+    Gunzip(std::io::Error),
 }
 
-async fn poll_s3_source(config: S3DiscoverySource) -> anyhow::Result<Update> {
-    let region = rusoto_core::Region::default();
-    let s3 = rusoto_s3::S3Client::new(region);
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        match self {
+            Error::EmptyObjectError(_) | Error::Decode(_) | Error::Gunzip(_) => Category::Protocol,
+            Error::S3(_) | Error::ReadBody(_) => Category::Network,
+            Error::File { .. } => Category::Config,
+            Error::Task(_) => Category::Internal,
+            Error::Dns(e) => e.category(),
+            Error::Http(e) => e.category(),
+            Error::Ec2(_) => Category::Network,
+            Error::Tls(_) | Error::Header(_) | Error::Region(_) | Error::Credentials(_) => {
+                Category::Config
+            }
+            Error::Client(_) => Category::Internal,
+        }
+    }
+}
+
+/// Session name roles are assumed with, identifying statsrelay in audit logs
+const ROLE_SESSION_NAME: &str = "statsrelay";
+/// Leading bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The region of a source, or a custom region at the endpoint of an S3
+/// compatible service
+fn aws_region(region: Option<&str>, endpoint: Option<&str>) -> Result<Region, Error> {
+    Ok(match (region, endpoint) {
+        (region, Some(endpoint)) => Region::Custom {
+            name: region.unwrap_or(Region::default().name()).to_owned(),
+            endpoint: endpoint.to_owned(),
+        },
+        (Some(region), None) => region.parse()?,
+        (None, None) => Region::default(),
+    })
+}
+
+/// An S3 client reading with the credentials, or the role assumed with them
+fn s3_client<P>(
+    config: &S3DiscoverySource,
+    region: Region,
+    credentials: P,
+) -> Result<S3Client, Error>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    let dispatcher = rusoto_core::HttpClient::new()?;
+    Ok(match &config.role_arn {
+        Some(role_arn) => {
+            let sts =
+                StsClient::new_with(rusoto_core::HttpClient::new()?, credentials, region.clone());
+            let role = StsAssumeRoleSessionCredentialsProvider::new(
+                sts,
+                role_arn.clone(),
+                ROLE_SESSION_NAME.to_owned(),
+                config.external_id.clone(),
+                None,
+                None,
+                None,
+            );
+            S3Client::new_with(dispatcher, role, region)
+        }
+        None => S3Client::new_with(dispatcher, credentials, region),
+    })
+}
+
+/// Decode a discovery object, which may be gzipped
+async fn decode_object(body: &[u8]) -> Result<Update, Error> {
+    if !body.starts_with(&GZIP_MAGIC) {
+        return Ok(serde_json::from_slice(body)?);
+    }
+    let mut decoded = Vec::with_capacity(body.len() * 4);
+    GzipDecoder::new(body)
+        .read_to_end(&mut decoded)
+        .await
+        .map_err(Error::Gunzip)?;
+    Ok(serde_json::from_slice(&decoded)?)
+}
+
+async fn poll_s3_source(config: S3DiscoverySource) -> Result<Update, Error> {
+    let region = aws_region(config.region.as_deref(), config.endpoint.as_deref())?;
+    let s3 = match &config.profile {
+        Some(profile) => s3_client(
+            &config,
+            region,
+            ProfileProvider::with_default_credentials(profile)?,
+        )?,
+        None => s3_client(&config, region, DefaultCredentialsProvider::new()?)?,
+    };
     let req = rusoto_s3::GetObjectRequest {
         bucket: config.bucket.clone(),
         key: config.key.clone(),
         ..Default::default()
     };
-    let resp = s3.get_object(req).await?;
+    let resp = s3
+        .get_object(req)
+        .await
+        .map_err(|e| Error::S3(Box::new(e)))?;
     let mut buffer = Vec::with_capacity(resp.content_length.unwrap_or(0_i64) as usize);
-    let mut update = match resp.body {
+    match resp.body {
         Some(contents) => {
-            contents.into_async_read().read_to_end(&mut buffer).await?;
-            let update: Update = serde_json::from_slice(buffer.as_ref())?;
-            update
-        }
-        None => {
-            warn!("no cluster state located at {:?}", config.key);
-            return Err(Error::EmptyObjectError.into());
+            contents
+                .into_async_read()
+                .read_to_end(&mut buffer)
+                .await
+                .map_err(Error::ReadBody)?;
+            decode_object(&buffer).await
         }
+        None => Err(Error::EmptyObjectError(config.key)),
+    }
+}
+
+/// Tag AWS sets on the instances of an auto scaling group
+const ASG_NAME_TAG: &str = "aws:autoscaling:groupName";
+
+/// Filters selecting the running instances of an auto scaling group, or with
+/// the configured tags
+fn ec2_filters(config: &Ec2AsgDiscoverySource) -> Vec<rusoto_ec2::Filter> {
+    let filter = |name: String, value: &str| rusoto_ec2::Filter {
+        name: Some(name),
+        values: Some(vec![value.to_owned()]),
     };
+    let mut tags: Vec<(&String, &String)> = config.tags.iter().flatten().collect();
+    tags.sort();
+    std::iter::once(filter("instance-state-name".to_owned(), "running"))
+        .chain(
+            config
+                .asg_name
+                .iter()
+                .map(|name| filter(format!("tag:{}", ASG_NAME_TAG), name)),
+        )
+        .chain(
+            tags.into_iter()
+                .map(|(key, value)| filter(format!("tag:{}", key), value)),
+        )
+        .collect()
+}
 
-    for trans in config.transforms.unwrap_or_default().iter() {
-        if let Some(new_update) = trans.transform(&update) {
-            update = new_update;
+/// The private addresses of the instances of a page of results
+fn ec2_addresses(result: DescribeInstancesResult) -> impl Iterator<Item = String> {
+    result
+        .reservations
+        .into_iter()
+        .flatten()
+        .flat_map(|reservation| reservation.instances.into_iter().flatten())
+        .filter_map(|instance| instance.private_ip_address)
+}
+
+async fn poll_ec2_asg_source(config: Ec2AsgDiscoverySource) -> Result<Update, Error> {
+    let region = aws_region(config.region.as_deref(), None)?;
+    let ec2 = rusoto_ec2::Ec2Client::new(region);
+    let filters = ec2_filters(&config);
+    let mut addresses = Vec::new();
+    let mut next_token = None;
+    loop {
+        let req = DescribeInstancesRequest {
+            filters: Some(filters.clone()),
+            next_token,
+            ..Default::default()
+        };
+        let result = ec2
+            .describe_instances(req)
+            .await
+            .map_err(|e| Error::Ec2(Box::new(e)))?;
+        next_token = result.next_token.clone();
+        addresses.extend(ec2_addresses(result));
+        if next_token.is_none() {
+            break;
         }
     }
-    Ok(update)
+    // Sorted so the shard map is stable while the group is unchanged
+    addresses.sort();
+    Ok(Update {
+        hosts: addresses
+            .into_iter()
+            .map(|address| format!("{}:{}", address, config.port))
+            .collect(),
+    })
 }
 
-async fn poll_file_source(config: PathDiscoverySource, path: String) -> anyhow::Result<Update> {
+async fn poll_file_source(path: String) -> Result<Update, Error> {
     let result = tokio::task::spawn_blocking(move || {
-        let file = File::open(path)?;
+        let file = File::open(&path).map_err(|source| Error::File {
+            path: path.clone(),
+            source,
+        })?;
         let reader = BufReader::new(file);
-        let mut update: Update = serde_json::from_reader(reader)?;
-
-        for trans in config.transforms.unwrap_or_default().iter() {
-            if let Some(new_update) = trans.transform(&update) {
-                update = new_update;
-            }
-        }
-        Ok(update)
+        Ok(serde_json::from_reader(reader)?)
     })
     .await?;
     result
 }
 
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait between polls after failures, unless the interval is longer
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+/// Share of the interval a poll is moved earlier or later by at random
+const POLL_JITTER: f64 = 0.1;
+/// How often the seconds since a source's last successful poll are updated
+const STALENESS_REFRESH: Duration = Duration::from_secs(5);
+
+/// Polls a discovery document over HTTP, sending the validators of the last
+/// response so the server can answer that the document hasn't changed.
+struct HttpPoller {
+    client: HttpClient,
+    config: HttpDiscoverySource,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    timeout: Duration,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl HttpPoller {
+    fn new(config: HttpDiscoverySource) -> Result<Self, Error> {
+        let tls = config.tls.as_ref().map(ClientTls::new).transpose()?;
+        let mut headers = vec![
+            (ACCEPT, HeaderValue::from_static("application/json")),
+            (
+                USER_AGENT,
+                HeaderValue::from_static(concat!("statsrelay/", env!("CARGO_PKG_VERSION"))),
+            ),
+        ];
+        let authorization = match (&config.token, &config.username) {
+            (Some(token), _) => Some(format!("Bearer {}", token)),
+            (None, Some(username)) => Some(http_client::basic_authorization(
+                username,
+                config.password.as_deref(),
+            )),
+            (None, None) => None,
+        };
+        if let Some(authorization) = authorization {
+            let mut value = HeaderValue::from_str(&authorization)
+                .map_err(|_| Error::Header(AUTHORIZATION.to_string()))?;
+            value.set_sensitive(true);
+            headers.push((AUTHORIZATION, value));
+        }
+        for (name, value) in config.headers.iter().flatten() {
+            let invalid = || Error::Header(name.clone());
+            headers.push((
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+                HeaderValue::from_str(value).map_err(|_| invalid())?,
+            ));
+        }
+        Ok(HttpPoller {
+            client: http_client::client(tls),
+            timeout: config
+                .timeout_ms
+                .map_or(HTTP_TIMEOUT, Duration::from_millis),
+            config,
+            headers,
+            etag: None,
+            last_modified: None,
+        })
+    }
+
+    /// Fetch the document, or None if it hasn't changed since the last fetch
+    async fn poll(&mut self) -> Result<Option<Update>, Error> {
+        let mut headers = self.headers.clone();
+        if let Some(etag) = &self.etag {
+            headers.push((IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.push((IF_MODIFIED_SINCE, last_modified.clone()));
+        }
+        let response =
+            http_client::get(&self.client, &self.config.url, &headers, self.timeout).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let update: Update = serde_json::from_slice(response.body())?;
+        // Only keep validators once the document is decoded, so a malformed
+        // document is fetched again in full
+        self.etag = response.headers().get(ETAG).cloned();
+        self.last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        Ok(Some(update))
+    }
+}
+
+/// A stream of the documents of an HTTP source, emitting them when changed
+fn http_stream(mut schedule: Schedule, config: HttpDiscoverySource) -> impl Stream<Item = Update> {
+    let mut last_update = Update::default();
+    stream! {
+        let mut poller = loop {
+            match HttpPoller::new(config.clone()) {
+                Ok(poller) => break poller,
+                Err(e) => {
+                    let wait = schedule.failure(&e);
+                    schedule.wait(wait).await;
+                }
+            }
+        };
+        loop {
+            let wait = match poller.poll().await {
+                Err(e) => schedule.failure(&e),
+                Ok(Some(update)) => {
+                    if update != last_update {
+                        yield update.clone();
+                    }
+                    last_update = update;
+                    schedule.success(None)
+                }
+                Ok(None) => schedule.success(None),
+            };
+            schedule.wait(wait).await;
+        }
+    }
+}
+
+/// The endpoints of DNS records, sorted so the shard map is stable across
+/// resolutions. Service records are ordered by priority.
+fn dns_endpoints(records: Vec<dns::Record>, port: Option<u16>) -> Vec<String> {
+    let mut addresses = Vec::new();
+    let mut services = Vec::new();
+    for record in records {
+        match record.data {
+            RecordData::Address(ip) => addresses.push(ip),
+            RecordData::Srv {
+                priority,
+                port,
+                target,
+                ..
+            } => services.push((priority, target, port)),
+        }
+    }
+    addresses.sort();
+    services.sort();
+    let port = port.unwrap_or_default();
+    addresses
+        .into_iter()
+        .map(|ip| std::net::SocketAddr::new(ip, port).to_string())
+        .chain(
+            services
+                .into_iter()
+                .map(|(_, target, port)| format!("{}:{}", target, port)),
+        )
+        .collect()
+}
+
+/// Resolve a DNS source, returning the update along with the shortest TTL
+/// of its records. Finding no records is an error, keeping the endpoints of
+/// the last resolution rather than emptying the ring.
+async fn poll_dns_source(config: DnsDiscoverySource) -> Result<(Update, Option<u32>), Error> {
+    let nameserver = match config.nameserver.as_deref() {
+        Some(nameserver) => {
+            crate::config::parse_nameserver(nameserver).ok_or(dns::Error::NoNameserver("config"))?
+        }
+        None => dns::system_nameserver()?,
+    };
+    let record_type = match config.record_type {
+        DnsRecordType::A => RecordType::A,
+        DnsRecordType::Aaaa => RecordType::Aaaa,
+        DnsRecordType::Srv => RecordType::Srv,
+    };
+    let records = dns::resolve(nameserver, &config.name, record_type).await?;
+    if records.is_empty() {
+        return Err(dns::Error::NoRecords(config.name).into());
+    }
+    let ttl = records.iter().map(|record| record.ttl).min();
+    let update = Update {
+        hosts: dns_endpoints(records, config.port),
+    };
+    Ok((update, ttl))
+}
+
+/// A stream of the endpoints of a DNS source, resolving again when the
+/// records expire, or at the interval if sooner.
+fn dns_stream(mut schedule: Schedule, config: DnsDiscoverySource) -> impl Stream<Item = Update> {
+    let mut last_update = Update::default();
+    stream! {
+        loop {
+            let wait = match poll_dns_source(config.clone()).await {
+                Err(e) => schedule.failure(&e),
+                Ok((update, ttl)) => {
+                    if update != last_update {
+                        yield update.clone();
+                    }
+                    last_update = update;
+                    schedule.success(ttl.map(|ttl| Duration::from_secs(ttl.max(1) as u64)))
+                }
+            };
+            schedule.wait(wait).await;
+        }
+    }
+}
+
+/// How long to wait between the polls of a source: the interval, spread by
+/// a jitter so relays started together don't poll in step, or after failed
+/// polls, an exponential backoff from the interval. Failed polls and the time
+/// of the last successful poll are recorded for each source, along with the
+/// seconds since, which are kept up to date while waiting.
+struct Schedule {
+    source: String,
+    interval: Duration,
+    backoff: Backoff,
+    failures: u32,
+    /// When the last poll succeeded, or the schedule started if none has
+    succeeded_at: Instant,
+    errors: ErrorCounters,
+    poll_failures: stats::CounterVec,
+    last_success: stats::GaugeVec,
+    since_success: stats::GaugeVec,
+}
+
+impl Schedule {
+    fn new(stats: &SourceStats, source: &str, interval: u32) -> Self {
+        let interval = Duration::from_secs(interval as u64);
+        Schedule {
+            source: source.to_owned(),
+            interval,
+            backoff: Backoff {
+                initial: interval,
+                max: interval.max(MAX_BACKOFF),
+            },
+            failures: 0,
+            succeeded_at: Instant::now(),
+            errors: stats.errors.clone(),
+            poll_failures: stats.poll_failures.clone(),
+            last_success: stats.last_success.clone(),
+            since_success: stats.since_success.clone(),
+        }
+    }
+
+    /// The wait after a successful poll, which is shortened to `expires` if
+    /// the update expires sooner than the interval
+    fn success(&mut self, expires: Option<Duration>) -> Duration {
+        self.failures = 0;
+        self.succeeded_at = Instant::now();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_success
+            .set(&[self.source.as_str()], now.as_secs_f64());
+        let wait = expires.map_or(self.interval, |expires| expires.min(self.interval));
+        wait.mul_f64(1_f64 + POLL_JITTER * (2_f64 * fastrand::f64() - 1_f64))
+    }
+
+    /// The wait after a failed poll
+    fn failure(&mut self, e: &Error) -> Duration {
+        self.errors.report(e);
+        self.poll_failures.inc(&[self.source.as_str()]);
+        self.failures = self.failures.saturating_add(1);
+        self.backoff.delay(self.failures, fastrand::f64())
+    }
+
+    /// Wait until the next poll
+    async fn wait(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        loop {
+            let now = Instant::now();
+            self.since_success.set(
+                &[self.source.as_str()],
+                now.duration_since(self.succeeded_at).as_secs_f64(),
+            );
+            if now >= until {
+                return;
+            }
+            tokio::time::sleep_until(until.min(now + STALENESS_REFRESH)).await;
+        }
+    }
+}
+
+/// The stats of discovery sources, labeled by source
+struct SourceStats {
+    errors: ErrorCounters,
+    poll_failures: stats::CounterVec,
+    last_success: stats::GaugeVec,
+    since_success: stats::GaugeVec,
+}
+
+impl SourceStats {
+    fn new(scope: &stats::Scope) -> Self {
+        SourceStats {
+            errors: ErrorCounters::new(scope, "discovery"),
+            poll_failures: scope.counter_vec("poll_failures", &["source"]).unwrap(),
+            last_success: scope
+                .gauge_vec("last_success_timestamp_seconds", &["source"])
+                .unwrap(),
+            since_success: scope
+                .gauge_vec("seconds_since_success", &["source"])
+                .unwrap(),
+        }
+    }
+}
+
 /// A generic stream which takes a callable async function taking an
-/// update (or lack thereof), polling at the defined interval, emitting the
+/// update (or lack thereof), polling on the schedule, emitting the
 /// output when changed as a stream.
-fn polled_stream<T, C>(config: T, interval: u64, callable: C) -> impl Stream<Item = Update>
+fn polled_stream<T, C>(mut schedule: Schedule, config: T, callable: C) -> impl Stream<Item = Update>
 where
     T: Clone + Send + Sync,
-    C: Fn(T) -> Pin<Box<dyn futures::Future<Output = anyhow::Result<Update>> + Send>>,
+    C: Fn(T) -> Pin<Box<dyn futures::Future<Output = Result<Update, Error>> + Send>>,
 {
     let mut last_update = Update::default();
-    let duration = Duration::from_secs(interval as u64);
-    let start = Instant::now().add(duration);
     stream! {
-
-        let mut ticker = tokio::time::interval_at(start, duration);
         loop {
-            let new_update = match callable(config.clone()).await {
-                Err(e) => {
-                    warn!("unable to fetch discovery source due to error {:?}", e);
-                    ticker.tick().await;
-                    continue;
-                },
-                Ok(update) => update,
+            let wait = match callable(config.clone()).await {
+                Err(e) => schedule.failure(&e),
+                Ok(new_update) => {
+                    if new_update != last_update {
+                        yield new_update.clone();
+                    }
+                    last_update = new_update;
+                    schedule.success(None)
+                }
             };
-            if new_update != last_update {
-                yield new_update.clone();
-            }
-            last_update = new_update;
-            ticker.tick().await;
+            schedule.wait(wait).await;
         }
     }
 }
 
-pub fn as_stream(config: &Discovery) -> impl Stream<Item = (String, Update)> {
+pub fn as_stream(
+    stats: &stats::Scope,
+    config: &Discovery,
+    exclusions: &Exclusions,
+) -> impl Stream<Item = (String, Update)> {
     let mut streams: StreamMap<String, Pin<Box<dyn Stream<Item = Update> + Send>>> =
         StreamMap::new();
+    let source_stats = SourceStats::new(stats);
 
     for (name, source) in config.sources.iter() {
-        match source {
-            DiscoverySource::S3(source) => {
-                let ns = Box::pin(polled_stream(
-                    source.clone(),
-                    source.interval as u64,
-                    move |s| Box::pin(poll_s3_source(s)),
-                ));
-                //let ns = Box::pin(s3_stream(source.clone()));
-                streams.insert(name.clone(), ns);
+        let schedule = |interval| Schedule::new(&source_stats, name, interval);
+        let ns: Pin<Box<dyn Stream<Item = Update> + Send>> = match source {
+            DiscoverySource::S3(source) => Box::pin(polled_stream(
+                schedule(source.interval),
+                source.clone(),
+                move |s| Box::pin(poll_s3_source(s)),
+            )),
+            DiscoverySource::StaticFile(source) => Box::pin(polled_stream(
+                schedule(source.interval),
+                source.path.clone(),
+                move |s| Box::pin(poll_file_source(s)),
+            )),
+            DiscoverySource::Dns(source) => {
+                Box::pin(dns_stream(schedule(source.interval), source.clone()))
             }
-            DiscoverySource::StaticFile(source) => {
-                let cs = source.clone();
-                let ns = Box::pin(polled_stream(
-                    source.path.clone(),
-                    source.interval as u64,
-                    move |s| Box::pin(poll_file_source(cs.clone(), s)),
-                ));
-                //let ns = Box::pin(static_file_stream(source.clone()));
-                streams.insert(name.clone(), ns);
+            DiscoverySource::Ec2Asg(source) => Box::pin(polled_stream(
+                schedule(source.interval),
+                source.clone(),
+                move |s| Box::pin(poll_ec2_asg_source(s)),
+            )),
+            DiscoverySource::Http(source) => {
+                Box::pin(http_stream(schedule(source.interval), source.clone()))
             }
-        }
+        };
+        let ns = transformed_stream(ns, source.transforms().to_vec(), exclusions.clone());
+        streams.insert(name.clone(), Box::pin(ns));
     }
     streams
 }
@@ -221,18 +865,41 @@ impl Default for Cache {
     }
 }
 
-pub fn reflector<S>(cache: Cache, stream: S) -> impl Stream<Item = (String, Update)>
+/// Store the updates of a stream in the cache as they pass, counting the
+/// updates of each source in `updates` and exporting the endpoints of its
+/// latest update as `endpoints`
+pub fn reflector<S>(
+    cache: Cache,
+    stats: &stats::Scope,
+    stream: S,
+) -> impl Stream<Item = (String, Update)>
 where
     S: Stream<Item = (String, Update)>,
 {
-    stream.inspect(move |event| cache.store(event))
+    let updates = stats.counter_vec("updates", &["source"]).unwrap();
+    let endpoints = stats.gauge_vec("endpoints", &["source"]).unwrap();
+    stream.inspect(move |event| {
+        updates.inc(&[event.0.as_str()]);
+        endpoints.set(&[event.0.as_str()], event.1.hosts.len() as f64);
+        cache.store(event)
+    })
 }
 
 #[cfg(test)]
 pub mod tests {
     use crate::config::DiscoveryTransform;
 
-    use super::{Transformer, Update};
+    use super::{
+        apply_transforms, aws_region, decode_object, dns, dns_endpoints, ec2_addresses,
+        ec2_filters, poll_dns_source, transformed_stream, Cache, Error, Exclusions, HttpPoller,
+        Region, Schedule, SourceStats, Transformer, Update,
+    };
+    use crate::config::{
+        DnsDiscoverySource, DnsRecordType, Ec2AsgDiscoverySource, HttpDiscoverySource,
+    };
+    use crate::dns::{Record, RecordData};
+    use futures::StreamExt;
+    use std::time::Duration;
 
     #[test]
     fn format() {
@@ -266,4 +933,313 @@ pub mod tests {
 
         assert!(bad_transformer.transform(&o1).is_none());
     }
+
+    #[test]
+    fn normalize() {
+        let o1 = Update {
+            hosts: ["c:1", "a:1", "b:2", "a:1", "d:1"]
+                .iter()
+                .map(|s| (*s).into())
+                .collect(),
+        };
+        let transforms = [
+            DiscoveryTransform::Filter {
+                pattern: ":1$".into(),
+            },
+            DiscoveryTransform::Dedup,
+            DiscoveryTransform::Sort,
+        ];
+        let f = apply_transforms(&transforms, None, o1.clone());
+        assert_eq!(f.hosts, vec!["a:1", "c:1", "d:1"]);
+
+        // Shuffles are the same for the same seed, whatever the input order
+        let shuffle = |seed, update: &Update| {
+            let transforms = [
+                DiscoveryTransform::Sort,
+                DiscoveryTransform::Shuffle { seed },
+            ];
+            apply_transforms(&transforms, None, update.clone())
+        };
+        let mut reversed = o1.clone();
+        reversed.hosts.reverse();
+        assert_eq!(shuffle(7, &o1), shuffle(7, &reversed));
+        let mut hosts = shuffle(7, &o1).hosts;
+        hosts.sort();
+        assert_eq!(
+            hosts,
+            DiscoveryTransform::Sort.transform(&o1).unwrap().hosts
+        );
+    }
+
+    #[test]
+    fn schedule() {
+        let scope = crate::stats::Collector::default().scope("discovery");
+        let stats = SourceStats::new(&scope);
+        let mut schedule = Schedule::new(&stats, "s3", 60);
+        let within = |wait: Duration, low: u64, high: u64| {
+            wait >= Duration::from_secs(low) && wait <= Duration::from_secs(high)
+        };
+        for _ in 0..100 {
+            assert!(within(schedule.success(None), 54, 66));
+        }
+        assert!(within(
+            schedule.success(Some(Duration::from_secs(10))),
+            9,
+            11
+        ));
+        assert!(stats.last_success.get(&["s3"]) > 0_f64);
+
+        // Failures back off from the interval, and reset once a poll succeeds
+        let error = || Error::EmptyObjectError("key".to_owned());
+        assert!(within(schedule.failure(&error()), 30, 60));
+        assert!(within(schedule.failure(&error()), 60, 120));
+        for _ in 0..10 {
+            schedule.failure(&error());
+        }
+        assert!(within(schedule.failure(&error()), 300, 600));
+        assert_eq!(stats.poll_failures.get(&["s3"]), 13_f64);
+        assert!(within(schedule.success(None), 54, 66));
+        assert!(within(schedule.failure(&error()), 30, 60));
+    }
+
+    #[tokio::test]
+    async fn staleness() {
+        let scope = crate::stats::Collector::default().scope("discovery");
+        let stats = SourceStats::new(&scope);
+        let mut schedule = Schedule::new(&stats, "s3", 60);
+        schedule.wait(Duration::from_millis(20)).await;
+        let stale = stats.since_success.get(&["s3"]);
+        assert!(stale >= 0.02, "{}", stale);
+        schedule.success(None);
+        schedule.wait(Duration::ZERO).await;
+        assert!(stats.since_success.get(&["s3"]) < stale);
+    }
+
+    #[tokio::test]
+    async fn reflector() {
+        let scope = crate::stats::Collector::default().scope("discovery");
+        let update = |hosts: &[&str]| Update {
+            hosts: hosts.iter().map(|s| (*s).into()).collect(),
+        };
+        let events = [
+            ("a".to_owned(), update(&["a:1", "b:1"])),
+            ("b".to_owned(), update(&["c:1"])),
+            ("a".to_owned(), update(&["a:1", "b:1", "c:1"])),
+        ];
+        let cache = Cache::new();
+        let reflected: Vec<_> =
+            super::reflector(cache.clone(), &scope, futures::stream::iter(events))
+                .collect()
+                .await;
+        assert_eq!(reflected.len(), 3);
+        assert_eq!(cache.get("a").unwrap().sources().len(), 3);
+
+        let updates = scope.counter_vec("updates", &["source"]).unwrap();
+        let endpoints = scope.gauge_vec("endpoints", &["source"]).unwrap();
+        assert_eq!(updates.get(&["a"]), 2_f64);
+        assert_eq!(updates.get(&["b"]), 1_f64);
+        assert_eq!(endpoints.get(&["a"]), 3_f64);
+        assert_eq!(endpoints.get(&["b"]), 1_f64);
+    }
+
+    #[tokio::test]
+    async fn exclude() {
+        let update = Update {
+            hosts: ["a:1", "b:1", "c:2"].iter().map(|s| (*s).into()).collect(),
+        };
+        let transforms = vec![DiscoveryTransform::Exclude {
+            hosts: Some(vec!["a:1".into()]),
+            pattern: Some(":2$".into()),
+        }];
+        let exclusions = Exclusions::new();
+        let source = futures::stream::iter([update]).chain(futures::stream::pending());
+        let mut stream = Box::pin(transformed_stream(
+            Box::pin(source),
+            transforms,
+            exclusions.clone(),
+        ));
+        assert_eq!(stream.next().await.unwrap().hosts, vec!["b:1"]);
+
+        // Overrides replace the listed hosts, and emit the update again
+        exclusions.set(Some(vec!["b:1".into()]));
+        assert_eq!(stream.next().await.unwrap().hosts, vec!["a:1"]);
+        exclusions.set(None);
+        assert_eq!(stream.next().await.unwrap().hosts, vec!["b:1"]);
+    }
+
+    #[test]
+    fn endpoints() {
+        let address = |ip: &str| Record {
+            ttl: 30,
+            data: RecordData::Address(ip.parse().unwrap()),
+        };
+        let records = vec![address("10.0.0.2"), address("10.0.0.1"), address("fd00::1")];
+        assert_eq!(
+            dns_endpoints(records, Some(8125)),
+            vec!["10.0.0.1:8125", "10.0.0.2:8125", "[fd00::1]:8125"]
+        );
+
+        let service = |priority, target: &str, port| Record {
+            ttl: 30,
+            data: RecordData::Srv {
+                priority,
+                weight: 0,
+                port,
+                target: target.to_owned(),
+            },
+        };
+        let records = vec![
+            service(20, "b.local", 8126),
+            service(10, "c.local", 8125),
+            service(20, "a.local", 8127),
+        ];
+        assert_eq!(
+            dns_endpoints(records, None),
+            vec!["c.local:8125", "a.local:8127", "b.local:8126"]
+        );
+    }
+
+    #[tokio::test]
+    async fn dns_no_records() {
+        let empty = |query: &[u8]| dns::test::reply(query, 0);
+        let nameserver = dns::test::nameserver(empty, empty).await;
+        let source = DnsDiscoverySource {
+            name: "statsd.local".to_owned(),
+            record_type: DnsRecordType::A,
+            port: Some(8125),
+            interval: 30,
+            nameserver: Some(nameserver.to_string()),
+            transforms: None,
+        };
+        assert!(matches!(
+            poll_dns_source(source).await,
+            Err(Error::Dns(dns::Error::NoRecords(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn s3_objects() {
+        use async_compression::tokio::bufread::GzipEncoder;
+        use tokio::io::AsyncReadExt;
+
+        let json = br#"{"hosts": ["a:1", "b:1"]}"#;
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(&json[..])
+            .read_to_end(&mut gzipped)
+            .await
+            .unwrap();
+        for body in [&json[..], &gzipped] {
+            let update = decode_object(body).await.unwrap();
+            assert_eq!(update.sources(), &["a:1", "b:1"]);
+        }
+        assert!(matches!(
+            decode_object(&gzipped[..gzipped.len() / 2]).await,
+            Err(Error::Gunzip(_))
+        ));
+
+        assert_eq!(
+            aws_region(Some("eu-west-1"), None).unwrap(),
+            Region::EuWest1
+        );
+        assert_eq!(
+            aws_region(Some("minio"), Some("http://minio:9000")).unwrap(),
+            Region::Custom {
+                name: "minio".to_owned(),
+                endpoint: "http://minio:9000".to_owned(),
+            }
+        );
+        assert!(aws_region(Some("mars-1"), None).is_err());
+    }
+
+    #[test]
+    fn ec2_asg() {
+        let config = Ec2AsgDiscoverySource {
+            asg_name: Some("statsd".to_owned()),
+            tags: Some([("env".to_owned(), "prod".to_owned())].into()),
+            port: 8125,
+            region: None,
+            interval: 60,
+            transforms: None,
+        };
+        let filters: Vec<(String, Vec<String>)> = ec2_filters(&config)
+            .into_iter()
+            .map(|f| (f.name.unwrap(), f.values.unwrap()))
+            .collect();
+        assert_eq!(
+            filters,
+            vec![
+                ("instance-state-name".to_owned(), vec!["running".to_owned()]),
+                (
+                    "tag:aws:autoscaling:groupName".to_owned(),
+                    vec!["statsd".to_owned()]
+                ),
+                ("tag:env".to_owned(), vec!["prod".to_owned()]),
+            ]
+        );
+
+        let instance = |ip: Option<&str>| rusoto_ec2::Instance {
+            private_ip_address: ip.map(str::to_owned),
+            ..Default::default()
+        };
+        let result = rusoto_ec2::DescribeInstancesResult {
+            reservations: Some(vec![
+                rusoto_ec2::Reservation {
+                    instances: Some(vec![instance(Some("10.0.0.2")), instance(None)]),
+                    ..Default::default()
+                },
+                rusoto_ec2::Reservation {
+                    instances: Some(vec![instance(Some("10.0.0.1"))]),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            ec2_addresses(result).collect::<Vec<_>>(),
+            vec!["10.0.0.2", "10.0.0.1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn http_conditional_polls() {
+        use hyper::header::{AUTHORIZATION, ETAG, IF_NONE_MATCH};
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server};
+        use std::convert::Infallible;
+
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                assert_eq!(req.headers()[AUTHORIZATION], "Bearer secret");
+                let response = match req.headers().get(IF_NONE_MATCH) {
+                    Some(etag) if etag == "\"v1\"" => {
+                        Response::builder().status(304).body(Body::empty()).unwrap()
+                    }
+                    _ => Response::builder()
+                        .header(ETAG, "\"v1\"")
+                        .body(Body::from(r#"{"hosts": ["a", "b"]}"#))
+                        .unwrap(),
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}/shards.json", server.local_addr());
+        tokio::spawn(server);
+
+        let mut poller = HttpPoller::new(HttpDiscoverySource {
+            url,
+            interval: 1,
+            token: Some("secret".to_owned()),
+            username: None,
+            password: None,
+            headers: None,
+            tls: None,
+            timeout_ms: None,
+            transforms: None,
+        })
+        .unwrap();
+        let update = poller.poll().await.unwrap().unwrap();
+        assert_eq!(update.hosts, vec!["a", "b"]);
+        assert!(poller.poll().await.unwrap().is_none());
+    }
 }
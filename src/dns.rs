@@ -0,0 +1,519 @@
+//! A minimal DNS client, resolving the A, AAAA and SRV records used for
+//! discovery along with their TTLs, which the system resolver does not give.
+//! Queries are sent over UDP to a single nameserver, advertising a large
+//! EDNS buffer so the records of big services fit in one response, and sent
+//! again over TCP when the response is truncated anyway. Names are resolved
+//! as given, without the search domains of resolv.conf.
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::error::{Categorized, Category};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const TIMEOUT: Duration = Duration::from_secs(5);
+const EDNS_BUFFER_SIZE: u16 = 4096;
+const CLASS_IN: u16 = 1;
+const TYPE_OPT: u16 = 41;
+const MAX_LABEL_LENGTH: usize = 63;
+const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Srv,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+            RecordType::Srv => 33,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    Address(IpAddr),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("dns query failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("dns query for {0} timed out")]
+    Timeout(String),
+    #[error("dns query for {name} failed with response code {rcode}")]
+    Response { name: String, rcode: u8 },
+    #[error("dns query for {0} found no records")]
+    NoRecords(String),
+    #[error("dns response for {0} was truncated")]
+    Truncated(String),
+    #[error("malformed dns response for {0}")]
+    Malformed(String),
+    #[error("no nameserver found in {0}")]
+    NoNameserver(&'static str),
+    #[error("invalid dns name {0}")]
+    InvalidName(String),
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        match self {
+            Error::Io(_) | Error::Timeout(_) => Category::Network,
+            Error::Response { .. }
+            | Error::NoRecords(_)
+            | Error::Truncated(_)
+            | Error::Malformed(_) => Category::Protocol,
+            Error::NoNameserver(_) | Error::InvalidName(_) => Category::Config,
+        }
+    }
+}
+
+/// The first nameserver of the system's resolv.conf
+pub fn system_nameserver() -> Result<SocketAddr, Error> {
+    let contents = std::fs::read_to_string(RESOLV_CONF)?;
+    contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+        .ok_or(Error::NoNameserver(RESOLV_CONF))
+}
+
+/// Build a recursive query for the records of a name, failing on names
+/// which can't be encoded
+fn query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, Error> {
+    let trimmed = name.trim_end_matches('.');
+    if trimmed.len() + 2 > MAX_NAME_LENGTH
+        || trimmed
+            .split('.')
+            .any(|label| label.is_empty() || label.len() > MAX_LABEL_LENGTH)
+    {
+        return Err(Error::InvalidName(name.to_owned()));
+    }
+    let mut buf = Vec::with_capacity(name.len() + 30);
+    buf.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, with one question and one additional record
+    buf.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in trimmed.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&record_type.code().to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // EDNS OPT record advertising the buffer size accepted
+    buf.push(0);
+    buf.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    buf.extend_from_slice(&EDNS_BUFFER_SIZE.to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(buf)
+}
+
+/// Reads the fields of a response, failing on any out of bounds read
+struct Reader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.position..self.position + len)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Read a possibly compressed name, leaving the reader after it
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut position = self.position;
+        let mut end = None;
+        // Bound the pointers followed, so a pointer loop can't hang
+        for _ in 0..128 {
+            let len = *self.buf.get(position)? as usize;
+            match len {
+                0 => {
+                    self.position = end.unwrap_or(position + 1);
+                    return Some(labels.join("."));
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self.buf.get(position + 1)? as usize;
+                    end.get_or_insert(position + 2);
+                    position = (len & 0x3f) << 8 | low;
+                }
+                len => {
+                    let label = self.buf.get(position + 1..position + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    position += 1 + len;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parse the records of a response to a query, ignoring records of other
+/// types such as the CNAMEs leading to them. The response must repeat the
+/// query's question.
+fn parse_response(
+    id: u16,
+    name: &str,
+    record_type: RecordType,
+    buf: &[u8],
+) -> Result<Vec<Record>, Error> {
+    let malformed = || Error::Malformed(name.to_owned());
+    let mut reader = Reader { buf, position: 0 };
+    let header = reader.bytes(12).ok_or_else(malformed)?;
+    if u16::from_be_bytes([header[0], header[1]]) != id {
+        return Err(malformed());
+    }
+    if header[2] & 0x02 != 0 {
+        return Err(Error::Truncated(name.to_owned()));
+    }
+    let rcode = header[3] & 0x0f;
+    if rcode != 0 {
+        return Err(Error::Response {
+            name: name.to_owned(),
+            rcode,
+        });
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+    if questions != 1 {
+        return Err(malformed());
+    }
+    let question = reader.name().ok_or_else(malformed)?;
+    let qtype = reader.u16().ok_or_else(malformed)?;
+    let qclass = reader.u16().ok_or_else(malformed)?;
+    if !question.eq_ignore_ascii_case(name.trim_end_matches('.'))
+        || qtype != record_type.code()
+        || qclass != CLASS_IN
+    {
+        return Err(malformed());
+    }
+
+    let mut records = Vec::with_capacity(answers as usize);
+    for _ in 0..answers {
+        reader.name().ok_or_else(malformed)?;
+        let rtype = reader.u16().ok_or_else(malformed)?;
+        let _class = reader.u16().ok_or_else(malformed)?;
+        let ttl = reader.u32().ok_or_else(malformed)?;
+        let len = reader.u16().ok_or_else(malformed)? as usize;
+        let data_start = reader.position;
+        let data = reader.bytes(len).ok_or_else(malformed)?;
+        if rtype != record_type.code() {
+            continue;
+        }
+        let data = match record_type {
+            RecordType::A => {
+                let octets: [u8; 4] = data.try_into().map_err(|_| malformed())?;
+                RecordData::Address(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            RecordType::Aaaa => {
+                let octets: [u8; 16] = data.try_into().map_err(|_| malformed())?;
+                RecordData::Address(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            RecordType::Srv => {
+                let mut srv = Reader {
+                    buf,
+                    position: data_start,
+                };
+                RecordData::Srv {
+                    priority: srv.u16().ok_or_else(malformed)?,
+                    weight: srv.u16().ok_or_else(malformed)?,
+                    port: srv.u16().ok_or_else(malformed)?,
+                    target: srv.name().ok_or_else(malformed)?,
+                }
+            }
+        };
+        records.push(Record { ttl, data });
+    }
+    Ok(records)
+}
+
+/// Send a query over UDP, returning the response
+async fn exchange_udp(nameserver: SocketAddr, id: u16, query: &[u8]) -> Result<Vec<u8>, Error> {
+    let bind: SocketAddr = if nameserver.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0_u8; EDNS_BUFFER_SIZE as usize];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Ignore stray responses to other queries
+        if len >= 2 && buf[..2] == id.to_be_bytes() {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+/// Send a query over TCP, each message prefixed by its length, returning
+/// the response
+async fn exchange_tcp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0_u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Resolve the records of a name with a nameserver
+pub async fn resolve(
+    nameserver: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+) -> Result<Vec<Record>, Error> {
+    let id = fastrand::u16(..);
+    let query = query(id, name, record_type)?;
+    let timeout = |_| Error::Timeout(name.to_owned());
+    let response = tokio::time::timeout(TIMEOUT, exchange_udp(nameserver, id, &query))
+        .await
+        .map_err(timeout)??;
+    match parse_response(id, name, record_type, &response) {
+        Err(Error::Truncated(_)) => {
+            let response = tokio::time::timeout(TIMEOUT, exchange_tcp(nameserver, &query))
+                .await
+                .map_err(timeout)??;
+            parse_response(id, name, record_type, &response)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Append a resource record for `name` pointed to at offset 12
+    fn answer(buf: &mut Vec<u8>, rtype: u16, ttl: u32, data: &[u8]) {
+        buf.extend_from_slice(&[0xc0, 12]);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+
+    /// A response to a query, before its answers are appended
+    pub fn reply(query: &[u8], answers: u16) -> Vec<u8> {
+        // Drop the OPT record, and mark as a response with answers
+        let mut buf = query[..query.len() - 11].to_vec();
+        buf[2] = 0x81;
+        buf[3] = 0x80;
+        buf[6..8].copy_from_slice(&answers.to_be_bytes());
+        buf[10..12].copy_from_slice(&[0, 0]);
+        buf
+    }
+
+    fn response(id: u16, name: &str, rtype: RecordType, answers: u16) -> Vec<u8> {
+        reply(&query(id, name, rtype).unwrap(), answers)
+    }
+
+    /// A nameserver answering queries with `udp`, and with `tcp` over TCP
+    /// on the same port
+    pub async fn nameserver(udp: fn(&[u8]) -> Vec<u8>, tcp: fn(&[u8]) -> Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&udp(&buf[..len]), from).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let len = stream.read_u16().await.unwrap() as usize;
+                let mut buf = vec![0_u8; len];
+                stream.read_exact(&mut buf).await.unwrap();
+                let response = tcp(&buf);
+                stream.write_u16(response.len() as u16).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn build_query() {
+        let buf = query(0x1234, "statsd.local.", RecordType::Srv).unwrap();
+        assert_eq!(&buf[..2], &[0x12, 0x34]);
+        assert_eq!(&buf[12..26], b"\x06statsd\x05local\x00");
+        assert_eq!(&buf[26..30], &[0, 33, 0, 1]);
+        assert_eq!(buf.len(), 30 + 11);
+
+        let long = format!("{}.local", "a".repeat(64));
+        for name in [long.as_str(), "statsd..local", ""] {
+            assert!(matches!(
+                query(1, name, RecordType::A),
+                Err(Error::InvalidName(_))
+            ));
+        }
+        assert!(query(1, &format!("{}.local", "a".repeat(63)), RecordType::A).is_ok());
+    }
+
+    #[test]
+    fn parse_addresses() {
+        let mut buf = response(7, "statsd.local", RecordType::A, 3);
+        answer(&mut buf, 1, 30, &[10, 0, 0, 1]);
+        // CNAMEs are skipped
+        answer(&mut buf, 5, 30, &[0xc0, 12]);
+        answer(&mut buf, 1, 60, &[10, 0, 0, 2]);
+        let records = parse_response(7, "statsd.local", RecordType::A, &buf).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    ttl: 30,
+                    data: RecordData::Address("10.0.0.1".parse().unwrap()),
+                },
+                Record {
+                    ttl: 60,
+                    data: RecordData::Address("10.0.0.2".parse().unwrap()),
+                },
+            ]
+        );
+
+        let mut buf = response(7, "statsd.local", RecordType::Aaaa, 1);
+        let ip: Ipv6Addr = "fd00::1".parse().unwrap();
+        answer(&mut buf, 28, 5, &ip.octets());
+        let records = parse_response(7, "statsd.local", RecordType::Aaaa, &buf).unwrap();
+        assert_eq!(records[0].data, RecordData::Address(IpAddr::V6(ip)));
+    }
+
+    #[test]
+    fn parse_srv() {
+        let mut buf = response(9, "_statsd._udp.local", RecordType::Srv, 2);
+        // Targets spelled out, and compressed to a suffix of the question
+        let mut data = vec![0, 10, 0, 5, 0x1f, 0xbd];
+        data.extend_from_slice(b"\x05host1\x05local\x00");
+        answer(&mut buf, 33, 30, &data);
+        let mut data = vec![0, 20, 0, 5, 0x1f, 0xbe];
+        // "local" starts after "_statsd" and "_udp" in the question
+        data.extend_from_slice(b"\x05host2\xc0");
+        data.push(12 + 8 + 5);
+        answer(&mut buf, 33, 30, &data);
+        let records = parse_response(9, "_statsd._udp.local", RecordType::Srv, &buf).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.data.clone()).collect::<Vec<_>>(),
+            vec![
+                RecordData::Srv {
+                    priority: 10,
+                    weight: 5,
+                    port: 8125,
+                    target: "host1.local".to_owned(),
+                },
+                RecordData::Srv {
+                    priority: 20,
+                    weight: 5,
+                    port: 8126,
+                    target: "host2.local".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut buf = response(3, "statsd.local", RecordType::A, 1);
+        answer(&mut buf, 1, 30, &[10, 0, 0, 1]);
+        assert!(matches!(
+            parse_response(4, "statsd.local", RecordType::A, &buf),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            parse_response(3, "statsd.local", RecordType::A, &buf[..buf.len() - 2]),
+            Err(Error::Malformed(_))
+        ));
+        buf[3] = 0x83;
+        assert!(matches!(
+            parse_response(3, "statsd.local", RecordType::A, &buf),
+            Err(Error::Response { rcode: 3, .. })
+        ));
+        buf[2] = 0x83;
+        assert!(matches!(
+            parse_response(3, "statsd.local", RecordType::A, &buf),
+            Err(Error::Truncated(_))
+        ));
+
+        // Answers to another question
+        let mut buf = response(3, "statsd.local", RecordType::A, 1);
+        answer(&mut buf, 1, 30, &[10, 0, 0, 1]);
+        assert!(parse_response(3, "STATSD.local.", RecordType::A, &buf).is_ok());
+        for (name, rtype) in [
+            ("other.local", RecordType::A),
+            ("statsd.local", RecordType::Aaaa),
+        ] {
+            assert!(matches!(
+                parse_response(3, name, rtype, &buf),
+                Err(Error::Malformed(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_over_tcp() {
+        let address = nameserver(
+            |query| {
+                let mut buf = reply(query, 0);
+                buf[2] |= 0x02;
+                buf
+            },
+            |query| {
+                let mut buf = reply(query, 1);
+                answer(&mut buf, 1, 30, &[10, 0, 0, 1]);
+                buf
+            },
+        )
+        .await;
+        let records = resolve(address, "statsd.local", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![Record {
+                ttl: 30,
+                data: RecordData::Address("10.0.0.1".parse().unwrap()),
+            }]
+        );
+    }
+}
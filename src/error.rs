@@ -0,0 +1,128 @@
+use std::fmt;
+
+use log::{log, Level};
+
+use crate::stats;
+
+/// Broad classes of errors shared by every subsystem, so that failures can be
+/// counted and logged in a way that separates operator mistakes (config) from
+/// infrastructure problems (network) and misbehaving clients (protocol).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Config,
+    Network,
+    Protocol,
+    Internal,
+}
+
+impl From<&Category> for &str {
+    fn from(c: &Category) -> Self {
+        match c {
+            Category::Config => "config",
+            Category::Network => "network",
+            Category::Protocol => "protocol",
+            Category::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s: &str = self.into();
+        write!(f, "{}", s)
+    }
+}
+
+/// Implemented by each subsystem error type to place it in a Category.
+pub trait Categorized: std::error::Error {
+    fn category(&self) -> Category;
+}
+
+/// A set of counters, one per error Category, registered under the
+/// `errors` sub-scope of a subsystem's stats scope. Reported errors are
+/// counted and logged in a consistent `<subsystem> <category> error: <error>`
+/// format.
+#[derive(Clone, Debug)]
+pub struct ErrorCounters {
+    subsystem: String,
+    config: stats::Counter,
+    network: stats::Counter,
+    protocol: stats::Counter,
+    internal: stats::Counter,
+}
+
+impl ErrorCounters {
+    pub fn new(scope: &stats::Scope, subsystem: &str) -> Self {
+        let scope = scope.scope("errors");
+        ErrorCounters {
+            subsystem: subsystem.to_owned(),
+            config: scope.counter("config").unwrap(),
+            network: scope.counter("network").unwrap(),
+            protocol: scope.counter("protocol").unwrap(),
+            internal: scope.counter("internal").unwrap(),
+        }
+    }
+
+    pub fn counter(&self, category: Category) -> &stats::Counter {
+        match category {
+            Category::Config => &self.config,
+            Category::Network => &self.network,
+            Category::Protocol => &self.protocol,
+            Category::Internal => &self.internal,
+        }
+    }
+
+    /// Count an error without logging it, for high frequency errors such as
+    /// individual malformed lines.
+    pub fn record<E: Categorized + ?Sized>(&self, err: &E) {
+        self.counter(err.category()).inc();
+    }
+
+    /// Count and log an error at warning level.
+    pub fn report<E: Categorized + ?Sized>(&self, err: &E) {
+        self.report_at(Level::Warn, err);
+    }
+
+    /// Count and log an error at the given level.
+    pub fn report_at<E: Categorized + ?Sized>(&self, level: Level, err: &E) {
+        self.record(err);
+        log!(
+            level,
+            "{} {} error: {}",
+            self.subsystem,
+            err.category(),
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[derive(thiserror::Error, Debug)]
+    #[error("test error")]
+    struct TestError(Category);
+
+    impl Categorized for TestError {
+        fn category(&self) -> Category {
+            self.0
+        }
+    }
+
+    #[test]
+    fn count_by_category() {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        let errors = ErrorCounters::new(&scope, "test");
+        errors.record(&TestError(Category::Network));
+        errors.report(&TestError(Category::Network));
+        errors.report_at(Level::Debug, &TestError(Category::Config));
+
+        assert_eq!(errors.counter(Category::Network).get(), 2_f64);
+        assert_eq!(errors.counter(Category::Config).get(), 1_f64);
+        assert_eq!(errors.counter(Category::Protocol).get(), 0_f64);
+        // The counters are shared with anything else using the same scope
+        let again = scope.scope("errors").counter("network").unwrap();
+        assert_eq!(again.get(), 2_f64);
+    }
+}
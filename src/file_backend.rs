@@ -0,0 +1,496 @@
+//! A backend appending raw statsd lines to files, for audit capture and
+//! later replay. Files are rotated by size and age, optionally compressed,
+//! and only the most recent rotated files are kept.
+//!
+//! Files are named by their prefix and the time they were opened, so sort in
+//! the order they were written:
+//!
+//! ```text
+//! statsrelay-20200913T122640Z-000000.log.zst
+//! ```
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use regex::bytes::Regex;
+use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval, Instant};
+
+use crate::compression::compress;
+use crate::config::{Compression, FileBackendConfig, FsyncPolicy};
+use crate::error::{Categorized, Category, ErrorCounters};
+use crate::stats;
+use crate::statsd_proto::{Event, Pdu};
+
+const PREFIX: &str = "statsrelay";
+const EXTENSION: &str = "log";
+const ROTATE_BYTES: u64 = 128 * 1024 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_QUEUE: usize = 100000;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not open {path}: {source}")]
+    Open { path: PathBuf, source: io::Error },
+    #[error("could not write to {path}: {source}")]
+    Write { path: PathBuf, source: io::Error },
+    #[error("could not remove {path}: {source}")]
+    Remove { path: PathBuf, source: io::Error },
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        Category::Internal
+    }
+}
+
+struct Settings {
+    dir: PathBuf,
+    prefix: String,
+    /// File name extension, including any for compression
+    extension: String,
+    rotate_bytes: u64,
+    rotate_after: Option<Duration>,
+    retain_files: Option<usize>,
+    compression: Option<Compression>,
+    fsync: FsyncPolicy,
+    flush_interval: Duration,
+}
+
+impl Settings {
+    /// Whether a file in the directory was written by this backend
+    fn owns(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                name.starts_with(&format!("{}-", self.prefix)) && name.ends_with(&self.extension)
+            })
+    }
+}
+
+struct Stats {
+    lines_written: stats::Counter,
+    lines_dropped: stats::Counter,
+    rotations: stats::Counter,
+    errors: ErrorCounters,
+}
+
+/// The file currently being written to
+struct Current {
+    path: PathBuf,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Handle to the same file, used to sync it to disk
+    file: File,
+    /// Bytes of lines written, before any compression
+    bytes: u64,
+    opened: Instant,
+}
+
+impl Current {
+    async fn open(settings: &Settings, sequence: &mut u64) -> Result<Self, Error> {
+        let opened_at = chrono::Utc::now().format("%Y%m%dT%H%M%SZ");
+        // A file opened within the same second, possibly by a previous
+        // process, is never appended to
+        loop {
+            let path = settings.dir.join(format!(
+                "{}-{}-{:06}{}",
+                settings.prefix, opened_at, sequence, settings.extension
+            ));
+            *sequence += 1;
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => {
+                    let clone = file.try_clone().await.map_err(|source| Error::Open {
+                        path: path.clone(),
+                        source,
+                    })?;
+                    let buffered = BufWriter::new(file);
+                    let writer: Box<dyn AsyncWrite + Send + Unpin> = match settings.compression {
+                        Some(compression) => compress(buffered, compression),
+                        None => Box::new(buffered),
+                    };
+                    return Ok(Current {
+                        path,
+                        writer,
+                        file: clone,
+                        bytes: 0,
+                        opened: Instant::now(),
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(source) => return Err(Error::Open { path, source }),
+            }
+        }
+    }
+
+    async fn write(&mut self, line: &[u8]) -> Result<(), Error> {
+        let result = async {
+            self.writer.write_all(line).await?;
+            self.writer.write_all(b"\n").await
+        }
+        .await;
+        self.bytes += line.len() as u64 + 1;
+        result.map_err(|source| Error::Write {
+            path: self.path.clone(),
+            source,
+        })
+    }
+
+    async fn flush(&mut self, sync: bool) -> Result<(), Error> {
+        let result = async {
+            self.writer.flush().await?;
+            if sync {
+                self.file.sync_data().await?;
+            }
+            Ok(())
+        }
+        .await;
+        result.map_err(|source| Error::Write {
+            path: self.path.clone(),
+            source,
+        })
+    }
+
+    /// Finish the file, writing out any compression trailer
+    async fn close(mut self, sync: bool) -> Result<(), Error> {
+        let result = async {
+            self.writer.shutdown().await?;
+            if sync {
+                self.file.sync_all().await?;
+            }
+            Ok(())
+        }
+        .await;
+        result.map_err(|source| Error::Write {
+            path: self.path.clone(),
+            source,
+        })
+    }
+
+    /// Finish the file after a failed write, so it can still be read back
+    /// up to that point, or remove it if it can't be finished.
+    async fn abandon(self, sync: bool) -> Result<(), Error> {
+        let path = self.path.clone();
+        if self.close(sync).await.is_ok() {
+            return Ok(());
+        }
+        fs::remove_file(&path)
+            .await
+            .map_err(|source| Error::Remove { path, source })
+    }
+}
+
+/// Delete the oldest of this backend's files beyond the number retained.
+async fn remove_expired(settings: &Settings, retain: usize) -> Result<(), Error> {
+    let read_error = |source| Error::Open {
+        path: settings.dir.clone(),
+        source,
+    };
+    let mut entries = fs::read_dir(&settings.dir).await.map_err(read_error)?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(read_error)? {
+        let path = entry.path();
+        if settings.owns(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    let expired = files.len().saturating_sub(retain);
+    for path in files.into_iter().take(expired) {
+        fs::remove_file(&path)
+            .await
+            .map_err(|source| Error::Remove { path, source })?;
+    }
+    Ok(())
+}
+
+/// Write queued lines out to files until the queue is closed and empty.
+async fn file_writer(
+    settings: Settings,
+    stats: Stats,
+    mut recv: mpsc::Receiver<Bytes>,
+    _done: watch::Sender<()>,
+) {
+    let mut current: Option<Current> = None;
+    let mut sequence = 0;
+    let mut ticker = interval(settings.flush_interval);
+    loop {
+        let rotate = select! {
+            line = recv.recv() => {
+                let line = match line {
+                    Some(line) => line,
+                    None => break,
+                };
+                if current.is_none() {
+                    match Current::open(&settings, &mut sequence).await {
+                        Ok(opened) => current = Some(opened),
+                        Err(e) => {
+                            stats.errors.report(&e);
+                            stats.lines_dropped.inc();
+                            continue;
+                        }
+                    }
+                }
+                let file = current.as_mut().unwrap();
+                if let Err(e) = file.write(&line).await {
+                    // Start over with a new file for the next line
+                    stats.errors.report(&e);
+                    stats.lines_dropped.inc();
+                    if let Some(file) = current.take() {
+                        if let Err(e) = file.abandon(settings.fsync != FsyncPolicy::Never).await {
+                            stats.errors.report(&e);
+                        }
+                    }
+                    continue;
+                }
+                stats.lines_written.inc();
+                file.bytes >= settings.rotate_bytes
+            }
+            _ = ticker.tick() => {
+                let file = match current.as_mut() {
+                    Some(file) => file,
+                    None => continue,
+                };
+                if let Err(e) = file.flush(settings.fsync == FsyncPolicy::Flush).await {
+                    stats.errors.report(&e);
+                }
+                settings
+                    .rotate_after
+                    .is_some_and(|after| file.opened.elapsed() >= after)
+            }
+        };
+        if rotate {
+            if let Some(file) = current.take() {
+                if let Err(e) = file.close(settings.fsync != FsyncPolicy::Never).await {
+                    stats.errors.report(&e);
+                }
+                stats.rotations.inc();
+            }
+            if let Some(retain) = settings.retain_files {
+                if let Err(e) = remove_expired(&settings, retain).await {
+                    stats.errors.report(&e);
+                }
+            }
+        }
+    }
+    if let Some(file) = current.take() {
+        if let Err(e) = file.close(settings.fsync != FsyncPolicy::Never).await {
+            stats.errors.report(&e);
+        }
+    }
+}
+
+struct Inner {
+    conf: FileBackendConfig,
+    input_filter: Option<Regex>,
+    sender: mpsc::Sender<Bytes>,
+    done: watch::Receiver<()>,
+    lines_dropped: stats::Counter,
+}
+
+#[derive(Clone)]
+pub struct FileBackend {
+    inner: Arc<Inner>,
+}
+
+impl FileBackend {
+    pub fn new(stats: stats::Scope, conf: &FileBackendConfig) -> anyhow::Result<Self> {
+        let input_filter = conf.input_filter.as_deref().map(Regex::new).transpose()?;
+        std::fs::create_dir_all(&conf.dir)?;
+        let compression_extension = match conf.compression {
+            None => "",
+            Some(Compression::Gzip) => ".gz",
+            Some(Compression::Zstd) => ".zst",
+        };
+        let settings = Settings {
+            dir: PathBuf::from(&conf.dir),
+            prefix: conf.prefix.clone().unwrap_or_else(|| PREFIX.to_owned()),
+            extension: format!(".{}{}", EXTENSION, compression_extension),
+            rotate_bytes: conf.rotate_bytes.unwrap_or(ROTATE_BYTES),
+            rotate_after: conf.rotate_seconds.map(Duration::from_secs),
+            retain_files: conf.retain_files,
+            compression: conf.compression,
+            fsync: conf.fsync,
+            flush_interval: conf
+                .flush_interval_ms
+                .map_or(FLUSH_INTERVAL, Duration::from_millis),
+        };
+        let lines_dropped = stats.counter("lines_dropped").unwrap();
+        let writer_stats = Stats {
+            lines_written: stats.counter("lines_written").unwrap(),
+            lines_dropped: lines_dropped.clone(),
+            rotations: stats.counter("rotations").unwrap(),
+            errors: ErrorCounters::new(&stats, "file"),
+        };
+
+        let (sender, recv) = mpsc::channel(conf.max_queue.unwrap_or(MAX_QUEUE));
+        // The sender half is held by the writing task, and dropped once it
+        // exits, to signal the backend has finished.
+        let (done_sender, done) = watch::channel(());
+        tokio::spawn(file_writer(settings, writer_stats, recv, done_sender));
+
+        Ok(FileBackend {
+            inner: Arc::new(Inner {
+                conf: conf.clone(),
+                input_filter,
+                sender,
+                done,
+                lines_dropped,
+            }),
+        })
+    }
+
+    pub fn conf(&self) -> &FileBackendConfig {
+        &self.inner.conf
+    }
+
+    pub fn provide_statsd(&self, input: &Event) {
+        let pdu = Pdu::from(input);
+        if !self
+            .inner
+            .input_filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(pdu.name()))
+        {
+            return;
+        }
+        if self.inner.sender.try_send(pdu.into_bytes()).is_err() {
+            self.inner.lines_dropped.inc();
+        }
+    }
+
+    /// Returns a future which resolves once every queued line has been
+    /// written and the current file closed. This only happens once all
+    /// clones of this backend have been dropped.
+    pub fn finished(&self) -> impl Future<Output = ()> {
+        let mut done = self.inner.done.clone();
+        async move { while done.changed().await.is_ok() {} }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use async_compression::tokio::bufread::ZstdDecoder;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+
+    /// A writer failing every call, as a full or failing disk would
+    struct Failing;
+
+    impl AsyncWrite for Failing {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::Other.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::Other.into()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::Other.into()))
+        }
+    }
+
+    fn config(dir: &Path) -> FileBackendConfig {
+        FileBackendConfig {
+            dir: dir.to_str().unwrap().to_owned(),
+            prefix: Some("audit".to_owned()),
+            rotate_bytes: Some(20),
+            rotate_seconds: None,
+            retain_files: Some(2),
+            compression: Some(Compression::Zstd),
+            fsync: FsyncPolicy::Rotate,
+            flush_interval_ms: None,
+            max_queue: None,
+            input_filter: Some("^keep".to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn rotate_and_retain() {
+        let dir = tempfile::tempdir().unwrap();
+        let scope = crate::stats::Collector::default().scope("test");
+        let backend = FileBackend::new(scope.clone(), &config(dir.path())).unwrap();
+        // Each pair of lines fills a file
+        for line in [
+            "keep.a:1|c",
+            "drop:1|c",
+            "keep.b:1|c",
+            "keep.c:1|c",
+            "keep.d:1|c",
+            "keep.e:1|c",
+            "keep.f:1|c",
+            "keep.g:1|c",
+        ] {
+            let pdu = Pdu::parse(Bytes::from(line)).unwrap();
+            backend.provide_statsd(&Event::Pdu(pdu));
+        }
+        let finished = backend.finished();
+        drop(backend);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        // Of the three rotated files only two are kept, along with the last
+        // partially filled file
+        assert_eq!(files.len(), 3);
+        assert_eq!(scope.counter("rotations").unwrap().get(), 3_f64);
+        let mut contents = Vec::new();
+        for path in files {
+            assert!(path.to_str().unwrap().ends_with(".log.zst"));
+            let compressed = std::fs::read(path).unwrap();
+            let mut lines = String::new();
+            ZstdDecoder::new(compressed.as_slice())
+                .read_to_string(&mut lines)
+                .await
+                .unwrap();
+            contents.push(lines);
+        }
+        assert_eq!(
+            contents,
+            vec![
+                "keep.c:1|c\nkeep.d:1|c\n",
+                "keep.e:1|c\nkeep.f:1|c\n",
+                "keep.g:1|c\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn abandon_unfinished() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit-partial.log.zst");
+        let file = File::create(&path).await.unwrap();
+        let mut current = Current {
+            path: path.clone(),
+            writer: Box::new(Failing),
+            file,
+            bytes: 0,
+            opened: Instant::now(),
+        };
+        assert!(current.write(b"keep.a:1|c").await.is_err());
+        current.abandon(true).await.unwrap();
+        assert!(!path.exists());
+    }
+}
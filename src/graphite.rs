@@ -0,0 +1,122 @@
+//! Conversion of statsd metrics to the graphite plaintext protocol, as
+//! accepted by carbon and carbon-relay:
+//!
+//! ```text
+//! name;tag=value value timestamp
+//! ```
+//!
+//! Tags are written in the graphite 1.1 tagged series format.
+use std::time::SystemTime;
+
+use bytes::Bytes;
+
+use crate::config::GraphiteTimestamp;
+use crate::statsd_proto::{Parsed, Type};
+
+/// Graphite names and tags are delimited by spaces, semicolons and equals
+/// signs, so any in a statsd name or tag are replaced.
+fn sanitize(input: &[u8], out: &mut Vec<u8>) {
+    out.extend(input.iter().map(|c| match c {
+        b' ' | b';' | b'=' | b'\n' => b'_',
+        _ => *c,
+    }));
+}
+
+/// Seconds since the unix epoch
+fn unix_seconds(now: SystemTime) -> u64 {
+    now.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format a metric as a graphite plaintext line, without a trailing newline.
+/// Counters are scaled up by their sample rate, as graphite has no notion
+/// of sampling.
+pub fn to_plaintext<P: Parsed>(
+    metric: &P,
+    timestamp: &GraphiteTimestamp,
+    now: SystemTime,
+) -> Bytes {
+    let tag_timestamp = match timestamp {
+        GraphiteTimestamp::Received => None,
+        GraphiteTimestamp::Tag { name } => metric
+            .tags()
+            .iter()
+            .find(|t| t.name == name.as_bytes())
+            .and_then(|t| std::str::from_utf8(&t.value).ok())
+            .and_then(|v| v.parse::<u64>().ok()),
+    };
+    let timestamp_tag = match timestamp {
+        GraphiteTimestamp::Tag { name } => Some(name.as_bytes()),
+        GraphiteTimestamp::Received => None,
+    };
+
+    let mut line = Vec::with_capacity(metric.name().len() + metric.tags().len() * 32 + 32);
+    sanitize(metric.name(), &mut line);
+    for tag in metric.tags() {
+        if Some(tag.name.as_slice()) == timestamp_tag {
+            continue;
+        }
+        line.push(b';');
+        sanitize(&tag.name, &mut line);
+        line.push(b'=');
+        sanitize(&tag.value, &mut line);
+    }
+    let value = match (metric.metric_type(), metric.sample_rate()) {
+        (Type::Counter, Some(rate)) => metric.value() / rate,
+        _ => metric.value(),
+    };
+    line.push(b' ');
+    line.extend(value.to_string().as_bytes());
+    line.push(b' ');
+    line.extend(
+        tag_timestamp
+            .unwrap_or_else(|| unix_seconds(now))
+            .to_string()
+            .as_bytes(),
+    );
+    Bytes::from(line)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::statsd_proto::{Owned, Pdu};
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    fn owned(line: &'static [u8]) -> Owned {
+        Owned::try_from(Pdu::parse(Bytes::from_static(line)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_to_plaintext() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1600000000);
+        let received = GraphiteTimestamp::Received;
+        assert_eq!(
+            to_plaintext(&owned(b"foo.bar:3|g"), &received, now),
+            &b"foo.bar 3 1600000000"[..]
+        );
+        assert_eq!(
+            to_plaintext(
+                &owned(b"foo bar:1|c|@0.5|#dc:us;east,host:a"),
+                &received,
+                now
+            ),
+            &b"foo_bar;dc=us_east;host=a 2 1600000000"[..]
+        );
+
+        let tag = GraphiteTimestamp::Tag {
+            name: "ts".to_owned(),
+        };
+        assert_eq!(
+            to_plaintext(&owned(b"foo:1.5|ms|#ts:1500000000,host:a"), &tag, now),
+            &b"foo;host=a 1.5 1500000000"[..]
+        );
+        // Without a usable tag, the time the line was received is used
+        assert_eq!(
+            to_plaintext(&owned(b"foo:1|ms|#ts:soon"), &tag, now),
+            &b"foo 1 1600000000"[..]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Active health checks for backend endpoints. Each checked endpoint is
+//! probed periodically, and marked unhealthy after enough consecutive failed
+//! probes, or healthy again after enough consecutive successful ones.
+//! Backends eject unhealthy endpoints from their rings until reinstated.
+//! Endpoints may also start out unhealthy, joining the ring once a first
+//! probe succeeds.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use stream_cancel::Tripwire;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::select;
+use tokio::time::{sleep, timeout};
+
+use crate::stats;
+use crate::statsd_client::connect;
+use crate::tls::ClientTls;
+
+/// Line sent to an endpoint to ping it, answered by statsrelay with `ok`
+const PING: &[u8] = b"health\n";
+const PING_REPLY: &str = "ok";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive failed probes which mark an endpoint unhealthy
+    pub unhealthy_threshold: u32,
+    /// Consecutive successful probes which mark an endpoint healthy again
+    pub healthy_threshold: u32,
+    /// Ping the endpoint over the connection rather than only connecting,
+    /// for endpoints which are themselves statsrelay
+    pub ping: bool,
+    /// Endpoints joining a running ring start unhealthy, and are probed
+    /// straight away
+    pub verify_new: bool,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+            ping: false,
+            verify_new: false,
+        }
+    }
+}
+
+async fn probe(endpoint: &str, tls: Option<&ClientTls>, ping: bool) -> std::io::Result<()> {
+    let mut connection = connect(endpoint, tls).await?;
+    if !ping {
+        return Ok(());
+    }
+    connection.write_all(PING).await?;
+    connection.flush().await?;
+    let mut reply = String::new();
+    BufReader::new(connection).read_line(&mut reply).await?;
+    if reply.trim_end() != PING_REPLY {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unexpected ping reply {:?}", reply.trim_end()),
+        ));
+    }
+    Ok(())
+}
+
+/// Probe an endpoint until the tripwire is set, keeping `healthy` up to
+/// date. Changes in health are logged, counted in `health_ejections` and
+/// `health_reinstatements`, and exported in the `endpoint_healthy` gauge. An
+/// endpoint starting unhealthy is unverified: it is probed straight away, and
+/// marked healthy by its first successful probe, counted in
+/// `health_verifications`.
+pub async fn checker(
+    stats: stats::Scope,
+    endpoint: String,
+    check: HealthCheck,
+    tls: Option<ClientTls>,
+    healthy: Arc<AtomicBool>,
+    mut tripwire: Tripwire,
+) {
+    let ejections = stats.counter("health_ejections").unwrap();
+    let reinstatements = stats.counter("health_reinstatements").unwrap();
+    let failures = stats
+        .counter_vec("health_check_failures", &["endpoint"])
+        .unwrap();
+    let verifications = stats.counter("health_verifications").unwrap();
+    let healthy_gauge = stats.gauge_vec("endpoint_healthy", &["endpoint"]).unwrap();
+    let mut verified = healthy.load(Ordering::Acquire);
+    healthy_gauge.set(&[&endpoint], if verified { 1_f64 } else { 0_f64 });
+    // Consecutive probes disagreeing with the current health
+    let mut streak = 0_u32;
+    let mut wait = if verified {
+        check.interval
+    } else {
+        Duration::ZERO
+    };
+    loop {
+        let result = select! {
+            result = async {
+                sleep(wait).await;
+                timeout(check.timeout, probe(&endpoint, tls.as_ref(), check.ping)).await
+            } => result,
+            _ = &mut tripwire => return,
+        };
+        wait = check.interval;
+        let ok = match result {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                info!("health check of {} failed: {}", endpoint, e);
+                false
+            }
+            Err(_) => {
+                info!("health check of {} timed out", endpoint);
+                false
+            }
+        };
+        if !ok {
+            failures.inc(&[&endpoint]);
+        }
+        let was_healthy = healthy.load(Ordering::Acquire);
+        if ok == was_healthy {
+            streak = 0;
+            continue;
+        }
+        streak += 1;
+        let threshold = if was_healthy {
+            check.unhealthy_threshold
+        } else if !verified {
+            1
+        } else {
+            check.healthy_threshold
+        };
+        if streak < threshold {
+            continue;
+        }
+        streak = 0;
+        healthy.store(ok, Ordering::Release);
+        healthy_gauge.set(&[&endpoint], if ok { 1_f64 } else { 0_f64 });
+        if ok && !verified {
+            info!("endpoint {} is reachable, adding", endpoint);
+            verified = true;
+            verifications.inc();
+        } else if ok {
+            info!("endpoint {} is healthy, reinstating", endpoint);
+            reinstatements.inc();
+        } else {
+            warn!("endpoint {} is unhealthy, ejecting", endpoint);
+            ejections.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        probe(&endpoint, None, false).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            for reply in [b"ok\n".as_ref(), b"degraded\n".as_ref()] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0_u8; PING.len()];
+                socket.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, PING);
+                socket.write_all(reply).await.unwrap();
+            }
+        });
+        probe(&endpoint, None, true).await.unwrap();
+        probe(&endpoint, None, true).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_checker() {
+        // Reserve a port with nothing listening on it
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let check = HealthCheck {
+            interval: Duration::from_millis(5),
+            timeout: Duration::from_secs(1),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+            ping: false,
+            verify_new: false,
+        };
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (trigger, tripwire) = Tripwire::new();
+        tokio::spawn(checker(
+            scope.clone(),
+            addr.to_string(),
+            check,
+            None,
+            healthy.clone(),
+            tripwire,
+        ));
+        let wait_for = |state: bool| {
+            let healthy = healthy.clone();
+            timeout(Duration::from_secs(5), async move {
+                while healthy.load(Ordering::Acquire) != state {
+                    sleep(Duration::from_millis(5)).await;
+                }
+            })
+        };
+        wait_for(false).await.unwrap();
+        assert_eq!(scope.counter("health_ejections").unwrap().get(), 1_f64);
+        let _listener = TcpListener::bind(addr).await.unwrap();
+        wait_for(true).await.unwrap();
+        assert_eq!(scope.counter("health_reinstatements").unwrap().get(), 1_f64);
+        drop(trigger);
+    }
+
+    #[tokio::test]
+    async fn test_verify_new() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let scope = crate::stats::Collector::default().scope("test");
+        // Verification doesn't wait for the interval
+        let check = HealthCheck {
+            interval: Duration::from_secs(3600),
+            verify_new: true,
+            ..HealthCheck::default()
+        };
+        let healthy = Arc::new(AtomicBool::new(false));
+        let (trigger, tripwire) = Tripwire::new();
+        tokio::spawn(checker(
+            scope.clone(),
+            listener.local_addr().unwrap().to_string(),
+            check,
+            None,
+            healthy.clone(),
+            tripwire,
+        ));
+        timeout(Duration::from_secs(5), async {
+            while !healthy.load(Ordering::Acquire) {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(scope.counter("health_verifications").unwrap().get(), 1_f64);
+        assert_eq!(scope.counter("health_reinstatements").unwrap().get(), 0_f64);
+        drop(trigger);
+    }
+}
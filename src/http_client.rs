@@ -0,0 +1,265 @@
+//! A small HTTP client for backends which write to HTTP APIs, and discovery
+//! sources which read from them. Requests are sent with a timeout, and posts
+//! which fail in a way that could succeed on another attempt are retried with
+//! backoff. `https` URLs connect with the client TLS settings.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use base64::Engine;
+use bytes::Bytes;
+use hyper::client::connect::{Connected, Connection as HyperConnection};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, timeout};
+
+use crate::error::{Categorized, Category, ErrorCounters};
+use crate::stats;
+use crate::statsd_client::{connect, Backoff, Connection};
+use crate::tls::ClientTls;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not build request to {url}: {source}")]
+    Request {
+        url: String,
+        source: hyper::http::Error,
+    },
+    #[error("request to {url} failed: {source}")]
+    Http { url: String, source: hyper::Error },
+    #[error("request to {0} timed out")]
+    Timeout(String),
+    #[error("request to {url} failed with status {status}")]
+    Status { url: String, status: StatusCode },
+}
+
+impl Error {
+    /// Whether sending the same request again could succeed. Requests the
+    /// endpoint rejected as bad would only be rejected again.
+    fn retryable(&self) -> bool {
+        match self {
+            Error::Request { .. } => false,
+            Error::Http { .. } | Error::Timeout(_) => true,
+            Error::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        match self {
+            Error::Request { .. } => Category::Config,
+            _ => Category::Network,
+        }
+    }
+}
+
+/// A connection made by [`Connector`](Connector)
+pub struct Stream(Connection);
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl HyperConnection for Stream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/// Connects to `http` URLs over TCP, and `https` URLs over TLS
+#[derive(Clone)]
+pub struct Connector {
+    tls: Option<ClientTls>,
+}
+
+impl Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Stream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let https = uri.scheme_str() == Some("https");
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URL has no host"))?;
+            let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+            let endpoint = format!("{}:{}", host, port);
+            let tls = match (https, tls.as_ref()) {
+                (true, None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "https URL without TLS settings",
+                    ))
+                }
+                (true, tls) => tls,
+                (false, _) => None,
+            };
+            Ok(Stream(connect(&endpoint, tls).await?))
+        })
+    }
+}
+
+pub type HttpClient = Client<Connector>;
+
+pub fn client(tls: Option<ClientTls>) -> HttpClient {
+    Client::builder().build(Connector { tls })
+}
+
+/// The value of an `Authorization` header for HTTP basic authentication
+pub fn basic_authorization(username: &str, password: Option<&str>) -> String {
+    format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(format!(
+            "{}:{}",
+            username,
+            password.unwrap_or_default()
+        ))
+    )
+}
+
+/// How long each request may take, and how often a failed request is
+/// retried
+pub struct Retry {
+    pub max_retries: u32,
+    pub backoff: Backoff,
+    pub timeout: Duration,
+}
+
+/// Post a body, failing unless the response has a success status.
+pub async fn post(
+    client: &HttpClient,
+    url: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    body: Bytes,
+    request_timeout: Duration,
+) -> Result<(), Error> {
+    let mut request = Request::post(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let request = request
+        .body(Body::from(body))
+        .map_err(|source| Error::Request {
+            url: url.to_owned(),
+            source,
+        })?;
+    let response = match timeout(request_timeout, client.request(request)).await {
+        Ok(response) => response.map_err(|source| Error::Http {
+            url: url.to_owned(),
+            source,
+        })?,
+        Err(_) => return Err(Error::Timeout(url.to_owned())),
+    };
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::Status {
+            url: url.to_owned(),
+            status,
+        });
+    }
+    Ok(())
+}
+
+/// Get a document, failing unless the response has a success or not modified
+/// status. The body is read within the timeout too.
+pub async fn get(
+    client: &HttpClient,
+    url: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    request_timeout: Duration,
+) -> Result<Response<Bytes>, Error> {
+    let mut request = Request::get(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let request = request
+        .body(Body::empty())
+        .map_err(|source| Error::Request {
+            url: url.to_owned(),
+            source,
+        })?;
+    let fetch = async {
+        let (parts, body) = client.request(request).await?.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(Response::from_parts(parts, body))
+    };
+    let response = match timeout(request_timeout, fetch).await {
+        Ok(response) => response.map_err(|source| Error::Http {
+            url: url.to_owned(),
+            source,
+        })?,
+        Err(_) => return Err(Error::Timeout(url.to_owned())),
+    };
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+        return Err(Error::Status {
+            url: url.to_owned(),
+            status,
+        });
+    }
+    Ok(response)
+}
+
+/// Post a body, retrying failures which could succeed on another attempt.
+/// Failed attempts are recorded in `errors` and counted in `retries`, and the
+/// error of the last attempt returned if none succeeded.
+pub async fn post_with_retries(
+    client: &HttpClient,
+    url: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    body: Bytes,
+    retry: &Retry,
+    retries: &stats::Counter,
+    errors: &ErrorCounters,
+) -> Result<(), Error> {
+    let mut failures = 0;
+    loop {
+        match post(client, url, headers, body.clone(), retry.timeout).await {
+            Err(e) if e.retryable() && failures < retry.max_retries => {
+                errors.record(&e);
+                retries.inc();
+                failures += 1;
+                sleep(retry.backoff.delay(failures, fastrand::f64())).await;
+            }
+            result => return result,
+        }
+    }
+}
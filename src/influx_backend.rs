@@ -0,0 +1,388 @@
+//! A backend writing metrics to InfluxDB, or anything else accepting the
+//! influx line protocol over HTTP such as VictoriaMetrics:
+//!
+//! ```text
+//! measurement,tag=value value=1.5 1600000000000000000
+//! ```
+//!
+//! Lines are queued as they are converted, and sent in batches by a single
+//! task, retrying failed requests with backoff.
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
+use regex::bytes::Regex;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout_at, Instant};
+
+use crate::config::InfluxBackendConfig;
+use crate::error::ErrorCounters;
+use crate::http_client::{self, HttpClient, Retry};
+use crate::stats;
+use crate::statsd_client::Backoff;
+use crate::statsd_proto::{Event, Owned, Parsed, Type};
+use crate::tls::ClientTls;
+
+const BATCH_LINES: usize = 5000;
+const BATCH_BYTES: usize = 1024 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_QUEUE: usize = 100000;
+const MAX_RETRIES: u32 = 5;
+const RETRY_INITIAL: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Escape the characters the line protocol delimits with. Measurements need
+/// commas and spaces escaped, and tags equals signs as well. Newlines can
+/// not be escaped, so are replaced.
+fn escape(input: &[u8], tag: bool, out: &mut BytesMut) {
+    for c in input {
+        match c {
+            b',' | b' ' => out.put_slice(&[b'\\', *c]),
+            b'=' if tag => out.put_slice(b"\\="),
+            b'\n' => out.put_u8(b'_'),
+            _ => out.put_u8(*c),
+        }
+    }
+}
+
+/// Format a metric as a line, without a trailing newline. Counters are
+/// scaled up by their sample rate. Values the line protocol can not
+/// represent, such as NaN, give None.
+pub fn to_line<P: Parsed>(metric: &P, now: SystemTime) -> Option<Bytes> {
+    let value = match (metric.metric_type(), metric.sample_rate()) {
+        (Type::Counter, Some(rate)) => metric.value() / rate,
+        _ => metric.value(),
+    };
+    if !value.is_finite() {
+        return None;
+    }
+    let timestamp = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    let mut tags: Vec<_> = metric
+        .tags()
+        .iter()
+        .filter(|tag| !tag.value.is_empty())
+        .collect();
+    // Influx recommends tags sorted by key, which speeds up writes
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut line = BytesMut::with_capacity(metric.name().len() + tags.len() * 32 + 64);
+    escape(metric.name(), false, &mut line);
+    for tag in tags {
+        line.put_u8(b',');
+        escape(&tag.name, true, &mut line);
+        line.put_u8(b'=');
+        escape(&tag.value, true, &mut line);
+    }
+    line.put_slice(format!(" value={} {}", value, timestamp).as_bytes());
+    Some(line.freeze())
+}
+
+struct Settings {
+    url: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    retry: Retry,
+    batch_lines: usize,
+    batch_bytes: usize,
+    flush_interval: Duration,
+}
+
+struct SenderStats {
+    lines_sent: stats::Counter,
+    lines_dropped: stats::Counter,
+    request_retries: stats::Counter,
+    errors: ErrorCounters,
+}
+
+struct Inner {
+    conf: InfluxBackendConfig,
+    input_filter: Option<Regex>,
+    sender: mpsc::Sender<Bytes>,
+    done: watch::Receiver<()>,
+    lines_dropped: stats::Counter,
+    format_failures: stats::Counter,
+}
+
+#[derive(Clone)]
+pub struct InfluxBackend {
+    inner: Arc<Inner>,
+}
+
+impl InfluxBackend {
+    pub fn new(stats: stats::Scope, conf: &InfluxBackendConfig) -> anyhow::Result<Self> {
+        let input_filter = conf.input_filter.as_deref().map(Regex::new).transpose()?;
+        let tls = conf.tls.as_ref().map(ClientTls::new).transpose()?;
+        let mut headers = vec![
+            (
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            ),
+            (
+                USER_AGENT,
+                HeaderValue::from_static(concat!("statsrelay/", env!("CARGO_PKG_VERSION"))),
+            ),
+        ];
+        let authorization = match (&conf.token, &conf.username) {
+            (Some(token), _) => Some(format!("Token {}", token)),
+            (None, Some(username)) => Some(http_client::basic_authorization(
+                username,
+                conf.password.as_deref(),
+            )),
+            (None, None) => None,
+        };
+        if let Some(authorization) = authorization {
+            let mut value = HeaderValue::from_str(&authorization)?;
+            value.set_sensitive(true);
+            headers.push((AUTHORIZATION, value));
+        }
+        let settings = Settings {
+            url: conf.url.clone(),
+            headers,
+            retry: Retry {
+                max_retries: conf.max_retries.unwrap_or(MAX_RETRIES),
+                backoff: Backoff {
+                    initial: conf
+                        .retry_initial_ms
+                        .map_or(RETRY_INITIAL, Duration::from_millis),
+                    max: conf.retry_max_ms.map_or(RETRY_MAX, Duration::from_millis),
+                },
+                timeout: conf.timeout_ms.map_or(TIMEOUT, Duration::from_millis),
+            },
+            batch_lines: conf.batch_lines.unwrap_or(BATCH_LINES),
+            batch_bytes: conf.batch_bytes.unwrap_or(BATCH_BYTES),
+            flush_interval: conf
+                .flush_interval_ms
+                .map_or(FLUSH_INTERVAL, Duration::from_millis),
+        };
+        let lines_dropped = stats.counter("lines_dropped").unwrap();
+        let sender_stats = SenderStats {
+            lines_sent: stats.counter("lines_sent").unwrap(),
+            lines_dropped: lines_dropped.clone(),
+            request_retries: stats.counter("request_retries").unwrap(),
+            errors: ErrorCounters::new(&stats, "influx"),
+        };
+
+        let (sender, recv) = mpsc::channel(conf.max_queue.unwrap_or(MAX_QUEUE));
+        // The sender half is held by the sending task, and dropped once it
+        // exits, to signal the backend has finished.
+        let (done_sender, done) = watch::channel(());
+        tokio::spawn(batch_sender(
+            http_client::client(tls),
+            settings,
+            sender_stats,
+            recv,
+            done_sender,
+        ));
+
+        Ok(InfluxBackend {
+            inner: Arc::new(Inner {
+                conf: conf.clone(),
+                input_filter,
+                sender,
+                done,
+                lines_dropped,
+                format_failures: stats.counter("format_failures").unwrap(),
+            }),
+        })
+    }
+
+    pub fn conf(&self) -> &InfluxBackendConfig {
+        &self.inner.conf
+    }
+
+    pub fn provide_statsd(&self, input: &Event) {
+        let owned = match Owned::try_from(input) {
+            Ok(owned) => owned,
+            Err(_) => {
+                self.inner.format_failures.inc();
+                return;
+            }
+        };
+        if !self
+            .inner
+            .input_filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(owned.name()))
+        {
+            return;
+        }
+        let line = match to_line(&owned, SystemTime::now()) {
+            Some(line) => line,
+            None => {
+                self.inner.format_failures.inc();
+                return;
+            }
+        };
+        if self.inner.sender.try_send(line).is_err() {
+            self.inner.lines_dropped.inc();
+        }
+    }
+
+    /// Returns a future which resolves once every queued line has been sent
+    /// and the backend's task has exited. This only happens once all clones
+    /// of this backend have been dropped.
+    pub fn finished(&self) -> impl Future<Output = ()> {
+        let mut done = self.inner.done.clone();
+        async move { while done.changed().await.is_ok() {} }
+    }
+}
+
+/// Send queued lines in batches, until the queue is closed and empty. A
+/// batch is sent once it is full, or once its first line has waited for the
+/// flush interval.
+async fn batch_sender(
+    client: HttpClient,
+    settings: Settings,
+    stats: SenderStats,
+    mut recv: mpsc::Receiver<Bytes>,
+    _done: watch::Sender<()>,
+) {
+    let mut batch = BytesMut::new();
+    let mut closed = false;
+    while !closed {
+        let mut lines = 0;
+        match recv.recv().await {
+            Some(line) => {
+                batch.put_slice(&line);
+                batch.put_u8(b'\n');
+                lines += 1;
+            }
+            None => return,
+        }
+        let deadline = Instant::now() + settings.flush_interval;
+        while lines < settings.batch_lines && batch.len() < settings.batch_bytes {
+            match timeout_at(deadline, recv.recv()).await {
+                Ok(Some(line)) => {
+                    batch.put_slice(&line);
+                    batch.put_u8(b'\n');
+                    lines += 1;
+                }
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        let result = http_client::post_with_retries(
+            &client,
+            &settings.url,
+            &settings.headers,
+            batch.split().freeze(),
+            &settings.retry,
+            &stats.request_retries,
+            &stats.errors,
+        )
+        .await;
+        match result {
+            Ok(()) => stats.lines_sent.inc_by(lines as f64),
+            Err(e) => {
+                stats.errors.report(&e);
+                stats.lines_dropped.inc_by(lines as f64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::timeout;
+
+    fn owned(line: &'static [u8]) -> Owned {
+        Owned::try_from(crate::statsd_proto::Pdu::parse(Bytes::from_static(line)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_to_line() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1600000000);
+        assert_eq!(
+            to_line(&owned(b"foo.bar:3|g"), now).unwrap(),
+            &b"foo.bar value=3 1600000000000000000"[..]
+        );
+        assert_eq!(
+            to_line(&owned(b"foo bar:1|c|@0.5|#host:a,dc:us=east"), now).unwrap(),
+            &b"foo\\ bar,dc=us\\=east,host=a value=2 1600000000000000000"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_writes() {
+        let (requests, mut recv) = mpsc::unbounded_channel();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let make_svc = make_service_fn(move |_conn| {
+            let requests = requests.clone();
+            let attempts = attempts.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = requests.clone();
+                    let attempts = attempts.clone();
+                    async move {
+                        assert_eq!(req.headers()[AUTHORIZATION], "Token secret");
+                        // Fail the first attempt, to be retried
+                        if attempts.fetch_add(1, Ordering::AcqRel) == 0 {
+                            return Ok::<_, Infallible>(
+                                Response::builder().status(500).body(Body::empty()).unwrap(),
+                            );
+                        }
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        requests.send(body).unwrap();
+                        Ok(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}/api/v2/write?bucket=metrics", server.local_addr());
+        tokio::spawn(server);
+
+        let scope = crate::stats::Collector::default().scope("test");
+        let backend = InfluxBackend::new(
+            scope.clone(),
+            &InfluxBackendConfig {
+                url,
+                token: Some("secret".to_owned()),
+                username: None,
+                password: None,
+                tls: None,
+                batch_lines: Some(2),
+                batch_bytes: None,
+                flush_interval_ms: Some(3600 * 1000),
+                max_queue: None,
+                max_retries: None,
+                retry_initial_ms: Some(1),
+                retry_max_ms: Some(1),
+                timeout_ms: None,
+                input_filter: None,
+            },
+        )
+        .unwrap();
+        for line in [&b"a:1|c"[..], b"b:1|c", b"c:1|c"] {
+            let pdu = crate::statsd_proto::Pdu::parse(Bytes::from_static(line)).unwrap();
+            backend.provide_statsd(&Event::Pdu(pdu));
+        }
+        let finished = backend.finished();
+        drop(backend);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        // Two full lines are sent as soon as they are queued, and the last
+        // once the queue closes
+        let first = recv.recv().await.unwrap();
+        assert_eq!(first.iter().filter(|c| **c == b'\n').count(), 2);
+        assert!(first.starts_with(b"a value=1 "));
+        let second = recv.recv().await.unwrap();
+        assert!(second.starts_with(b"c value=1 "));
+        assert_eq!(scope.counter("lines_sent").unwrap().get(), 3_f64);
+        assert_eq!(scope.counter("request_retries").unwrap().get(), 1_f64);
+    }
+}
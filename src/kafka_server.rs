@@ -0,0 +1,205 @@
+use bytes::BytesMut;
+use log::{info, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, ConsumerContext, StreamConsumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::Message;
+use rdkafka::statistics::Statistics;
+use rdkafka::ClientContext;
+use stream_cancel::Tripwire;
+use thiserror::Error;
+use tokio::select;
+
+use crate::backends::Backends;
+use crate::config::KafkaServerConfig;
+use crate::error::{Categorized, Category, ErrorCounters};
+use crate::stats;
+use crate::statsd_proto::Event;
+use crate::statsd_server::{parse_line, process_buffer_newlines};
+
+const DEFAULT_LAG_INTERVAL_SECONDS: u64 = 10;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not create kafka consumer: {0}")]
+    Create(#[source] KafkaError),
+    #[error("could not subscribe to kafka topics {topics:?}: {source}")]
+    Subscribe {
+        topics: Vec<String>,
+        source: KafkaError,
+    },
+    #[error("kafka receive failed: {0}")]
+    Receive(#[source] KafkaError),
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        match self {
+            Error::Create(_) | Error::Subscribe { .. } => Category::Config,
+            Error::Receive(_) => Category::Network,
+        }
+    }
+}
+
+/// Consumer context publishing the consumer lag of every assigned partition
+/// from the periodic librdkafka statistics.
+struct LagContext {
+    lag: stats::GaugeVec,
+}
+
+impl LagContext {
+    fn record(&self, statistics: &Statistics) {
+        for (topic, topic_stats) in statistics.topics.iter() {
+            for (partition, partition_stats) in topic_stats.partitions.iter() {
+                // Partition -1 is librdkafka's internal unassigned partition,
+                // and a negative lag means the lag is not yet known
+                if *partition < 0 || partition_stats.consumer_lag < 0 {
+                    continue;
+                }
+                self.lag.set(
+                    &[topic.as_str(), partition.to_string().as_str()],
+                    partition_stats.consumer_lag as f64,
+                );
+            }
+        }
+    }
+}
+
+impl ClientContext for LagContext {
+    fn stats(&self, statistics: Statistics) {
+        self.record(&statistics);
+    }
+}
+
+impl ConsumerContext for LagContext {}
+
+/// Parse a message payload of newline delimited statsd lines. The final line
+/// does not need a trailing newline.
+fn process_payload(payload: &[u8], errors: &ErrorCounters) -> Vec<Event> {
+    let mut buf = BytesMut::from(payload);
+    let mut events = process_buffer_newlines(&mut buf, errors, None);
+    if !buf.is_empty() {
+        match parse_line(buf.freeze(), None) {
+            Ok(pdu) => events.push(Event::Pdu(pdu)),
+            Err(e) => errors.record(&e),
+        }
+    }
+    events
+}
+
+/// Consume statsd payloads from the configured Kafka topics until the
+/// tripwire is set, sending parsed lines down the configured route.
+pub async fn run(
+    stats: stats::Scope,
+    mut tripwire: Tripwire,
+    config: KafkaServerConfig,
+    backends: Backends,
+) -> Result<(), Error> {
+    let errors = ErrorCounters::new(&stats, "kafka_server");
+    let messages = stats.counter("messages").unwrap();
+    let processed_lines = stats.counter("processed_lines").unwrap();
+    let incoming_bytes = stats.counter("incoming_bytes").unwrap();
+    let context = LagContext {
+        lag: stats
+            .gauge_vec("consumer_lag", &["topic", "partition"])
+            .unwrap(),
+    };
+
+    let lag_interval = config
+        .lag_interval_seconds
+        .unwrap_or(DEFAULT_LAG_INTERVAL_SECONDS);
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", &config.brokers)
+        .set("group.id", &config.group_id)
+        .set("statistics.interval.ms", (lag_interval * 1000).to_string());
+    for (key, value) in config.options.iter() {
+        client_config.set(key, value);
+    }
+    let consumer: StreamConsumer<LagContext> = client_config
+        .create_with_context(context)
+        .map_err(Error::Create)?;
+    let topics: Vec<&str> = config.topics.iter().map(String::as_str).collect();
+    consumer
+        .subscribe(&topics)
+        .map_err(|source| Error::Subscribe {
+            topics: config.topics.clone(),
+            source,
+        })?;
+    info!(
+        "kafka server consuming {:?} from {} as group {}",
+        config.topics, config.brokers, config.group_id
+    );
+
+    loop {
+        let message = select! {
+            message = consumer.recv() => message,
+            _ = &mut tripwire => break,
+        };
+        match message {
+            Ok(message) => {
+                messages.inc();
+                if let Some(payload) = message.payload() {
+                    incoming_bytes.inc_by(payload.len() as f64);
+                    let events = process_payload(payload, &errors);
+                    processed_lines.inc_by(events.len() as f64);
+                    backends.provide_statsd_slice(&events, &config.route);
+                }
+            }
+            // The consumer reconnects on its own, so receive errors are
+            // reported and consumption carries on
+            Err(e) => errors.report(&Error::Receive(e)),
+        }
+    }
+    warn!("kafka server consuming {:?} stopped", config.topics);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use rdkafka::statistics::{Partition, Topic};
+
+    #[test]
+    fn test_process_payload() {
+        let scope = crate::stats::Collector::default().scope("test");
+        let errors = ErrorCounters::new(&scope, "test");
+        let events = process_payload(b"a:1|c\nb:2|g\nc:3|ms", &errors);
+        assert_eq!(events.len(), 3);
+        let events = process_payload(b"a:1|c\nnot a metric\n", &errors);
+        assert_eq!(events.len(), 1);
+        assert_eq!(errors.counter(Category::Protocol).get(), 1_f64);
+    }
+
+    #[test]
+    fn test_lag_context() {
+        let scope = crate::stats::Collector::default().scope("test");
+        let context = LagContext {
+            lag: scope.gauge_vec("lag", &["topic", "partition"]).unwrap(),
+        };
+        let partition = |partition, consumer_lag| Partition {
+            partition,
+            consumer_lag,
+            ..Default::default()
+        };
+        let mut statistics = Statistics::default();
+        statistics.topics.insert(
+            "metrics".to_owned(),
+            Topic {
+                topic: "metrics".to_owned(),
+                partitions: vec![
+                    (-1, partition(-1, 50)),
+                    (0, partition(0, 7)),
+                    (1, partition(1, -1)),
+                ]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            },
+        );
+        context.record(&statistics);
+        assert_eq!(context.lag.get(&["metrics", "0"]), 7_f64);
+        assert_eq!(context.lag.get(&["metrics", "1"]), 0_f64);
+        assert_eq!(context.lag.get(&["metrics", "-1"]), 0_f64);
+    }
+}
@@ -1,15 +1,37 @@
 pub mod admin;
+pub mod alerts;
 pub mod backends;
+pub mod breaker;
+pub mod compression;
 pub mod config;
 pub mod cuckoofilter;
 pub mod discovery;
+pub mod dns;
+pub mod error;
+pub mod file_backend;
+pub mod graphite;
+pub mod health;
+pub mod http_client;
+pub mod influx_backend;
+#[cfg(feature = "kafka")]
+pub mod kafka_server;
+pub mod net;
+pub mod null_backend;
+#[cfg(feature = "otlp")]
+pub mod otlp_server;
 pub mod processors;
+pub mod prometheus_backend;
+pub mod rate_limit;
 pub mod shard;
+pub mod shutdown;
+pub mod spill;
 pub mod stats;
 pub mod statsd_backend;
 pub mod statsd_client;
 pub mod statsd_proto;
 pub mod statsd_server;
+pub mod systemd;
+pub mod tls;
 pub mod built_info {
     // The file has been placed there by the build script.
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
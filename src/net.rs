@@ -0,0 +1,102 @@
+//! Binding of TCP and UDP listeners with explicit control over the IP
+//! address family, rather than whatever the bind address happens to resolve
+//! to first.
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::IpFamily;
+
+/// Listen backlog for TCP listeners, matching tokio's default.
+const LISTEN_BACKLOG: i32 = 1024;
+
+impl IpFamily {
+    fn accepts(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 | IpFamily::Dual => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolve a bind address to the first address of the requested family, or
+/// to the first address at all if no family is given.
+pub fn resolve(bind: &str, family: Option<IpFamily>) -> Result<SocketAddr> {
+    bind.to_socket_addrs()?
+        .find(|addr| family.is_none_or(|f| f.accepts(addr)))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("{} has no {:?} address", bind, family),
+            )
+        })
+}
+
+fn socket(
+    addr: SocketAddr,
+    family: Option<IpFamily>,
+    ty: Type,
+    protocol: Protocol,
+) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    // Without an explicit family, IPv6 sockets keep the system default for
+    // also accepting IPv4 traffic
+    if let Some(family) = family.filter(|_| addr.is_ipv6()) {
+        socket.set_only_v6(family == IpFamily::V6)?;
+    }
+    Ok(socket)
+}
+
+/// Bind a non-blocking TCP listener.
+pub fn bind_tcp(bind: &str, family: Option<IpFamily>) -> Result<TcpListener> {
+    let addr = resolve(bind, family)?;
+    let socket = socket(addr, family, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Bind `count` UDP sockets to the same address. More than one socket is
+/// bound with SO_REUSEPORT, and the first socket fixes the port should the
+/// address ask for any free port.
+pub fn bind_udp(bind: &str, family: Option<IpFamily>, count: usize) -> Result<Vec<UdpSocket>> {
+    let mut addr = resolve(bind, family)?;
+    let mut sockets: Vec<UdpSocket> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = socket(addr, family, Type::DGRAM, Protocol::UDP)?;
+        if count > 1 {
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&addr.into())?;
+        let socket: UdpSocket = socket.into();
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let v4 = resolve("127.0.0.1:0", Some(IpFamily::V4)).unwrap();
+        assert!(v4.is_ipv4());
+        assert!(resolve("127.0.0.1:0", Some(IpFamily::V6)).is_err());
+        assert!(resolve("[::1]:0", Some(IpFamily::Dual)).unwrap().is_ipv6());
+        assert!(resolve("[::1]:0", None).unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn test_bind_udp_reuseport() {
+        let sockets = bind_udp("127.0.0.1:0", Some(IpFamily::V4), 2).unwrap();
+        assert_eq!(
+            sockets[0].local_addr().unwrap(),
+            sockets[1].local_addr().unwrap()
+        );
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use log::info;
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::{
+    MetricsService, MetricsServiceServer,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::{
+    ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+};
+use opentelemetry_proto::tonic::common::v1::{any_value, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, AggregationTemporality, HistogramDataPoint, NumberDataPoint,
+};
+use parking_lot::Mutex;
+use stream_cancel::Tripwire;
+use thiserror::Error;
+
+use crate::backends::Backends;
+use crate::config::{OtlpServerConfig, Route};
+use crate::error::{Categorized, Category};
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Tag, Type};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid otlp bind address {addr}: {source}")]
+    Address {
+        addr: String,
+        source: std::net::AddrParseError,
+    },
+    #[error("otlp server on {addr} failed: {source}")]
+    Transport {
+        addr: String,
+        source: tonic::transport::Error,
+    },
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        match self {
+            Error::Address { .. } => Category::Config,
+            Error::Transport { .. } => Category::Network,
+        }
+    }
+}
+
+/// Cumulative series kept unless configured
+const DEFAULT_MAX_SERIES: usize = 100_000;
+
+/// The last values of cumulative series, in two generations. New series are
+/// added to the current generation, and series of the previous one move to
+/// it when seen again. Once the current generation holds half the most
+/// series, it becomes the previous one, forgetting the series not seen since
+/// the last time.
+#[derive(Default)]
+struct Series {
+    current: HashMap<Id, f64>,
+    previous: HashMap<Id, f64>,
+}
+
+/// Converts OTLP metrics into statsd samples. Statsd counters are deltas, so
+/// the last value of every cumulative series is kept in order to convert
+/// cumulative sums and histograms into deltas.
+struct Converter {
+    cumulative: Mutex<Series>,
+    max_series: usize,
+    evicted_series: stats::Counter,
+}
+
+impl Converter {
+    fn new(max_series: usize, evicted_series: stats::Counter) -> Self {
+        Converter {
+            cumulative: Mutex::new(Series::default()),
+            max_series,
+            evicted_series,
+        }
+    }
+
+    /// Turn a cumulative value into the change since the last value seen for
+    /// the series. The first value of a series only sets a baseline, as does
+    /// the first value after it is evicted. A value lower than the last is
+    /// taken as a restart of the reporting process.
+    fn delta(&self, id: &Id, value: f64) -> Option<f64> {
+        let mut series = self.cumulative.lock();
+        let last = match series.current.get_mut(id) {
+            Some(last) => Some(std::mem::replace(last, value)),
+            None => {
+                let last = series.previous.remove(id);
+                if series.current.len() >= (self.max_series / 2).max(1) {
+                    let current = std::mem::take(&mut series.current);
+                    let evicted = std::mem::replace(&mut series.previous, current);
+                    self.evicted_series.inc_by(evicted.len() as f64);
+                }
+                series.current.insert(id.clone(), value);
+                last
+            }
+        };
+        match last {
+            None => None,
+            Some(last) if value >= last => Some(value - last),
+            Some(_) => Some(value),
+        }
+    }
+
+    fn counter(&self, id: Id, value: f64, cumulative: bool, out: &mut Vec<Event>) {
+        let value = if cumulative {
+            match self.delta(&id, value) {
+                Some(delta) => delta,
+                None => return,
+            }
+        } else {
+            value
+        };
+        out.push(Event::Parsed(Owned::new(id, value, None)));
+    }
+
+    fn number_point(
+        &self,
+        name: &str,
+        mtype: Type,
+        cumulative: bool,
+        resource: &[Tag],
+        point: &NumberDataPoint,
+        out: &mut Vec<Event>,
+    ) {
+        let value = match point.value {
+            Some(number_data_point::Value::AsDouble(v)) => v,
+            Some(number_data_point::Value::AsInt(v)) => v as f64,
+            None => return,
+        };
+        let id = id(name.as_bytes(), mtype, resource, &point.attributes);
+        match mtype {
+            Type::Counter => self.counter(id, value, cumulative, out),
+            _ => out.push(Event::Parsed(Owned::new(id, value, None))),
+        }
+    }
+
+    /// Histograms are reported as a `.count` and `.sum` counter pair, as
+    /// statsd has no representation for pre-bucketed distributions.
+    fn histogram_point(
+        &self,
+        name: &str,
+        cumulative: bool,
+        resource: &[Tag],
+        point: &HistogramDataPoint,
+        out: &mut Vec<Event>,
+    ) {
+        let count = format!("{}.count", name);
+        let count_id = id(count.as_bytes(), Type::Counter, resource, &point.attributes);
+        self.counter(count_id, point.count as f64, cumulative, out);
+        if let Some(sum) = point.sum {
+            let sum_name = format!("{}.sum", name);
+            let sum_id = id(
+                sum_name.as_bytes(),
+                Type::Counter,
+                resource,
+                &point.attributes,
+            );
+            self.counter(sum_id, sum, cumulative, out);
+        }
+    }
+
+    /// Convert an export request into events, returning the number of data
+    /// points which could not be represented.
+    fn convert(&self, request: ExportMetricsServiceRequest, out: &mut Vec<Event>) -> usize {
+        let mut unsupported = 0;
+        for resource_metrics in request.resource_metrics {
+            let resource: Vec<Tag> = resource_metrics
+                .resource
+                .map(|r| r.attributes.iter().filter_map(tag).collect())
+                .unwrap_or_default();
+            let metrics = resource_metrics
+                .scope_metrics
+                .into_iter()
+                .flat_map(|s| s.metrics);
+            for m in metrics {
+                let name = m.name.as_str();
+                match m.data {
+                    Some(metric::Data::Gauge(gauge)) => {
+                        for point in gauge.data_points.iter() {
+                            self.number_point(name, Type::Gauge, false, &resource, point, out);
+                        }
+                    }
+                    Some(metric::Data::Sum(sum)) => {
+                        let cumulative = is_cumulative(sum.aggregation_temporality);
+                        // Up-down counters have no statsd counter equivalent
+                        let mtype = if sum.is_monotonic {
+                            Type::Counter
+                        } else {
+                            Type::Gauge
+                        };
+                        for point in sum.data_points.iter() {
+                            self.number_point(name, mtype, cumulative, &resource, point, out);
+                        }
+                    }
+                    Some(metric::Data::Histogram(histogram)) => {
+                        let cumulative = is_cumulative(histogram.aggregation_temporality);
+                        for point in histogram.data_points.iter() {
+                            self.histogram_point(name, cumulative, &resource, point, out);
+                        }
+                    }
+                    Some(metric::Data::ExponentialHistogram(h)) => {
+                        unsupported += h.data_points.len()
+                    }
+                    Some(metric::Data::Summary(s)) => unsupported += s.data_points.len(),
+                    None => (),
+                }
+            }
+        }
+        unsupported
+    }
+}
+
+fn is_cumulative(temporality: i32) -> bool {
+    temporality == AggregationTemporality::Cumulative as i32
+}
+
+/// Convert an OTLP attribute into a statsd tag. Only scalar values have a
+/// sensible tag representation, other values are skipped.
+fn tag(kv: &KeyValue) -> Option<Tag> {
+    let value = match kv.value.as_ref()?.value.as_ref()? {
+        any_value::Value::StringValue(s) => s.clone(),
+        any_value::Value::BoolValue(b) => b.to_string(),
+        any_value::Value::IntValue(i) => i.to_string(),
+        any_value::Value::DoubleValue(d) => d.to_string(),
+        _ => return None,
+    };
+    Some(Tag {
+        name: kv.key.as_bytes().to_vec(),
+        value: value.into_bytes(),
+    })
+}
+
+fn id(name: &[u8], mtype: Type, resource: &[Tag], attributes: &[KeyValue]) -> Id {
+    let mut tags = resource.to_vec();
+    tags.extend(attributes.iter().filter_map(tag));
+    Id {
+        name: name.to_vec(),
+        mtype,
+        tags,
+    }
+}
+
+struct OtlpMetrics {
+    converter: Converter,
+    backends: Backends,
+    route: Vec<Route>,
+    requests: stats::Counter,
+    points: stats::Counter,
+    unsupported_points: stats::Counter,
+}
+
+#[tonic::async_trait]
+impl MetricsService for OtlpMetrics {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        self.requests.inc();
+        let mut events = Vec::new();
+        let unsupported = self.converter.convert(request.into_inner(), &mut events);
+        self.points.inc_by(events.len() as f64);
+        self.unsupported_points.inc_by(unsupported as f64);
+        self.backends.provide_statsd_slice(&events, &self.route);
+        Ok(tonic::Response::new(ExportMetricsServiceResponse {
+            partial_success: None,
+        }))
+    }
+}
+
+/// Run an OTLP/gRPC metrics receiver until the tripwire is set, converting
+/// received metrics into events sent down the configured route.
+pub async fn run(
+    stats: stats::Scope,
+    tripwire: Tripwire,
+    config: OtlpServerConfig,
+    backends: Backends,
+) -> Result<(), Error> {
+    let addr: SocketAddr = config.bind.parse().map_err(|source| Error::Address {
+        addr: config.bind.clone(),
+        source,
+    })?;
+    let service = OtlpMetrics {
+        converter: Converter::new(
+            config.max_series.unwrap_or(DEFAULT_MAX_SERIES),
+            stats.counter("evicted_series").unwrap(),
+        ),
+        backends,
+        route: config.route.clone(),
+        requests: stats.counter("requests").unwrap(),
+        points: stats.counter("points").unwrap(),
+        unsupported_points: stats.counter("unsupported_points").unwrap(),
+    };
+    info!("otlp server running on {}", config.bind);
+    tonic::transport::Server::builder()
+        .add_service(MetricsServiceServer::new(service))
+        .serve_with_shutdown(addr, async move {
+            tripwire.await;
+        })
+        .await
+        .map_err(|source| Error::Transport {
+            addr: config.bind.clone(),
+            source,
+        })?;
+    info!("otlp server on {} stopped", config.bind);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::statsd_proto::Parsed;
+    use opentelemetry_proto::tonic::common::v1::AnyValue;
+    use opentelemetry_proto::tonic::metrics::v1::{
+        Gauge, Histogram, Metric, ResourceMetrics, ScopeMetrics, Sum,
+    };
+    use opentelemetry_proto::tonic::resource::v1::Resource;
+
+    fn attribute(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_owned(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_owned())),
+            }),
+        }
+    }
+
+    fn point(value: f64) -> NumberDataPoint {
+        NumberDataPoint {
+            attributes: vec![attribute("route", "/")],
+            value: Some(number_data_point::Value::AsDouble(value)),
+            ..Default::default()
+        }
+    }
+
+    fn request(metrics: Vec<Metric>) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![attribute("service.name", "web")],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    metrics,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+
+    fn metric(name: &str, data: metric::Data) -> Metric {
+        Metric {
+            name: name.to_owned(),
+            data: Some(data),
+            ..Default::default()
+        }
+    }
+
+    fn converter(max_series: usize) -> Converter {
+        let scope = stats::Collector::default().scope("otlp");
+        Converter::new(max_series, scope.counter("evicted_series").unwrap())
+    }
+
+    fn owned(events: &[Event]) -> Vec<&Owned> {
+        events
+            .iter()
+            .map(|e| match e {
+                Event::Parsed(o) => o,
+                Event::Pdu(_) => panic!("expected parsed events"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn convert_gauge_and_delta_sum() {
+        let converter = converter(DEFAULT_MAX_SERIES);
+        let mut events = Vec::new();
+        let unsupported = converter.convert(
+            request(vec![
+                metric(
+                    "queue.size",
+                    metric::Data::Gauge(Gauge {
+                        data_points: vec![point(12.0)],
+                    }),
+                ),
+                metric(
+                    "requests",
+                    metric::Data::Sum(Sum {
+                        data_points: vec![point(3.0)],
+                        aggregation_temporality: AggregationTemporality::Delta as i32,
+                        is_monotonic: true,
+                    }),
+                ),
+            ]),
+            &mut events,
+        );
+        assert_eq!(unsupported, 0);
+        let events = owned(&events);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name(), b"queue.size");
+        assert_eq!(events[0].metric_type(), &Type::Gauge);
+        assert_eq!(events[0].value(), 12.0);
+        assert_eq!(events[1].metric_type(), &Type::Counter);
+        assert_eq!(events[1].value(), 3.0);
+        // Resource attributes come before data point attributes
+        let tags: Vec<_> = events[1].tags().iter().map(|t| t.to_string()).collect();
+        assert_eq!(tags, vec!["[service.name=web]", "[route=/]"]);
+    }
+
+    #[test]
+    fn convert_cumulative_sum() {
+        let converter = converter(DEFAULT_MAX_SERIES);
+        let sum = |value| {
+            request(vec![metric(
+                "requests",
+                metric::Data::Sum(Sum {
+                    data_points: vec![point(value)],
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                    is_monotonic: true,
+                }),
+            )])
+        };
+        let mut events = Vec::new();
+        // The first report only sets a baseline
+        converter.convert(sum(10.0), &mut events);
+        assert!(events.is_empty());
+        converter.convert(sum(15.0), &mut events);
+        assert_eq!(owned(&events)[0].value(), 5.0);
+        // A restarted process starts counting again from zero
+        events.clear();
+        converter.convert(sum(2.0), &mut events);
+        assert_eq!(owned(&events)[0].value(), 2.0);
+    }
+
+    #[test]
+    fn convert_histogram() {
+        let converter = converter(DEFAULT_MAX_SERIES);
+        let mut events = Vec::new();
+        converter.convert(
+            request(vec![metric(
+                "latency",
+                metric::Data::Histogram(Histogram {
+                    data_points: vec![HistogramDataPoint {
+                        count: 4,
+                        sum: Some(100.0),
+                        ..Default::default()
+                    }],
+                    aggregation_temporality: AggregationTemporality::Delta as i32,
+                }),
+            )]),
+            &mut events,
+        );
+        let events = owned(&events);
+        assert_eq!(events[0].name(), b"latency.count");
+        assert_eq!(events[0].value(), 4.0);
+        assert_eq!(events[1].name(), b"latency.sum");
+        assert_eq!(events[1].value(), 100.0);
+    }
+
+    #[test]
+    fn evict_cumulative_series() {
+        let converter = converter(4);
+        let sum = |name: &str, value| {
+            request(vec![metric(
+                name,
+                metric::Data::Sum(Sum {
+                    data_points: vec![point(value)],
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                    is_monotonic: true,
+                }),
+            )])
+        };
+        let mut events = Vec::new();
+        for name in ["a", "b", "c"] {
+            converter.convert(sum(name, 10.0), &mut events);
+        }
+        assert!(events.is_empty());
+        // Seen again, a is kept, while b is forgotten once d is added
+        converter.convert(sum("a", 12.0), &mut events);
+        assert_eq!(owned(&events)[0].value(), 2.0);
+        events.clear();
+        converter.convert(sum("d", 10.0), &mut events);
+        converter.convert(sum("b", 15.0), &mut events);
+        assert!(events.is_empty());
+        assert_eq!(converter.evicted_series.get(), 1_f64);
+    }
+}
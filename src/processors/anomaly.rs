@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use smallvec::smallvec;
+
+use super::{name_and_type, parse, processing_errors, Error, Output, Processor, Selector};
+use crate::backends::Backends;
+use crate::config::processor::{self, AnomalyAction};
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Parsed, Pdu, Type};
+
+const DEFAULT_MIN_SAMPLES: u64 = 10;
+const DEFAULT_EXPIRE: Duration = Duration::from_secs(600);
+
+/// Exponentially weighted moving average and variance of a metric's values
+struct Moments {
+    mean: f64,
+    variance: f64,
+    samples: u64,
+    at: Instant,
+}
+
+/// Flags metrics whose values are further from their moving average than a
+/// number of standard deviations, by tagging them or sending them to other
+/// routes.
+pub struct Anomaly {
+    selector: Selector,
+    check_sets: bool,
+    alpha: f64,
+    z_score: f64,
+    min_samples: u64,
+    capacity: usize,
+    expire: Duration,
+    action: AnomalyAction,
+    tracked: Mutex<HashMap<Id, Moments>>,
+    route: Vec<Route>,
+
+    counter_anomalies: stats::Counter,
+    counter_untracked: stats::Counter,
+    gauge_tracked: stats::Gauge,
+    counter_processing_errors: stats::Counter,
+}
+
+impl Anomaly {
+    pub fn new(scope: stats::Scope, from_config: &processor::Anomaly) -> Result<Self, Error> {
+        Ok(Anomaly {
+            selector: Selector::new(from_config.name.as_deref(), &from_config.types)?,
+            // The members of sets are not values to average
+            check_sets: !from_config.types.is_empty(),
+            alpha: from_config.alpha,
+            z_score: from_config.z_score,
+            min_samples: from_config.min_samples.unwrap_or(DEFAULT_MIN_SAMPLES),
+            capacity: from_config.capacity,
+            expire: from_config
+                .expire_seconds
+                .map_or(DEFAULT_EXPIRE, Duration::from_secs),
+            action: from_config.action.clone(),
+            tracked: Mutex::new(HashMap::new()),
+            route: from_config.route.clone(),
+            counter_anomalies: scope.counter("anomalies").unwrap(),
+            counter_untracked: scope.counter("untracked").unwrap(),
+            gauge_tracked: scope.gauge("tracked").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    /// Record a value of a metric, returning whether it is anomalous. Values
+    /// are checked against the moving average and variance from before
+    /// them, once a metric has had enough values and they have varied.
+    fn record(&self, id: &Id, value: f64, now: Instant) -> bool {
+        let mut tracked = self.tracked.lock();
+        let moments = match tracked.get_mut(id) {
+            Some(moments) => moments,
+            None => {
+                if tracked.len() < self.capacity {
+                    tracked.insert(
+                        id.clone(),
+                        Moments {
+                            mean: value,
+                            variance: 0_f64,
+                            samples: 1,
+                            at: now,
+                        },
+                    );
+                } else {
+                    self.counter_untracked.inc();
+                }
+                return false;
+            }
+        };
+        let diff = value - moments.mean;
+        let deviation = moments.variance.sqrt();
+        let anomalous = moments.samples >= self.min_samples
+            && deviation > 0_f64
+            && diff.abs() / deviation > self.z_score;
+
+        let increment = self.alpha * diff;
+        moments.mean += increment;
+        moments.variance = (1_f64 - self.alpha) * (moments.variance + diff * increment);
+        moments.samples += 1;
+        moments.at = now;
+        anomalous
+    }
+
+    fn expire(&self, now: Instant) {
+        let mut tracked = self.tracked.lock();
+        tracked.retain(|_, moments| now.saturating_duration_since(moments.at) < self.expire);
+        self.gauge_tracked.set(tracked.len() as f64);
+    }
+}
+
+impl Processor for Anomaly {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let output = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let (name, mtype) = name_and_type(event);
+        if !self.selector.matches(name, mtype) || (mtype == Some(Type::Set) && !self.check_sets) {
+            return Some(output);
+        }
+        let owned = match parse(event, &self.counter_processing_errors) {
+            Some(owned) => owned,
+            None => return Some(output),
+        };
+        if !self.record(owned.id(), owned.value(), Instant::now()) {
+            return Some(output);
+        }
+
+        self.counter_anomalies.inc();
+        match &self.action {
+            AnomalyAction::Tag(tag) => Some(Output {
+                new_events: Some(smallvec![Event::Pdu(
+                    Pdu::from(event).with_tags(tag.as_bytes())
+                )]),
+                route: self.route.as_ref(),
+            }),
+            AnomalyAction::RouteTo(route) => Some(Output {
+                new_events: None,
+                route: route.as_ref(),
+            }),
+        }
+    }
+
+    fn tick(&self, _time: std::time::SystemTime, _backends: &Backends) {
+        self.expire(Instant::now());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::config::RouteType;
+    use crate::processors::test::event;
+
+    fn anomaly(action: AnomalyAction) -> Anomaly {
+        Anomaly::new(
+            stats::Collector::default().scope("a"),
+            &processor::Anomaly {
+                name: None,
+                types: vec![],
+                alpha: 0.5,
+                z_score: 3_f64,
+                min_samples: Some(3),
+                capacity: 10,
+                expire_seconds: None,
+                action,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tag_anomalies() {
+        let anomaly = anomaly(AnomalyAction::Tag("anomaly:true".to_owned()));
+        for line in [
+            "latency:10|ms",
+            "latency:12|ms",
+            "latency:10|ms",
+            "latency:12|ms",
+        ] {
+            let output = anomaly.provide_statsd(&event(line)).unwrap();
+            assert!(output.new_events.is_none());
+        }
+        // Metrics with other tags are tracked separately
+        let output = anomaly
+            .provide_statsd(&event("latency:100|ms|#host:a"))
+            .unwrap();
+        assert!(output.new_events.is_none());
+        let output = anomaly.provide_statsd(&event("latency:100|ms")).unwrap();
+        let tagged = Pdu::from(&output.new_events.unwrap()[0]);
+        assert_eq!(tagged.as_bytes(), b"latency:100|ms|#anomaly:true");
+        assert_eq!(anomaly.counter_anomalies.get(), 1_f64);
+    }
+
+    #[test]
+    fn route_anomalies() {
+        let quarantine = vec![Route {
+            route_type: RouteType::Processor,
+            route_to: "quarantine".to_owned(),
+        }];
+        let anomaly = anomaly(AnomalyAction::RouteTo(quarantine.clone()));
+        for line in ["queue:10|g", "queue:12|g", "queue:10|g", "queue:12|g"] {
+            assert!(anomaly
+                .provide_statsd(&event(line))
+                .unwrap()
+                .route
+                .is_empty());
+        }
+        let output = anomaly.provide_statsd(&event("queue:-50|g")).unwrap();
+        assert!(output.new_events.is_none());
+        assert_eq!(output.route, quarantine.as_slice());
+        // Sets are not checked
+        for line in [
+            "users:1|s",
+            "users:2|s",
+            "users:1|s",
+            "users:2|s",
+            "users:900|s",
+        ] {
+            assert!(anomaly
+                .provide_statsd(&event(line))
+                .unwrap()
+                .route
+                .is_empty());
+        }
+    }
+}
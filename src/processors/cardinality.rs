@@ -1,21 +1,28 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
 use std::time::{Duration, SystemTime};
 
-use super::super::config;
+use super::super::config::{self, processor::CardinalityAction};
 use super::super::statsd_proto::Event;
-use super::{Output, Processor};
-use crate::stats::{Counter, Gauge, Scope};
+use super::tag_value_limit::{self, TagValues};
+use super::{parse, processing_errors, Error, Output, Processor};
+use crate::stats::{Counter, CounterVec, Gauge, GaugeVec, Scope};
 use crate::{
     backends::Backends,
-    statsd_proto::{Owned, Parsed},
+    statsd_proto::{Id, Owned, Parsed, Tag},
 };
 
 use crate::cuckoofilter::{self, CuckooFilter};
 use ahash::AHasher;
+use hyperloglog::HyperLogLog;
 use parking_lot::Mutex;
+use regex::bytes::RegexSet;
+use serde_json::json;
+use smallvec::smallvec;
 
-use log::warn;
+use log::{info, warn};
 
 struct TimeBoundedCuckoo<H>
 where
@@ -95,60 +102,523 @@ where
     }
 }
 
+const TAG_KEY_ERROR_RATE: f64 = 0.02;
+const DEFAULT_TOP_TAG_KEYS: usize = 10;
+
+/// Approximate distinct value counts for a bounded set of tag keys, reset
+/// every rotation window.
+struct TagKeyCardinality {
+    max_keys: usize,
+    top: usize,
+    window: Duration,
+    reset_at: SystemTime,
+    keys: HashMap<Vec<u8>, HyperLogLog>,
+}
+
+impl TagKeyCardinality {
+    fn new(config: &config::processor::CardinalityTagKeys, window: Duration) -> Self {
+        TagKeyCardinality {
+            max_keys: config.max_keys,
+            top: config.top.unwrap_or(DEFAULT_TOP_TAG_KEYS),
+            window,
+            reset_at: SystemTime::now() + window,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Record the tag values of a sample, returning the number of tags which
+    /// could not be tracked as the key limit has been reached.
+    fn observe(&mut self, tags: &[Tag]) -> usize {
+        let mut untracked = 0;
+        for tag in tags {
+            if let Some(hll) = self.keys.get_mut(&tag.name) {
+                hll.insert(&tag.value);
+            } else if self.keys.len() < self.max_keys {
+                let mut hll = HyperLogLog::new(TAG_KEY_ERROR_RATE);
+                hll.insert(&tag.value);
+                self.keys.insert(tag.name.clone(), hll);
+            } else {
+                untracked += 1;
+            }
+        }
+        untracked
+    }
+
+    /// The tracked tag keys with the most distinct values, largest first.
+    fn top(&self) -> Vec<(String, f64)> {
+        let mut counts: Vec<_> = self
+            .keys
+            .iter()
+            .map(|(key, hll)| (String::from_utf8_lossy(key).into_owned(), hll.len()))
+            .collect();
+        counts.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        counts.truncate(self.top);
+        counts
+    }
+
+    fn rotate(&mut self, with_time: SystemTime) {
+        if with_time >= self.reset_at {
+            self.keys.clear();
+            self.reset_at = with_time + self.window;
+        }
+    }
+}
+
+const DEFAULT_OVERFLOW_VALUE: &str = "__overflow";
+
+const DEFAULT_OFFENDER_PREFIX_DEPTH: usize = 2;
+const DEFAULT_OFFENDER_CAPACITY: usize = 100;
+const DEFAULT_TOP_OFFENDERS: usize = 10;
+
+/// Approximate counts of new metrics by name prefix, reset every rotation
+/// window. At most `capacity` prefixes are held, using the space saving
+/// algorithm: an unseen prefix replaces the least counted one and inherits
+/// its count, so counts are overestimated rather than heavy prefixes missed.
+struct TopOffenders {
+    prefix_depth: usize,
+    capacity: usize,
+    top: usize,
+    window: Duration,
+    reset_at: SystemTime,
+    counts: HashMap<Vec<u8>, u64>,
+    /// The top offenders of the last complete window
+    last: Vec<(String, u64)>,
+}
+
+impl TopOffenders {
+    fn new(config: &config::processor::CardinalityTopOffenders, window: Duration) -> Self {
+        TopOffenders {
+            prefix_depth: config.prefix_depth.unwrap_or(DEFAULT_OFFENDER_PREFIX_DEPTH),
+            capacity: config.capacity.unwrap_or(DEFAULT_OFFENDER_CAPACITY),
+            top: config.top.unwrap_or(DEFAULT_TOP_OFFENDERS),
+            window,
+            reset_at: SystemTime::now() + window,
+            counts: HashMap::new(),
+            last: Vec::new(),
+        }
+    }
+
+    /// The first `prefix_depth` components of a metric name
+    fn prefix<'a>(&self, name: &'a [u8]) -> &'a [u8] {
+        let end = name
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c == b'.')
+            .nth(self.prefix_depth - 1)
+            .map_or(name.len(), |(index, _)| index);
+        &name[..end]
+    }
+
+    /// Count a new metric against its name prefix
+    fn observe(&mut self, name: &[u8]) {
+        let prefix = self.prefix(name);
+        if let Some(count) = self.counts.get_mut(prefix) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() >= self.capacity {
+            let (least, least_count) = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(prefix, count)| (prefix.clone(), *count))
+                .unwrap();
+            self.counts.remove(&least);
+            count += least_count;
+        }
+        self.counts.insert(prefix.to_vec(), count);
+    }
+
+    /// The prefixes with the most new metrics this window, largest first.
+    fn current(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(prefix, count)| (String::from_utf8_lossy(prefix).into_owned(), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(self.top);
+        counts
+    }
+
+    /// Start a new window if the current one has ended, returning the top
+    /// offenders of the ended window.
+    fn rotate(&mut self, with_time: SystemTime) -> Option<&[(String, u64)]> {
+        if with_time < self.reset_at {
+            return None;
+        }
+        self.last = self.current();
+        self.counts.clear();
+        self.reset_at = with_time + self.window;
+        Some(&self.last)
+    }
+}
+
+/// Metrics which pass without being counted against any limit
+struct Allowlist {
+    names: Option<RegexSet>,
+    prefixes: Vec<Vec<u8>>,
+    tags: Vec<Vec<Tag>>,
+}
+
+impl Allowlist {
+    fn new(config: &config::processor::CardinalityAllow) -> Result<Self, Error> {
+        let names = if config.names.is_empty() {
+            None
+        } else {
+            Some(RegexSet::new(&config.names)?)
+        };
+        let tags = config
+            .tags
+            .iter()
+            .map(|tags| {
+                tags.iter()
+                    .map(|tag| {
+                        let (name, value) = tag.split_once(':').ok_or_else(|| {
+                            Error::InvalidConfig(format!("allowed tag {} has no value", tag))
+                        })?;
+                        Ok(Tag {
+                            name: name.as_bytes().to_vec(),
+                            value: value.as_bytes().to_vec(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Error>>()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Allowlist {
+            names,
+            prefixes: config
+                .prefixes
+                .iter()
+                .map(|prefix| prefix.as_bytes().to_vec())
+                .collect(),
+            tags,
+        })
+    }
+
+    fn allows(&self, sample: &Event) -> bool {
+        let name = sample_name(sample);
+        if self.prefixes.iter().any(|prefix| name.starts_with(prefix))
+            || self
+                .names
+                .as_ref()
+                .is_some_and(|names| names.is_match(name))
+        {
+            return true;
+        }
+        if self.tags.is_empty() {
+            return false;
+        }
+        // Avoid parsing samples which can't have tags
+        if let Event::Pdu(pdu) = sample {
+            if pdu.tags().is_none() {
+                return false;
+            }
+        }
+        let owned: Owned = match sample.try_into() {
+            Ok(owned) => owned,
+            Err(_) => return false,
+        };
+        self.tags
+            .iter()
+            .any(|combination| combination.iter().all(|tag| owned.tags().contains(tag)))
+    }
+}
+
+/// A limit on the distinct metrics whose names start with a prefix
+struct PrefixLimit {
+    prefix: String,
+    limit: usize,
+    filter: Mutex<MultiCuckoo<AHasher>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Admission {
+    /// The metric was already counted
+    Seen,
+    /// The metric is new and was counted
+    Added,
+    /// The metric is new and past the limit
+    Flagged,
+}
+
+/// Add a sample to a filter unless it is new and the filter is already over
+/// its limit, returning the filter size and whether the sample was added.
+fn admit(filter: &Mutex<MultiCuckoo<AHasher>>, limit: usize, sample: &Event) -> (usize, Admission) {
+    let mut filter = filter.lock();
+    let len = filter.len();
+    if filter.contains(sample) {
+        return (len, Admission::Seen);
+    }
+    if len > limit {
+        return (len, Admission::Flagged);
+    }
+    let _ = filter.add(sample);
+    (len, Admission::Added)
+}
+
+fn sample_name(sample: &Event) -> &[u8] {
+    match sample {
+        Event::Pdu(pdu) => pdu.name(),
+        Event::Parsed(parsed) => parsed.name(),
+    }
+}
+
 pub struct Cardinality {
     route: Vec<config::Route>,
     filter: Mutex<MultiCuckoo<AHasher>>,
     limit: usize,
+    prefix_limits: Vec<PrefixLimit>,
+    allow: Option<Allowlist>,
+    action: CardinalityAction,
     counter_flagged_metrics: Counter,
+    counter_allowed_metrics: Counter,
     gauge_metric_hwm: Gauge,
+    counter_prefix_flagged_metrics: CounterVec,
+    gauge_prefix_metric_hwm: GaugeVec,
+    tag_keys: Option<Mutex<TagKeyCardinality>>,
+    tag_values: Option<Mutex<TagValues>>,
+    top_offenders: Option<Mutex<TopOffenders>>,
+    counter_overflowed_tag_values: CounterVec,
+    gauge_distinct_tag_values: GaugeVec,
+    counter_untracked_tag_keys: Counter,
+    counter_processing_errors: Counter,
+    gauge_tag_key_values: GaugeVec,
 }
 
 impl Cardinality {
-    pub fn new(scope: Scope, from_config: &config::processor::Cardinality) -> Self {
+    pub fn new(scope: Scope, from_config: &config::processor::Cardinality) -> Result<Self, Error> {
         let window = Duration::from_secs(from_config.rotate_after_seconds);
         // Record a limit gauge for visibility
         let limit_gauge = scope.gauge("limit").unwrap();
         limit_gauge.set(from_config.size_limit as f64);
-        Cardinality {
+        let prefix_limit_gauge = scope.gauge_vec("prefix_limit", &["prefix"]).unwrap();
+        let prefix_limits = from_config
+            .limits
+            .iter()
+            .map(|limit| {
+                prefix_limit_gauge.set(&[limit.prefix.as_str()], limit.limit as f64);
+                PrefixLimit {
+                    prefix: limit.prefix.clone(),
+                    limit: limit.limit,
+                    filter: Mutex::new(MultiCuckoo::new(from_config.buckets, &window)),
+                }
+            })
+            .collect();
+        let allow = from_config.allow.as_ref().map(Allowlist::new).transpose()?;
+        Ok(Cardinality {
             route: from_config.route.clone(),
             filter: Mutex::new(MultiCuckoo::new(from_config.buckets, &window)),
             limit: from_config.size_limit as usize,
+            prefix_limits,
+            allow,
+            action: from_config.action.clone(),
             counter_flagged_metrics: scope.counter("flagged_metrics").unwrap(),
+            counter_allowed_metrics: scope.counter("allowed_metrics").unwrap(),
             gauge_metric_hwm: scope.gauge("count_hwm").unwrap(),
-        }
+            counter_prefix_flagged_metrics: scope
+                .counter_vec("prefix_flagged_metrics", &["prefix"])
+                .unwrap(),
+            gauge_prefix_metric_hwm: scope.gauge_vec("prefix_count_hwm", &["prefix"]).unwrap(),
+            tag_keys: from_config
+                .tag_keys
+                .as_ref()
+                .map(|tk| Mutex::new(TagKeyCardinality::new(tk, window))),
+            top_offenders: from_config
+                .top_offenders
+                .as_ref()
+                .map(|to| Mutex::new(TopOffenders::new(to, window))),
+            tag_values: from_config.tag_values.as_ref().map(|tv| {
+                let overflow_value = tv.overflow_value.as_deref();
+                Mutex::new(TagValues::new(
+                    &tv.keys,
+                    tv.max_values,
+                    overflow_value.unwrap_or(DEFAULT_OVERFLOW_VALUE),
+                    Some(window),
+                ))
+            }),
+            counter_overflowed_tag_values: scope
+                .counter_vec("overflowed_tag_values", &["tag_key"])
+                .unwrap(),
+            gauge_distinct_tag_values: scope
+                .gauge_vec("distinct_tag_values", &["tag_key"])
+                .unwrap(),
+            counter_untracked_tag_keys: scope.counter("untracked_tag_keys").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+            gauge_tag_key_values: scope.gauge_vec("tag_key_values", &["tag_key"]).unwrap(),
+        })
     }
 
     fn rotate(&self) {
-        self.filter.lock().rotate(SystemTime::now())
+        let now = SystemTime::now();
+        self.filter.lock().rotate(now);
+        for prefix_limit in self.prefix_limits.iter() {
+            prefix_limit.filter.lock().rotate(now);
+        }
+        if let Some(tag_keys) = self.tag_keys.as_ref() {
+            let mut tag_keys = tag_keys.lock();
+            self.export_tag_keys(&tag_keys);
+            tag_keys.rotate(now);
+        }
+        if let Some(tag_values) = self.tag_values.as_ref() {
+            let mut tag_values = tag_values.lock();
+            for (key, count) in tag_values.counts() {
+                self.gauge_distinct_tag_values
+                    .set(&[key.as_str()], count as f64);
+            }
+            tag_values.rotate(now);
+        }
+        if let Some(top_offenders) = self.top_offenders.as_ref() {
+            let mut top_offenders = top_offenders.lock();
+            if let Some(top) = top_offenders.rotate(now) {
+                if !top.is_empty() {
+                    let top: Vec<_> = top
+                        .iter()
+                        .map(|(prefix, count)| format!("{}={}", prefix, count))
+                        .collect();
+                    info!(
+                        "cardinality top offenders by new metrics: {}",
+                        top.join(", ")
+                    );
+                }
+            }
+        }
+    }
+
+    /// Replace the exported per tag key gauges with the current top offenders
+    fn export_tag_keys(&self, tag_keys: &TagKeyCardinality) {
+        self.gauge_tag_key_values.reset();
+        for (key, count) in tag_keys.top() {
+            self.gauge_tag_key_values.set(&[key.as_str()], count);
+        }
+    }
+
+    /// The first prefix limit matching the name of a sample
+    fn prefix_limit(&self, sample: &Event) -> Option<&PrefixLimit> {
+        if self.prefix_limits.is_empty() {
+            return None;
+        }
+        let name = sample_name(sample);
+        self.prefix_limits
+            .iter()
+            .find(|limit| name.starts_with(limit.prefix.as_bytes()))
+    }
+
+    fn observe_tags(&self, tag_keys: &Mutex<TagKeyCardinality>, sample: &Event) {
+        // Avoid parsing samples which can't have tags
+        if let Event::Pdu(pdu) = sample {
+            if pdu.tags().is_none() {
+                return;
+            }
+        }
+        let owned: Owned = match sample.try_into() {
+            Ok(owned) => owned,
+            Err(_) => return,
+        };
+        let untracked = tag_keys.lock().observe(owned.tags());
+        if untracked > 0 {
+            self.counter_untracked_tag_keys.inc_by(untracked as f64);
+        }
     }
 }
 
 impl Processor for Cardinality {
     fn provide_statsd(&self, sample: &Event) -> Option<Output> {
-        let mut filter = self.filter.lock();
-        let contains = filter.contains(sample);
-        let len = filter.len();
-        self.gauge_metric_hwm.set(len as f64);
+        if let Some(tag_keys) = self.tag_keys.as_ref() {
+            self.observe_tags(tag_keys, sample);
+        }
+        if self
+            .allow
+            .as_ref()
+            .is_some_and(|allow| allow.allows(sample))
+        {
+            self.counter_allowed_metrics.inc();
+            return Some(Output {
+                route: self.route.as_ref(),
+                new_events: None,
+            });
+        }
+        let rewritten = self.tag_values.as_ref().and_then(|tag_values| {
+            tag_value_limit::rewrite(tag_values, sample, &self.counter_overflowed_tag_values)
+        });
+        let sample = rewritten.as_ref().unwrap_or(sample);
+        let admission = match self.prefix_limit(sample) {
+            Some(prefix_limit) => {
+                let label = [prefix_limit.prefix.as_str()];
+                let (len, admission) = admit(&prefix_limit.filter, prefix_limit.limit, sample);
+                self.gauge_prefix_metric_hwm.set(&label, len as f64);
+                if admission == Admission::Flagged {
+                    self.counter_prefix_flagged_metrics.inc(&label);
+                }
+                admission
+            }
+            None => {
+                let (len, admission) = admit(&self.filter, self.limit, sample);
+                self.gauge_metric_hwm.set(len as f64);
+                admission
+            }
+        };
+        if admission != Admission::Seen {
+            if let Some(top_offenders) = self.top_offenders.as_ref() {
+                top_offenders.lock().observe(sample_name(sample));
+            }
+        }
 
-        if !contains && len > self.limit {
+        if admission == Admission::Flagged {
             if (self.counter_flagged_metrics.get() as u64) % 1000 == 0 {
                 // Enforce parsing of the metric to give a clean debug log
-                let owned: Owned = sample.try_into().ok()?;
-                warn!("metric flagged for cardinality limits: {}", owned.id());
+                let owned: Result<Owned, _> = sample.try_into();
+                if let Ok(owned) = owned {
+                    warn!("metric flagged for cardinality limits: {}", owned.id());
+                }
             }
             self.counter_flagged_metrics.inc();
-            return None;
+            return match &self.action {
+                CardinalityAction::Drop => None,
+                CardinalityAction::StripTags => {
+                    let owned = parse(sample, &self.counter_processing_errors)?;
+                    let id = Id {
+                        name: owned.name().to_vec(),
+                        mtype: *owned.metric_type(),
+                        tags: vec![],
+                    };
+                    let stripped = Owned::new(id, owned.value(), owned.sample_rate());
+                    Some(Output {
+                        route: self.route.as_ref(),
+                        new_events: Some(smallvec![Event::Parsed(stripped)]),
+                    })
+                }
+                CardinalityAction::RouteTo(route) => Some(Output {
+                    route: route.as_ref(),
+                    new_events: rewritten.map(|event| smallvec![event]),
+                }),
+            };
         }
-        let _ = filter.add(sample);
         Some(Output {
             route: self.route.as_ref(),
-            new_events: None,
+            new_events: rewritten.map(|event| smallvec![event]),
         })
     }
 
     fn tick(&self, _time: std::time::SystemTime, _backends: &Backends) {
         self.rotate();
     }
+
+    fn report(&self) -> Option<serde_json::Value> {
+        let top_offenders = self.top_offenders.as_ref()?.lock();
+        let offenders = |top: &[(String, u64)]| -> Vec<serde_json::Value> {
+            top.iter()
+                .map(|(prefix, count)| json!({"prefix": prefix, "new_metrics": count}))
+                .collect()
+        };
+        Some(json!({
+            "top_offenders": {
+                "current": offenders(&top_offenders.current()),
+                "last": offenders(&top_offenders.last),
+            }
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -216,10 +686,16 @@ pub mod test {
             size_limit: 100_usize,
             rotate_after_seconds: 10,
             buckets: 2,
+            tag_keys: None,
+            tag_values: None,
+            top_offenders: None,
+            limits: vec![],
+            allow: None,
+            action: CardinalityAction::Drop,
             route: vec![],
         };
         let scope = crate::stats::Collector::default().scope("test");
-        let filter = Cardinality::new(scope, &config);
+        let filter = Cardinality::new(scope, &config).unwrap();
         for name in &names[0..101] {
             assert!(filter.provide_statsd(name).is_some());
         }
@@ -243,4 +719,356 @@ pub mod test {
             filter.counter_flagged_metrics.get()
         );
     }
+
+    #[test]
+    fn test_tag_key_cardinality() {
+        let sample = |host: u32, region: u32| {
+            let id = Id {
+                name: b"metric".to_vec(),
+                mtype: Type::Counter,
+                tags: vec![
+                    Tag {
+                        name: b"host".to_vec(),
+                        value: format!("host{}", host).into_bytes(),
+                    },
+                    Tag {
+                        name: b"region".to_vec(),
+                        value: format!("region{}", region).into_bytes(),
+                    },
+                ],
+            };
+            Event::Parsed(Owned::new(id, 1.0, None))
+        };
+
+        let config = config::processor::Cardinality {
+            size_limit: 10000_usize,
+            rotate_after_seconds: 10,
+            buckets: 2,
+            tag_keys: Some(config::processor::CardinalityTagKeys {
+                max_keys: 1,
+                top: None,
+            }),
+            tag_values: None,
+            top_offenders: None,
+            limits: vec![],
+            allow: None,
+            action: CardinalityAction::Drop,
+            route: vec![],
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let filter = Cardinality::new(scope, &config).unwrap();
+        for host in 0..1000 {
+            filter.provide_statsd(&sample(host, host % 3));
+        }
+        // Only the first key seen is tracked, the rest are counted
+        assert_eq!(filter.counter_untracked_tag_keys.get(), 1000_f64);
+        let tag_keys = filter.tag_keys.as_ref().unwrap().lock();
+        let top = tag_keys.top();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, "host");
+        assert!(
+            (top[0].1 - 1000_f64).abs() < 100_f64,
+            "estimate out of range {}",
+            top[0].1
+        );
+
+        filter.export_tag_keys(&tag_keys);
+        assert_eq!(filter.gauge_tag_key_values.get(&["host"]), top[0].1);
+    }
+
+    #[test]
+    fn test_prefix_limits() {
+        let sample = |name: String| {
+            let id = Id {
+                name: name.into_bytes(),
+                mtype: Type::Counter,
+                tags: vec![],
+            };
+            Event::Parsed(Owned::new(id, 1.0, None))
+        };
+
+        let config = config::processor::Cardinality {
+            size_limit: 10000_usize,
+            rotate_after_seconds: 10,
+            buckets: 2,
+            tag_keys: None,
+            tag_values: None,
+            top_offenders: None,
+            limits: vec![
+                config::processor::CardinalityLimit {
+                    prefix: "app1.".to_owned(),
+                    limit: 10,
+                },
+                config::processor::CardinalityLimit {
+                    prefix: "app".to_owned(),
+                    limit: 100,
+                },
+            ],
+            allow: None,
+            action: CardinalityAction::Drop,
+            route: vec![],
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let filter = Cardinality::new(scope, &config).unwrap();
+        let passed = |prefix: &str| {
+            (0..200)
+                .filter(|val| {
+                    filter
+                        .provide_statsd(&sample(format!("{}{}", prefix, val)))
+                        .is_some()
+                })
+                .count()
+        };
+        // Limits allow one metric past the limit, as the global limit does
+        assert_eq!(passed("app1."), 11);
+        // app2 metrics fall through to the second prefix
+        assert_eq!(passed("app2."), 101);
+        assert_eq!(passed("infra."), 200);
+
+        assert_eq!(
+            filter.counter_prefix_flagged_metrics.get(&["app1."]),
+            189_f64
+        );
+        assert_eq!(filter.counter_prefix_flagged_metrics.get(&["app"]), 99_f64);
+        assert_eq!(filter.counter_flagged_metrics.get(), 288_f64);
+        assert_eq!(filter.gauge_prefix_metric_hwm.get(&["app1."]), 11_f64);
+        assert_eq!(filter.gauge_metric_hwm.get(), 199_f64);
+        // A metric already seen under a full limit still passes
+        assert!(filter
+            .provide_statsd(&sample("app1.0".to_owned()))
+            .is_some());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let sample = |line: String| {
+            Event::Pdu(crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap())
+        };
+
+        let config = config::processor::Cardinality {
+            size_limit: 10_usize,
+            rotate_after_seconds: 10,
+            buckets: 2,
+            tag_keys: None,
+            tag_values: None,
+            top_offenders: None,
+            limits: vec![],
+            allow: Some(config::processor::CardinalityAllow {
+                names: vec![r"^slo\.".to_owned()],
+                prefixes: vec!["critical.".to_owned()],
+                tags: vec![vec!["tier:1".to_owned(), "env:prod".to_owned()]],
+            }),
+            action: CardinalityAction::Drop,
+            route: vec![],
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let filter = Cardinality::new(scope, &config).unwrap();
+        for val in 0..100 {
+            filter.provide_statsd(&sample(format!("other.{}:1|c", val)));
+        }
+        assert!(filter
+            .provide_statsd(&sample("other.new:1|c".to_owned()))
+            .is_none());
+        for val in 0..100 {
+            for line in [
+                format!("slo.{}:1|c", val),
+                format!("critical.{}:1|c", val),
+                format!("tagged.{}:1|c|#env:prod,tier:1", val),
+            ] {
+                assert!(filter.provide_statsd(&sample(line)).is_some());
+            }
+        }
+        // Every tag of a combination must be present
+        assert!(filter
+            .provide_statsd(&sample("tagged.new:1|c|#tier:1".to_owned()))
+            .is_none());
+        assert_eq!(filter.counter_allowed_metrics.get(), 300_f64);
+        // Allowed metrics are not counted against the limit
+        assert_eq!(filter.filter.lock().len(), 11);
+    }
+
+    #[test]
+    fn test_tag_value_cardinality() {
+        let sample = |line: String| {
+            Event::Pdu(crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap())
+        };
+
+        let config = config::processor::Cardinality {
+            size_limit: 10000_usize,
+            rotate_after_seconds: 10,
+            buckets: 2,
+            tag_keys: None,
+            tag_values: Some(config::processor::CardinalityTagValues {
+                keys: vec!["user_id".to_owned()],
+                max_values: 10,
+                overflow_value: None,
+            }),
+            top_offenders: None,
+            limits: vec![],
+            allow: None,
+            action: CardinalityAction::Drop,
+            route: vec![],
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let filter = Cardinality::new(scope, &config).unwrap();
+        let mut rewritten = 0;
+        for user in 0..100 {
+            let line = format!("logins:1|c|#host:a,user_id:{}", user);
+            let output = filter.provide_statsd(&sample(line)).unwrap();
+            if let Some(events) = output.new_events {
+                rewritten += 1;
+                let owned: Owned = (&events[0]).try_into().unwrap();
+                assert_eq!(
+                    owned.tags(),
+                    &[
+                        Tag {
+                            name: b"host".to_vec(),
+                            value: b"a".to_vec(),
+                        },
+                        Tag {
+                            name: b"user_id".to_vec(),
+                            value: b"__overflow".to_vec(),
+                        },
+                    ]
+                );
+            }
+        }
+        assert_eq!(rewritten, 90);
+        // Values seen before the limit was reached pass unchanged
+        let output = filter
+            .provide_statsd(&sample("logins:1|c|#user_id:3".to_owned()))
+            .unwrap();
+        assert!(output.new_events.is_none());
+        assert_eq!(
+            filter.counter_overflowed_tag_values.get(&["user_id"]),
+            90_f64
+        );
+        filter.rotate();
+        assert_eq!(filter.gauge_distinct_tag_values.get(&["user_id"]), 10_f64);
+    }
+
+    #[test]
+    fn test_overflow_actions() {
+        let sample = |val: u32| {
+            Event::Pdu(
+                crate::statsd_proto::Pdu::parse(bytes::Bytes::from(format!(
+                    "metric.{}:1|c|#host:a",
+                    val
+                )))
+                .unwrap(),
+            )
+        };
+        let quarantine = vec![config::Route {
+            route_type: config::RouteType::Statsd,
+            route_to: "quarantine".to_owned(),
+        }];
+        let cardinality = |action| {
+            let config = config::processor::Cardinality {
+                size_limit: 10_usize,
+                rotate_after_seconds: 10,
+                buckets: 2,
+                tag_keys: None,
+                tag_values: None,
+                top_offenders: None,
+                limits: vec![],
+                allow: None,
+                action,
+                route: vec![],
+            };
+            let scope = crate::stats::Collector::default().scope("test");
+            let filter = Cardinality::new(scope, &config).unwrap();
+            for val in 0..11 {
+                let output = filter.provide_statsd(&sample(val)).unwrap();
+                assert!(output.route.is_empty());
+            }
+            filter
+        };
+
+        let filter = cardinality(CardinalityAction::StripTags);
+        let output = filter.provide_statsd(&sample(11)).unwrap();
+        let owned: Owned = (&output.new_events.unwrap()[0]).try_into().unwrap();
+        assert_eq!(owned.name(), b"metric.11");
+        assert!(owned.tags().is_empty());
+        assert_eq!(filter.counter_flagged_metrics.get(), 1_f64);
+
+        let filter = cardinality(CardinalityAction::RouteTo(quarantine.clone()));
+        let output = filter.provide_statsd(&sample(11)).unwrap();
+        assert_eq!(output.route, quarantine.as_slice());
+        assert!(output.new_events.is_none());
+        assert_eq!(filter.counter_flagged_metrics.get(), 1_f64);
+    }
+
+    #[test]
+    fn test_top_offenders() {
+        let mut top = TopOffenders::new(
+            &config::processor::CardinalityTopOffenders {
+                prefix_depth: None,
+                capacity: Some(2),
+                top: Some(2),
+            },
+            Duration::from_secs(10),
+        );
+        assert_eq!(top.prefix(b"app1.web.requests"), b"app1.web");
+        assert_eq!(top.prefix(b"app1"), b"app1");
+        for _ in 0..10 {
+            top.observe(b"app1.web.requests");
+        }
+        for _ in 0..5 {
+            top.observe(b"app2.db.queries");
+        }
+        // Replaces app2.db, inheriting its count
+        top.observe(b"infra.host.cpu");
+        assert_eq!(
+            top.current(),
+            vec![("app1.web".to_owned(), 10), ("infra.host".to_owned(), 6)]
+        );
+
+        let later = SystemTime::now() + Duration::from_secs(11);
+        assert_eq!(top.rotate(later).unwrap().len(), 2);
+        assert!(top.current().is_empty());
+        assert_eq!(top.last[0], ("app1.web".to_owned(), 10));
+    }
+
+    #[test]
+    fn test_top_offenders_report() {
+        let sample = |name: String| {
+            let id = Id {
+                name: name.into_bytes(),
+                mtype: Type::Counter,
+                tags: vec![],
+            };
+            Event::Parsed(Owned::new(id, 1.0, None))
+        };
+        let config = config::processor::Cardinality {
+            size_limit: 10_usize,
+            rotate_after_seconds: 10,
+            buckets: 2,
+            tag_keys: None,
+            tag_values: None,
+            top_offenders: Some(config::processor::CardinalityTopOffenders {
+                prefix_depth: Some(1),
+                capacity: None,
+                top: None,
+            }),
+            limits: vec![],
+            allow: None,
+            action: CardinalityAction::Drop,
+            route: vec![],
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let filter = Cardinality::new(scope, &config).unwrap();
+        for val in 0..20 {
+            filter.provide_statsd(&sample(format!("app1.{}", val)));
+            // Metrics already seen are not new
+            filter.provide_statsd(&sample("app2.seen".to_owned()));
+        }
+        // Flagged metrics are counted as new
+        assert_eq!(
+            filter.report().unwrap()["top_offenders"]["current"],
+            json!([
+                {"prefix": "app1", "new_metrics": 20},
+                {"prefix": "app2", "new_metrics": 1},
+            ])
+        );
+    }
 }
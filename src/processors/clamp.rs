@@ -0,0 +1,142 @@
+use smallvec::smallvec;
+
+use super::{name_and_type, parse, processing_errors, Error, Output, Processor, Selector};
+use crate::config::processor::{self, OutOfBounds};
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Owned, Parsed};
+
+struct Rule {
+    selector: Selector,
+    min: f64,
+    max: f64,
+    action: OutOfBounds,
+}
+
+impl Rule {
+    fn new(config: &processor::ClampRule) -> Result<Self, Error> {
+        Ok(Rule {
+            selector: Selector::new(config.name.as_deref(), &config.types)?,
+            min: config.min.unwrap_or(f64::NEG_INFINITY),
+            max: config.max.unwrap_or(f64::INFINITY),
+            action: config.action,
+        })
+    }
+}
+
+/// Clamps or drops metric values outside configured bounds, so bogus values
+/// from clients do not skew downstream aggregates.
+pub struct Clamp {
+    rules: Vec<Rule>,
+    route: Vec<Route>,
+
+    counter_corrected: stats::CounterVec,
+    counter_processing_errors: stats::Counter,
+}
+
+impl Clamp {
+    pub fn new(scope: stats::Scope, from_config: &processor::Clamp) -> Result<Self, Error> {
+        Ok(Clamp {
+            rules: from_config
+                .rules
+                .iter()
+                .map(Rule::new)
+                .collect::<Result<_, _>>()?,
+            route: from_config.route.clone(),
+            counter_corrected: scope.counter_vec("corrected", &["action"]).unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+}
+
+impl Processor for Clamp {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let unchanged = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let (name, mtype) = name_and_type(event);
+        let rule = match self
+            .rules
+            .iter()
+            .find(|rule| rule.selector.matches(name, mtype))
+        {
+            Some(rule) => rule,
+            None => return Some(unchanged),
+        };
+        let owned = parse(event, &self.counter_processing_errors)?;
+        let value = owned.value();
+        if value >= rule.min && value <= rule.max {
+            return Some(unchanged);
+        }
+        match rule.action {
+            OutOfBounds::Drop => {
+                self.counter_corrected.inc(&["dropped"]);
+                None
+            }
+            OutOfBounds::Clamp => {
+                self.counter_corrected.inc(&["clamped"]);
+                let clamped = Owned::new(
+                    owned.id().clone(),
+                    value.clamp(rule.min, rule.max),
+                    owned.sample_rate(),
+                );
+                Some(Output {
+                    new_events: Some(smallvec![Event::Parsed(clamped)]),
+                    route: self.route.as_ref(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use std::convert::TryInto;
+
+    #[test]
+    fn clamp_and_drop() {
+        let config = processor::Clamp {
+            rules: vec![
+                processor::ClampRule {
+                    name: None,
+                    types: vec!["timer".to_owned()],
+                    min: Some(0_f64),
+                    max: Some(60000_f64),
+                    action: OutOfBounds::Clamp,
+                },
+                processor::ClampRule {
+                    name: Some(r"^requests\.".to_owned()),
+                    types: vec![],
+                    min: None,
+                    max: Some(1e12),
+                    action: OutOfBounds::Drop,
+                },
+            ],
+            route: vec![],
+        };
+        let sink = stats::Collector::default();
+        let clamp = Clamp::new(sink.scope("c"), &config).unwrap();
+        let bounded = |line: &'static str| {
+            let event = event(line);
+            clamp.provide_statsd(&event).map(|output| {
+                output.new_events.map(|events| {
+                    let owned: Owned = (&events[0]).try_into().unwrap();
+                    owned.value()
+                })
+            })
+        };
+
+        assert_eq!(bounded("latency:-5|ms"), Some(Some(0_f64)));
+        assert_eq!(bounded("latency:90000|ms"), Some(Some(60000_f64)));
+        assert_eq!(bounded("latency:50|ms"), Some(None));
+        assert_eq!(bounded("requests.total:1000000000000000|c"), None);
+        assert_eq!(bounded("requests.total:5|c"), Some(None));
+        assert_eq!(bounded("other:1000000000000000|c"), Some(None));
+        assert_eq!(clamp.counter_corrected.get(&["clamped"]), 2_f64);
+        assert_eq!(clamp.counter_corrected.get(&["dropped"]), 1_f64);
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::{name_and_type, processing_errors, Error, Output, Processor, Selector};
+use crate::backends::Backends;
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Tag, Type};
+
+const DEFAULT_ABSENCE_NAME: &str = "metric.missing";
+
+/// Metrics watched for, by the window each was last seen in
+struct Watched {
+    last_seen: HashMap<Id, u64>,
+    window: u64,
+    window_start: Instant,
+}
+
+/// Watches for metrics which stop arriving, emitting a counter for each
+/// metric once it has been missing for a number of whole windows. Reported
+/// metrics are no longer watched for until they are seen again.
+pub struct DeadMetric {
+    selector: Selector,
+    window: Duration,
+    missing_windows: u64,
+    capacity: usize,
+    absence_name: Vec<u8>,
+    watched: Mutex<Watched>,
+    absence_route: Vec<Route>,
+    route: Vec<Route>,
+
+    counter_missing: stats::Counter,
+    counter_unwatched: stats::Counter,
+    gauge_watched: stats::Gauge,
+    counter_processing_errors: stats::Counter,
+}
+
+impl DeadMetric {
+    pub fn new(scope: stats::Scope, from_config: &processor::DeadMetric) -> Result<Self, Error> {
+        Ok(DeadMetric {
+            selector: Selector::new(from_config.name.as_deref(), &from_config.types)?,
+            window: Duration::from_secs(from_config.window as u64),
+            missing_windows: from_config.missing_windows as u64,
+            capacity: from_config.capacity,
+            absence_name: from_config
+                .absence_name
+                .as_deref()
+                .unwrap_or(DEFAULT_ABSENCE_NAME)
+                .as_bytes()
+                .to_vec(),
+            watched: Mutex::new(Watched {
+                last_seen: HashMap::new(),
+                window: 0,
+                window_start: Instant::now(),
+            }),
+            absence_route: from_config.absence_route.clone(),
+            route: from_config.route.clone(),
+            counter_missing: scope.counter("missing").unwrap(),
+            counter_unwatched: scope.counter("unwatched").unwrap(),
+            gauge_watched: scope.gauge("watched").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    /// The counter reporting a missing metric. Its `name` tag replaces one
+    /// of the metric's own.
+    fn absence(&self, id: Id) -> Event {
+        let mut tags = Vec::with_capacity(id.tags.len() + 1);
+        tags.push(Tag {
+            name: b"name".to_vec(),
+            value: id.name,
+        });
+        tags.extend(id.tags.into_iter().filter(|tag| tag.name != b"name"));
+        Event::Parsed(Owned::new(
+            Id {
+                name: self.absence_name.clone(),
+                mtype: Type::Counter,
+                tags,
+            },
+            1_f64,
+            None,
+        ))
+    }
+
+    /// Move to the window `now` is in, returning the counters reporting the
+    /// metrics which have become missing
+    fn advance(&self, now: Instant) -> Vec<Event> {
+        let mut watched = self.watched.lock();
+        while now.duration_since(watched.window_start) >= self.window {
+            watched.window += 1;
+            watched.window_start += self.window;
+        }
+        // Metrics are missing once they were not seen in any of the last
+        // missing_windows whole windows
+        let cutoff = watched.window.saturating_sub(self.missing_windows);
+        let mut missing = Vec::new();
+        watched.last_seen.retain(|id, last_seen| {
+            if *last_seen < cutoff {
+                missing.push(id.clone());
+                false
+            } else {
+                true
+            }
+        });
+        self.gauge_watched.set(watched.last_seen.len() as f64);
+        drop(watched);
+
+        self.counter_missing.inc_by(missing.len() as f64);
+        missing.into_iter().map(|id| self.absence(id)).collect()
+    }
+}
+
+impl Processor for DeadMetric {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let output = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let (name, mtype) = name_and_type(event);
+        if !self.selector.matches(name, mtype) {
+            return Some(output);
+        }
+        let id = match event {
+            Event::Pdu(pdu) => match Id::try_from(pdu) {
+                Ok(id) => id,
+                Err(_) => {
+                    self.counter_processing_errors.inc();
+                    return Some(output);
+                }
+            },
+            Event::Parsed(parsed) => parsed.id().clone(),
+        };
+
+        let mut watched = self.watched.lock();
+        let window = watched.window;
+        if let Some(last_seen) = watched.last_seen.get_mut(&id) {
+            *last_seen = window;
+        } else if watched.last_seen.len() < self.capacity {
+            watched.last_seen.insert(id, window);
+        } else {
+            self.counter_unwatched.inc();
+        }
+        Some(output)
+    }
+
+    fn tick(&self, _time: std::time::SystemTime, backends: &Backends) {
+        let missing = self.advance(Instant::now());
+        if !missing.is_empty() {
+            backends.provide_statsd_slice(&missing, self.absence_route.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use crate::statsd_proto::Pdu;
+
+    fn dead_metric(capacity: usize) -> DeadMetric {
+        DeadMetric::new(
+            stats::Collector::default().scope("d"),
+            &processor::DeadMetric {
+                name: Some("^agent\\.".to_owned()),
+                types: vec![],
+                window: 10,
+                missing_windows: 2,
+                capacity,
+                absence_name: None,
+                absence_route: vec![],
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    fn lines(events: &[Event]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| String::from_utf8(Pdu::from(event).as_bytes().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn report_missing_metrics() {
+        let dead = dead_metric(10);
+        let start = dead.watched.lock().window_start;
+        let after = |seconds| start + Duration::from_secs(seconds);
+
+        dead.provide_statsd(&event("agent.up:1|g|#host:a"));
+        dead.provide_statsd(&event("agent.up:1|g|#host:b"));
+        dead.provide_statsd(&event("other:1|g"));
+        assert!(dead.advance(after(15)).is_empty());
+
+        // host:a keeps reporting, host:b is missing for windows 1 and 2
+        dead.provide_statsd(&event("agent.up:1|g|#host:a"));
+        assert!(dead.advance(after(25)).is_empty());
+        dead.provide_statsd(&event("agent.up:1|g|#host:a"));
+        assert_eq!(
+            lines(&dead.advance(after(30))),
+            vec!["metric.missing:1.0|c|#name:agent.up,host:b"]
+        );
+        // Reported metrics are forgotten
+        assert_eq!(dead.advance(after(70)).len(), 1);
+        assert!(dead.advance(after(80)).is_empty());
+        assert_eq!(dead.counter_missing.get(), 2_f64);
+
+        dead.provide_statsd(&event("agent.up:1|g|#name:web,host:a"));
+        assert_eq!(
+            lines(&dead.advance(after(120))),
+            vec!["metric.missing:1.0|c|#name:agent.up,host:a"]
+        );
+    }
+
+    #[test]
+    fn capacity() {
+        let dead = dead_metric(1);
+        dead.provide_statsd(&event("agent.up:1|g|#host:a"));
+        dead.provide_statsd(&event("agent.up:1|g|#host:b"));
+        dead.provide_statsd(&event("agent.up:1|g|#host:a"));
+        assert_eq!(dead.counter_unwatched.get(), 1_f64);
+        assert_eq!(dead.watched.lock().last_seen.len(), 1);
+    }
+}
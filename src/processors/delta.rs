@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use regex::bytes::Regex;
+use smallvec::smallvec;
+
+use super::{parse, processing_errors, Error, Output, Processor};
+use crate::backends::Backends;
+use crate::config::processor::{self, DeltaOutput};
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Type};
+
+const DEFAULT_EXPIRE: Duration = Duration::from_secs(600);
+
+/// The last value seen of a total, and when
+struct Last {
+    value: f64,
+    at: Instant,
+}
+
+/// Converts gauges holding ever increasing totals, such as those scraped
+/// from other sources, into counters of their increase each time they are
+/// reported, or of their increase per second.
+pub struct Delta {
+    filter: Option<Regex>,
+    output: DeltaOutput,
+    expire: Duration,
+    totals: Mutex<HashMap<Id, Last>>,
+    route: Vec<Route>,
+
+    counter_resets: stats::Counter,
+    gauge_tracked: stats::Gauge,
+    counter_processing_errors: stats::Counter,
+}
+
+impl Delta {
+    pub fn new(scope: stats::Scope, from_config: &processor::Delta) -> Result<Self, Error> {
+        Ok(Delta {
+            filter: from_config.filter.as_deref().map(Regex::new).transpose()?,
+            output: from_config.output,
+            expire: from_config
+                .expire_seconds
+                .map_or(DEFAULT_EXPIRE, Duration::from_secs),
+            totals: Mutex::new(HashMap::new()),
+            route: from_config.route.clone(),
+            counter_resets: scope.counter("resets").unwrap(),
+            gauge_tracked: scope.gauge("tracked").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    /// Record the latest value of a total, returning the value of the
+    /// counter to emit for it, if any. The first value of a total only sets
+    /// where it starts from. A total lower than its last value is taken to
+    /// have restarted from zero.
+    fn record(&self, id: &Id, value: f64, now: Instant) -> Option<f64> {
+        let mut totals = self.totals.lock();
+        let last = match totals.get_mut(id) {
+            Some(last) => last,
+            None => {
+                totals.insert(id.clone(), Last { value, at: now });
+                return None;
+            }
+        };
+        let elapsed = now.saturating_duration_since(last.at).as_secs_f64();
+        if self.output == DeltaOutput::Rate && elapsed <= 0_f64 {
+            return None;
+        }
+        let delta = if value < last.value {
+            self.counter_resets.inc();
+            value
+        } else {
+            value - last.value
+        };
+        *last = Last { value, at: now };
+        match self.output {
+            DeltaOutput::Delta => Some(delta),
+            DeltaOutput::Rate => Some(delta / elapsed),
+        }
+    }
+
+    fn expire(&self, now: Instant) {
+        let mut totals = self.totals.lock();
+        totals.retain(|_, last| now.saturating_duration_since(last.at) < self.expire);
+        self.gauge_tracked.set(totals.len() as f64);
+    }
+}
+
+impl Processor for Delta {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let (name, mtype) = match event {
+            Event::Pdu(pdu) => (pdu.name(), Type::try_from(pdu.pdu_type()).ok()),
+            Event::Parsed(parsed) => (parsed.name(), Some(*parsed.metric_type())),
+        };
+        if mtype != Some(Type::Gauge)
+            || !self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.is_match(name))
+        {
+            return Some(Output {
+                new_events: None,
+                route: self.route.as_ref(),
+            });
+        }
+        let owned = parse(event, &self.counter_processing_errors)?;
+        let value = self.record(owned.id(), owned.value(), Instant::now())?;
+        let id = Id {
+            name: owned.name().to_vec(),
+            mtype: Type::Counter,
+            tags: owned.tags().to_vec(),
+        };
+        Some(Output {
+            new_events: Some(smallvec![Event::Parsed(Owned::new(id, value, None))]),
+            route: self.route.as_ref(),
+        })
+    }
+
+    fn tick(&self, _time: std::time::SystemTime, _backends: &Backends) {
+        self.expire(Instant::now());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use std::convert::TryInto;
+
+    fn delta(output: DeltaOutput) -> Delta {
+        let sink = stats::Collector::default();
+        Delta::new(
+            sink.scope("d"),
+            &processor::Delta {
+                filter: Some(r"_total$".to_owned()),
+                output,
+                expire_seconds: None,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn convert_totals() {
+        let delta = delta(DeltaOutput::Delta);
+        let converted = |line: &'static str| {
+            let event = event(line);
+            delta.provide_statsd(&event).map(|output| {
+                output.new_events.map(|events| {
+                    let owned: Owned = (&events[0]).try_into().unwrap();
+                    assert_eq!(*owned.metric_type(), Type::Counter);
+                    owned.value()
+                })
+            })
+        };
+        // The first value is only remembered
+        assert_eq!(converted("bytes_total:100|g"), None);
+        assert_eq!(converted("bytes_total:150|g"), Some(Some(50_f64)));
+        assert_eq!(converted("bytes_total:150|g"), Some(Some(0_f64)));
+        // Restarted from zero
+        assert_eq!(converted("bytes_total:20|g"), Some(Some(20_f64)));
+        assert_eq!(delta.counter_resets.get(), 1_f64);
+        // Other metrics pass unchanged
+        assert_eq!(converted("queue:20|g"), Some(None));
+        assert_eq!(converted("bytes_total:20|c"), Some(None));
+    }
+
+    #[test]
+    fn convert_rates() {
+        let delta = delta(DeltaOutput::Rate);
+        let id = Id {
+            name: b"bytes_total".to_vec(),
+            mtype: Type::Gauge,
+            tags: vec![],
+        };
+        let start = Instant::now();
+        assert_eq!(delta.record(&id, 100_f64, start), None);
+        assert_eq!(delta.record(&id, 100_f64, start), None);
+        assert_eq!(
+            delta.record(&id, 300_f64, start + Duration::from_secs(10)),
+            Some(20_f64)
+        );
+
+        delta.expire(start + Duration::from_secs(10) + DEFAULT_EXPIRE);
+        assert_eq!(delta.gauge_tracked.get(), 0_f64);
+    }
+}
@@ -0,0 +1,369 @@
+//! Filter expressions matching metrics by name, type and tags, so processors
+//! can take match criteria which would otherwise need several processors
+//! chained together. For example:
+//!
+//! ```text
+//! name =~ "^api\." && tags.env == "prod" && type == counter
+//! ```
+//!
+//! `name`, `type` and `tags.<key>` are compared with `==` and `!=`, and
+//! `name` and `tags.<key>` matched against regexes with `=~` and `!~`. A
+//! tag comparison holds when any tag of the key has a matching value, and
+//! `tags.<key>` on its own holds when the metric has a tag of that key.
+//! Comparisons are combined with `&&`, `||`, `!` and parentheses, `&&`
+//! binding tighter than `||`. Values are quoted strings, or bare words such
+//! as `counter`. Expressions are compiled when processors are loaded.
+use std::convert::TryInto;
+
+use regex::bytes::Regex;
+
+use super::{name_and_type, Error};
+use crate::statsd_proto::{Event, Owned, Parsed, Tag, Type};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Eq,
+    Ne,
+    Match,
+    NotMatch,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => value.push(c),
+                            // Other escapes are kept for regexes, such as \.
+                            Some(c) => {
+                                value.push('\\');
+                                value.push(c);
+                            }
+                            None => return Err("unterminated string".to_owned()),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated string".to_owned()),
+                    }
+                }
+                Token::Str(value)
+            }
+            '=' | '!' | '&' | '|' => {
+                let next = chars.peek().copied();
+                let token = match (c, next) {
+                    ('=', Some('=')) => Token::Eq,
+                    ('=', Some('~')) => Token::Match,
+                    ('!', Some('=')) => Token::Ne,
+                    ('!', Some('~')) => Token::NotMatch,
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    ('!', _) => {
+                        tokens.push(Token::Not);
+                        continue;
+                    }
+                    _ => return Err(format!("unexpected {:?}", c)),
+                };
+                chars.next();
+                token
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_' || c == '.' || c == '-') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+            c => return Err(format!("unexpected {:?}", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Field {
+    Name,
+    Type,
+    Tag(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum Value {
+    Bytes(Vec<u8>),
+    Regex(Regex),
+    Type(Type),
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// A comparison, negated for `!=` and `!~`
+    Compare {
+        field: Field,
+        value: Value,
+        negated: bool,
+    },
+    HasTag(Vec<u8>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("expected )".to_owned()),
+                }
+            }
+            Some(Token::Word(word)) => self.comparison(&word),
+            token => Err(format!("expected a comparison, found {:?}", token)),
+        }
+    }
+
+    fn comparison(&mut self, field: &str) -> Result<Expr, String> {
+        let field = match field {
+            "name" => Field::Name,
+            "type" => Field::Type,
+            _ => match field.strip_prefix("tags.") {
+                Some(key) if !key.is_empty() => Field::Tag(key.as_bytes().to_vec()),
+                _ => return Err(format!("unknown field {}", field)),
+            },
+        };
+        let (regex, negated) = match self.peek() {
+            Some(Token::Eq) => (false, false),
+            Some(Token::Ne) => (false, true),
+            Some(Token::Match) => (true, false),
+            Some(Token::NotMatch) => (true, true),
+            _ => {
+                return match field {
+                    Field::Tag(key) => Ok(Expr::HasTag(key)),
+                    _ => Err("expected a comparison operator".to_owned()),
+                }
+            }
+        };
+        self.next();
+        let value = match self.next() {
+            Some(Token::Word(value)) | Some(Token::Str(value)) => value,
+            token => return Err(format!("expected a value, found {:?}", token)),
+        };
+        let value = match (&field, regex) {
+            (Field::Type, true) => return Err("types can't be matched by regex".to_owned()),
+            (Field::Type, false) => Value::Type(
+                Type::from_name(&value).ok_or_else(|| format!("unknown metric type {}", value))?,
+            ),
+            (_, true) => Value::Regex(Regex::new(&value).map_err(|e| e.to_string())?),
+            (_, false) => Value::Bytes(value.into_bytes()),
+        };
+        Ok(Expr::Compare {
+            field,
+            value,
+            negated,
+        })
+    }
+}
+
+fn compare(value: &Value, actual: &[u8]) -> bool {
+    match value {
+        Value::Bytes(bytes) => actual == bytes.as_slice(),
+        Value::Regex(regex) => regex.is_match(actual),
+        Value::Type(_) => false,
+    }
+}
+
+impl Expr {
+    fn needs_tags(&self) -> bool {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => a.needs_tags() || b.needs_tags(),
+            Expr::Not(expr) => expr.needs_tags(),
+            Expr::Compare { field, .. } => matches!(field, Field::Tag(_)),
+            Expr::HasTag(_) => true,
+        }
+    }
+
+    fn matches(&self, name: &[u8], mtype: Option<Type>, tags: &[Tag]) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(name, mtype, tags) && b.matches(name, mtype, tags),
+            Expr::Or(a, b) => a.matches(name, mtype, tags) || b.matches(name, mtype, tags),
+            Expr::Not(expr) => !expr.matches(name, mtype, tags),
+            Expr::HasTag(key) => tags.iter().any(|tag| tag.name == *key),
+            Expr::Compare {
+                field,
+                value,
+                negated,
+            } => {
+                let matched = match (field, value) {
+                    (Field::Name, _) => compare(value, name),
+                    (Field::Type, Value::Type(expected)) => mtype == Some(*expected),
+                    (Field::Type, _) => false,
+                    (Field::Tag(key), _) => tags
+                        .iter()
+                        .any(|tag| tag.name == *key && compare(value, &tag.value)),
+                };
+                matched != *negated
+            }
+        }
+    }
+}
+
+/// A compiled filter expression
+#[derive(Debug)]
+pub(crate) struct Filter {
+    expr: Expr,
+    needs_tags: bool,
+}
+
+impl Filter {
+    pub(crate) fn new(source: &str) -> Result<Self, Error> {
+        let invalid = |e: String| Error::InvalidConfig(format!("filter {:?}: {}", source, e));
+        let mut parser = Parser {
+            tokens: tokenize(source).map_err(invalid)?,
+            position: 0,
+        };
+        let expr = parser.or().map_err(invalid)?;
+        if let Some(token) = parser.peek() {
+            return Err(invalid(format!("unexpected {:?}", token)));
+        }
+        Ok(Filter {
+            needs_tags: expr.needs_tags(),
+            expr,
+        })
+    }
+
+    /// Whether matching needs the tags of metrics, which lines must be
+    /// parsed for
+    pub(crate) fn needs_tags(&self) -> bool {
+        self.needs_tags
+    }
+
+    pub(crate) fn matches(&self, name: &[u8], mtype: Option<Type>, tags: &[Tag]) -> bool {
+        self.expr.matches(name, mtype, tags)
+    }
+
+    /// Match an event, parsing lines for their tags only when needed
+    pub(crate) fn matches_event(&self, event: &Event) -> bool {
+        let (name, mtype) = name_and_type(event);
+        let owned: Option<Owned> = match event {
+            Event::Pdu(pdu) if self.needs_tags && pdu.tags().is_some() => event.try_into().ok(),
+            _ => None,
+        };
+        let tags = match (event, owned.as_ref()) {
+            (Event::Parsed(parsed), _) => parsed.tags(),
+            (_, Some(owned)) => owned.tags(),
+            _ => &[],
+        };
+        self.matches(name, mtype, tags)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+
+    fn matches(filter: &str, line: &'static str) -> bool {
+        let event = event(line);
+        Filter::new(filter).unwrap().matches_event(&event)
+    }
+
+    #[test]
+    fn match_expressions() {
+        let filter = r#"name =~ "^api\." && tags.env == "prod" && type == counter"#;
+        assert!(matches(filter, "api.requests:1|c|#env:prod"));
+        assert!(!matches(filter, "api.requests:1|c|#env:dev"));
+        assert!(!matches(filter, "api.latency:1|ms|#env:prod"));
+        assert!(!matches(filter, "apix.requests:1|c|#env:prod"));
+
+        let filter = "!(type == timer || tags.debug) && name != heartbeat";
+        assert!(matches(filter, "requests:1|c|#env:prod"));
+        assert!(!matches(filter, "latency:1|ms"));
+        assert!(!matches(filter, "requests:1|c|#debug"));
+        assert!(!matches(filter, "heartbeat:1|g"));
+
+        let filter = r#"tags.host !~ "^canary-" || name == "a.b""#;
+        assert!(matches(filter, "x:1|c|#host:web-1"));
+        assert!(matches(filter, "a.b:1|c|#host:canary-1"));
+        assert!(!matches(filter, "x:1|c|#host:canary-1"));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for filter in [
+            "",
+            "name ==",
+            "name == a &&",
+            "(name == a",
+            "name == a)",
+            "host == a",
+            "type == histogram",
+            "type =~ c",
+            "name =~ \"(\"",
+            "name = a",
+            "tags.env == \"prod",
+        ] {
+            assert!(
+                Filter::new(filter).is_err(),
+                "{} should not compile",
+                filter
+            );
+        }
+        assert!(!Filter::new("name == a").unwrap().needs_tags());
+        assert!(Filter::new("name == a || !tags.env").unwrap().needs_tags());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::{parse, processing_errors, Output, Processor};
+use crate::backends::Backends;
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Parsed, Type};
+
+/// The last value passed on for a gauge, and when
+struct Emitted {
+    value: f64,
+    at: Instant,
+}
+
+/// Suppresses gauges repeating the value they last had, passing them on
+/// when their value changes or once a heartbeat interval has passed.
+pub struct GaugeDedup {
+    heartbeat: Duration,
+    gauges: Mutex<HashMap<Id, Emitted>>,
+    route: Vec<Route>,
+
+    counter_suppressed: stats::Counter,
+    gauge_tracked: stats::Gauge,
+    counter_processing_errors: stats::Counter,
+}
+
+impl GaugeDedup {
+    pub fn new(scope: stats::Scope, from_config: &processor::GaugeDedup) -> Self {
+        GaugeDedup {
+            heartbeat: Duration::from_secs(from_config.heartbeat_seconds),
+            gauges: Mutex::new(HashMap::new()),
+            route: from_config.route.clone(),
+            counter_suppressed: scope.counter("suppressed").unwrap(),
+            gauge_tracked: scope.gauge("tracked").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        }
+    }
+
+    /// Record a gauge value, returning whether it should be passed on
+    fn record(&self, id: Id, value: f64, now: Instant) -> bool {
+        let mut gauges = self.gauges.lock();
+        match gauges.get_mut(&id) {
+            Some(emitted)
+                if emitted.value.to_bits() == value.to_bits()
+                    && now.duration_since(emitted.at) < self.heartbeat =>
+            {
+                false
+            }
+            Some(emitted) => {
+                *emitted = Emitted { value, at: now };
+                true
+            }
+            None => {
+                gauges.insert(id, Emitted { value, at: now });
+                true
+            }
+        }
+    }
+
+    /// Forget the last value of a gauge, as a change to it has passed
+    fn forget(&self, id: &Id) {
+        self.gauges.lock().remove(id);
+    }
+
+    /// Forget gauges last passed on a heartbeat ago, as their next value is
+    /// passed on regardless
+    fn expire(&self, now: Instant) {
+        let mut gauges = self.gauges.lock();
+        gauges.retain(|_, emitted| now.duration_since(emitted.at) < self.heartbeat);
+        self.gauge_tracked.set(gauges.len() as f64);
+    }
+}
+
+impl Processor for GaugeDedup {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let output = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let (id, value) = match event {
+            Event::Pdu(pdu) => {
+                if Type::try_from(pdu.pdu_type()).ok() != Some(Type::Gauge) {
+                    return Some(output);
+                }
+                // Signed gauge values adjust the last value rather than
+                // setting it, so repeating one is not a repeat, and the
+                // value downstream is no longer the one last passed on
+                if pdu.value().starts_with(b"+") || pdu.value().starts_with(b"-") {
+                    match Id::try_from(pdu) {
+                        Ok(id) => self.forget(&id),
+                        Err(_) => self.counter_processing_errors.inc(),
+                    }
+                    return Some(output);
+                }
+                let owned = parse(event, &self.counter_processing_errors)?;
+                (owned.id().clone(), owned.value())
+            }
+            Event::Parsed(parsed) if *parsed.metric_type() == Type::Gauge => {
+                (parsed.id().clone(), parsed.value())
+            }
+            Event::Parsed(_) => return Some(output),
+        };
+        if !self.record(id, value, Instant::now()) {
+            self.counter_suppressed.inc();
+            return None;
+        }
+        Some(output)
+    }
+
+    fn tick(&self, _time: std::time::SystemTime, _backends: &Backends) {
+        self.expire(Instant::now());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+
+    #[test]
+    fn suppress_repeated_gauges() {
+        let sink = stats::Collector::default();
+        let dedup = GaugeDedup::new(
+            sink.scope("d"),
+            &processor::GaugeDedup {
+                heartbeat_seconds: 60,
+                route: vec![],
+            },
+        );
+        assert!(dedup.provide_statsd(&event("queue:5|g")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:5|g")).is_none());
+        assert!(dedup.provide_statsd(&event("queue:5|g|#host:a")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:6|g")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:6|g")).is_none());
+        // Other types and signed gauges always pass
+        assert!(dedup.provide_statsd(&event("queue:6|c")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:6|c")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:+1|g")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:+1|g")).is_some());
+        assert_eq!(dedup.counter_suppressed.get(), 2_f64);
+    }
+
+    #[test]
+    fn absolute_after_change() {
+        let sink = stats::Collector::default();
+        let dedup = GaugeDedup::new(
+            sink.scope("d"),
+            &processor::GaugeDedup {
+                heartbeat_seconds: 60,
+                route: vec![],
+            },
+        );
+        assert!(dedup.provide_statsd(&event("queue:5|g|#host:a")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:-2|g|#host:a")).is_some());
+        // Downstream the gauge is 3, so setting it back to 5 is a change
+        assert!(dedup.provide_statsd(&event("queue:5|g|#host:a")).is_some());
+        assert!(dedup.provide_statsd(&event("queue:5|g|#host:a")).is_none());
+    }
+
+    #[test]
+    fn heartbeat() {
+        let sink = stats::Collector::default();
+        let dedup = GaugeDedup::new(
+            sink.scope("d"),
+            &processor::GaugeDedup {
+                heartbeat_seconds: 60,
+                route: vec![],
+            },
+        );
+        let id = |name: &str| Id {
+            name: name.as_bytes().to_vec(),
+            mtype: Type::Gauge,
+            tags: vec![],
+        };
+        let start = Instant::now();
+        assert!(dedup.record(id("a"), 1_f64, start));
+        assert!(dedup.record(id("b"), 1_f64, start + Duration::from_secs(30)));
+        assert!(!dedup.record(id("a"), 1_f64, start + Duration::from_secs(59)));
+        assert!(dedup.record(id("a"), 1_f64, start + Duration::from_secs(60)));
+
+        dedup.expire(start + Duration::from_secs(100));
+        assert_eq!(dedup.gauge_tracked.get(), 1_f64);
+    }
+}
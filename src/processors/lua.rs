@@ -0,0 +1,342 @@
+//! A processor running a Lua script over each metric, for quick transforms
+//! without changing statsrelay. Built with the `lua` cargo feature.
+//!
+//! The script defines a `process(metric)` function, called with a table of
+//! the metric's `name`, `type` (such as `counter`), `value`, `sample_rate`
+//! (or nil) and `tags` (a table of tag values by name). Tags are sent on
+//! sorted by name, and only the last value of a repeated tag is kept.
+//!
+//! `process` returns the table, modified or not, to send the metric on, or
+//! nil to drop it. Scripts can also call:
+//!
+//! - `emit(metric)` to send on another metric table, along with the result
+//!   of `process`.
+//! - `route(name)` to send this call's metrics to the processor's route of
+//!   that name instead of its default route.
+//!
+//! Scripts only have the base, `string`, `table`, `math` and `utf8`
+//! libraries, without `dofile`, `loadfile` or `require`, and each call may
+//! run a limited number of instructions. Metrics are passed on unchanged
+//! when a script fails or runs out of instructions, and counted as
+//! processing errors.
+use std::collections::HashMap;
+
+use log::warn;
+use mlua::{Function, HookTriggers, LuaOptions, RegistryKey, StdLib, Table};
+use parking_lot::Mutex;
+use smallvec::SmallVec;
+
+use super::{parse, processing_errors, Error, Output, Processor};
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Tag, Type};
+
+/// Instructions each call may run, unless configured
+const DEFAULT_MAX_INSTRUCTIONS: u32 = 1_000_000;
+
+/// Metrics emitted and the route chosen by the current call of `process`
+#[derive(Default)]
+struct CallState {
+    emitted: Vec<Owned>,
+    route: Option<String>,
+}
+
+fn to_table<'lua>(lua: &'lua mlua::Lua, metric: &Owned) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("name", lua.create_string(metric.name())?)?;
+    table.set("type", metric.metric_type().name())?;
+    table.set("value", metric.value())?;
+    table.set("sample_rate", metric.sample_rate())?;
+    let tags = lua.create_table()?;
+    for tag in metric.tags() {
+        tags.set(
+            lua.create_string(&tag.name)?,
+            lua.create_string(&tag.value)?,
+        )?;
+    }
+    table.set("tags", tags)?;
+    Ok(table)
+}
+
+fn from_table(table: &Table<'_>) -> mlua::Result<Owned> {
+    let name = table.get::<_, mlua::String>("name")?.as_bytes().to_vec();
+    let type_name = table.get::<_, mlua::String>("type")?;
+    let mtype = Type::from_name(type_name.to_str()?).ok_or_else(|| {
+        mlua::Error::RuntimeError(format!("unknown metric type {:?}", type_name.to_str()))
+    })?;
+    let value: f64 = table.get("value")?;
+    if !value.is_finite() {
+        return Err(mlua::Error::RuntimeError("value is not finite".to_owned()));
+    }
+    let sample_rate: Option<f64> = table.get("sample_rate")?;
+    let mut tags = Vec::new();
+    if let Some(table) = table.get::<_, Option<Table>>("tags")? {
+        for pair in table.pairs::<mlua::String, mlua::String>() {
+            let (name, value) = pair?;
+            tags.push(Tag {
+                name: name.as_bytes().to_vec(),
+                value: value.as_bytes().to_vec(),
+            });
+        }
+    }
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Owned::new(Id { name, mtype, tags }, value, sample_rate))
+}
+
+/// Stop the script once it runs `max_instructions` more instructions.
+/// Setting the hook restarts its count.
+fn set_limit(lua: &mlua::Lua, max_instructions: u32) {
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(max_instructions),
+        |_, _| {
+            Err(mlua::Error::RuntimeError(
+                "instruction limit reached".to_owned(),
+            ))
+        },
+    );
+}
+
+pub struct Lua {
+    lua: Mutex<mlua::Lua>,
+    process: RegistryKey,
+    routes: HashMap<String, Vec<Route>>,
+    route: Vec<Route>,
+    max_instructions: u32,
+
+    counter_processing_errors: stats::Counter,
+}
+
+impl Lua {
+    pub fn new(scope: stats::Scope, from_config: &processor::Lua) -> Result<Self, Error> {
+        let invalid =
+            |e: String| Error::InvalidConfig(format!("lua script {}: {}", from_config.script, e));
+        let source = std::fs::read(&from_config.script).map_err(|e| invalid(e.to_string()))?;
+
+        let lua = mlua::Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )
+        .map_err(|e| invalid(e.to_string()))?;
+        let max_instructions = from_config
+            .max_instructions
+            .unwrap_or(DEFAULT_MAX_INSTRUCTIONS);
+        let process = (|| -> mlua::Result<RegistryKey> {
+            let globals = lua.globals();
+            globals.set("dofile", mlua::Nil)?;
+            globals.set("loadfile", mlua::Nil)?;
+            globals.set(
+                "emit",
+                lua.create_function(|lua, table: Table| {
+                    let metric = from_table(&table)?;
+                    if let Some(mut state) = lua.app_data_mut::<CallState>() {
+                        state.emitted.push(metric);
+                    }
+                    Ok(())
+                })?,
+            )?;
+            let names: Vec<String> = from_config.routes.keys().cloned().collect();
+            globals.set(
+                "route",
+                lua.create_function(move |lua, name: String| {
+                    if !names.contains(&name) {
+                        return Err(mlua::Error::RuntimeError(format!("unknown route {}", name)));
+                    }
+                    if let Some(mut state) = lua.app_data_mut::<CallState>() {
+                        state.route = Some(name);
+                    }
+                    Ok(())
+                })?,
+            )?;
+            set_limit(&lua, max_instructions);
+            lua.load(&source).set_name(&from_config.script).exec()?;
+            let process: Function = globals.get("process")?;
+            lua.create_registry_value(process)
+        })()
+        .map_err(|e| invalid(e.to_string()))?;
+
+        Ok(Lua {
+            lua: Mutex::new(lua),
+            process,
+            routes: from_config.routes.clone(),
+            route: from_config.route.clone(),
+            max_instructions,
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    /// Run the script over a metric, returning the metrics to send on and
+    /// the name of the route chosen, if any
+    fn call(&self, metric: &Owned) -> mlua::Result<(Vec<Owned>, Option<String>)> {
+        let lua = self.lua.lock();
+        set_limit(&lua, self.max_instructions);
+        lua.set_app_data(CallState::default());
+        let result = (|| {
+            let process: Function = lua.registry_value(&self.process)?;
+            process.call::<_, Option<Table>>(to_table(&lua, metric)?)
+        })()
+        .and_then(|result| result.as_ref().map(from_table).transpose());
+        let state = lua.remove_app_data::<CallState>().unwrap_or_default();
+
+        let mut metrics = Vec::with_capacity(state.emitted.len() + 1);
+        metrics.extend(result?);
+        metrics.extend(state.emitted);
+        Ok((metrics, state.route))
+    }
+}
+
+impl Processor for Lua {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let unchanged = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let metric = match parse(event, &self.counter_processing_errors) {
+            Some(metric) => metric,
+            None => return Some(unchanged),
+        };
+        let (metrics, route) = match self.call(&metric) {
+            Ok(called) => called,
+            Err(e) => {
+                if (self.counter_processing_errors.get() as u64).is_multiple_of(1000) {
+                    warn!("lua script failed: {}", e);
+                }
+                self.counter_processing_errors.inc();
+                return Some(unchanged);
+            }
+        };
+        if metrics.is_empty() {
+            return None;
+        }
+        let route = match route {
+            Some(name) => self.routes[&name].as_ref(),
+            None => self.route.as_ref(),
+        };
+        Some(Output {
+            new_events: Some(
+                metrics
+                    .into_iter()
+                    .map(Event::Parsed)
+                    .collect::<SmallVec<_>>(),
+            ),
+            route,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::processors::test::event;
+    use crate::statsd_proto::Pdu;
+
+    fn lua(script: &str) -> (tempfile::TempDir, Lua) {
+        lua_limited(script, None)
+    }
+
+    fn lua_limited(script: &str, max_instructions: Option<u32>) -> (tempfile::TempDir, Lua) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.lua");
+        std::fs::write(&path, script).unwrap();
+        let config = processor::Lua {
+            script: path.to_str().unwrap().to_owned(),
+            max_instructions,
+            routes: vec![(
+                "slow".to_owned(),
+                vec![Route {
+                    route_type: crate::config::RouteType::Processor,
+                    route_to: "slow".to_owned(),
+                }],
+            )]
+            .into_iter()
+            .collect(),
+            route: vec![],
+        };
+        let scope = crate::stats::Collector::default().scope("lua");
+        (dir, Lua::new(scope, &config).unwrap())
+    }
+
+    fn lines(output: &Output<'_>) -> Vec<String> {
+        output
+            .new_events
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|event| String::from_utf8(Pdu::from(event).as_bytes().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn modify_drop_and_emit() {
+        let (_dir, lua) = lua(r#"
+            function process(metric)
+                if metric.name == "drop.me" then
+                    return nil
+                end
+                if metric.type == "timer" then
+                    route("slow")
+                end
+                metric.name = "renamed." .. metric.name
+                metric.tags.env = nil
+                metric.tags.team = "core"
+                emit({name = "copies", type = "counter", value = 1})
+                return metric
+            end
+        "#);
+
+        assert!(lua.provide_statsd(&event("drop.me:1|c")).is_none());
+
+        let output = lua
+            .provide_statsd(&event("requests:2|c|#env:prod,host:a"))
+            .unwrap();
+        assert_eq!(
+            lines(&output),
+            vec!["renamed.requests:2.0|c|#host:a,team:core", "copies:1.0|c"]
+        );
+        assert!(output.route.is_empty());
+
+        let output = lua.provide_statsd(&event("latency:5|ms")).unwrap();
+        assert_eq!(output.route[0].route_to, "slow");
+    }
+
+    #[test]
+    fn errors_pass_unchanged() {
+        let (_dir, lua) = lua(r#"
+            function process(metric)
+                metric.type = "histogram"
+                return metric
+            end
+        "#);
+        let output = lua.provide_statsd(&event("requests:2|c")).unwrap();
+        assert!(output.new_events.is_none());
+        assert_eq!(lua.counter_processing_errors.get(), 1_f64);
+    }
+
+    #[test]
+    fn sandboxed() {
+        let (_dir, lua) = lua_limited(
+            r#"
+            function process(metric)
+                if metric.name == "loop" then
+                    while true do end
+                end
+                if os ~= nil or io ~= nil or require ~= nil or dofile ~= nil then
+                    return nil
+                end
+                return metric
+            end
+        "#,
+            Some(10_000),
+        );
+        let output = lua.provide_statsd(&event("loop:1|c")).unwrap();
+        assert!(output.new_events.is_none());
+        assert_eq!(lua.counter_processing_errors.get(), 1_f64);
+
+        // The limit is per call, and the call above doesn't use this one's
+        for _ in 0..3 {
+            let output = lua.provide_statsd(&event("requests:2|c")).unwrap();
+            assert_eq!(lines(&output), vec!["requests:2.0|c"]);
+        }
+        assert_eq!(lua.counter_processing_errors.get(), 1_f64);
+    }
+}
@@ -1,12 +1,108 @@
 use super::backends::Backends;
 use crate::config;
-use crate::statsd_proto::Event;
+use crate::error::{Categorized, Category};
+use crate::stats;
+use crate::statsd_proto::{Event, Owned, Parsed, Type};
+use regex::bytes::Regex;
 use smallvec::SmallVec;
+use std::convert::{TryFrom, TryInto};
+use thiserror::Error;
 
+pub mod anomaly;
 pub mod cardinality;
+pub mod clamp;
+pub mod dead_metric;
+pub mod delta;
+pub mod filter;
+pub mod gauge_dedup;
+#[cfg(feature = "lua")]
+pub mod lua;
+pub mod normalize_name;
 pub mod regex_filter;
+pub mod rewrite;
+pub mod rollup;
+pub mod router;
+pub mod sample_rate;
 pub mod sampler;
+pub mod scale;
+pub mod set_union;
+pub mod splitter;
 pub mod tag;
+pub mod tag_filter;
+pub mod tag_obfuscator;
+pub mod tag_value_limit;
+pub mod throttle;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod worker_pool;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid processor configuration: {0}")]
+    InvalidConfig(String),
+    #[error("invalid regex in processor configuration: {0}")]
+    Regex(#[from] regex::Error),
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        Category::Config
+    }
+}
+
+/// The name of an event, and its type if it is valid
+pub(crate) fn name_and_type(event: &Event) -> (&[u8], Option<Type>) {
+    match event {
+        Event::Pdu(pdu) => (pdu.name(), Type::try_from(pdu.pdu_type()).ok()),
+        Event::Parsed(parsed) => (parsed.name(), Some(*parsed.metric_type())),
+    }
+}
+
+/// Selects metrics by a regex of their names and by their types, as the
+/// rules of several processors are configured. Either matches everything
+/// when not set.
+pub(crate) struct Selector {
+    name: Option<Regex>,
+    types: Vec<Type>,
+}
+
+impl Selector {
+    pub(crate) fn new(name: Option<&str>, types: &[String]) -> Result<Self, Error> {
+        let types = types
+            .iter()
+            .map(|mtype| {
+                Type::from_name(mtype)
+                    .ok_or_else(|| Error::InvalidConfig(format!("unknown metric type {}", mtype)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Selector {
+            name: name.map(Regex::new).transpose()?,
+            types,
+        })
+    }
+
+    pub(crate) fn matches(&self, name: &[u8], mtype: Option<Type>) -> bool {
+        self.name.as_ref().is_none_or(|regex| regex.is_match(name))
+            && (self.types.is_empty() || mtype.is_some_and(|mtype| self.types.contains(&mtype)))
+    }
+}
+
+/// The counter of events a processor could not process, such as events
+/// which failed to parse. Every processor has one, in its own scope.
+pub(crate) fn processing_errors(scope: &stats::Scope) -> stats::Counter {
+    scope.counter("processing_errors").unwrap()
+}
+
+/// Parse an event, counting it as a processing error if it fails to parse
+pub(crate) fn parse(event: &Event, processing_errors: &stats::Counter) -> Option<Owned> {
+    match event.try_into() {
+        Ok(owned) => Some(owned),
+        Err(_) => {
+            processing_errors.inc();
+            None
+        }
+    }
+}
 
 pub struct Output<'a> {
     /// Lists of new events returned if the processor has modified the
@@ -21,5 +117,161 @@ pub trait Processor {
     /// Backends structure is provided to re-inject messages into processor
     /// framework if desired.
     fn tick(&self, _time: std::time::SystemTime, _backends: &Backends) {}
+    /// Flush is called once on shutdown, after all listeners have stopped.
+    /// Processors holding buffered state should emit it immediately,
+    /// regardless of any window they would normally wait for.
+    fn flush(&self, _time: std::time::SystemTime, _backends: &Backends) {}
+    /// State of the processor for operators, served as JSON on the admin
+    /// server's `/processors` endpoint.
+    fn report(&self) -> Option<serde_json::Value> {
+        None
+    }
+    /// Whether the events this processor doesn't pass on are kept to emit
+    /// later, such as by aggregating them, rather than dropped.
+    fn aggregates(&self) -> bool {
+        false
+    }
     fn provide_statsd(&self, sample: &Event) -> Option<Output>;
 }
+
+/// A processor along with the counters kept for every processor in its
+/// scope, so a pipeline can be followed from `/metrics` alone: events given
+/// to it, events it passed on, counting each event it emitted in place of
+/// one, and events it dropped, or absorbed if it aggregates them. Processing
+/// errors are counted by the processor.
+pub struct Instrumented {
+    processor: Box<dyn Processor + Send + Sync>,
+
+    counter_events_in: stats::Counter,
+    counter_events_out: stats::Counter,
+    counter_events_dropped: stats::Counter,
+    counter_events_absorbed: stats::Counter,
+}
+
+impl Instrumented {
+    pub fn new(scope: &stats::Scope, processor: Box<dyn Processor + Send + Sync>) -> Self {
+        // Registered here so every processor has the counter, even those
+        // which can't fail
+        processing_errors(scope);
+        Instrumented {
+            processor,
+            counter_events_in: scope.counter("events_in").unwrap(),
+            counter_events_out: scope.counter("events_out").unwrap(),
+            counter_events_dropped: scope.counter("events_dropped").unwrap(),
+            counter_events_absorbed: scope.counter("events_absorbed").unwrap(),
+        }
+    }
+}
+
+impl Processor for Instrumented {
+    fn tick(&self, time: std::time::SystemTime, backends: &Backends) {
+        self.processor.tick(time, backends)
+    }
+
+    fn flush(&self, time: std::time::SystemTime, backends: &Backends) {
+        self.processor.flush(time, backends)
+    }
+
+    fn report(&self) -> Option<serde_json::Value> {
+        self.processor.report()
+    }
+
+    fn aggregates(&self) -> bool {
+        self.processor.aggregates()
+    }
+
+    fn provide_statsd(&self, sample: &Event) -> Option<Output<'_>> {
+        self.counter_events_in.inc();
+        let output = self.processor.provide_statsd(sample);
+        match &output {
+            Some(output) => self
+                .counter_events_out
+                .inc_by(output.new_events.as_ref().map_or(1, |events| events.len()) as f64),
+            None if self.processor.aggregates() => self.counter_events_absorbed.inc(),
+            None => self.counter_events_dropped.inc(),
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// A processor which records every event it is given, for asserting on
+    /// the output of other processors.
+    #[derive(Clone, Default)]
+    pub struct Capture {
+        pub events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl Processor for Capture {
+        fn provide_statsd(&self, sample: &Event) -> Option<Output<'_>> {
+            self.events.lock().push(sample.clone());
+            None
+        }
+    }
+
+    /// An event of a statsd line, as read from a socket
+    pub fn event(line: &'static str) -> Event {
+        Event::Pdu(
+            crate::statsd_proto::Pdu::parse(bytes::Bytes::from_static(line.as_bytes())).unwrap(),
+        )
+    }
+
+    /// An event of a statsd line already parsed, as output by a processor
+    pub fn parsed(line: &'static str) -> Event {
+        Event::Parsed(Owned::try_from(&event(line)).unwrap())
+    }
+
+    /// Build a Backends containing a single Capture processor, returning the
+    /// route to it.
+    pub fn capture_backends() -> (Backends, Capture, Vec<config::Route>) {
+        let backends = Backends::new(crate::stats::Collector::default().scope("test"));
+        let capture = Capture::default();
+        backends
+            .replace_processor("capture", Box::new(capture.clone()))
+            .unwrap();
+        let route = vec![config::Route {
+            route_type: config::RouteType::Processor,
+            route_to: "capture".to_owned(),
+        }];
+        (backends, capture, route)
+    }
+
+    #[test]
+    fn instrumented_counts() {
+        let scope = crate::stats::Collector::default().scope("tag");
+        let processor =
+            Instrumented::new(&scope, Box::new(tag::Normalizer::new(scope.clone(), &[])));
+        for line in ["a:1|c|#k:v", "b:2|c", "c:x|c"] {
+            processor.provide_statsd(&event(line));
+        }
+        assert_eq!(processor.counter_events_in.get(), 3_f64);
+        assert_eq!(processor.counter_events_out.get(), 2_f64);
+        assert_eq!(processor.counter_events_dropped.get(), 1_f64);
+        assert_eq!(processor.counter_events_absorbed.get(), 0_f64);
+        assert_eq!(processing_errors(&scope).get(), 1_f64);
+
+        let scope = crate::stats::Collector::default().scope("union");
+        let union = set_union::SetUnion::new(
+            scope.clone(),
+            &config::processor::SetUnion {
+                window: 10,
+                name: None,
+                max_members: 10,
+                route: vec![],
+            },
+        )
+        .unwrap();
+        let processor = Instrumented::new(&scope, Box::new(union));
+        for line in ["users:a|s", "users:b|s", "requests:1|c"] {
+            processor.provide_statsd(&event(line));
+        }
+        assert_eq!(processor.counter_events_out.get(), 1_f64);
+        assert_eq!(processor.counter_events_absorbed.get(), 2_f64);
+        assert_eq!(processor.counter_events_dropped.get(), 0_f64);
+    }
+}
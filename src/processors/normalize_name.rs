@@ -0,0 +1,158 @@
+use smallvec::smallvec;
+
+use super::{Error, Output, Processor};
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed};
+
+const DEFAULT_REPLACEMENT: char = '_';
+
+fn allowed(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'-' || c == b'.'
+}
+
+/// Cleans up metric names which sinks such as graphite and prometheus would
+/// reject or mangle: names are lowercased, characters other than ASCII
+/// letters and digits, `_`, `-` and `.` are replaced, and runs of dots are
+/// collapsed into one.
+pub struct NormalizeName {
+    lowercase: bool,
+    replacement: char,
+    route: Vec<Route>,
+
+    counter_normalized: stats::Counter,
+}
+
+impl NormalizeName {
+    pub fn new(scope: stats::Scope, from_config: &processor::NormalizeName) -> Result<Self, Error> {
+        Ok(NormalizeName {
+            lowercase: from_config.lowercase,
+            replacement: from_config.replacement.unwrap_or(DEFAULT_REPLACEMENT),
+            route: from_config.route.clone(),
+            counter_normalized: scope.counter("normalized").unwrap(),
+        })
+    }
+
+    fn is_normal(&self, name: &[u8]) -> bool {
+        name.iter()
+            .all(|c| allowed(*c) && !(self.lowercase && c.is_ascii_uppercase()))
+            && !name.windows(2).any(|pair| pair == b"..")
+    }
+
+    /// The normalized name, if it differs from the name. Each character of a
+    /// multi-byte UTF-8 sequence is replaced once.
+    fn normalize(&self, name: &[u8]) -> Option<Vec<u8>> {
+        if self.is_normal(name) {
+            return None;
+        }
+        let mut normalized = String::with_capacity(name.len());
+        for c in String::from_utf8_lossy(name).chars() {
+            if c == '.' && normalized.ends_with('.') {
+                continue;
+            }
+            if c.is_ascii() && allowed(c as u8) {
+                normalized.push(if self.lowercase {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                });
+            } else {
+                normalized.push(self.replacement);
+            }
+        }
+        Some(normalized.into_bytes())
+    }
+}
+
+impl Processor for NormalizeName {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let normalized = match event {
+            Event::Pdu(pdu) => self
+                .normalize(pdu.name())
+                .map(|name| Event::Pdu(pdu.with_name(&name))),
+            Event::Parsed(parsed) => self.normalize(parsed.name()).map(|name| {
+                let id = Id {
+                    name,
+                    mtype: *parsed.metric_type(),
+                    tags: parsed.tags().to_vec(),
+                };
+                Event::Parsed(Owned::new(id, parsed.value(), parsed.sample_rate()))
+            }),
+        };
+        if normalized.is_some() {
+            self.counter_normalized.inc();
+        }
+        Some(Output {
+            new_events: normalized.map(|event| smallvec![event]),
+            route: self.route.as_ref(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::{event, parsed};
+    use crate::statsd_proto::Pdu;
+
+    fn normalize_name(lowercase: bool) -> NormalizeName {
+        NormalizeName::new(
+            stats::Collector::default().scope("n"),
+            &processor::NormalizeName {
+                lowercase,
+                replacement: None,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    /// The line sent on for a line
+    fn normalize(normalize: &NormalizeName, line: &'static str) -> String {
+        let event = event(line);
+        let output = normalize.provide_statsd(&event).unwrap();
+        let pdu = match output.new_events {
+            Some(events) => Pdu::from(&events[0]),
+            None => Pdu::from(&event),
+        };
+        String::from_utf8(pdu.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn normalize_names() {
+        let lowercase = normalize_name(true);
+        assert_eq!(
+            normalize(&lowercase, "App.Requests:1|c|#Host:A"),
+            "app.requests:1|c|#Host:A"
+        );
+        assert_eq!(
+            normalize(&lowercase, "api /users/{id}..latency:3|ms|@0.5"),
+            "api__users__id_.latency:3|ms|@0.5"
+        );
+        assert_eq!(
+            normalize(&lowercase, "caf\u{e9}.visits:1|c"),
+            "caf_.visits:1|c"
+        );
+        assert_eq!(normalize(&lowercase, "ok.name-1_x:1|g"), "ok.name-1_x:1|g");
+        assert_eq!(lowercase.counter_normalized.get(), 3_f64);
+
+        let keep_case = normalize_name(false);
+        assert_eq!(
+            normalize(&keep_case, "App...Requests:1|c"),
+            "App.Requests:1|c"
+        );
+        assert_eq!(keep_case.counter_normalized.get(), 1_f64);
+    }
+
+    #[test]
+    fn normalize_parsed() {
+        let normalize = normalize_name(true);
+        let output = normalize
+            .provide_statsd(&parsed("Foo Bar:2|c|#a:b"))
+            .unwrap();
+        let pdu = Pdu::from(&output.new_events.unwrap()[0]);
+        assert_eq!(pdu.as_bytes(), b"foo_bar:2.0|c|#a:b");
+    }
+}
@@ -1,6 +1,7 @@
 use regex::RegexSet;
 
-use super::{Output, Processor};
+use super::filter::Filter;
+use super::{processing_errors, Error, Output, Processor};
 use crate::stats;
 use crate::{config::processor, statsd_proto::Event};
 use crate::{config::Route, statsd_proto::Parsed};
@@ -8,34 +9,50 @@ use crate::{config::Route, statsd_proto::Parsed};
 pub struct RegexFilter {
     allow: Option<RegexSet>,
     remove: Option<RegexSet>,
+    allow_filter: Option<Filter>,
+    remove_filter: Option<Filter>,
     route: Vec<Route>,
 
     counter_remove: stats::Counter,
+    counter_processing_errors: stats::Counter,
 }
 
 impl RegexFilter {
-    pub fn new(
-        scope: stats::Scope,
-        from_config: &processor::RegexFilter,
-    ) -> Result<Self, regex::Error> {
+    pub fn new(scope: stats::Scope, from_config: &processor::RegexFilter) -> Result<Self, Error> {
         let allow = from_config.allow.as_ref().map(RegexSet::new).transpose()?;
         let remove = from_config.remove.as_ref().map(RegexSet::new).transpose()?;
         Ok(RegexFilter {
             allow,
             remove,
+            allow_filter: from_config
+                .allow_filter
+                .as_deref()
+                .map(Filter::new)
+                .transpose()?,
+            remove_filter: from_config
+                .remove_filter
+                .as_deref()
+                .map(Filter::new)
+                .transpose()?,
             route: from_config.route.clone(),
             counter_remove: scope.counter("removed").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
         })
     }
 }
 
 impl Processor for RegexFilter {
     fn provide_statsd(&self, event: &Event) -> Option<Output> {
-        let name = std::str::from_utf8(match event {
+        let name = match std::str::from_utf8(match event {
             Event::Parsed(parsed) => parsed.id().name.as_ref(),
             Event::Pdu(pdu) => pdu.name(),
-        })
-        .ok()?;
+        }) {
+            Ok(name) => name,
+            Err(_) => {
+                self.counter_processing_errors.inc();
+                return None;
+            }
+        };
         if let Some(allow) = &self.allow {
             if !allow.is_match(name) {
                 self.counter_remove.inc();
@@ -48,6 +65,18 @@ impl Processor for RegexFilter {
                 return None;
             }
         }
+        if let Some(allow) = &self.allow_filter {
+            if !allow.matches_event(event) {
+                self.counter_remove.inc();
+                return None;
+            }
+        }
+        if let Some(remove) = &self.remove_filter {
+            if remove.matches_event(event) {
+                self.counter_remove.inc();
+                return None;
+            }
+        }
         Some(Output {
             new_events: None,
             route: self.route.as_ref(),
@@ -66,6 +95,8 @@ pub mod test {
             route: vec![],
             remove: Some(vec![r"^hello.*".to_owned(), r"^goodbye.*".to_owned()]),
             allow: None,
+            allow_filter: None,
+            remove_filter: None,
         };
         let sink = stats::Collector::default();
         let scope = sink.scope("prefix");
@@ -89,4 +120,24 @@ pub mod test {
             "should not remove"
         );
     }
+
+    #[test]
+    fn filter_expressions() {
+        let c = processor::RegexFilter {
+            route: vec![],
+            remove: None,
+            allow: None,
+            allow_filter: Some("type == counter || type == gauge".to_owned()),
+            remove_filter: Some("tags.env == dev".to_owned()),
+        };
+        let filter = RegexFilter::new(stats::Collector::default().scope("f"), &c).unwrap();
+        let kept = |line: &'static str| {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from_static(line.as_bytes()));
+            filter.provide_statsd(&Event::Pdu(pdu.unwrap())).is_some()
+        };
+        assert!(kept("requests:1|c|#env:prod"));
+        assert!(!kept("requests:1|c|#env:dev"));
+        assert!(!kept("latency:1|ms"));
+        assert_eq!(filter.counter_remove.get(), 2_f64);
+    }
 }
@@ -0,0 +1,172 @@
+use regex::bytes::Regex;
+use smallvec::smallvec;
+
+use super::{parse, processing_errors, Error, Output, Processor};
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Tag};
+use crate::{config::processor, config::Route};
+
+struct Rule {
+    regex: Regex,
+    replace: Vec<u8>,
+    tags: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Rule {
+    /// Rewrite a metric name and its tags, or None if the rule does not match
+    fn apply(&self, name: &[u8], tags: &[Tag]) -> Option<(Vec<u8>, Vec<Tag>)> {
+        let captures = self.regex.captures(name)?;
+        let matched = captures.get(0)?;
+        let mut rewritten = name[..matched.start()].to_vec();
+        captures.expand(&self.replace, &mut rewritten);
+        rewritten.extend_from_slice(&name[matched.end()..]);
+
+        let mut tags: Vec<Tag> = tags
+            .iter()
+            .filter(|tag| !self.tags.iter().any(|(name, _)| *name == tag.name))
+            .cloned()
+            .collect();
+        for (name, value) in self.tags.iter() {
+            let mut expanded = Vec::new();
+            captures.expand(value, &mut expanded);
+            tags.push(Tag {
+                name: name.clone(),
+                value: expanded,
+            });
+        }
+        Some((rewritten, tags))
+    }
+}
+
+/// Rewrites metric names with regex replacements, optionally moving parts of
+/// the name into tags.
+pub struct Rewrite {
+    rules: Vec<Rule>,
+    route: Vec<Route>,
+
+    counter_rewritten: stats::Counter,
+    counter_processing_errors: stats::Counter,
+}
+
+impl Rewrite {
+    pub fn new(scope: stats::Scope, from_config: &processor::Rewrite) -> Result<Self, Error> {
+        let rules = from_config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    regex: Regex::new(&rule.pattern)?,
+                    replace: rule.replace.as_bytes().to_vec(),
+                    tags: rule
+                        .tags
+                        .iter()
+                        .map(|tag| (tag.name.as_bytes().to_vec(), tag.value.as_bytes().to_vec()))
+                        .collect(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Rewrite {
+            rules,
+            route: from_config.route.clone(),
+            counter_rewritten: scope.counter("rewritten").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+}
+
+impl Processor for Rewrite {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let name = match event {
+            Event::Parsed(parsed) => parsed.name(),
+            Event::Pdu(pdu) => pdu.name(),
+        };
+        if !self.rules.iter().any(|rule| rule.regex.is_match(name)) {
+            return Some(Output {
+                new_events: None,
+                route: self.route.as_ref(),
+            });
+        }
+        let owned = parse(event, &self.counter_processing_errors)?;
+        let (name, tags) = self
+            .rules
+            .iter()
+            .find_map(|rule| rule.apply(owned.name(), owned.tags()))?;
+        self.counter_rewritten.inc();
+        let id = Id {
+            name,
+            mtype: *owned.metric_type(),
+            tags,
+        };
+        Some(Output {
+            new_events: Some(smallvec![Event::Parsed(Owned::new(
+                id,
+                owned.value(),
+                owned.sample_rate()
+            ))]),
+            route: self.route.as_ref(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use std::convert::TryInto;
+
+    #[test]
+    fn rewrite_captures_into_tags() {
+        let c = processor::Rewrite {
+            route: vec![],
+            rules: vec![
+                processor::RewriteRule {
+                    pattern: r"^servers\.(?P<host>[^.]+)\.(\w+)$".to_owned(),
+                    replace: "servers.$2".to_owned(),
+                    tags: vec![processor::RewriteTag {
+                        name: "host".to_owned(),
+                        value: "${host}".to_owned(),
+                    }],
+                },
+                processor::RewriteRule {
+                    pattern: r"^servers\.".to_owned(),
+                    replace: "hosts.".to_owned(),
+                    tags: vec![],
+                },
+            ],
+        };
+        let sink = stats::Collector::default();
+        let filter = Rewrite::new(sink.scope("prefix"), &c).unwrap();
+        let rewrite = |line: &'static str| -> Option<Owned> {
+            let event = event(line);
+            let output = filter.provide_statsd(&event).unwrap();
+            output
+                .new_events
+                .map(|events| (&events[0]).try_into().unwrap())
+        };
+
+        let owned = rewrite("servers.web1.cpu:1|c|#host:old,env:prod").unwrap();
+        assert_eq!(owned.name(), b"servers.cpu");
+        assert_eq!(
+            owned.tags(),
+            &[
+                Tag {
+                    name: b"env".to_vec(),
+                    value: b"prod".to_vec(),
+                },
+                Tag {
+                    name: b"host".to_vec(),
+                    value: b"web1".to_vec(),
+                },
+            ]
+        );
+
+        // The first matching rule is used
+        let owned = rewrite("servers.web1.disk.used:1|c").unwrap();
+        assert_eq!(owned.name(), b"hosts.web1.disk.used");
+        assert!(owned.tags().is_empty());
+
+        assert!(rewrite("other.metric:1|c").is_none());
+        assert_eq!(filter.counter_rewritten.get(), 2_f64);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+
+use parking_lot::Mutex;
+
+use super::sampler::Sampler;
+use super::{parse, processing_errors, Error, Output, Processor};
+use crate::backends::Backends;
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed};
+
+/// Aggregates metrics with some of their tags removed into lower
+/// cardinality rollups, passing the original metrics on unchanged. Rollups
+/// are aggregated by a sampler, and sent to the sampler's route.
+pub struct Rollup {
+    strip_tags: HashSet<Vec<u8>>,
+    sampler: Sampler,
+    /// Stripped metrics the sampler passes through rather than aggregating,
+    /// sent on to the rollup route each tick
+    pending: Mutex<Vec<Event>>,
+    rollup_route: Vec<Route>,
+    route: Vec<Route>,
+
+    counter_processing_errors: stats::Counter,
+}
+
+impl Rollup {
+    pub fn new(scope: stats::Scope, from_config: &processor::Rollup) -> Result<Self, Error> {
+        Ok(Rollup {
+            strip_tags: from_config
+                .strip_tags
+                .iter()
+                .map(|key| key.as_bytes().to_vec())
+                .collect(),
+            sampler: Sampler::new(scope.scope("sampler"), &from_config.sampler)?,
+            pending: Mutex::new(Vec::new()),
+            rollup_route: from_config.sampler.route.clone(),
+            route: from_config.route.clone(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    /// The metric with the stripped tags removed
+    fn strip(&self, event: &Event) -> Option<Event> {
+        if let Event::Pdu(pdu) = event {
+            if pdu.tags().is_none() {
+                return Some(event.clone());
+            }
+        }
+        let owned = parse(event, &self.counter_processing_errors)?;
+        let id = Id {
+            name: owned.name().to_vec(),
+            mtype: *owned.metric_type(),
+            tags: owned
+                .tags()
+                .iter()
+                .filter(|tag| !self.strip_tags.contains(&tag.name))
+                .cloned()
+                .collect(),
+        };
+        Some(Event::Parsed(Owned::new(
+            id,
+            owned.value(),
+            owned.sample_rate(),
+        )))
+    }
+
+    fn send_pending(&self, backends: &Backends) {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        backends.provide_statsd_slice(&pending, self.rollup_route.as_ref());
+    }
+}
+
+impl Processor for Rollup {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        if let Some(stripped) = self.strip(event) {
+            if let Some(output) = self.sampler.provide_statsd(&stripped) {
+                let mut pending = self.pending.lock();
+                match output.new_events {
+                    Some(events) => pending.extend(events),
+                    None => pending.push(stripped),
+                }
+            }
+        }
+        Some(Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        })
+    }
+
+    fn tick(&self, time: std::time::SystemTime, backends: &Backends) {
+        self.send_pending(backends);
+        self.sampler.tick(time, backends);
+    }
+
+    fn flush(&self, time: std::time::SystemTime, backends: &Backends) {
+        self.send_pending(backends);
+        self.sampler.flush(time, backends);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::config::RouteType;
+    use crate::processors::test::event;
+    use crate::processors::test::Capture;
+    use crate::statsd_proto::Pdu;
+
+    #[test]
+    fn rollup_strips_and_aggregates() {
+        let route = |to: &str| {
+            vec![Route {
+                route_type: RouteType::Processor,
+                route_to: to.to_owned(),
+            }]
+        };
+        let sampler = crate::processors::sampler::test::sampler_config(route("rollups"));
+        let config = processor::Rollup {
+            strip_tags: vec!["host".to_owned()],
+            sampler,
+            route: route("detailed"),
+        };
+        let sink = stats::Collector::default();
+        let rollup = Rollup::new(sink.scope("r"), &config).unwrap();
+        for line in [
+            "requests:1|c|#host:a,env:prod",
+            "requests:2|c|#host:b,env:prod",
+            "queue:5|G|#host:a",
+        ] {
+            let event = event(line);
+            let output = rollup.provide_statsd(&event).unwrap();
+            assert!(output.new_events.is_none());
+            assert_eq!(output.route, route("detailed").as_slice());
+        }
+
+        let backends = Backends::new(sink.scope("backends"));
+        let rollups = Capture::default();
+        backends
+            .replace_processor("rollups", Box::new(rollups.clone()))
+            .unwrap();
+        rollup.flush(std::time::SystemTime::now(), &backends);
+
+        let mut lines: Vec<Vec<u8>> = rollups
+            .events
+            .lock()
+            .iter()
+            .map(|event| Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        lines.sort();
+        // Both requests lines are counted in one line, as a sampler emits
+        assert_eq!(
+            lines,
+            vec![
+                b"queue:5.0|G".to_vec(),
+                b"requests:1.5|c|@0.5|#env:prod".to_vec()
+            ]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::convert::TryInto;
+use std::sync::OnceLock;
+
+use super::filter::Filter;
+use super::{name_and_type, Error, Output, Processor, Selector};
+use crate::config::processor::{self, RouterMode};
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Owned, Parsed, Tag, Type};
+
+struct Rule {
+    selector: Selector,
+    /// Tag names, with the value they must have if any
+    tags: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    filter: Option<Filter>,
+    route: Vec<Route>,
+}
+
+impl Rule {
+    fn new(config: &processor::RouterRule) -> Result<Self, Error> {
+        let tags = config
+            .tags
+            .iter()
+            .map(|tag| match tag.split_once(':') {
+                Some((name, value)) => (name.as_bytes().to_vec(), Some(value.as_bytes().to_vec())),
+                None => (tag.as_bytes().to_vec(), None),
+            })
+            .collect();
+        Ok(Rule {
+            selector: Selector::new(config.name.as_deref(), &config.types)?,
+            tags,
+            filter: config.filter.as_deref().map(Filter::new).transpose()?,
+            route: config.route.clone(),
+        })
+    }
+
+    fn matches(&self, name: &[u8], mtype: Option<Type>, tags: &[Tag]) -> bool {
+        self.selector.matches(name, mtype)
+            && self.tags.iter().all(|(name, value)| {
+                tags.iter().any(|tag| {
+                    tag.name == *name && value.as_ref().is_none_or(|value| tag.value == *value)
+                })
+            })
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(name, mtype, tags))
+    }
+
+    fn needs_tags(&self) -> bool {
+        !self.tags.is_empty() || self.filter.as_ref().is_some_and(Filter::needs_tags)
+    }
+}
+
+/// Routes metrics by rules matching their name, type and tags.
+pub struct Router {
+    rules: Vec<Rule>,
+    mode: RouterMode,
+    /// Routes of each combination of matching rules in `all_matches` mode,
+    /// indexed by a bit mask of the rules, built as they are first needed
+    combined: Vec<OnceLock<Vec<Route>>>,
+    parse_tags: bool,
+    route: Vec<Route>,
+
+    counter_matches: stats::CounterVec,
+    rule_labels: Vec<String>,
+    counter_unmatched: stats::Counter,
+}
+
+impl Router {
+    pub fn new(scope: stats::Scope, from_config: &processor::Router) -> Result<Self, Error> {
+        let rules: Vec<Rule> = from_config
+            .rules
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<_, _>>()?;
+        let combined = match from_config.mode {
+            RouterMode::FirstMatch => Vec::new(),
+            RouterMode::AllMatches => {
+                if rules.len() > processor::MAX_ALL_MATCHES_RULES {
+                    return Err(Error::InvalidConfig(format!(
+                        "all_matches routers can have at most {} rules",
+                        processor::MAX_ALL_MATCHES_RULES
+                    )));
+                }
+                (0..1 << rules.len()).map(|_| OnceLock::new()).collect()
+            }
+        };
+        Ok(Router {
+            parse_tags: rules.iter().any(Rule::needs_tags),
+            rule_labels: (0..rules.len()).map(|index| index.to_string()).collect(),
+            rules,
+            mode: from_config.mode,
+            combined,
+            route: from_config.route.clone(),
+            counter_matches: scope.counter_vec("rule_matches", &["rule"]).unwrap(),
+            counter_unmatched: scope.counter("unmatched").unwrap(),
+        })
+    }
+
+    fn combined_route(&self, mask: usize) -> &[Route] {
+        self.combined[mask].get_or_init(|| {
+            let mut route: Vec<Route> = Vec::new();
+            for (index, rule) in self.rules.iter().enumerate() {
+                if mask & (1 << index) == 0 {
+                    continue;
+                }
+                for dest in rule.route.iter() {
+                    if !route.contains(dest) {
+                        route.push(dest.clone());
+                    }
+                }
+            }
+            route
+        })
+    }
+}
+
+impl Processor for Router {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let (name, mtype) = name_and_type(event);
+        // Only parse lines for their tags when a rule matches on tags
+        let owned: Option<Owned> = match event {
+            Event::Pdu(pdu) if self.parse_tags && pdu.tags().is_some() => event.try_into().ok(),
+            _ => None,
+        };
+        let tags = match (event, owned.as_ref()) {
+            (Event::Parsed(parsed), _) => parsed.tags(),
+            (_, Some(owned)) => owned.tags(),
+            _ => &[],
+        };
+
+        let mut matching = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches(name, mtype, tags))
+            .map(|(index, _)| index);
+        let route = match self.mode {
+            RouterMode::FirstMatch => matching.next().map(|index| {
+                self.counter_matches
+                    .inc(&[self.rule_labels[index].as_str()]);
+                self.rules[index].route.as_ref()
+            }),
+            RouterMode::AllMatches => {
+                let mask = matching.fold(0, |mask, index| {
+                    self.counter_matches
+                        .inc(&[self.rule_labels[index].as_str()]);
+                    mask | 1 << index
+                });
+                (mask != 0).then(|| self.combined_route(mask))
+            }
+        };
+        let route = route.unwrap_or_else(|| {
+            self.counter_unmatched.inc();
+            self.route.as_ref()
+        });
+        Some(Output {
+            new_events: None,
+            route,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::config::RouteType;
+    use crate::processors::test::event;
+
+    fn route(to: &str) -> Vec<Route> {
+        vec![Route {
+            route_type: RouteType::Statsd,
+            route_to: to.to_owned(),
+        }]
+    }
+
+    fn config(mode: RouterMode) -> processor::Router {
+        processor::Router {
+            rules: vec![
+                processor::RouterRule {
+                    name: Some(r"^app\.".to_owned()),
+                    types: vec!["timer".to_owned()],
+                    tags: vec![],
+                    filter: None,
+                    route: route("timers"),
+                },
+                processor::RouterRule {
+                    name: None,
+                    types: vec![],
+                    tags: vec!["env:prod".to_owned(), "team".to_owned()],
+                    filter: None,
+                    route: route("prod"),
+                },
+            ],
+            mode,
+            route: route("default"),
+        }
+    }
+
+    fn routed<'a>(router: &'a Router, line: &'static str) -> Vec<&'a str> {
+        let event = event(line);
+        router
+            .provide_statsd(&event)
+            .unwrap()
+            .route
+            .iter()
+            .map(|dest| dest.route_to.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn first_match() {
+        let sink = stats::Collector::default();
+        let router = Router::new(sink.scope("r"), &config(RouterMode::FirstMatch)).unwrap();
+        assert_eq!(routed(&router, "app.req:1|ms|#env:prod,team:a"), ["timers"]);
+        assert_eq!(routed(&router, "app.req:1|c|#env:prod,team:a"), ["prod"]);
+        // Every tag of a rule must be present
+        assert_eq!(routed(&router, "app.req:1|c|#env:prod"), ["default"]);
+        assert_eq!(routed(&router, "app.req:1|c|#env:dev,team:a"), ["default"]);
+        assert_eq!(router.counter_matches.get(&["0"]), 1_f64);
+        assert_eq!(router.counter_unmatched.get(), 2_f64);
+    }
+
+    #[test]
+    fn all_matches() {
+        let sink = stats::Collector::default();
+        let router = Router::new(sink.scope("r"), &config(RouterMode::AllMatches)).unwrap();
+        assert_eq!(
+            routed(&router, "app.req:1|ms|#env:prod,team:a"),
+            ["timers", "prod"]
+        );
+        assert_eq!(routed(&router, "app.req:1|ms"), ["timers"]);
+        assert_eq!(routed(&router, "other:1|ms"), ["default"]);
+    }
+
+    #[test]
+    fn filter_rules() {
+        let sink = stats::Collector::default();
+        let config = processor::Router {
+            rules: vec![processor::RouterRule {
+                name: None,
+                types: vec![],
+                tags: vec![],
+                filter: Some(
+                    r#"name =~ "^api\." && tags.env == "prod" && type == counter"#.to_owned(),
+                ),
+                route: route("api"),
+            }],
+            mode: RouterMode::FirstMatch,
+            route: route("default"),
+        };
+        let router = Router::new(sink.scope("r"), &config).unwrap();
+        assert_eq!(routed(&router, "api.req:1|c|#env:prod"), ["api"]);
+        assert_eq!(routed(&router, "api.req:1|ms|#env:prod"), ["default"]);
+        assert_eq!(routed(&router, "api.req:1|c|#env:dev"), ["default"]);
+    }
+}
@@ -0,0 +1,173 @@
+use smallvec::smallvec;
+
+use super::{name_and_type, parse, processing_errors, Error, Output, Processor, Selector};
+use crate::config::processor::{self, InvalidSampleRate, SampleRateAction};
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Owned, Parsed, Type};
+
+/// Normalizes the sample rates of lines, as clients sampling at different
+/// rates corrupt downstream aggregates: counters can be scaled up to what
+/// they stand for, rates removed, and lines with invalid rates dropped.
+pub struct SampleRate {
+    selector: Selector,
+    action: SampleRateAction,
+    invalid: InvalidSampleRate,
+    route: Vec<Route>,
+
+    counter_normalized: stats::Counter,
+    counter_invalid: stats::Counter,
+    counter_processing_errors: stats::Counter,
+}
+
+/// A line's rate, if it is a number above 0 and at most 1
+fn valid_rate(rate: &[u8]) -> Option<f64> {
+    match lexical::parse::<f64, _>(rate) {
+        Ok(rate) if rate > 0_f64 && rate <= 1_f64 => Some(rate),
+        _ => None,
+    }
+}
+
+/// The line without its rate
+fn strip(event: &Event) -> Event {
+    match event {
+        Event::Pdu(pdu) => Event::Pdu(pdu.without_sample_rate()),
+        Event::Parsed(parsed) => {
+            Event::Parsed(Owned::new(parsed.id().clone(), parsed.value(), None))
+        }
+    }
+}
+
+impl SampleRate {
+    pub fn new(scope: stats::Scope, from_config: &processor::SampleRate) -> Result<Self, Error> {
+        Ok(SampleRate {
+            selector: Selector::new(from_config.name.as_deref(), &from_config.types)?,
+            action: from_config.action,
+            invalid: from_config.invalid,
+            route: from_config.route.clone(),
+            counter_normalized: scope.counter("normalized").unwrap(),
+            counter_invalid: scope.counter("invalid_rates").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    fn output(&self, event: Event) -> Option<Output<'_>> {
+        self.counter_normalized.inc();
+        Some(Output {
+            new_events: Some(smallvec![event]),
+            route: self.route.as_ref(),
+        })
+    }
+}
+
+impl Processor for SampleRate {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let unchanged = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let (name, mtype) = name_and_type(event);
+        if !self.selector.matches(name, mtype) {
+            return Some(unchanged);
+        }
+        let rate = match event {
+            Event::Pdu(pdu) => pdu.sample_rate().map(valid_rate),
+            Event::Parsed(parsed) => parsed.sample_rate().map(Some),
+        };
+        let rate = match rate {
+            None => return Some(unchanged),
+            Some(Some(rate)) => rate,
+            Some(None) => {
+                self.counter_invalid.inc();
+                return match self.invalid {
+                    InvalidSampleRate::Drop => None,
+                    InvalidSampleRate::Strip => self.output(strip(event)),
+                };
+            }
+        };
+
+        match self.action {
+            SampleRateAction::Keep => Some(unchanged),
+            SampleRateAction::Strip => self.output(strip(event)),
+            SampleRateAction::Scale if mtype == Some(Type::Counter) => {
+                let owned = parse(event, &self.counter_processing_errors)?;
+                self.output(Event::Parsed(Owned::new(
+                    owned.id().clone(),
+                    owned.value() / rate,
+                    None,
+                )))
+            }
+            SampleRateAction::Scale => Some(unchanged),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use crate::statsd_proto::Pdu;
+
+    fn sample_rate(action: SampleRateAction, invalid: InvalidSampleRate) -> SampleRate {
+        SampleRate::new(
+            stats::Collector::default().scope("rate"),
+            &processor::SampleRate {
+                name: None,
+                types: vec![],
+                action,
+                invalid,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    /// The line sent on for a line, if any
+    fn normalize(rate: &SampleRate, line: &'static str) -> Option<String> {
+        let event = event(line);
+        let output = rate.provide_statsd(&event)?;
+        let pdu = match output.new_events {
+            Some(events) => Pdu::from(&events[0]),
+            None => Pdu::from(&event),
+        };
+        Some(String::from_utf8(pdu.as_bytes().to_vec()).unwrap())
+    }
+
+    #[test]
+    fn scale_counters() {
+        let rate = sample_rate(SampleRateAction::Scale, InvalidSampleRate::Drop);
+        assert_eq!(
+            normalize(&rate, "requests:3|c|@0.5|#a:b").unwrap(),
+            "requests:6.0|c|#a:b"
+        );
+        assert_eq!(
+            normalize(&rate, "latency:3|ms|@0.5").unwrap(),
+            "latency:3|ms|@0.5"
+        );
+        assert_eq!(normalize(&rate, "requests:3|c").unwrap(), "requests:3|c");
+        assert_eq!(normalize(&rate, "requests:3|c|@2"), None);
+        assert_eq!(normalize(&rate, "requests:3|c|@x"), None);
+        assert_eq!(rate.counter_normalized.get(), 1_f64);
+        assert_eq!(rate.counter_invalid.get(), 2_f64);
+    }
+
+    #[test]
+    fn strip_rates() {
+        let rate = sample_rate(SampleRateAction::Strip, InvalidSampleRate::Drop);
+        assert_eq!(
+            normalize(&rate, "latency:3|ms|@0.5").unwrap(),
+            "latency:3|ms"
+        );
+
+        let rate = sample_rate(SampleRateAction::Keep, InvalidSampleRate::Strip);
+        assert_eq!(
+            normalize(&rate, "requests:3|c|@0.5").unwrap(),
+            "requests:3|c|@0.5"
+        );
+        assert_eq!(
+            normalize(&rate, "requests:3|c|@0|#a:b").unwrap(),
+            "requests:3|c|#a:b"
+        );
+    }
+}
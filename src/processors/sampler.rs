@@ -1,19 +1,34 @@
 use super::Output;
 use crate::backends::Backends;
+use crate::config::processor::{KeyOverflow, TimerOutput};
 use crate::processors;
+use crate::stats;
 use crate::statsd_proto::Id;
-use crate::statsd_proto::{Event, Owned, Type};
+use crate::statsd_proto::{Event, Owned, Pdu, Type};
 use crate::{config, statsd_proto::Parsed};
 
 use ahash::RandomState;
+use hyperloglog::HyperLogLog;
 use parking_lot::Mutex;
+use regex::bytes::Regex;
+use smallvec::SmallVec;
 use std::cell::RefCell;
-use thiserror::Error;
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const DEFAULT_RESERVOIR: u32 = 100;
+const DEFAULT_SET_EXACT_LIMIT: usize = 1000;
+const DEFAULT_MAX_SETS: usize = 100000;
+const SET_ERROR_RATE: f64 = 0.01;
+const DEFAULT_SHARDS: usize = 16;
+const FLUSH_SECONDS_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
 
 fn scale(value: f64, sample_rate: Option<f64>) -> (f64, f64) {
     match sample_rate {
@@ -29,10 +44,45 @@ fn scale(value: f64, sample_rate: Option<f64>) -> (f64, f64) {
     }
 }
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("invalid sampler configuration")]
-    InvalidConfig,
+/// Name suffix for a percentile, such as `.p99`, or `.p99_9` for 99.9
+fn percentile_suffix(percentile: f64) -> String {
+    format!(".p{}", percentile.to_string().replace('.', "_"))
+}
+
+/// When the window following a flush at `time` is flushed. Windows end at
+/// multiples of the window since the origin, the Unix epoch for aligned
+/// windows, and each flush is delayed by the jitter.
+fn next_flush(
+    window: Duration,
+    origin: SystemTime,
+    jitter: Duration,
+    time: SystemTime,
+) -> SystemTime {
+    if window.is_zero() {
+        return time;
+    }
+    let since_origin = time
+        .checked_sub(jitter)
+        .and_then(|t| t.duration_since(origin).ok())
+        .unwrap_or_default();
+    let windows = since_origin.as_nanos() / window.as_nanos() + 1;
+    origin + Duration::from_nanos((windows * window.as_nanos()) as u64) + jitter
+}
+
+/// Aggregates of a later sub-window folded into those of an earlier one
+trait Merge {
+    fn merge(&mut self, later: Self);
+}
+
+/// The earlier aggregate, if any, with the later one merged in
+fn merged<V: Merge>(earlier: Option<V>, later: V) -> V {
+    match earlier {
+        Some(mut earlier) => {
+            earlier.merge(later);
+            earlier
+        }
+        None => later,
+    }
 }
 
 #[derive(Debug, Default)]
@@ -49,6 +99,13 @@ impl Counter {
     }
 }
 
+impl Merge for Counter {
+    fn merge(&mut self, later: Counter) {
+        self.value += later.value;
+        self.samples += later.samples;
+    }
+}
+
 #[derive(Debug)]
 struct Timer {
     values: Vec<f64>,
@@ -56,6 +113,8 @@ struct Timer {
     reservoir_size: u32,
     count: f64,
     sum: f64,
+    lower: f64,
+    upper: f64,
 }
 
 impl Timer {
@@ -66,10 +125,25 @@ impl Timer {
             reservoir_size,
             count: 0_f64,
             sum: 0_f64,
+            lower: f64::INFINITY,
+            upper: f64::NEG_INFINITY,
         }
     }
 
     fn add(&mut self, value: f64, sample_rate: Option<f64>) {
+        self.sample(value, 1);
+        let (sum, count) = scale(value, sample_rate);
+        // Keep track of a sample rate scaled count independently from the
+        // reservoir sample fill
+        self.count += count;
+        self.sum += sum;
+        self.lower = self.lower.min(value);
+        self.upper = self.upper.max(value);
+    }
+
+    /// Offer a value standing for `weight` values to the reservoir
+    fn sample(&mut self, value: f64, weight: u32) {
+        self.filled_count += weight;
         // Do an initial fill if we haven't filled the full reservoir
         if self.values.len() < self.reservoir_size as usize {
             self.values.push(value);
@@ -79,135 +153,818 @@ impl Timer {
                 _ => (),
             }
         }
-        let (sum, count) = scale(value, sample_rate);
-        // Keep track of a sample rate scaled count independently from the
-        // reservoir sample fill
-        self.count += count;
-        self.sum += sum;
-        self.filled_count += 1;
+    }
+
+    /// The values of the reservoir, each with the rate it was sampled at
+    fn samples<'a>(&'a self, id: &'a Id) -> impl Iterator<Item = Event> + 'a {
+        let sample_rate = self.values.len() as f64 / self.count;
+        self.values
+            .iter()
+            .map(move |value| Event::Parsed(Owned::new(id.clone(), *value, Some(sample_rate))))
+    }
+
+    /// Percentiles of the reservoir, which holds every value until it fills,
+    /// by nearest rank. None are taken from an empty reservoir.
+    fn percentiles(&self, id: &Id, percentiles: &[f64]) -> Vec<Event> {
+        if self.values.is_empty() {
+            return Vec::new();
+        }
+        let mut values = self.values.clone();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        percentiles
+            .iter()
+            .map(|p| {
+                let rank = (p / 100_f64 * values.len() as f64).ceil() as usize;
+                let value = values[rank.clamp(1, values.len()) - 1];
+                let id = Id {
+                    name: [id.name.as_slice(), percentile_suffix(*p).as_bytes()].concat(),
+                    mtype: Type::Gauge,
+                    tags: id.tags.clone(),
+                };
+                Event::Parsed(Owned::new(id, value, None))
+            })
+            .collect()
+    }
+
+    /// Statsd style aggregates of the timer's values. The count and sum are
+    /// emitted as counters, so the aggregates of several relays add up, and
+    /// the rest as gauges.
+    fn aggregates(&self, id: &Id) -> [Event; 5] {
+        let event = |suffix: &[u8], mtype, value| {
+            let id = Id {
+                name: [id.name.as_slice(), suffix].concat(),
+                mtype,
+                tags: id.tags.clone(),
+            };
+            Event::Parsed(Owned::new(id, value, None))
+        };
+        [
+            event(b".count", Type::Counter, self.count),
+            event(b".sum", Type::Counter, self.sum),
+            event(b".upper", Type::Gauge, self.upper),
+            event(b".lower", Type::Gauge, self.lower),
+            event(b".mean", Type::Gauge, self.sum / self.count),
+        ]
+    }
+}
+
+impl Merge for Timer {
+    /// Each value of the later reservoir stands for an equal share of the
+    /// values it was sampled from
+    fn merge(&mut self, later: Timer) {
+        let filled_count = self.filled_count + later.filled_count;
+        let weight = later.filled_count / (later.values.len() as u32).max(1);
+        for value in later.values {
+            self.sample(value, weight);
+        }
+        self.filled_count = filled_count;
+        self.count += later.count;
+        self.sum += later.sum;
+        self.lower = self.lower.min(later.lower);
+        self.upper = self.upper.max(later.upper);
     }
 }
 
 #[derive(Debug, Default)]
 struct Gauge {
     value: f64,
+    /// Only changes were recorded, so the value is their total rather than
+    /// the gauge's value
+    delta: bool,
 }
 
 impl Gauge {
+    /// Set the gauge, or change it by a delta
+    fn update(&mut self, value: f64, delta: bool) {
+        if delta {
+            self.value += value;
+        } else {
+            self.value = value;
+            self.delta = false;
+        }
+    }
+
+    /// The gauge's value, or the total of its changes written with a sign
+    /// so they are applied to the gauge downstream
     fn to_event(&self, id: &Id) -> Event {
-        Event::Parsed(Owned::new(id.clone(), self.value, None))
+        let event = Event::Parsed(Owned::new(id.clone(), self.value, None));
+        // Negative values are written with a sign already
+        if !self.delta || self.value < 0_f64 {
+            return event;
+        }
+        let pdu = Pdu::from(&event);
+        Event::Pdu(pdu.with_value(&[b"+", pdu.value()].concat()))
+    }
+}
+
+impl Merge for Gauge {
+    fn merge(&mut self, later: Gauge) {
+        self.update(later.value, later.delta);
+    }
+}
+
+#[derive(Clone)]
+enum Members {
+    Exact(HashSet<Vec<u8>, RandomState>),
+    Estimated(HyperLogLog),
+}
+
+/// The distinct members of a set, held exactly up to a limit, after which
+/// they are counted by HyperLogLog
+#[derive(Clone)]
+struct Set {
+    members: Members,
+    exact_limit: usize,
+    /// Estimates are made with the hash keys of a shared template, so the
+    /// estimates of different sub-windows can be merged
+    template: Arc<HyperLogLog>,
+}
+
+impl Set {
+    fn new(exact_limit: usize, template: Arc<HyperLogLog>) -> Self {
+        Set {
+            members: Members::Exact(HashSet::default()),
+            exact_limit,
+            template,
+        }
+    }
+
+    fn add(&mut self, member: &[u8]) {
+        match &mut self.members {
+            Members::Exact(exact) if exact.contains(member) => (),
+            Members::Exact(exact) if exact.len() < self.exact_limit => {
+                exact.insert(member.to_vec());
+            }
+            Members::Exact(exact) => {
+                let mut hll = HyperLogLog::new_from_template(&self.template);
+                for existing in exact.iter() {
+                    hll.insert(&existing.as_slice());
+                }
+                hll.insert(&member);
+                self.members = Members::Estimated(hll);
+            }
+            Members::Estimated(hll) => hll.insert(&member),
+        }
+    }
+
+    fn len(&self) -> f64 {
+        match &self.members {
+            Members::Exact(exact) => exact.len() as f64,
+            Members::Estimated(hll) => hll.len().round(),
+        }
+    }
+
+    /// A gauge of the number of distinct members
+    fn to_event(&self, id: &Id) -> Event {
+        let id = Id {
+            name: id.name.clone(),
+            mtype: Type::Gauge,
+            tags: id.tags.clone(),
+        };
+        Event::Parsed(Owned::new(id, self.len(), None))
+    }
+}
+
+impl Merge for Set {
+    fn merge(&mut self, later: Set) {
+        match later.members {
+            Members::Exact(exact) => {
+                for member in exact.iter() {
+                    self.add(member);
+                }
+            }
+            Members::Estimated(mut hll) => {
+                match &self.members {
+                    Members::Exact(exact) => {
+                        for member in exact.iter() {
+                            hll.insert(&member.as_slice());
+                        }
+                    }
+                    Members::Estimated(estimated) => hll.merge(estimated),
+                }
+                self.members = Members::Estimated(hll);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Set {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let estimated = matches!(self.members, Members::Estimated(_));
+        f.debug_struct("Set")
+            .field("len", &self.len())
+            .field("estimated", &estimated)
+            .finish()
+    }
+}
+
+/// What happened to a value recorded under an Id
+enum Recorded<V> {
+    Updated,
+    Added,
+    /// Added, evicting the least recently recorded Id and its aggregates
+    Evicted(Id, V),
+    /// Not recorded, as too many Ids are tracked
+    Untracked,
+}
+
+/// Aggregates by Id, optionally capped at a number of Ids
+#[derive(Debug)]
+struct Keys<V> {
+    values: HashMap<Id, (V, u64), RandomState>,
+    /// Ids by when each was last recorded, only kept when evicting
+    recency: BTreeMap<u64, Id>,
+    clock: u64,
+}
+
+impl<V> Default for Keys<V> {
+    fn default() -> Self {
+        Keys {
+            values: HashMap::default(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<V> Keys<V> {
+    /// The aggregate of an Id, marked as the most recently recorded
+    fn touch(&mut self, id: &Id, evict: bool) -> Option<&mut V> {
+        self.clock += 1;
+        // Note: Using the entry API would make logical sense to avoid
+        // re-hashing the same Id on insert, however it costs more to
+        // clone the Id as the entry API does not allow for trait Clone
+        // key references and supporting lazy-cloning.
+        let (value, touched) = self.values.get_mut(id)?;
+        if evict {
+            if let Some(id) = self.recency.remove(touched) {
+                self.recency.insert(self.clock, id);
+            }
+            *touched = self.clock;
+        }
+        Some(value)
+    }
+
+    fn insert(&mut self, id: &Id, evict: bool, value: V) {
+        self.clock += 1;
+        if evict {
+            self.recency.insert(self.clock, id.clone());
+        }
+        self.values.insert(id.clone(), (value, self.clock));
+    }
+
+    fn remove(&mut self, id: &Id) -> Option<V> {
+        let (value, touched) = self.values.remove(id)?;
+        self.recency.remove(&touched);
+        Some(value)
+    }
+}
+
+/// Aggregates by sub-window, oldest first
+type SubWindows<V> = VecDeque<(u64, Keys<V>)>;
+
+/// Remove the least recently recorded Id of the oldest sub-window with any,
+/// returned with its aggregates of every sub-window merged in, so each Id is
+/// emitted in order, and how many aggregates were removed.
+fn evict_oldest<V: Merge>(shard: &mut SubWindows<V>) -> Option<(Id, V, usize)> {
+    let (_, oldest) = shard
+        .iter_mut()
+        .find_map(|(_, keys)| keys.recency.pop_first())?;
+    let mut aggregate = None;
+    let mut removed = 0;
+    for (_, keys) in shard.iter_mut() {
+        if let Some(value) = keys.remove(&oldest) {
+            aggregate = Some(merged(aggregate, value));
+            removed += 1;
+        }
+    }
+    Some((oldest, aggregate?, removed))
+}
+
+/// Aggregates spread over shards by hash of their Id, each behind its own
+/// lock, so lines of different metrics recorded concurrently rarely contend.
+/// Each shard holds its aggregates by sub-window, oldest first, so a flush
+/// takes the sub-windows of ended windows while lines are still recorded
+/// into the current one. Ids are evicted from the shard needing room, or
+/// from another if it has none.
+#[derive(Debug)]
+struct Sharded<V> {
+    hasher: RandomState,
+    shards: Vec<Mutex<SubWindows<V>>>,
+    /// Aggregates across every shard and sub-window not yet flushed
+    len: AtomicUsize,
+}
+
+impl<V: Merge> Sharded<V> {
+    fn new(shards: usize) -> Self {
+        Sharded {
+            hasher: RandomState::new(),
+            shards: (0..shards).map(|_| Mutex::new(VecDeque::new())).collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, id: &Id) -> &Mutex<SubWindows<V>> {
+        let hash = self.hasher.hash_one(id) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    /// Update the aggregate of an Id in a sub-window, creating it unless
+    /// `limit` aggregates are held and no other Id can be evicted
+    fn record<N, U>(
+        &self,
+        sub_window: u64,
+        id: &Id,
+        limit: Option<usize>,
+        evict: bool,
+        new: N,
+        update: U,
+    ) -> Recorded<V>
+    where
+        N: FnOnce() -> V,
+        U: FnOnce(&mut V),
+    {
+        let own = self.shard(id);
+        let mut shard = own.lock();
+        // Lines from a clock stepped backwards join the latest sub-window
+        if shard.back().is_none_or(|(latest, _)| *latest < sub_window) {
+            shard.push_back((sub_window, Keys::default()));
+        }
+        if let Some(value) = shard.back_mut().unwrap().1.touch(id, evict) {
+            update(value);
+            return Recorded::Updated;
+        }
+        let mut recorded = Recorded::Added;
+        if limit.is_some_and(|limit| self.len.load(Ordering::Relaxed) >= limit) {
+            if !evict {
+                return Recorded::Untracked;
+            }
+            // Only other shards free right now are tried, as waiting on
+            // one while holding this lock could deadlock
+            let evicted = evict_oldest(&mut shard).or_else(|| {
+                self.shards
+                    .iter()
+                    .filter(|other| !std::ptr::eq(*other, own))
+                    .find_map(|other| evict_oldest(&mut *other.try_lock()?))
+            });
+            match evicted {
+                Some((evicted, value, removed)) => {
+                    self.len.fetch_sub(removed, Ordering::Relaxed);
+                    recorded = Recorded::Evicted(evicted, value);
+                }
+                None => return Recorded::Untracked,
+            }
+        }
+        let mut value = new();
+        update(&mut value);
+        shard.back_mut().unwrap().1.insert(id, evict, value);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        recorded
+    }
+
+    /// Remove the aggregates of every sub-window before `before`, a shard at
+    /// a time, merging the sub-windows of each Id. Returns how many Ids
+    /// there were.
+    fn take_each<F: FnMut(Id, V)>(&self, before: u64, mut f: F) -> usize {
+        let mut taken = 0;
+        for shard in self.shards.iter() {
+            // Only the shard's list of sub-windows is changed under its lock
+            let sub_windows: Vec<Keys<V>> = {
+                let mut shard = shard.lock();
+                let ended = shard
+                    .iter()
+                    .take_while(|(sub_window, _)| *sub_window < before)
+                    .count();
+                shard.drain(..ended).map(|(_, keys)| keys).collect()
+            };
+            let aggregates = sub_windows.iter().map(|keys| keys.values.len()).sum();
+            self.len.fetch_sub(aggregates, Ordering::Relaxed);
+            let mut sub_windows = sub_windows.into_iter();
+            let mut values = match sub_windows.next() {
+                Some(keys) => keys.values,
+                None => continue,
+            };
+            for keys in sub_windows {
+                for (id, (value, touched)) in keys.values {
+                    match values.entry(id) {
+                        Entry::Occupied(mut entry) => entry.get_mut().0.merge(value),
+                        Entry::Vacant(entry) => {
+                            entry.insert((value, touched));
+                        }
+                    }
+                }
+            }
+            taken += values.len();
+            for (id, (value, _)) in values {
+                f(id, value);
+            }
+        }
+        taken
     }
 }
 
+#[derive(Debug)]
+struct DebugTimers {
+    name: Regex,
+    route: Vec<config::Route>,
+}
+
 #[derive(Debug)]
 pub struct Sampler {
     config: config::processor::Sampler,
-    counters: Mutex<RefCell<HashMap<Id, Counter, RandomState>>>,
-    timers: Mutex<RefCell<HashMap<Id, Timer, RandomState>>>,
-    gauges: Mutex<RefCell<HashMap<Id, Gauge, RandomState>>>,
+    counters: Sharded<Counter>,
+    timers: Sharded<Timer>,
+    gauges: Sharded<Gauge>,
+    direct_gauges: Sharded<Gauge>,
+    sets: Sharded<Set>,
+    /// Every set starts as a copy of this empty set
+    empty_set: Set,
+
+    /// Timers whose raw samples are also sent to a debug route
+    debug_timers: Option<DebugTimers>,
 
-    last_flush: Mutex<RefCell<std::time::SystemTime>>,
+    /// When the next window is flushed, guarding all flushes
+    next_flush: Mutex<RefCell<SystemTime>>,
+    /// Windows end at multiples of the window since the origin
+    origin: SystemTime,
+    /// Delay of each flush from the end of its window
+    jitter: Duration,
+    /// Each window is aggregated in this many sub-windows
+    sub_windows: u64,
+    sub_window: Duration,
+
+    tracked_keys: stats::GaugeVec,
+    flushed_keys: stats::GaugeVec,
+    evicted_keys: stats::CounterVec,
+    aggregated_lines: stats::CounterVec,
+    untracked_lines: stats::CounterVec,
+    emitted_lines: stats::Counter,
+    debug_lines: stats::Counter,
+    processing_errors: stats::Counter,
+    flush_seconds: stats::Histogram,
 
     route_to: Vec<config::Route>,
 }
 
 impl Sampler {
-    pub fn new(config: &config::processor::Sampler) -> Result<Self, Error> {
-        let counters: RefCell<HashMap<Id, Counter, RandomState>> = RefCell::new(HashMap::default());
-        let timers: RefCell<HashMap<Id, Timer, RandomState>> = RefCell::new(HashMap::default());
-        let gauges: RefCell<HashMap<Id, Gauge, RandomState>> = RefCell::new(HashMap::default());
+    pub fn new(
+        scope: stats::Scope,
+        config: &config::processor::Sampler,
+    ) -> Result<Self, processors::Error> {
+        let shards = config.shards.unwrap_or(DEFAULT_SHARDS);
+        let window = Duration::from_secs(config.window as u64);
+        let (origin, jitter) = if config.align_to_window {
+            let jitter = config
+                .flush_jitter_ms
+                .map_or(0, |jitter| fastrand::u64(0..=jitter));
+            (UNIX_EPOCH, Duration::from_millis(jitter))
+        } else {
+            (SystemTime::now(), Duration::ZERO)
+        };
+        let sub_windows = config.sub_windows.unwrap_or(1);
+        let exact_limit = config
+            .sets
+            .as_ref()
+            .and_then(|sets| sets.exact_limit)
+            .unwrap_or(DEFAULT_SET_EXACT_LIMIT);
         Ok(Sampler {
             config: config.clone(),
-            counters: Mutex::new(counters),
-            timers: Mutex::new(timers),
-            gauges: Mutex::new(gauges),
+            counters: Sharded::new(shards),
+            timers: Sharded::new(shards),
+            gauges: Sharded::new(shards),
+            direct_gauges: Sharded::new(shards),
+            sets: Sharded::new(shards),
+            empty_set: Set::new(exact_limit, Arc::new(HyperLogLog::new(SET_ERROR_RATE))),
+            debug_timers: config
+                .debug_timers
+                .as_ref()
+                .map(|debug| -> Result<_, processors::Error> {
+                    Ok(DebugTimers {
+                        name: Regex::new(&debug.name)?,
+                        route: debug.route.clone(),
+                    })
+                })
+                .transpose()?,
             route_to: config.route.clone(),
-            last_flush: Mutex::new(RefCell::new(std::time::SystemTime::now())),
+            next_flush: Mutex::new(RefCell::new(next_flush(
+                window,
+                origin,
+                jitter,
+                SystemTime::now(),
+            ))),
+            origin,
+            jitter,
+            sub_windows: sub_windows as u64,
+            sub_window: window / sub_windows,
+            tracked_keys: scope.gauge_vec("tracked_keys", &["type"]).unwrap(),
+            flushed_keys: scope.gauge_vec("flushed_keys", &["type"]).unwrap(),
+            evicted_keys: scope.counter_vec("evicted_keys", &["type"]).unwrap(),
+            aggregated_lines: scope.counter_vec("aggregated_lines", &["type"]).unwrap(),
+            untracked_lines: scope.counter_vec("untracked_lines", &["type"]).unwrap(),
+            emitted_lines: scope.counter("emitted_lines").unwrap(),
+            debug_lines: scope.counter("debug_lines").unwrap(),
+            processing_errors: processors::processing_errors(&scope),
+            flush_seconds: scope
+                .histogram("flush_seconds", FLUSH_SECONDS_BUCKETS)
+                .unwrap(),
         })
     }
 
-    fn record_timer(&self, owned: &Owned) {
-        let lock = self.timers.lock();
-        let mut hm = lock.borrow_mut();
+    /// When the window following a flush at `time` is flushed
+    fn next_flush(&self, time: SystemTime) -> SystemTime {
+        let window = Duration::from_secs(self.config.window as u64);
+        next_flush(window, self.origin, self.jitter, time)
+    }
 
-        match hm.get_mut(owned.id()) {
-            Some(v) => {
-                v.add(owned.value(), owned.sample_rate());
+    /// The sub-window a time is in
+    fn sub_window(&self, time: SystemTime) -> u64 {
+        if self.sub_window.is_zero() {
+            return 0;
+        }
+        let since_origin = time.duration_since(self.origin).unwrap_or_default();
+        (since_origin.as_nanos() / self.sub_window.as_nanos()) as u64
+    }
+
+    /// Record a value under an Id, returning the output for the event: none
+    /// if it was aggregated, the events of an aggregate evicted for it, or
+    /// the event itself if it could not be aggregated.
+    fn record<V, N, U, E>(
+        &self,
+        keys: &Sharded<V>,
+        id: &Id,
+        limit: Option<usize>,
+        new: N,
+        update: U,
+        events: E,
+    ) -> Option<Output<'_>>
+    where
+        V: Merge,
+        N: FnOnce() -> V,
+        U: FnOnce(&mut V),
+        E: FnOnce(&Id, V, &mut SmallVec<[Event; 4]>),
+    {
+        let evict = self.config.key_overflow == KeyOverflow::Evict;
+        // Lines are aggregated into the sub-window they arrive in
+        let sub_window = self.sub_window(SystemTime::now());
+        let recorded = keys.record(sub_window, id, limit, evict, new, update);
+        let label = [id.mtype.name()];
+        if !matches!(recorded, Recorded::Untracked) {
+            self.aggregated_lines.inc(&label);
+        }
+        match recorded {
+            Recorded::Updated => None,
+            Recorded::Added => {
+                self.set_tracked(&label, keys);
+                None
             }
-            None => {
-                let mut timer = Timer::new(
-                    self.config
-                        .timer_reservoir_size
-                        .unwrap_or(DEFAULT_RESERVOIR),
-                );
-                timer.add(owned.value(), owned.sample_rate());
-                hm.insert(owned.id().clone(), timer);
+            Recorded::Evicted(evicted, value) => {
+                self.evicted_keys.inc(&label);
+                self.set_tracked(&label, keys);
+                let mut new_events = SmallVec::new();
+                events(&evicted, value, &mut new_events);
+                Some(Output {
+                    route: &self.route_to,
+                    new_events: Some(new_events),
+                })
+            }
+            Recorded::Untracked => {
+                self.untracked_lines.inc(&label);
+                Some(Output {
+                    route: &self.route_to,
+                    new_events: None,
+                })
             }
         }
     }
 
-    fn record_gauge(&self, owned: &Owned) {
-        let lock = self.gauges.lock();
-        let mut hm = lock.borrow_mut();
-        // Note: Using the entry API would make logical sense to avoid
-        // re-hashing the same Id on insert, however it costs more to
-        // clone the Id as the entry API does not allow for trait Clone
-        // key references and supporting lazy-cloning.
-        match hm.get_mut(owned.id()) {
-            Some(v) => v.value = owned.value(),
-            None => {
-                hm.insert(
-                    owned.id().clone(),
-                    Gauge {
-                        value: owned.value(),
-                    },
-                );
+    /// Set the gauge of aggregates tracked for a type
+    fn set_tracked<V>(&self, label: &[&str; 1], keys: &Sharded<V>) {
+        let mut tracked = keys.len.load(Ordering::Relaxed);
+        // Another line may have set a newer count before this one, so set
+        // again until the count is still current once set
+        loop {
+            self.tracked_keys.set(label, tracked as f64);
+            let current = keys.len.load(Ordering::Relaxed);
+            if current == tracked {
+                break;
             }
-        };
+            tracked = current;
+        }
+    }
+
+    fn record_timer(&self, owned: &Owned) -> Option<Output<'_>> {
+        self.record(
+            &self.timers,
+            owned.id(),
+            self.config.max_keys,
+            || {
+                Timer::new(
+                    self.config
+                        .timer_reservoir_size
+                        .unwrap_or(DEFAULT_RESERVOIR),
+                )
+            },
+            |timer| timer.add(owned.value(), owned.sample_rate()),
+            |id, timer, events| self.timer_events(id, timer, events),
+        )
     }
 
-    fn record_counter(&self, owned: &Owned) {
+    /// Record the last value of a gauge or direct gauge, or apply a change
+    /// to a gauge
+    fn record_gauge(
+        &self,
+        gauges: &Sharded<Gauge>,
+        owned: &Owned,
+        delta: bool,
+    ) -> Option<Output<'_>> {
+        self.record(
+            gauges,
+            owned.id(),
+            self.config.max_keys,
+            || Gauge {
+                value: 0_f64,
+                delta,
+            },
+            |gauge| gauge.update(owned.value(), delta),
+            |id, gauge, events| events.push(gauge.to_event(id)),
+        )
+    }
+
+    fn record_counter(&self, owned: &Owned) -> Option<Output<'_>> {
         // Adjust values based on sample rate. In the end, emission will
         // re-scale everything back to the sample rate.
         let (scaled, counts) = scale(owned.value(), owned.sample_rate());
+        self.record(
+            &self.counters,
+            owned.id(),
+            self.config.max_keys,
+            Counter::default,
+            |counter| {
+                counter.value += scaled;
+                counter.samples += counts;
+            },
+            |id, counter, events| events.push(counter.to_event(id)),
+        )
+    }
 
-        let lock = self.counters.lock();
-        let mut hm = lock.borrow_mut();
+    fn record_set(
+        &self,
+        id: &Id,
+        member: &[u8],
+        config: &config::processor::SamplerSets,
+    ) -> Option<Output<'_>> {
+        let limit = config
+            .max_sets
+            .or(self.config.max_keys)
+            .unwrap_or(DEFAULT_MAX_SETS);
+        self.record(
+            &self.sets,
+            id,
+            Some(limit),
+            || self.empty_set.clone(),
+            |set| set.add(member),
+            |id, set, events| events.push(set.to_event(id)),
+        )
+    }
 
-        match hm.get_mut(owned.id()) {
-            Some(v) => {
-                v.value += scaled;
-                v.samples += counts;
+    /// The events a timer's window is emitted as
+    fn timer_events(&self, id: &Id, timer: Timer, events: &mut SmallVec<[Event; 4]>) {
+        let output = self.config.timer_output;
+        if output != TimerOutput::Reservoir {
+            events.extend(timer.aggregates(id));
+        }
+        if !self.config.timer_percentiles.is_empty() {
+            events.extend(timer.percentiles(id, &self.config.timer_percentiles));
+        }
+        if output == TimerOutput::Aggregates {
+            return;
+        }
+        events.extend(timer.samples(id));
+    }
+
+    /// Emit and reset the values aggregated in sub-windows before `before`.
+    /// Callers must hold the next_flush lock.
+    fn emit(&self, backends: &Backends, before: u64) {
+        let started = Instant::now();
+        self.tracked_keys.reset();
+        let mut emitted = 0;
+        let mut send = |pdu: &Event| {
+            backends.provide_statsd(pdu, self.route_to.as_ref());
+            emitted += 1;
+        };
+
+        let gauges = self
+            .gauges
+            .take_each(before, |id, gauge| send(&gauge.to_event(&id)));
+        self.flushed_keys.set(&[Type::Gauge.name()], gauges as f64);
+
+        let counters = self
+            .counters
+            .take_each(before, |id, counter| send(&counter.to_event(&id)));
+        self.flushed_keys
+            .set(&[Type::Counter.name()], counters as f64);
+
+        let direct_gauges = self
+            .direct_gauges
+            .take_each(before, |id, gauge| send(&gauge.to_event(&id)));
+        self.flushed_keys
+            .set(&[Type::DirectGauge.name()], direct_gauges as f64);
+
+        let sets = self
+            .sets
+            .take_each(before, |id, set| send(&set.to_event(&id)));
+        self.flushed_keys.set(&[Type::Set.name()], sets as f64);
+
+        let mut events = SmallVec::new();
+        let timers = self.timers.take_each(before, |id, timer| {
+            if let Some(debug) = &self.debug_timers {
+                if debug.name.is_match(&id.name) {
+                    let samples: Vec<_> = timer.samples(&id).collect();
+                    backends.provide_statsd_slice(&samples, debug.route.as_ref());
+                    self.debug_lines.inc_by(samples.len() as f64);
+                }
             }
-            None => {
-                hm.insert(
-                    owned.id().clone(),
-                    Counter {
-                        value: scaled,
-                        samples: counts,
-                    },
-                );
+            self.timer_events(&id, timer, &mut events);
+            for pdu in events.drain(..) {
+                send(&pdu);
+            }
+        });
+        self.flushed_keys.set(&[Type::Timer.name()], timers as f64);
+
+        // Aggregates of sub-windows yet to end are still tracked
+        for (mtype, len) in [
+            (Type::Gauge, &self.gauges.len),
+            (Type::Counter, &self.counters.len),
+            (Type::DirectGauge, &self.direct_gauges.len),
+            (Type::Set, &self.sets.len),
+            (Type::Timer, &self.timers.len),
+        ]
+        .iter()
+        {
+            let tracked = len.load(Ordering::Relaxed);
+            if tracked > 0 {
+                self.tracked_keys.set(&[mtype.name()], tracked as f64);
             }
         }
+
+        self.emitted_lines.inc_by(emitted as f64);
+        self.flush_seconds.observe(started.elapsed().as_secs_f64());
     }
 }
 
 impl processors::Processor for Sampler {
+    fn aggregates(&self) -> bool {
+        true
+    }
+
     fn provide_statsd(&self, sample: &Event) -> Option<processors::Output> {
+        if let Some(sets) = &self.config.sets {
+            // Set members need not be numbers, so sets are not parsed
+            let pdu = match sample {
+                Event::Pdu(pdu) if pdu.pdu_type() == b"s" => Some(pdu.clone()),
+                Event::Parsed(owned) if owned.metric_type() == &Type::Set => Some(Pdu::from(owned)),
+                _ => None,
+            };
+            if let Some(pdu) = pdu {
+                return match (&pdu).try_into() {
+                    Ok(id) => self.record_set(&id, pdu.value(), sets),
+                    Err(_) => {
+                        self.processing_errors.inc();
+                        None
+                    }
+                };
+            }
+        }
         let owned: Result<Owned, _> = sample.try_into();
         match owned {
-            Err(_) => None,
-            Ok(owned) if owned.metric_type() == &Type::Timer => {
-                self.record_timer(&owned);
-                None
-            }
-            Ok(owned) if owned.metric_type() == &Type::Counter => {
-                self.record_counter(&owned);
+            Err(_) => {
+                self.processing_errors.inc();
                 None
             }
+            Ok(owned) if owned.metric_type() == &Type::Timer => self.record_timer(&owned),
+            Ok(owned) if owned.metric_type() == &Type::Counter => self.record_counter(&owned),
             Ok(owned) if owned.metric_type() == &Type::Gauge => {
-                self.record_gauge(&owned);
-                None
+                // Gauges written with a sign change the gauge, as for statsd
+                let delta = !self.config.overwrite_gauges
+                    && matches!(sample, Event::Pdu(pdu) if pdu.is_gauge_delta());
+                self.record_gauge(&self.gauges, &owned, delta)
+            }
+            // Direct gauges are set as they arrive, so are always passed
+            // through straight away, along with any gauge they evict
+            Ok(owned) if owned.metric_type() == &Type::DirectGauge => {
+                let evicted = if self.config.reemit_direct_gauges {
+                    self.record_gauge(&self.direct_gauges, &owned, false)
+                        .and_then(|output| output.new_events)
+                } else {
+                    None
+                };
+                Some(Output {
+                    route: &self.route_to,
+                    new_events: evicted.map(|mut events| {
+                        events.insert(0, sample.clone());
+                        events
+                    }),
+                })
             }
             Ok(_) => Some(Output {
                 route: &self.route_to,
@@ -217,41 +974,28 @@ impl processors::Processor for Sampler {
     }
 
     fn tick(&self, time: std::time::SystemTime, backends: &Backends) {
-        // Take a lock on the last flush, which guards all other flushes.
-        let flush_lock = self.last_flush.lock();
-        let earlier = *flush_lock.borrow();
-        match time.duration_since(earlier) {
-            Err(_) => {
-                return;
-            }
-            Ok(duration) if duration.as_secs() < self.config.window as u64 => {
-                return;
-            }
-            Ok(_) => (),
+        // Take a lock on the next flush, which guards all other flushes.
+        let flush_lock = self.next_flush.lock();
+        if time < *flush_lock.borrow() {
+            return;
         }
 
-        let mut gauges = self.gauges.lock().replace(HashMap::default());
-        for (id, gauge) in gauges.drain() {
-            let pdu = gauge.to_event(&id);
-            backends.provide_statsd(&pdu, self.route_to.as_ref())
-        }
-
-        let mut counters = self.counters.lock().replace(HashMap::default());
-        for (id, counter) in counters.drain() {
-            let pdu = counter.to_event(&id);
-            backends.provide_statsd(&pdu, self.route_to.as_ref());
-        }
-
-        let mut timers = self.timers.lock().replace(HashMap::default());
-        for (id, timer) in timers.drain() {
-            let sample_rate = timer.values.len() as f64 / timer.count;
-            for value in timer.values {
-                let pdu = Event::Parsed(Owned::new(id.clone(), value, Some(sample_rate)));
-                backends.provide_statsd(&pdu, self.route_to.as_ref());
-            }
-        }
+        // Only windows which have ended are emitted, so lines arriving
+        // during the jitter or since the tick wait for the next flush
+        let before = if self.sub_window.is_zero() {
+            u64::MAX
+        } else {
+            let ended = time.checked_sub(self.jitter).unwrap_or(time);
+            self.sub_window(ended) / self.sub_windows * self.sub_windows
+        };
+        self.emit(backends, before);
+        flush_lock.replace(self.next_flush(time));
+    }
 
-        flush_lock.replace(time);
+    fn flush(&self, time: std::time::SystemTime, backends: &Backends) {
+        let flush_lock = self.next_flush.lock();
+        self.emit(backends, u64::MAX);
+        flush_lock.replace(self.next_flush(time));
     }
 }
 
@@ -259,6 +1003,26 @@ impl processors::Processor for Sampler {
 pub mod test {
     use super::*;
 
+    pub fn sampler_config(route: Vec<config::Route>) -> config::processor::Sampler {
+        config::processor::Sampler {
+            window: 3600,
+            align_to_window: false,
+            flush_jitter_ms: None,
+            timer_reservoir_size: None,
+            timer_output: TimerOutput::Reservoir,
+            timer_percentiles: vec![],
+            sets: None,
+            reemit_direct_gauges: false,
+            max_keys: None,
+            key_overflow: KeyOverflow::PassThrough,
+            shards: None,
+            sub_windows: None,
+            overwrite_gauges: false,
+            debug_timers: None,
+            route,
+        }
+    }
+
     #[test]
     fn fill_timer() {
         let mut timer = Timer::new(100);
@@ -269,5 +1033,566 @@ pub mod test {
         assert_eq!(timer.count, 200_f64);
         assert_eq!(timer.sum, 19900_f64);
         assert_eq!(timer.values.len(), 100);
+        assert_eq!(timer.lower, 0_f64);
+        assert_eq!(timer.upper, 199_f64);
+    }
+
+    #[test]
+    fn timer_aggregates() {
+        use crate::processors::Processor;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &config::processor::Sampler {
+                timer_output: TimerOutput::Aggregates,
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        for line in ["foo:10|ms|#a:b", "foo:20|ms|#a:b", "foo:60|ms|#a:b"] {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            sampler.provide_statsd(&Event::Pdu(pdu));
+        }
+        sampler.flush(std::time::SystemTime::now(), &backends);
+
+        let mut lines: Vec<Vec<u8>> = capture
+            .events
+            .lock()
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        lines.sort();
+        let expected: Vec<&[u8]> = vec![
+            b"foo.count:3.0|c|#a:b",
+            b"foo.lower:10.0|g|#a:b",
+            b"foo.mean:30.0|g|#a:b",
+            b"foo.sum:90.0|c|#a:b",
+            b"foo.upper:60.0|g|#a:b",
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn debug_timers() {
+        use crate::processors::test::Capture;
+        use crate::processors::Processor;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let debug = Capture::default();
+        backends
+            .replace_processor("debug", Box::new(debug.clone()))
+            .unwrap();
+        let sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &config::processor::Sampler {
+                timer_output: TimerOutput::Aggregates,
+                debug_timers: Some(config::processor::SamplerDebugTimers {
+                    name: "^api\\.".to_owned(),
+                    route: vec![config::Route {
+                        route_type: config::RouteType::Processor,
+                        route_to: "debug".to_owned(),
+                    }],
+                }),
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        for line in ["api.latency:10|ms", "api.latency:20|ms", "db.latency:5|ms"] {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            sampler.provide_statsd(&Event::Pdu(pdu));
+        }
+        sampler.flush(std::time::SystemTime::now(), &backends);
+
+        let mut lines: Vec<Vec<u8>> = debug
+            .events
+            .lock()
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        lines.sort();
+        let expected: Vec<&[u8]> = vec![b"api.latency:10.0|ms|@1.0", b"api.latency:20.0|ms|@1.0"];
+        assert_eq!(lines, expected);
+        assert_eq!(sampler.debug_lines.get(), 2_f64);
+        // Aggregates are still emitted for every timer
+        assert_eq!(capture.events.lock().len(), 10);
+    }
+
+    fn empty_set(exact_limit: usize) -> Set {
+        Set::new(exact_limit, Arc::new(HyperLogLog::new(SET_ERROR_RATE)))
+    }
+
+    #[test]
+    fn merge_timers() {
+        let mut timer = Timer::new(100);
+        let mut later = Timer::new(100);
+        for x in 0..200 {
+            timer.add(x as f64, None);
+            later.add((x + 200) as f64, None);
+        }
+        timer.merge(later);
+        assert_eq!(timer.filled_count, 400);
+        assert_eq!(timer.count, 400_f64);
+        assert_eq!(timer.sum, 79800_f64);
+        assert_eq!(timer.values.len(), 100);
+        assert_eq!(timer.lower, 0_f64);
+        assert_eq!(timer.upper, 399_f64);
+    }
+
+    #[test]
+    fn set_members() {
+        let mut set = empty_set(10);
+        for member in 0..10 {
+            set.add(member.to_string().as_bytes());
+            set.add(member.to_string().as_bytes());
+        }
+        assert_eq!(set.len(), 10_f64);
+        assert!(matches!(set.members, Members::Exact(_)));
+
+        // Past the exact limit, members are counted approximately
+        for member in 0..1000 {
+            set.add(member.to_string().as_bytes());
+        }
+        assert!(matches!(set.members, Members::Estimated(_)));
+        assert!((950_f64..1050_f64).contains(&set.len()), "{}", set.len());
+
+        // Sets of different sub-windows merge, exactly or not
+        let mut later = set.clone();
+        for member in 1000..2000 {
+            later.add(member.to_string().as_bytes());
+        }
+        let mut earlier = empty_set(10);
+        earlier.add(b"a");
+        earlier.merge(later);
+        earlier.merge(set);
+        assert!(
+            (1900_f64..2100_f64).contains(&earlier.len()),
+            "{}",
+            earlier.len()
+        );
+    }
+
+    #[test]
+    fn aggregate_sets() {
+        use crate::processors::Processor;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &config::processor::Sampler {
+                sets: Some(config::processor::SamplerSets {
+                    exact_limit: None,
+                    max_sets: Some(1),
+                }),
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        for line in ["users:bob|s|#a:b", "users:alice|s|#a:b", "users:bob|s|#a:b"] {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            assert!(sampler.provide_statsd(&Event::Pdu(pdu)).is_none());
+        }
+        // Sets beyond max_sets are passed through
+        let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from("hosts:a|s")).unwrap();
+        assert!(sampler.provide_statsd(&Event::Pdu(pdu)).is_some());
+
+        sampler.flush(std::time::SystemTime::now(), &backends);
+        let lines: Vec<Vec<u8>> = capture
+            .events
+            .lock()
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        assert_eq!(lines, vec![b"users:2.0|g|#a:b".to_vec()]);
+    }
+
+    #[test]
+    fn gauge_deltas() {
+        use crate::processors::Processor;
+
+        let gauges = |overwrite_gauges| {
+            let (backends, capture, route) = crate::processors::test::capture_backends();
+            let sampler = Sampler::new(
+                crate::stats::Collector::default().scope("test"),
+                &config::processor::Sampler {
+                    overwrite_gauges,
+                    ..sampler_config(route)
+                },
+            )
+            .unwrap();
+            for line in [
+                "temp:10|g",
+                "temp:+5|g",
+                "temp:-3|g",
+                "load:+2|g",
+                "load:+3|g",
+            ] {
+                let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+                assert!(sampler.provide_statsd(&Event::Pdu(pdu)).is_none());
+            }
+            sampler.flush(SystemTime::now(), &backends);
+            let mut lines: Vec<Vec<u8>> = capture
+                .events
+                .lock()
+                .iter()
+                .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+                .collect();
+            lines.sort();
+            lines
+        };
+
+        // Changes without a value to apply them to are passed on as a change
+        let expected: Vec<&[u8]> = vec![b"load:+5.0|g", b"temp:12.0|g"];
+        assert_eq!(gauges(false), expected);
+        let expected: Vec<&[u8]> = vec![b"load:3.0|g", b"temp:-3.0|g"];
+        assert_eq!(gauges(true), expected);
+    }
+
+    #[test]
+    fn reemit_direct_gauges() {
+        use crate::processors::Processor;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &config::processor::Sampler {
+                reemit_direct_gauges: true,
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        for line in ["temp:20|G", "temp:21|G"] {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            assert!(sampler.provide_statsd(&Event::Pdu(pdu)).is_some());
+        }
+
+        // Only the last value is re-emitted, once per window
+        let now = std::time::SystemTime::now();
+        sampler.flush(now, &backends);
+        sampler.flush(now, &backends);
+        let lines: Vec<Vec<u8>> = capture
+            .events
+            .lock()
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        assert_eq!(lines, vec![b"temp:21.0|G".to_vec()]);
+    }
+
+    #[test]
+    fn reemit_evicted_direct_gauges() {
+        use crate::processors::Processor;
+
+        let (_backends, _capture, route) = crate::processors::test::capture_backends();
+        let sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &config::processor::Sampler {
+                reemit_direct_gauges: true,
+                max_keys: Some(1),
+                key_overflow: KeyOverflow::Evict,
+                shards: Some(1),
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        let record = |line: &'static str| -> Option<Vec<Vec<u8>>> {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            let output = sampler.provide_statsd(&Event::Pdu(pdu)).unwrap();
+            output.new_events.map(|events| {
+                events
+                    .iter()
+                    .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+                    .collect()
+            })
+        };
+
+        assert_eq!(record("temp:20|G"), None);
+        // Evicting a gauge sends it on after the line which evicted it
+        assert_eq!(
+            record("load:3|G"),
+            Some(vec![b"load:3|G".to_vec(), b"temp:20.0|G".to_vec()])
+        );
+        assert_eq!(record("load:4|G"), None);
+    }
+
+    #[test]
+    fn timer_percentiles() {
+        assert_eq!(percentile_suffix(99_f64), ".p99");
+        assert_eq!(percentile_suffix(99.9), ".p99_9");
+
+        let mut timer = Timer::new(100);
+        for x in 1..=10 {
+            timer.add(x as f64, None);
+        }
+        let id = Id {
+            name: b"foo".to_vec(),
+            mtype: Type::Timer,
+            tags: vec![],
+        };
+        let lines: Vec<Vec<u8>> = timer
+            .percentiles(&id, &[50_f64, 90_f64, 99.9])
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        let expected: Vec<&[u8]> = vec![b"foo.p50:5.0|g", b"foo.p90:9.0|g", b"foo.p99_9:10.0|g"];
+        assert_eq!(lines, expected);
+
+        let mut empty = Timer::new(0);
+        empty.add(1_f64, None);
+        assert!(empty.percentiles(&id, &[50_f64]).is_empty());
+    }
+
+    #[test]
+    fn max_keys() {
+        use crate::processors::Processor;
+
+        let counter = |name: &str| {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(format!("{}:1|c", name)));
+            Event::Pdu(pdu.unwrap())
+        };
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let scope = crate::stats::Collector::default().scope("test");
+        let sampler = Sampler::new(
+            scope.clone(),
+            &config::processor::Sampler {
+                max_keys: Some(2),
+                ..sampler_config(route.clone())
+            },
+        )
+        .unwrap();
+        assert!(sampler.provide_statsd(&counter("a")).is_none());
+        assert!(sampler.provide_statsd(&counter("b")).is_none());
+        // Further metrics are passed through as they are
+        let output = sampler.provide_statsd(&counter("c")).unwrap();
+        assert!(output.new_events.is_none());
+        assert!(sampler.provide_statsd(&counter("a")).is_none());
+        let untracked = scope.counter_vec("untracked_lines", &["type"]).unwrap();
+        assert_eq!(untracked.get(&["counter"]), 1_f64);
+        let tracked = scope.gauge_vec("tracked_keys", &["type"]).unwrap();
+        assert_eq!(tracked.get(&["counter"]), 2_f64);
+
+        let scope = crate::stats::Collector::default().scope("test");
+        let sampler = Sampler::new(
+            scope.clone(),
+            &config::processor::Sampler {
+                max_keys: Some(2),
+                key_overflow: KeyOverflow::Evict,
+                // Ids are evicted from the shard of the Id needing room
+                shards: Some(1),
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        sampler.provide_statsd(&counter("a"));
+        sampler.provide_statsd(&counter("b"));
+        sampler.provide_statsd(&counter("a"));
+        // The least recently seen metric is emitted to make room
+        let output = sampler.provide_statsd(&counter("c")).unwrap();
+        let evicted: Vec<Vec<u8>> = output
+            .new_events
+            .unwrap()
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        assert_eq!(evicted, vec![b"b:1.0|c|@1.0".to_vec()]);
+        let evictions = scope.counter_vec("evicted_keys", &["type"]).unwrap();
+        assert_eq!(evictions.get(&["counter"]), 1_f64);
+
+        sampler.flush(std::time::SystemTime::now(), &backends);
+        let mut lines: Vec<Vec<u8>> = capture
+            .events
+            .lock()
+            .iter()
+            .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![b"a:1.0|c|@0.5".to_vec(), b"c:1.0|c|@1.0".to_vec()]
+        );
+    }
+
+    #[test]
+    fn concurrent_records() {
+        use crate::processors::Processor;
+        use std::sync::Arc;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let scope = crate::stats::Collector::default().scope("test");
+        let sampler = Arc::new(Sampler::new(scope.clone(), &sampler_config(route)).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let sampler = sampler.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        let line = format!("metric.{}:1|c", i % 10);
+                        let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line));
+                        sampler.provide_statsd(&Event::Pdu(pdu.unwrap()));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let tracked = scope.gauge_vec("tracked_keys", &["type"]).unwrap();
+        assert_eq!(tracked.get(&["counter"]), 10_f64);
+        let aggregated = scope.counter_vec("aggregated_lines", &["type"]).unwrap();
+        assert_eq!(aggregated.get(&["counter"]), 4000_f64);
+
+        sampler.flush(std::time::SystemTime::now(), &backends);
+        let flushed = scope.gauge_vec("flushed_keys", &["type"]).unwrap();
+        assert_eq!(flushed.get(&["counter"]), 10_f64);
+        assert_eq!(scope.counter("emitted_lines").unwrap().get(), 10_f64);
+        let flush_seconds = scope
+            .histogram("flush_seconds", FLUSH_SECONDS_BUCKETS)
+            .unwrap();
+        assert_eq!(flush_seconds.count(), 1);
+        let events = capture.events.lock();
+        assert_eq!(events.len(), 10);
+        for event in events.iter() {
+            let owned: Owned = event.try_into().unwrap();
+            assert_eq!(owned.sample_rate(), Some(1_f64 / 400_f64));
+        }
+    }
+
+    #[test]
+    fn aligned_flushes() {
+        let window = Duration::from_secs(10);
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        let jitter = Duration::from_secs(2);
+        let aligned = |jitter, time| next_flush(window, UNIX_EPOCH, jitter, time);
+        assert_eq!(aligned(jitter, at(125_000)), at(132_000));
+        assert_eq!(aligned(jitter, at(131_500)), at(132_000));
+        assert_eq!(aligned(jitter, at(132_000)), at(142_000));
+        assert_eq!(aligned(Duration::ZERO, at(125_000)), at(130_000));
+
+        // Other windows are counted from when the sampler started
+        assert_eq!(
+            next_flush(window, at(1_500), Duration::ZERO, at(125_000)),
+            at(131_500)
+        );
+    }
+
+    #[test]
+    fn sub_windows() {
+        use crate::processors::Processor;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let mut sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &config::processor::Sampler {
+                window: 10,
+                sub_windows: Some(2),
+                sets: Some(config::processor::SamplerSets {
+                    exact_limit: None,
+                    max_sets: None,
+                }),
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        let record = |sampler: &Sampler, line: &'static str| {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            assert!(sampler.provide_statsd(&Event::Pdu(pdu)).is_none());
+        };
+        let lines = || {
+            let mut lines: Vec<Vec<u8>> = capture
+                .events
+                .lock()
+                .drain(..)
+                .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+                .collect();
+            lines.sort();
+            lines
+        };
+        let now = SystemTime::now();
+
+        // Lines of both sub-windows of a window are merged
+        sampler.origin = now - Duration::from_secs(1);
+        for line in ["foo:1|c", "temp:1|g", "users:a|s"] {
+            record(&sampler, line);
+        }
+        sampler.origin = now - Duration::from_secs(6);
+        for line in ["foo:3|c", "temp:2|g", "users:b|s"] {
+            record(&sampler, line);
+        }
+        // Lines after the end of the window wait for the next flush
+        sampler.origin = now - Duration::from_secs(11);
+        record(&sampler, "bar:1|c");
+
+        sampler.next_flush.lock().replace(now);
+        sampler.tick(now, &backends);
+        let expected: Vec<&[u8]> = vec![b"foo:2.0|c|@0.5", b"temp:2.0|g", b"users:2.0|g"];
+        assert_eq!(lines(), expected);
+
+        sampler.flush(now, &backends);
+        assert_eq!(lines(), vec![b"bar:1.0|c|@1.0".to_vec()]);
+    }
+
+    #[test]
+    fn evict_across_sub_windows() {
+        use crate::processors::Processor;
+
+        let (_backends, _capture, route) = crate::processors::test::capture_backends();
+        let scope = crate::stats::Collector::default().scope("test");
+        let mut sampler = Sampler::new(
+            scope.clone(),
+            &config::processor::Sampler {
+                window: 10,
+                sub_windows: Some(2),
+                max_keys: Some(2),
+                key_overflow: KeyOverflow::Evict,
+                shards: Some(1),
+                ..sampler_config(route)
+            },
+        )
+        .unwrap();
+        let record = |sampler: &Sampler, line: &'static str| -> Vec<Vec<u8>> {
+            let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from(line)).unwrap();
+            sampler
+                .provide_statsd(&Event::Pdu(pdu))
+                .and_then(|output| output.new_events)
+                .into_iter()
+                .flatten()
+                .map(|event| crate::statsd_proto::Pdu::from(event).as_bytes().to_vec())
+                .collect()
+        };
+        let now = SystemTime::now();
+
+        sampler.origin = now - Duration::from_secs(1);
+        assert!(record(&sampler, "a:1|c").is_empty());
+        assert!(record(&sampler, "b:1|c").is_empty());
+        // The cap holds across sub-windows, so the first line of a new
+        // sub-window evicts from the earlier one
+        sampler.origin = now - Duration::from_secs(6);
+        assert_eq!(record(&sampler, "c:1|c"), vec![b"a:1.0|c|@1.0".to_vec()]);
+        assert_eq!(record(&sampler, "a:1|c"), vec![b"b:1.0|c|@1.0".to_vec()]);
+        assert!(record(&sampler, "c:1|c").is_empty());
+        let tracked = scope.gauge_vec("tracked_keys", &["type"]).unwrap();
+        assert_eq!(tracked.get(&["counter"]), 2_f64);
+        let evictions = scope.counter_vec("evicted_keys", &["type"]).unwrap();
+        assert_eq!(evictions.get(&["counter"]), 2_f64);
+    }
+
+    #[test]
+    fn flush_ignores_window() {
+        use crate::processors::Processor;
+
+        let (backends, capture, route) = crate::processors::test::capture_backends();
+        let sampler = Sampler::new(
+            crate::stats::Collector::default().scope("test"),
+            &sampler_config(route),
+        )
+        .unwrap();
+        let pdu = crate::statsd_proto::Pdu::parse(bytes::Bytes::from_static(b"foo:1|c")).unwrap();
+        sampler.provide_statsd(&Event::Pdu(pdu));
+
+        // A tick inside the window emits nothing, but a flush always emits
+        let now = std::time::SystemTime::now();
+        sampler.tick(now, &backends);
+        assert!(capture.events.lock().is_empty());
+        sampler.flush(now, &backends);
+        assert_eq!(capture.events.lock().len(), 1);
     }
 }
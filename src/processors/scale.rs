@@ -0,0 +1,108 @@
+use smallvec::smallvec;
+
+use super::{name_and_type, parse, processing_errors, Error, Output, Processor, Selector};
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Owned, Parsed};
+
+struct Rule {
+    selector: Selector,
+    factor: f64,
+}
+
+/// Multiplies metric values by a factor, such as to convert timers reported
+/// in nanoseconds to milliseconds.
+pub struct Scale {
+    rules: Vec<Rule>,
+    route: Vec<Route>,
+
+    counter_processing_errors: stats::Counter,
+}
+
+impl Scale {
+    pub fn new(scope: stats::Scope, from_config: &processor::Scale) -> Result<Self, Error> {
+        let rules = from_config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    selector: Selector::new(rule.name.as_deref(), &rule.types)?,
+                    factor: rule.factor,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Scale {
+            rules,
+            route: from_config.route.clone(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+}
+
+impl Processor for Scale {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let (name, mtype) = name_and_type(event);
+        let rule = match self
+            .rules
+            .iter()
+            .find(|rule| rule.selector.matches(name, mtype))
+        {
+            Some(rule) => rule,
+            None => {
+                return Some(Output {
+                    new_events: None,
+                    route: self.route.as_ref(),
+                })
+            }
+        };
+        let owned = parse(event, &self.counter_processing_errors)?;
+        let scaled = Owned::new(
+            owned.id().clone(),
+            owned.value() * rule.factor,
+            owned.sample_rate(),
+        );
+        Some(Output {
+            new_events: Some(smallvec![Event::Parsed(scaled)]),
+            route: self.route.as_ref(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use std::convert::TryInto;
+
+    #[test]
+    fn scale_values() {
+        let scale = Scale::new(
+            stats::Collector::default().scope("scale"),
+            &processor::Scale {
+                rules: vec![processor::ScaleRule {
+                    name: Some(r"_ns$".to_owned()),
+                    types: vec!["timer".to_owned()],
+                    factor: 0.000001,
+                }],
+                route: vec![],
+            },
+        )
+        .unwrap();
+        let scaled = |line: &'static str| {
+            let event = event(line);
+            scale
+                .provide_statsd(&event)
+                .unwrap()
+                .new_events
+                .map(|events| {
+                    let owned: Owned = (&events[0]).try_into().unwrap();
+                    owned.value()
+                })
+        };
+        assert_eq!(scaled("latency_ns:2500000|ms"), Some(2.5));
+        assert_eq!(scaled("latency_ns:2500000|c"), None);
+        assert_eq!(scaled("latency:25|ms"), None);
+    }
+}
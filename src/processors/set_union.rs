@@ -0,0 +1,233 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+
+use super::{processing_errors, Error, Output, Processor, Selector};
+use crate::backends::Backends;
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Parsed, Pdu, Type};
+
+/// The members of a set seen this window, along with a line of the set
+/// they are emitted as
+struct Union {
+    template: Pdu,
+    members: HashSet<Vec<u8>>,
+}
+
+struct Unions {
+    sets: HashMap<Id, Union>,
+    members: usize,
+    next_flush: SystemTime,
+}
+
+/// Accumulates the members of sets over a window, emitting each distinct
+/// member once per window, so downstreams receive one line per member rather
+/// than one from every frontend which saw it.
+pub struct SetUnion {
+    selector: Selector,
+    window: Duration,
+    max_members: usize,
+    unions: Mutex<Unions>,
+    route: Vec<Route>,
+
+    counter_unioned: stats::Counter,
+    counter_untracked: stats::Counter,
+    counter_emitted: stats::Counter,
+    counter_processing_errors: stats::Counter,
+}
+
+impl SetUnion {
+    pub fn new(scope: stats::Scope, from_config: &processor::SetUnion) -> Result<Self, Error> {
+        let window = Duration::from_secs(from_config.window as u64);
+        Ok(SetUnion {
+            selector: Selector::new(from_config.name.as_deref(), &[])?,
+            window,
+            max_members: from_config.max_members,
+            unions: Mutex::new(Unions {
+                sets: HashMap::new(),
+                members: 0,
+                next_flush: SystemTime::now() + window,
+            }),
+            route: from_config.route.clone(),
+            counter_unioned: scope.counter("unioned_lines").unwrap(),
+            counter_untracked: scope.counter("untracked_lines").unwrap(),
+            counter_emitted: scope.counter("emitted_members").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        })
+    }
+
+    /// Add a member to its set, returning whether it was held
+    fn record(&self, id: Id, pdu: &Pdu) -> bool {
+        let mut unions = self.unions.lock();
+        let unions = &mut *unions;
+        let full = unions.members >= self.max_members;
+        let union = match unions.sets.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if full => return false,
+            Entry::Vacant(entry) => entry.insert(Union {
+                template: pdu.without_sample_rate(),
+                members: HashSet::new(),
+            }),
+        };
+        if union.members.contains(pdu.value()) {
+            return true;
+        }
+        if full {
+            return false;
+        }
+        union.members.insert(pdu.value().to_vec());
+        unions.members += 1;
+        true
+    }
+
+    /// Take the members of every set, as the lines they are emitted as
+    fn take(&self, next_flush: SystemTime) -> Vec<Event> {
+        let sets = {
+            let mut unions = self.unions.lock();
+            unions.members = 0;
+            unions.next_flush = next_flush;
+            std::mem::take(&mut unions.sets)
+        };
+        let events: Vec<Event> = sets
+            .into_values()
+            .flat_map(|union| {
+                let template = union.template;
+                union
+                    .members
+                    .into_iter()
+                    .map(move |member| Event::Pdu(template.with_value(&member)))
+            })
+            .collect();
+        self.counter_emitted.inc_by(events.len() as f64);
+        events
+    }
+
+    fn emit(&self, time: SystemTime, backends: &Backends) {
+        let events = self.take(time + self.window);
+        if !events.is_empty() {
+            backends.provide_statsd_slice(&events, self.route.as_ref());
+        }
+    }
+}
+
+impl Processor for SetUnion {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let output = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let pdu = match event {
+            Event::Pdu(pdu) if pdu.pdu_type() == b"s" => pdu.clone(),
+            Event::Parsed(parsed) if parsed.metric_type() == &Type::Set => Pdu::from(parsed),
+            _ => return Some(output),
+        };
+        if !self.selector.matches(pdu.name(), Some(Type::Set)) {
+            return Some(output);
+        }
+        let id = match Id::try_from(&pdu) {
+            Ok(id) => id,
+            Err(_) => {
+                self.counter_processing_errors.inc();
+                return Some(output);
+            }
+        };
+        if !self.record(id, &pdu) {
+            self.counter_untracked.inc();
+            return Some(output);
+        }
+        self.counter_unioned.inc();
+        None
+    }
+
+    fn aggregates(&self) -> bool {
+        true
+    }
+
+    fn tick(&self, time: SystemTime, backends: &Backends) {
+        if time < self.unions.lock().next_flush {
+            return;
+        }
+        self.emit(time, backends);
+    }
+
+    fn flush(&self, time: SystemTime, backends: &Backends) {
+        self.emit(time, backends);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::capture_backends;
+    use crate::processors::test::event;
+
+    fn set_union(max_members: usize) -> SetUnion {
+        SetUnion::new(
+            stats::Collector::default().scope("u"),
+            &processor::SetUnion {
+                window: 10,
+                name: Some("^users".to_owned()),
+                max_members,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn union_each_window() {
+        let (backends, capture, route) = capture_backends();
+        let union = SetUnion {
+            route,
+            ..set_union(10)
+        };
+        for line in [
+            "users:alice|s|#app:a",
+            "users:bob|s|#app:a",
+            "users:alice|s|#app:a|@0.5",
+            "users:alice|s|#app:b",
+        ] {
+            assert!(union.provide_statsd(&event(line)).is_none());
+        }
+        // Other sets and types pass through
+        assert!(union.provide_statsd(&event("visits:alice|s")).is_some());
+        assert!(union.provide_statsd(&event("users:1|c")).is_some());
+
+        let now = SystemTime::now();
+        union.tick(now, &backends);
+        assert!(capture.events.lock().is_empty());
+        union.tick(now + Duration::from_secs(11), &backends);
+        let mut lines: Vec<Vec<u8>> = capture
+            .events
+            .lock()
+            .iter()
+            .map(|event| Pdu::from(event).as_bytes().to_vec())
+            .collect();
+        lines.sort();
+        let expected: Vec<&[u8]> = vec![
+            b"users:alice|s|#app:a",
+            b"users:alice|s|#app:b",
+            b"users:bob|s|#app:a",
+        ];
+        assert_eq!(lines, expected);
+        assert_eq!(union.counter_unioned.get(), 4_f64);
+        assert_eq!(union.counter_emitted.get(), 3_f64);
+    }
+
+    #[test]
+    fn max_members() {
+        let union = set_union(2);
+        for line in ["users:a|s", "users:b|s", "users:a|s"] {
+            assert!(union.provide_statsd(&event(line)).is_none());
+        }
+        assert!(union.provide_statsd(&event("users:c|s")).is_some());
+        assert!(union.provide_statsd(&event("users:a|s|#x:y")).is_some());
+        assert_eq!(union.counter_untracked.get(), 2_f64);
+    }
+}
@@ -0,0 +1,122 @@
+use super::{Output, Processor};
+use crate::config::processor;
+use crate::config::Route;
+use crate::shard::id_percentile;
+use crate::stats;
+use crate::statsd_proto::{Event, Pdu};
+
+struct Split {
+    /// Percentile of metrics up to which metrics are in this split
+    until: f64,
+    route: Vec<Route>,
+    label: String,
+}
+
+/// Splits metrics among route lists by percentage, consistently sending each
+/// metric to the same routes.
+pub struct Splitter {
+    splits: Vec<Split>,
+    route: Vec<Route>,
+
+    counter_lines: stats::CounterVec,
+}
+
+impl Splitter {
+    pub fn new(scope: stats::Scope, from_config: &processor::Splitter) -> Self {
+        let mut until = 0_f64;
+        let splits = from_config
+            .splits
+            .iter()
+            .enumerate()
+            .map(|(index, split)| {
+                until += split.percent;
+                Split {
+                    until,
+                    route: split.route.clone(),
+                    label: index.to_string(),
+                }
+            })
+            .collect();
+        Splitter {
+            splits,
+            route: from_config.route.clone(),
+            counter_lines: scope.counter_vec("split_lines", &["split"]).unwrap(),
+        }
+    }
+}
+
+impl Processor for Splitter {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let percentile = match event {
+            Event::Pdu(pdu) => id_percentile(pdu),
+            Event::Parsed(parsed) => id_percentile(&Pdu::from(parsed)),
+        };
+        let route = match self.splits.iter().find(|split| percentile < split.until) {
+            Some(split) => {
+                self.counter_lines.inc(&[split.label.as_str()]);
+                split.route.as_ref()
+            }
+            None => {
+                self.counter_lines.inc(&["none"]);
+                self.route.as_ref()
+            }
+        };
+        Some(Output {
+            new_events: None,
+            route,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::config::RouteType;
+
+    fn route(to: &str) -> Vec<Route> {
+        vec![Route {
+            route_type: RouteType::Statsd,
+            route_to: to.to_owned(),
+        }]
+    }
+
+    #[test]
+    fn split_by_percent() {
+        let config = processor::Splitter {
+            splits: vec![
+                processor::Split {
+                    percent: 10_f64,
+                    route: route("a"),
+                },
+                processor::Split {
+                    percent: 40_f64,
+                    route: route("b"),
+                },
+            ],
+            route: route("rest"),
+        };
+        let sink = stats::Collector::default();
+        let splitter = Splitter::new(sink.scope("s"), &config);
+        let events: Vec<Event> = (0..10000)
+            .map(|val| {
+                Event::Pdu(Pdu::parse(bytes::Bytes::from(format!("metric.{}:1|c", val))).unwrap())
+            })
+            .collect();
+        for event in events.iter() {
+            let first = splitter.provide_statsd(event).unwrap().route;
+            // Metrics are always sent to the same split
+            assert_eq!(first, splitter.provide_statsd(event).unwrap().route);
+        }
+
+        let near = |count: f64, expected: f64| (count - expected).abs() < expected * 0.1;
+        let lines = &splitter.counter_lines;
+        assert!(near(lines.get(&["0"]), 2000_f64), "{}", lines.get(&["0"]));
+        assert!(near(lines.get(&["1"]), 8000_f64), "{}", lines.get(&["1"]));
+        assert!(
+            near(lines.get(&["none"]), 10000_f64),
+            "{}",
+            lines.get(&["none"])
+        );
+    }
+}
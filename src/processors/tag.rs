@@ -1,35 +1,35 @@
 use crate::config;
 use crate::processors;
+use crate::stats;
 use crate::statsd_proto;
 use crate::statsd_proto::Event;
-use std::convert::TryInto;
 
 use smallvec::smallvec;
 
 pub struct Normalizer {
     route: Vec<config::Route>,
+
+    counter_processing_errors: stats::Counter,
 }
 
 impl Normalizer {
-    pub fn new(route: &[config::Route]) -> Self {
+    pub fn new(scope: stats::Scope, route: &[config::Route]) -> Self {
         Normalizer {
             route: route.to_vec(),
+            counter_processing_errors: processors::processing_errors(&scope),
         }
     }
 }
 
 impl processors::Processor for Normalizer {
     fn provide_statsd(&self, sample: &Event) -> Option<processors::Output> {
-        let owned: Result<statsd_proto::Owned, _> = sample.try_into();
-        owned
-            .map(|inp| {
-                let out = statsd_proto::convert::to_inline_tags(inp);
-                processors::Output {
-                    new_events: Some(smallvec![Event::Parsed(out)]),
-                    route: self.route.as_ref(),
-                }
-            })
-            .ok()
+        processors::parse(sample, &self.counter_processing_errors).map(|inp| {
+            let out = statsd_proto::convert::to_inline_tags(inp);
+            processors::Output {
+                new_events: Some(smallvec![Event::Parsed(out)]),
+                route: self.route.as_ref(),
+            }
+        })
     }
 }
 
@@ -37,6 +37,7 @@ impl processors::Processor for Normalizer {
 pub mod test {
     use processors::Processor;
     use statsd_proto::Parsed;
+    use std::convert::TryInto;
 
     use super::*;
 
@@ -47,7 +48,7 @@ pub mod test {
             route_to: "null".to_string(),
         }];
 
-        let tn = Normalizer::new(&route);
+        let tn = Normalizer::new(stats::Collector::default().scope("tag"), &route);
         let pdu =
             statsd_proto::Pdu::parse(bytes::Bytes::from_static(b"foo.bar:3|c|#tags:value|@1.0"))
                 .unwrap();
@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+
+use smallvec::smallvec;
+
+use super::{parse, processing_errors, Output, Processor};
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Tag};
+use crate::{config::processor, config::Route};
+
+/// Keeps only allowed tag keys, and removes denied tag keys, from metrics.
+pub struct TagFilter {
+    keep: Option<HashSet<Vec<u8>>>,
+    remove: HashSet<Vec<u8>>,
+    route: Vec<Route>,
+
+    counter_removed: stats::Counter,
+    counter_processing_errors: stats::Counter,
+}
+
+fn key_set(keys: &[String]) -> HashSet<Vec<u8>> {
+    keys.iter().map(|key| key.as_bytes().to_vec()).collect()
+}
+
+impl TagFilter {
+    pub fn new(scope: stats::Scope, from_config: &processor::TagFilter) -> Self {
+        TagFilter {
+            keep: from_config.keep.as_deref().map(key_set),
+            remove: from_config
+                .remove
+                .as_deref()
+                .map(key_set)
+                .unwrap_or_default(),
+            route: from_config.route.clone(),
+            counter_removed: scope.counter("removed_tags").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        }
+    }
+
+    fn allowed(&self, tag: &Tag) -> bool {
+        self.keep
+            .as_ref()
+            .is_none_or(|keep| keep.contains(&tag.name))
+            && !self.remove.contains(&tag.name)
+    }
+}
+
+impl Processor for TagFilter {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let unchanged = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        // Avoid parsing samples which can't have tags
+        if let Event::Pdu(pdu) = event {
+            if pdu.tags().is_none() {
+                return Some(unchanged);
+            }
+        }
+        let owned = parse(event, &self.counter_processing_errors)?;
+        if owned.tags().iter().all(|tag| self.allowed(tag)) {
+            return Some(unchanged);
+        }
+        let tags: Vec<Tag> = owned
+            .tags()
+            .iter()
+            .filter(|tag| self.allowed(tag))
+            .cloned()
+            .collect();
+        self.counter_removed
+            .inc_by((owned.tags().len() - tags.len()) as f64);
+        let id = Id {
+            name: owned.name().to_vec(),
+            mtype: *owned.metric_type(),
+            tags,
+        };
+        Some(Output {
+            new_events: Some(smallvec![Event::Parsed(Owned::new(
+                id,
+                owned.value(),
+                owned.sample_rate()
+            ))]),
+            route: self.route.as_ref(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use std::convert::TryInto;
+
+    #[test]
+    fn keep_and_remove_tags() {
+        let c = processor::TagFilter {
+            route: vec![],
+            keep: Some(vec!["host".to_owned(), "env".to_owned(), "user".to_owned()]),
+            remove: Some(vec!["user".to_owned()]),
+        };
+        let sink = stats::Collector::default();
+        let filter = TagFilter::new(sink.scope("prefix"), &c);
+        let filtered = |line: &'static str| -> Option<Owned> {
+            let event = event(line);
+            let output = filter.provide_statsd(&event).unwrap();
+            output
+                .new_events
+                .map(|events| (&events[0]).try_into().unwrap())
+        };
+
+        let owned = filtered("requests:1|c|#host:web1,user:alice,path:/a,env:prod").unwrap();
+        assert_eq!(owned.name(), b"requests");
+        assert_eq!(
+            owned.tags(),
+            &[
+                Tag {
+                    name: b"host".to_vec(),
+                    value: b"web1".to_vec(),
+                },
+                Tag {
+                    name: b"env".to_vec(),
+                    value: b"prod".to_vec(),
+                },
+            ]
+        );
+        assert_eq!(filter.counter_removed.get(), 2_f64);
+
+        // Metrics with only allowed tags, or none, pass unchanged
+        assert!(filtered("requests:1|c|#host:web1").is_none());
+        assert!(filtered("requests:1|c").is_none());
+    }
+}
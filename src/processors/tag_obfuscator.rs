@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+
+use smallvec::smallvec;
+
+use super::{parse, processing_errors, Output, Processor};
+use crate::config::processor::{self, ObfuscationMethod};
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Tag};
+
+/// Replaces the values of configured tag keys with a keyed hash or a
+/// truncated value, so identifiers can be pseudonymized before metrics leave
+/// the network. The hash is not a cryptographic MAC, but without the key
+/// known identifiers can not be hashed to find their metrics.
+pub struct TagObfuscator {
+    keys: HashSet<Vec<u8>>,
+    method: ObfuscationMethod,
+    hash_key: Vec<u8>,
+    length: Option<usize>,
+    route: Vec<Route>,
+
+    counter_obfuscated: stats::Counter,
+    counter_processing_errors: stats::Counter,
+}
+
+impl TagObfuscator {
+    pub fn new(scope: stats::Scope, from_config: &processor::TagObfuscator) -> Self {
+        TagObfuscator {
+            keys: from_config
+                .keys
+                .iter()
+                .map(|key| key.as_bytes().to_vec())
+                .collect(),
+            method: from_config.method,
+            hash_key: from_config
+                .hash_key
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec(),
+            length: from_config.length,
+            route: from_config.route.clone(),
+            counter_obfuscated: scope.counter("obfuscated_tags").unwrap(),
+            counter_processing_errors: processing_errors(&scope),
+        }
+    }
+
+    fn obfuscate(&self, value: &[u8]) -> Vec<u8> {
+        let mut obfuscated = match self.method {
+            ObfuscationMethod::Hash => {
+                let mut keyed = Cursor::new(&self.hash_key).chain(Cursor::new(value));
+                let hash = murmur3::murmur3_x64_128(&mut keyed, 0).unwrap_or(0);
+                format!("{:032x}", hash).into_bytes()
+            }
+            ObfuscationMethod::Truncate => value.to_vec(),
+        };
+        if let Some(length) = self.length {
+            obfuscated.truncate(length);
+        }
+        obfuscated
+    }
+}
+
+impl Processor for TagObfuscator {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let unchanged = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        // Avoid parsing samples which can't have tags
+        if let Event::Pdu(pdu) = event {
+            if pdu.tags().is_none() {
+                return Some(unchanged);
+            }
+        }
+        let owned = parse(event, &self.counter_processing_errors)?;
+        if !owned.tags().iter().any(|tag| self.keys.contains(&tag.name)) {
+            return Some(unchanged);
+        }
+        let tags: Vec<Tag> = owned
+            .tags()
+            .iter()
+            .map(|tag| {
+                if !self.keys.contains(&tag.name) {
+                    return tag.clone();
+                }
+                self.counter_obfuscated.inc();
+                Tag {
+                    name: tag.name.clone(),
+                    value: self.obfuscate(&tag.value),
+                }
+            })
+            .collect();
+        let id = Id {
+            name: owned.name().to_vec(),
+            mtype: *owned.metric_type(),
+            tags,
+        };
+        Some(Output {
+            new_events: Some(smallvec![Event::Parsed(Owned::new(
+                id,
+                owned.value(),
+                owned.sample_rate()
+            ))]),
+            route: self.route.as_ref(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::event;
+    use std::convert::TryInto;
+
+    fn obfuscated(obfuscator: &TagObfuscator, line: &'static str) -> Option<Owned> {
+        let event = event(line);
+        let output = obfuscator.provide_statsd(&event).unwrap();
+        output
+            .new_events
+            .map(|events| (&events[0]).try_into().unwrap())
+    }
+
+    #[test]
+    fn hash_tag_values() {
+        let config = |hash_key: &str| processor::TagObfuscator {
+            keys: vec!["email".to_owned()],
+            method: ObfuscationMethod::Hash,
+            hash_key: Some(hash_key.to_owned()),
+            length: Some(16),
+            route: vec![],
+        };
+        let sink = stats::Collector::default();
+        let obfuscator = TagObfuscator::new(sink.scope("a"), &config("secret"));
+
+        let owned = obfuscated(&obfuscator, "logins:1|c|#email:a@example.com,env:prod").unwrap();
+        let tags = owned.tags();
+        assert_eq!(tags[0].name, b"email");
+        assert_eq!(tags[0].value.len(), 16);
+        assert_ne!(tags[0].value, b"a@example.com");
+        assert_eq!(tags[1].value, b"prod");
+        assert_eq!(obfuscator.counter_obfuscated.get(), 1_f64);
+
+        // The same value always hashes the same with the same key
+        let again = obfuscated(&obfuscator, "logins:1|c|#email:a@example.com").unwrap();
+        assert_eq!(again.tags()[0].value, tags[0].value);
+        let other_key = TagObfuscator::new(sink.scope("b"), &config("other"));
+        let other = obfuscated(&other_key, "logins:1|c|#email:a@example.com").unwrap();
+        assert_ne!(other.tags()[0].value, tags[0].value);
+
+        assert!(obfuscated(&obfuscator, "logins:1|c|#env:prod").is_none());
+    }
+
+    #[test]
+    fn truncate_tag_values() {
+        let sink = stats::Collector::default();
+        let obfuscator = TagObfuscator::new(
+            sink.scope("a"),
+            &processor::TagObfuscator {
+                keys: vec!["user_id".to_owned()],
+                method: ObfuscationMethod::Truncate,
+                hash_key: None,
+                length: Some(3),
+                route: vec![],
+            },
+        );
+        let owned = obfuscated(&obfuscator, "logins:1|c|#user_id:12345").unwrap();
+        assert_eq!(owned.tags()[0].value, b"123");
+    }
+}
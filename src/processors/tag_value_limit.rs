@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+
+use super::{Error, Output, Processor};
+use crate::backends::Backends;
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Id, Owned, Parsed, Tag};
+
+const DEFAULT_OVERFLOW_VALUE: &str = "other";
+
+/// The distinct values seen of a set of tag keys, optionally reset every
+/// window. Values past the limit of a key are rewritten to an overflow value.
+pub(crate) struct TagValues {
+    max_values: usize,
+    overflow_value: Vec<u8>,
+    window: Option<Duration>,
+    reset_at: SystemTime,
+    keys: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+}
+
+impl TagValues {
+    pub(crate) fn new(
+        keys: &[String],
+        max_values: usize,
+        overflow_value: &str,
+        window: Option<Duration>,
+    ) -> Self {
+        let now = SystemTime::now();
+        TagValues {
+            max_values,
+            overflow_value: overflow_value.as_bytes().to_vec(),
+            window,
+            reset_at: window.map_or(now, |window| now + window),
+            keys: keys
+                .iter()
+                .map(|key| (key.as_bytes().to_vec(), HashSet::new()))
+                .collect(),
+        }
+    }
+
+    /// Record the values of limited tag keys, returning the tags with values
+    /// past the limit rewritten, or None if no tag was rewritten. The keys of
+    /// rewritten tags are added to `overflowed`.
+    fn limit(&mut self, tags: &[Tag], overflowed: &mut Vec<String>) -> Option<Vec<Tag>> {
+        let mut rewritten: Option<Vec<Tag>> = None;
+        for (index, tag) in tags.iter().enumerate() {
+            let values = match self.keys.get_mut(&tag.name) {
+                Some(values) => values,
+                None => continue,
+            };
+            if tag.value == self.overflow_value || values.contains(&tag.value) {
+                continue;
+            }
+            if values.len() < self.max_values {
+                values.insert(tag.value.clone());
+                continue;
+            }
+            rewritten.get_or_insert_with(|| tags.to_vec())[index].value =
+                self.overflow_value.clone();
+            overflowed.push(String::from_utf8_lossy(&tag.name).into_owned());
+        }
+        rewritten
+    }
+
+    /// The number of distinct values seen of each limited key
+    pub(crate) fn counts(&self) -> Vec<(String, usize)> {
+        self.keys
+            .iter()
+            .map(|(key, values)| (String::from_utf8_lossy(key).into_owned(), values.len()))
+            .collect()
+    }
+
+    pub(crate) fn rotate(&mut self, with_time: SystemTime) {
+        let window = match self.window {
+            Some(window) => window,
+            None => return,
+        };
+        if with_time >= self.reset_at {
+            for values in self.keys.values_mut() {
+                values.clear();
+            }
+            self.reset_at = with_time + window;
+        }
+    }
+}
+
+/// Rewrite the values of tags past their limit, returning the rewritten
+/// sample if any were. Rewritten tags are counted by key.
+pub(crate) fn rewrite(
+    tag_values: &Mutex<TagValues>,
+    sample: &Event,
+    counter_overflowed: &stats::CounterVec,
+) -> Option<Event> {
+    // Avoid parsing samples which can't have tags
+    if let Event::Pdu(pdu) = sample {
+        pdu.tags()?;
+    }
+    let owned: Owned = sample.try_into().ok()?;
+    let mut overflowed = Vec::new();
+    let tags = tag_values.lock().limit(owned.tags(), &mut overflowed)?;
+    for key in overflowed {
+        counter_overflowed.inc(&[key.as_str()]);
+    }
+    let id = Id {
+        name: owned.name().to_vec(),
+        mtype: *owned.metric_type(),
+        tags,
+    };
+    Some(Event::Parsed(Owned::new(
+        id,
+        owned.value(),
+        owned.sample_rate(),
+    )))
+}
+
+/// Caps the distinct values of tag keys, such as `user_id`, by rewriting
+/// values past the limit to a single overflow value. Unlike the cardinality
+/// filter, metrics are always passed on, just with coarser tags.
+pub struct TagValueLimit {
+    tag_values: Mutex<TagValues>,
+    route: Vec<Route>,
+
+    counter_overflowed: stats::CounterVec,
+    gauge_distinct: stats::GaugeVec,
+}
+
+impl TagValueLimit {
+    pub fn new(scope: stats::Scope, from_config: &processor::TagValueLimit) -> Result<Self, Error> {
+        Ok(TagValueLimit {
+            tag_values: Mutex::new(TagValues::new(
+                &from_config.keys,
+                from_config.max_values,
+                from_config
+                    .overflow_value
+                    .as_deref()
+                    .unwrap_or(DEFAULT_OVERFLOW_VALUE),
+                from_config.window_seconds.map(Duration::from_secs),
+            )),
+            route: from_config.route.clone(),
+            counter_overflowed: scope
+                .counter_vec("overflowed_tag_values", &["tag_key"])
+                .unwrap(),
+            gauge_distinct: scope
+                .gauge_vec("distinct_tag_values", &["tag_key"])
+                .unwrap(),
+        })
+    }
+}
+
+impl Processor for TagValueLimit {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        Some(Output {
+            new_events: rewrite(&self.tag_values, event, &self.counter_overflowed)
+                .map(|event| smallvec::smallvec![event]),
+            route: self.route.as_ref(),
+        })
+    }
+
+    fn tick(&self, time: SystemTime, _backends: &Backends) {
+        let mut tag_values = self.tag_values.lock();
+        for (key, count) in tag_values.counts() {
+            self.gauge_distinct.set(&[key.as_str()], count as f64);
+        }
+        tag_values.rotate(time);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::capture_backends;
+    use crate::statsd_proto::Pdu;
+
+    fn event(line: String) -> Event {
+        Event::Pdu(Pdu::parse(bytes::Bytes::from(line)).unwrap())
+    }
+
+    fn limit(window_seconds: Option<u64>) -> TagValueLimit {
+        TagValueLimit::new(
+            stats::Collector::default().scope("l"),
+            &processor::TagValueLimit {
+                keys: vec!["user_id".to_owned()],
+                max_values: 2,
+                overflow_value: None,
+                window_seconds,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    /// The line sent on for a line
+    fn rewritten(limit: &TagValueLimit, line: String) -> String {
+        let event = event(line);
+        let output = limit.provide_statsd(&event).unwrap();
+        let pdu = match output.new_events {
+            Some(events) => Pdu::from(&events[0]),
+            None => Pdu::from(&event),
+        };
+        String::from_utf8(pdu.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn rewrite_past_limit() {
+        let (backends, _capture, _route) = capture_backends();
+        let limit = limit(None);
+        for user in 0..4 {
+            rewritten(&limit, format!("logins:1|c|#host:a,user_id:{}", user));
+        }
+        assert_eq!(
+            rewritten(&limit, "logins:1|c|#host:a,user_id:9".to_owned()),
+            "logins:1.0|c|#host:a,user_id:other"
+        );
+        assert_eq!(
+            rewritten(&limit, "logins:1|c|#user_id:1".to_owned()),
+            "logins:1|c|#user_id:1"
+        );
+        assert_eq!(limit.counter_overflowed.get(&["user_id"]), 3_f64);
+
+        // Values are kept without a window
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        limit.tick(later, &backends);
+        assert_eq!(limit.gauge_distinct.get(&["user_id"]), 2_f64);
+        assert_eq!(
+            rewritten(&limit, "logins:1|c|#user_id:5".to_owned()),
+            "logins:1.0|c|#user_id:other"
+        );
+    }
+
+    #[test]
+    fn reset_every_window() {
+        let (backends, _capture, _route) = capture_backends();
+        let limit = limit(Some(10));
+        for user in 0..3 {
+            rewritten(&limit, format!("logins:1|c|#user_id:{}", user));
+        }
+        let later = SystemTime::now() + Duration::from_secs(11);
+        limit.tick(later, &backends);
+        assert_eq!(
+            rewritten(&limit, "logins:1|c|#user_id:5".to_owned()),
+            "logins:1|c|#user_id:5"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+use super::filter::Filter;
+use super::{Error, Output, Processor};
+use crate::config::processor::{self, ThrottleAction};
+use crate::config::Route;
+use crate::rate_limit::TokenBucket;
+use crate::stats;
+use crate::statsd_proto::{Event, Parsed};
+
+const DEFAULT_PREFIX_DEPTH: usize = 1;
+const OVERFLOW_TENANT: &str = "__overflow";
+
+struct Tenant {
+    bucket: TokenBucket,
+    label: String,
+}
+
+struct Tenants {
+    tenants: HashMap<Vec<u8>, Tenant>,
+    /// Shared by the tenants past `max_tenants`
+    overflow: Tenant,
+}
+
+/// Limits the lines passed for each tenant of a shared relay, so one team
+/// sending a flood of metrics does not crowd out the others. A line's tenant
+/// is the value of a tag, or else the start of its name.
+pub struct Throttle {
+    filter: Option<Filter>,
+    tag: Option<Vec<u8>>,
+    prefix_depth: usize,
+    lines_per_second: f64,
+    burst: Option<f64>,
+    rates: HashMap<Vec<u8>, f64>,
+    max_tenants: usize,
+    tenants: Mutex<Tenants>,
+    action: ThrottleAction,
+    route: Vec<Route>,
+
+    counter_passed: stats::CounterVec,
+    counter_throttled: stats::CounterVec,
+    gauge_tenants: stats::Gauge,
+}
+
+impl Throttle {
+    pub fn new(scope: stats::Scope, from_config: &processor::Throttle) -> Result<Self, Error> {
+        let lines_per_second = from_config.lines_per_second;
+        let overflow = TokenBucket::new(
+            lines_per_second,
+            from_config.burst.unwrap_or(lines_per_second),
+            Instant::now(),
+        );
+        Ok(Throttle {
+            filter: from_config.filter.as_deref().map(Filter::new).transpose()?,
+            tag: from_config.tag.as_ref().map(|tag| tag.as_bytes().to_vec()),
+            prefix_depth: from_config.prefix_depth.unwrap_or(DEFAULT_PREFIX_DEPTH),
+            lines_per_second,
+            burst: from_config.burst,
+            rates: from_config
+                .tenants
+                .iter()
+                .map(|(tenant, rate)| (tenant.as_bytes().to_vec(), *rate))
+                .collect(),
+            max_tenants: from_config.max_tenants,
+            tenants: Mutex::new(Tenants {
+                tenants: HashMap::new(),
+                overflow: Tenant {
+                    bucket: overflow,
+                    label: OVERFLOW_TENANT.to_owned(),
+                },
+            }),
+            action: from_config.action.clone(),
+            route: from_config.route.clone(),
+            counter_passed: scope.counter_vec("passed_lines", &["tenant"]).unwrap(),
+            counter_throttled: scope.counter_vec("throttled_lines", &["tenant"]).unwrap(),
+            gauge_tenants: scope.gauge("tenants").unwrap(),
+        })
+    }
+
+    /// The first `prefix_depth` components of a metric name
+    fn prefix<'a>(&self, name: &'a [u8]) -> &'a [u8] {
+        let end = name
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c == b'.')
+            .nth(self.prefix_depth - 1)
+            .map_or(name.len(), |(index, _)| index);
+        &name[..end]
+    }
+
+    /// The tenant a line is counted against
+    fn tenant<'a>(&self, event: &'a Event) -> &'a [u8] {
+        let tagged = self.tag.as_ref().and_then(|tag| match event {
+            Event::Pdu(pdu) => pdu.tags().and_then(|tags| {
+                tags.split(|c| *c == b',').find_map(|pair| {
+                    pair.strip_prefix(tag.as_slice())
+                        .and_then(|rest| rest.strip_prefix(b":"))
+                })
+            }),
+            Event::Parsed(parsed) => parsed
+                .tags()
+                .iter()
+                .find(|t| t.name == *tag)
+                .map(|t| t.value.as_slice()),
+        });
+        match (tagged, event) {
+            (Some(tenant), _) => tenant,
+            (None, Event::Pdu(pdu)) => self.prefix(pdu.name()),
+            (None, Event::Parsed(parsed)) => self.prefix(parsed.name()),
+        }
+    }
+
+    /// Take a line from a tenant's budget, returning whether it was taken
+    fn take(&self, tenant: &[u8], now: Instant) -> bool {
+        let mut tenants = self.tenants.lock();
+        if !tenants.tenants.contains_key(tenant) && tenants.tenants.len() < self.max_tenants {
+            let rate = self
+                .rates
+                .get(tenant)
+                .copied()
+                .unwrap_or(self.lines_per_second);
+            let created = Tenant {
+                bucket: TokenBucket::new(rate, self.burst.unwrap_or(rate), now),
+                label: String::from_utf8_lossy(tenant).into_owned(),
+            };
+            tenants.tenants.insert(tenant.to_vec(), created);
+            self.gauge_tenants.set(tenants.tenants.len() as f64);
+        }
+        let Tenants { tenants, overflow } = &mut *tenants;
+        let tenant = tenants.get_mut(tenant).unwrap_or(overflow);
+        let taken = tenant.bucket.try_take(1_f64, now);
+        if taken {
+            self.counter_passed.inc(&[tenant.label.as_str()]);
+        } else {
+            self.counter_throttled.inc(&[tenant.label.as_str()]);
+        }
+        taken
+    }
+}
+
+impl Processor for Throttle {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let throttled = self
+            .filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches_event(event));
+        if !throttled || self.take(self.tenant(event), Instant::now()) {
+            return Some(Output {
+                new_events: None,
+                route: self.route.as_ref(),
+            });
+        }
+        match &self.action {
+            ThrottleAction::Drop => None,
+            ThrottleAction::RouteTo(route) => Some(Output {
+                new_events: None,
+                route: route.as_ref(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use super::*;
+    use crate::processors::test::{event, parsed};
+    use std::time::Duration;
+
+    fn throttle(max_tenants: usize) -> Throttle {
+        Throttle::new(
+            stats::Collector::default().scope("t"),
+            &processor::Throttle {
+                filter: Some("type != gauge".to_owned()),
+                tag: Some("team".to_owned()),
+                prefix_depth: None,
+                lines_per_second: 2_f64,
+                burst: None,
+                tenants: vec![("search".to_owned(), 4_f64)].into_iter().collect(),
+                max_tenants,
+                action: ThrottleAction::Drop,
+                route: vec![],
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tenants() {
+        let throttle = throttle(10);
+        assert_eq!(
+            throttle.tenant(&event("app.requests:1|c|#team:web")),
+            b"web"
+        );
+        assert_eq!(
+            throttle.tenant(&event("app.requests:1|c|#teams:x,team:db")),
+            b"db"
+        );
+        assert_eq!(throttle.tenant(&event("app.requests:1|c|#host:a")), b"app");
+        assert_eq!(
+            throttle.tenant(&parsed("app.requests:1|c|#team:web")),
+            b"web"
+        );
+    }
+
+    #[test]
+    fn budgets_per_tenant() {
+        let throttle = throttle(10);
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert!(throttle.take(b"web", start));
+        }
+        assert!(!throttle.take(b"web", start));
+        // Other tenants have their own budgets
+        for _ in 0..4 {
+            assert!(throttle.take(b"search", start));
+        }
+        assert!(!throttle.take(b"search", start));
+        assert!(throttle.take(b"web", start + Duration::from_millis(500)));
+
+        assert_eq!(throttle.counter_passed.get(&["web"]), 3_f64);
+        assert_eq!(throttle.counter_throttled.get(&["web"]), 1_f64);
+        assert_eq!(throttle.counter_throttled.get(&["search"]), 1_f64);
+        assert_eq!(throttle.gauge_tenants.get(), 2_f64);
+    }
+
+    #[test]
+    fn overflow_tenants() {
+        let throttle = throttle(1);
+        let start = Instant::now();
+        assert!(throttle.take(b"web", start));
+        assert!(throttle.take(b"db", start));
+        assert!(throttle.take(b"batch", start));
+        assert!(!throttle.take(b"db", start));
+        assert_eq!(throttle.counter_passed.get(&[OVERFLOW_TENANT]), 2_f64);
+        assert_eq!(throttle.counter_throttled.get(&[OVERFLOW_TENANT]), 1_f64);
+
+        assert!(throttle.provide_statsd(&event("x:1|c|#team:web")).is_some());
+        assert!(throttle.provide_statsd(&event("x:1|c|#team:web")).is_none());
+        // Lines not matching the filter are never throttled
+        assert!(throttle.provide_statsd(&event("x:1|g|#team:web")).is_some());
+    }
+}
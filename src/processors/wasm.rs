@@ -0,0 +1,177 @@
+//! A processor running a WASM module over each metric, so custom transforms
+//! can be deployed without changing statsrelay. Built with the `wasm` cargo
+//! feature.
+//!
+//! Modules export their `memory` and two functions:
+//!
+//! - `alloc(len: i32) -> i32` returns a buffer of `len` bytes in the
+//!   module's memory, which the metric is written to.
+//! - `transform(ptr: i32, len: i32) -> i64` is given the buffer holding the
+//!   metric as a statsd line, and owns it from then on. It returns `-1` to
+//!   pass the metric on unchanged, or the location of its output packed as
+//!   `ptr << 32 | len`. The output is read before the next call, so a module
+//!   can reuse one buffer for it.
+//!
+//! Output is newline separated statsd lines, sent on in place of the metric.
+//! No lines drops the metric. A first line of `@name` sends the lines to the
+//! processor's route of that name instead of its default route.
+//!
+//! Each call has a budget of fuel, so a module which never returns can't
+//! hold up ingest. A call running out of fuel fails, and the metric is
+//! passed on unchanged.
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use log::warn;
+use parking_lot::Mutex;
+use smallvec::SmallVec;
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, TypedFunc};
+
+use super::{processing_errors, Error, Output, Processor};
+use crate::config::processor;
+use crate::config::Route;
+use crate::stats;
+use crate::statsd_proto::{Event, Pdu};
+
+/// `transform`'s result passing the metric on unchanged
+const UNCHANGED: i64 = -1;
+/// Fuel each call may use, unless configured
+const DEFAULT_FUEL: u64 = 1_000_000;
+
+/// An instance of a module, called by one thread at a time
+struct Instantiated {
+    store: Store<()>,
+    fuel: u64,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+}
+
+impl Instantiated {
+    fn new(engine: &Engine, module: &Module, fuel: u64) -> anyhow::Result<Self> {
+        let mut store = Store::new(engine, ());
+        // Instantiating runs the module's start function, if any
+        store.set_fuel(fuel)?;
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("module does not export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let transform = instance.get_typed_func::<(i32, i32), i64>(&mut store, "transform")?;
+        Ok(Instantiated {
+            store,
+            fuel,
+            memory,
+            alloc,
+            transform,
+        })
+    }
+
+    /// Run the transform over a line, returning its output, or None to pass
+    /// the line on unchanged
+    fn call(&mut self, line: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        self.store.set_fuel(self.fuel)?;
+        let ptr = self.alloc.call(&mut self.store, line.len() as i32)?;
+        self.memory.write(&mut self.store, ptr as usize, line)?;
+        let result = self
+            .transform
+            .call(&mut self.store, (ptr, line.len() as i32))?;
+        if result == UNCHANGED {
+            return Ok(None);
+        }
+        let start = (result >> 32) as u32 as usize;
+        let len = result as u32 as usize;
+        let output = self
+            .memory
+            .data(&self.store)
+            .get(start..start + len)
+            .ok_or_else(|| anyhow::anyhow!("transform output is out of bounds"))?;
+        Ok(Some(output.to_vec()))
+    }
+}
+
+pub struct Wasm {
+    instance: Mutex<Instantiated>,
+    routes: HashMap<Vec<u8>, Vec<Route>>,
+    route: Vec<Route>,
+
+    counter_processing_errors: stats::Counter,
+    counter_invalid_lines: stats::Counter,
+}
+
+impl Wasm {
+    pub fn new(scope: stats::Scope, from_config: &processor::Wasm) -> Result<Self, Error> {
+        let invalid = |e: anyhow::Error| {
+            Error::InvalidConfig(format!("wasm module {}: {}", from_config.module, e))
+        };
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(invalid)?;
+        let module = Module::from_file(&engine, &from_config.module).map_err(invalid)?;
+        let fuel = from_config.fuel.unwrap_or(DEFAULT_FUEL);
+        let instance = Instantiated::new(&engine, &module, fuel).map_err(invalid)?;
+        Ok(Wasm {
+            instance: Mutex::new(instance),
+            routes: from_config
+                .routes
+                .iter()
+                .map(|(name, route)| (name.as_bytes().to_vec(), route.clone()))
+                .collect(),
+            route: from_config.route.clone(),
+            counter_processing_errors: processing_errors(&scope),
+            counter_invalid_lines: scope.counter("invalid_lines").unwrap(),
+        })
+    }
+}
+
+impl Processor for Wasm {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        let unchanged = Output {
+            new_events: None,
+            route: self.route.as_ref(),
+        };
+        let line = match event {
+            Event::Pdu(pdu) => pdu.clone(),
+            Event::Parsed(parsed) => Pdu::from(parsed),
+        };
+        let output = match self.instance.lock().call(line.as_bytes()) {
+            Ok(Some(output)) => Bytes::from(output),
+            Ok(None) => return Some(unchanged),
+            Err(e) => {
+                // Metrics are passed on unchanged rather than lost when a
+                // module fails
+                if (self.counter_processing_errors.get() as u64).is_multiple_of(1000) {
+                    warn!("wasm transform failed: {}", e);
+                }
+                self.counter_processing_errors.inc();
+                return Some(unchanged);
+            }
+        };
+
+        let mut route = self.route.as_ref();
+        let mut events: SmallVec<[Event; 4]> = SmallVec::new();
+        for (index, line) in output.split(|c| *c == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            if index == 0 && line.starts_with(b"@") {
+                match self.routes.get(&line[1..]) {
+                    Some(named) => route = named.as_ref(),
+                    None => self.counter_invalid_lines.inc(),
+                }
+                continue;
+            }
+            match Pdu::parse(output.slice_ref(line)) {
+                Ok(pdu) => events.push(Event::Pdu(pdu)),
+                Err(_) => self.counter_invalid_lines.inc(),
+            }
+        }
+        if events.is_empty() {
+            return None;
+        }
+        Some(Output {
+            new_events: Some(events),
+            route,
+        })
+    }
+}
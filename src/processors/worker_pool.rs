@@ -0,0 +1,278 @@
+//! Runs a processor on its own worker threads, fed by a bounded queue, so an
+//! expensive processor such as a rewrite or a script does not hold up the
+//! thread reading lines from a socket. Lines arriving while the queue is full
+//! are dropped and counted. A line which panics the processor is counted,
+//! and the worker goes on with the next line.
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+use log::error;
+use parking_lot::{Condvar, Mutex};
+use tokio::sync::mpsc;
+
+use super::{Output, Processor};
+use crate::backends::Backends;
+use crate::config::processor;
+use crate::stats;
+use crate::statsd_proto::Event;
+
+const DEFAULT_THREADS: usize = 1;
+
+/// Lines queued or being processed, which flushing waits for
+#[derive(Default)]
+struct Pending {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl Pending {
+    fn add(&self) {
+        *self.count.lock() += 1;
+    }
+
+    fn done(&self) {
+        let mut count = self.count.lock();
+        *count -= 1;
+        if *count == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    fn wait_idle(&self) {
+        let mut count = self.count.lock();
+        while *count > 0 {
+            self.idle.wait(&mut count);
+        }
+    }
+}
+
+/// Marks a line done once processed, even if processing it panicked
+struct Processing<'a>(&'a Pending);
+
+impl Drop for Processing<'_> {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+pub struct WorkerPool {
+    processor: Arc<dyn Processor + Send + Sync>,
+    sender: mpsc::Sender<Event>,
+    pending: Arc<Pending>,
+
+    gauge_queue_depth: stats::Gauge,
+    counter_queue_dropped: stats::Counter,
+}
+
+impl WorkerPool {
+    /// Start the workers of a processor, which send what it outputs on
+    /// through the backends. Must be called within a tokio runtime. The
+    /// workers exit once the pool is dropped, such as when the backends are
+    /// drained.
+    pub fn new(
+        scope: &stats::Scope,
+        from_config: &processor::WorkerPool,
+        backends: &Backends,
+        processor: Box<dyn Processor + Send + Sync>,
+    ) -> Self {
+        let processor: Arc<dyn Processor + Send + Sync> = Arc::from(processor);
+        let (sender, receiver) = mpsc::channel(from_config.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(Pending::default());
+        let panics = scope.counter("worker_panics").unwrap();
+        for _ in 0..from_config.threads.unwrap_or(DEFAULT_THREADS) {
+            let processor = processor.clone();
+            let receiver = receiver.clone();
+            let backends = backends.clone();
+            let pending = pending.clone();
+            let panics = panics.clone();
+            tokio::task::spawn_blocking(move || {
+                work(&*processor, &receiver, &backends, &pending, &panics)
+            });
+        }
+        WorkerPool {
+            processor,
+            sender,
+            pending,
+            gauge_queue_depth: scope.gauge("queue_depth").unwrap(),
+            counter_queue_dropped: scope.counter("queue_dropped").unwrap(),
+        }
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+}
+
+/// Process lines from the queue until every sender is dropped
+fn work(
+    processor: &(dyn Processor + Send + Sync),
+    receiver: &Mutex<mpsc::Receiver<Event>>,
+    backends: &Backends,
+    pending: &Pending,
+    panics: &stats::Counter,
+) {
+    loop {
+        let event = match receiver.lock().blocking_recv() {
+            Some(event) => event,
+            None => return,
+        };
+        let _processing = Processing(pending);
+        let processed = catch_unwind(AssertUnwindSafe(|| {
+            if let Some(output) = processor.provide_statsd(&event) {
+                match &output.new_events {
+                    None => backends.provide_statsd(&event, output.route),
+                    Some(events) => backends.provide_statsd_slice(events, output.route),
+                }
+            }
+        }));
+        if processed.is_err() {
+            error!("processor panicked on a line in a worker");
+            panics.inc();
+        }
+    }
+}
+
+impl Processor for WorkerPool {
+    fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+        self.pending.add();
+        if self.sender.try_send(event.clone()).is_err() {
+            self.pending.done();
+            self.counter_queue_dropped.inc();
+        }
+        // Workers send the processor's output on themselves
+        None
+    }
+
+    fn tick(&self, time: std::time::SystemTime, backends: &Backends) {
+        self.gauge_queue_depth.set(self.queue_depth() as f64);
+        self.processor.tick(time, backends)
+    }
+
+    /// Flush the processor once the lines already queued are processed.
+    /// Workers need the backends to send lines on, so this must not be
+    /// called with the backends locked.
+    fn flush(&self, time: std::time::SystemTime, backends: &Backends) {
+        self.pending.wait_idle();
+        self.processor.flush(time, backends)
+    }
+
+    fn report(&self) -> Option<serde_json::Value> {
+        self.processor.report()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::processors::test::capture_backends;
+    use crate::statsd_proto::Pdu;
+
+    /// Tags every line, sending it to the route it was built with
+    struct Tagger {
+        route: Vec<crate::config::Route>,
+        /// Held until the tagger is dropped
+        _alive: Arc<()>,
+    }
+
+    impl Processor for Tagger {
+        fn provide_statsd(&self, event: &Event) -> Option<Output<'_>> {
+            if Pdu::from(event).name() == b"panic" {
+                panic!("tagger panicked");
+            }
+            Some(Output {
+                new_events: Some(smallvec::smallvec![Event::Pdu(
+                    Pdu::from(event).with_tags(b"worker:true")
+                )]),
+                route: self.route.as_ref(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn process_on_workers() {
+        let (backends, capture, route) = capture_backends();
+        let scope = stats::Collector::default().scope("pool");
+        let pool = WorkerPool::new(
+            &scope,
+            &processor::WorkerPool {
+                threads: Some(2),
+                queue_size: 100,
+            },
+            &backends,
+            Box::new(Tagger {
+                route,
+                _alive: Arc::new(()),
+            }),
+        );
+        let lines = std::iter::once("panic:1|c").chain(std::iter::repeat_n("foo:1|c", 10));
+        for line in lines {
+            let pdu = Pdu::parse(bytes::Bytes::from_static(line.as_bytes())).unwrap();
+            assert!(pool.provide_statsd(&Event::Pdu(pdu)).is_none());
+        }
+        // Waits for every line, including the one the processor panicked on
+        pool.flush(std::time::SystemTime::now(), &backends);
+        assert_eq!(scope.counter("worker_panics").unwrap().get(), 1_f64);
+
+        let events = capture.events.lock();
+        assert_eq!(events.len(), 10);
+        assert_eq!(Pdu::from(&events[0]).as_bytes(), b"foo:1|c|#worker:true");
+        pool.tick(std::time::SystemTime::now(), &backends);
+        assert_eq!(pool.gauge_queue_depth.get(), 0_f64);
+    }
+
+    #[tokio::test]
+    async fn exit_when_drained() {
+        let (backends, _capture, route) = capture_backends();
+        let scope = stats::Collector::default().scope("pool");
+        let alive = Arc::new(());
+        let pool = WorkerPool::new(
+            &scope,
+            &processor::WorkerPool {
+                threads: Some(2),
+                queue_size: 100,
+            },
+            &backends,
+            Box::new(Tagger {
+                route,
+                _alive: alive.clone(),
+            }),
+        );
+        backends.replace_processor("pool", Box::new(pool)).unwrap();
+        backends.drain_backends().await;
+        // The workers hold the tagger until they exit
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while Arc::strong_count(&alive) > 1 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_when_full() {
+        let (backends, _capture, route) = capture_backends();
+        let scope = stats::Collector::default().scope("pool");
+        // Held so the worker can't take lines off the queue
+        let (sender, receiver) = mpsc::channel(1);
+        let pool = WorkerPool {
+            processor: Arc::new(Tagger {
+                route,
+                _alive: Arc::new(()),
+            }),
+            sender,
+            pending: Arc::new(Pending::default()),
+            gauge_queue_depth: scope.gauge("queue_depth").unwrap(),
+            counter_queue_dropped: scope.counter("queue_dropped").unwrap(),
+        };
+        for _ in 0..3 {
+            let pdu = Pdu::parse(bytes::Bytes::from_static(b"foo:1|c")).unwrap();
+            pool.provide_statsd(&Event::Pdu(pdu));
+        }
+        assert_eq!(pool.counter_queue_dropped.get(), 2_f64);
+        pool.tick(std::time::SystemTime::now(), &backends);
+        assert_eq!(pool.gauge_queue_depth.get(), 1_f64);
+        drop(receiver);
+    }
+}
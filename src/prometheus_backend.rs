@@ -0,0 +1,629 @@
+//! A backend aggregating statsd metrics into series, and sending snapshots of
+//! them to a Prometheus remote_write endpoint as snappy compressed protobuf.
+//!
+//! Counters are kept as running totals, gauges as their last value, and
+//! timers as `_sum` and `_count` series, so the endpoint sees cumulative
+//! series just as if it had scraped them. Tags become labels. Series are
+//! split over a number of shards by their labels, each shard with its own
+//! queue of requests sent one at a time, so the samples of a series arrive in
+//! order. Failed requests are retried with backoff.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use parking_lot::Mutex;
+use prost::Message;
+use regex::bytes::Regex;
+use stream_cancel::{Trigger, Tripwire};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval_at, Instant};
+
+use crate::config::PrometheusBackendConfig;
+use crate::error::ErrorCounters;
+use crate::http_client::{self, HttpClient, Retry};
+use crate::stats;
+use crate::statsd_client::Backoff;
+use crate::statsd_proto::{Event, Owned, Parsed, Type};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+const SHARDS: usize = 1;
+const MAX_QUEUE: usize = 64;
+const MAX_SAMPLES_PER_SEND: usize = 2000;
+const MAX_RETRIES: u32 = 5;
+const RETRY_INITIAL: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Series not updated for this many flushes are forgotten, and no longer
+/// sent
+const STALE_FLUSHES: u64 = 10;
+
+/// The subset of the remote_write protobuf messages sent
+pub mod proto {
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct WriteRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub timeseries: Vec<TimeSeries>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct TimeSeries {
+        #[prost(message, repeated, tag = "1")]
+        pub labels: Vec<Label>,
+        #[prost(message, repeated, tag = "2")]
+        pub samples: Vec<Sample>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Label {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Sample {
+        #[prost(double, tag = "1")]
+        pub value: f64,
+        #[prost(int64, tag = "2")]
+        pub timestamp: i64,
+    }
+}
+
+/// Replace characters not allowed in a Prometheus metric name, or in a label
+/// name if `label` is set, with underscores.
+fn sanitize(input: &[u8], label: bool) -> String {
+    let mut out = String::with_capacity(input.len() + 1);
+    for (i, c) in input.iter().enumerate() {
+        match c {
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => out.push(*c as char),
+            b':' if !label => out.push(':'),
+            b'0'..=b'9' => {
+                if i == 0 {
+                    out.push('_');
+                }
+                out.push(*c as char)
+            }
+            _ => out.push('_'),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeriesKey {
+    name: String,
+    /// Sorted by name
+    labels: Vec<(String, String)>,
+}
+
+impl SeriesKey {
+    fn new<P: Parsed>(metric: &P) -> Self {
+        let mut labels: Vec<(String, String)> = metric
+            .tags()
+            .iter()
+            .filter(|tag| !tag.value.is_empty())
+            .map(|tag| {
+                (
+                    sanitize(&tag.name, true),
+                    String::from_utf8_lossy(&tag.value).into_owned(),
+                )
+            })
+            // Label names starting with __ are reserved
+            .filter(|(name, _)| !name.starts_with("__"))
+            .collect();
+        labels.sort();
+        labels.dedup_by(|a, b| a.0 == b.0);
+        SeriesKey {
+            name: sanitize(metric.name(), false),
+            labels,
+        }
+    }
+
+    fn time_series(&self, suffix: &str, value: f64, timestamp: i64) -> proto::TimeSeries {
+        let mut labels = Vec::with_capacity(self.labels.len() + 1);
+        labels.push(proto::Label {
+            name: "__name__".to_owned(),
+            value: format!("{}{}", self.name, suffix),
+        });
+        labels.extend(self.labels.iter().map(|(name, value)| proto::Label {
+            name: name.clone(),
+            value: value.clone(),
+        }));
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        proto::TimeSeries {
+            labels,
+            samples: vec![proto::Sample { value, timestamp }],
+        }
+    }
+
+    fn shard(&self, shards: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        (hasher.finish() % shards as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Counter(f64),
+    Gauge(f64),
+    Timer { sum: f64, count: f64 },
+}
+
+struct Series {
+    value: Value,
+    /// Flush generation the series was last updated in
+    updated: u64,
+}
+
+#[derive(Default)]
+struct State {
+    series: HashMap<SeriesKey, Series>,
+    generation: u64,
+}
+
+/// A request body ready to send, and the number of samples in it
+struct Batch {
+    body: Bytes,
+    samples: usize,
+}
+
+struct Aggregator {
+    state: Mutex<State>,
+    shards: usize,
+    max_samples_per_send: usize,
+    series_gauge: stats::Gauge,
+}
+
+impl Aggregator {
+    /// Record a metric in its series, returning false for sets, which have
+    /// no Prometheus equivalent.
+    fn record(&self, metric: &Owned) -> bool {
+        if metric.metric_type() == &Type::Set {
+            return false;
+        }
+        // Sample rates outside of (0, 1] are ignored, as elsewhere
+        let scale = metric
+            .sample_rate()
+            .filter(|rate| *rate > 0_f64 && *rate <= 1_f64)
+            .map_or(1_f64, |rate| 1_f64 / rate);
+        let value = metric.value();
+        let key = SeriesKey::new(metric);
+        let mut state = self.state.lock();
+        let generation = state.generation;
+        let series = state.series.entry(key).or_insert(Series {
+            value: Value::Gauge(0_f64),
+            updated: generation,
+        });
+        series.updated = generation;
+        series.value = match (metric.metric_type(), series.value) {
+            (Type::Counter, Value::Counter(total)) => Value::Counter(total + value * scale),
+            (Type::Counter, _) => Value::Counter(value * scale),
+            (Type::Gauge | Type::DirectGauge, _) => Value::Gauge(value),
+            (Type::Timer, Value::Timer { sum, count }) => Value::Timer {
+                sum: sum + value * scale,
+                count: count + scale,
+            },
+            (Type::Timer, _) => Value::Timer {
+                sum: value * scale,
+                count: scale,
+            },
+            (Type::Set, value) => value,
+        };
+        true
+    }
+
+    /// Snapshot every series with the given timestamp, forgetting stale
+    /// ones, and return the encoded request bodies for each shard.
+    fn flush(&self, now: SystemTime) -> Vec<Vec<Batch>> {
+        let timestamp = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        let mut shards: Vec<Vec<proto::TimeSeries>> = vec![Vec::new(); self.shards];
+        {
+            let mut state = self.state.lock();
+            let generation = state.generation;
+            state
+                .series
+                .retain(|_, series| generation - series.updated < STALE_FLUSHES);
+            state.generation += 1;
+            self.series_gauge.set(state.series.len() as f64);
+            for (key, series) in state.series.iter() {
+                let shard = &mut shards[key.shard(self.shards)];
+                match series.value {
+                    Value::Counter(value) | Value::Gauge(value) => {
+                        shard.push(key.time_series("", value, timestamp))
+                    }
+                    Value::Timer { sum, count } => {
+                        shard.push(key.time_series("_sum", sum, timestamp));
+                        shard.push(key.time_series("_count", count, timestamp));
+                    }
+                }
+            }
+        }
+        let mut encoder = snap::raw::Encoder::new();
+        shards
+            .into_iter()
+            .map(|series| {
+                series
+                    .chunks(self.max_samples_per_send)
+                    .map(|chunk| {
+                        let request = proto::WriteRequest {
+                            timeseries: chunk.to_vec(),
+                        };
+                        Batch {
+                            body: Bytes::from(
+                                encoder
+                                    .compress_vec(&request.encode_to_vec())
+                                    .expect("snappy compression of an in-memory buffer"),
+                            ),
+                            samples: chunk.len(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+struct Settings {
+    url: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    retry: Retry,
+}
+
+struct ShardStats {
+    samples_sent: stats::Counter,
+    samples_dropped: stats::Counter,
+    request_retries: stats::Counter,
+    errors: ErrorCounters,
+}
+
+struct Inner {
+    conf: PrometheusBackendConfig,
+    input_filter: Option<Regex>,
+    aggregator: Arc<Aggregator>,
+    unsupported_types: stats::Counter,
+    done: watch::Receiver<()>,
+    _trig: Trigger,
+}
+
+#[derive(Clone)]
+pub struct PrometheusBackend {
+    inner: Arc<Inner>,
+}
+
+impl PrometheusBackend {
+    pub fn new(stats: stats::Scope, conf: &PrometheusBackendConfig) -> anyhow::Result<Self> {
+        let input_filter = conf.input_filter.as_deref().map(Regex::new).transpose()?;
+        let shards = conf.shards.unwrap_or(SHARDS);
+        let aggregator = Arc::new(Aggregator {
+            state: Mutex::new(State::default()),
+            shards,
+            max_samples_per_send: conf.max_samples_per_send.unwrap_or(MAX_SAMPLES_PER_SEND),
+            series_gauge: stats.gauge("series").unwrap(),
+        });
+        let mut headers = vec![
+            (CONTENT_ENCODING, HeaderValue::from_static("snappy")),
+            (
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/x-protobuf"),
+            ),
+            (
+                HeaderName::from_static("x-prometheus-remote-write-version"),
+                HeaderValue::from_static("0.1.0"),
+            ),
+            (
+                USER_AGENT,
+                HeaderValue::from_static(concat!("statsrelay/", env!("CARGO_PKG_VERSION"))),
+            ),
+        ];
+        for (name, value) in conf.headers.iter() {
+            headers.push((
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            ));
+        }
+        let settings = Arc::new(Settings {
+            url: conf.url.clone(),
+            headers,
+            retry: Retry {
+                max_retries: conf.max_retries.unwrap_or(MAX_RETRIES),
+                backoff: Backoff {
+                    initial: conf
+                        .retry_initial_ms
+                        .map_or(RETRY_INITIAL, Duration::from_millis),
+                    max: conf.retry_max_ms.map_or(RETRY_MAX, Duration::from_millis),
+                },
+                timeout: conf.timeout_ms.map_or(TIMEOUT, Duration::from_millis),
+            },
+        });
+        let shard_stats = Arc::new(ShardStats {
+            samples_sent: stats.counter("samples_sent").unwrap(),
+            samples_dropped: stats.counter("samples_dropped").unwrap(),
+            request_retries: stats.counter("request_retries").unwrap(),
+            errors: ErrorCounters::new(&stats, "prometheus remote write"),
+        });
+
+        let (trig, trip) = Tripwire::new();
+        // Each shard task holds a sender, dropped once it exits, to signal
+        // the backend has finished.
+        let (done_sender, done) = watch::channel(());
+        let client = http_client::client(None);
+        let senders = (0..shards)
+            .map(|_| {
+                let (sender, recv) = mpsc::channel::<Batch>(conf.max_queue.unwrap_or(MAX_QUEUE));
+                tokio::spawn(shard_sender(
+                    client.clone(),
+                    settings.clone(),
+                    shard_stats.clone(),
+                    recv,
+                    done_sender.clone(),
+                ));
+                sender
+            })
+            .collect();
+        tokio::spawn(flusher(
+            aggregator.clone(),
+            conf.flush_interval_ms
+                .map_or(FLUSH_INTERVAL, Duration::from_millis),
+            senders,
+            shard_stats,
+            trip,
+        ));
+
+        Ok(PrometheusBackend {
+            inner: Arc::new(Inner {
+                conf: conf.clone(),
+                input_filter,
+                aggregator,
+                unsupported_types: stats.counter("unsupported_types").unwrap(),
+                done,
+                _trig: trig,
+            }),
+        })
+    }
+
+    pub fn conf(&self) -> &PrometheusBackendConfig {
+        &self.inner.conf
+    }
+
+    pub fn provide_statsd(&self, input: &Event) {
+        let owned = match Owned::try_from(input) {
+            Ok(owned) => owned,
+            Err(_) => return,
+        };
+        if !self
+            .inner
+            .input_filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(owned.name()))
+        {
+            return;
+        }
+        if !self.inner.aggregator.record(&owned) {
+            self.inner.unsupported_types.inc();
+        }
+    }
+
+    /// Returns a future which resolves once the last snapshot of the series
+    /// has been sent and the backend's tasks have exited. This only happens
+    /// once all clones of this backend have been dropped.
+    pub fn finished(&self) -> impl Future<Output = ()> {
+        let mut done = self.inner.done.clone();
+        async move { while done.changed().await.is_ok() {} }
+    }
+}
+
+/// Queue a snapshot of the series every interval, and a last one once the
+/// tripwire is set.
+async fn flusher(
+    aggregator: Arc<Aggregator>,
+    interval: Duration,
+    senders: Vec<mpsc::Sender<Batch>>,
+    stats: Arc<ShardStats>,
+    mut tripwire: Tripwire,
+) {
+    let mut ticker = interval_at(Instant::now() + interval, interval);
+    loop {
+        let last = select! {
+            _ = ticker.tick() => false,
+            _ = &mut tripwire => true,
+        };
+        let flushed = aggregator.flush(SystemTime::now());
+        for (sender, batches) in senders.iter().zip(flushed) {
+            for batch in batches {
+                let samples = batch.samples;
+                if sender.try_send(batch).is_err() {
+                    stats.samples_dropped.inc_by(samples as f64);
+                }
+            }
+        }
+        if last {
+            return;
+        }
+    }
+}
+
+/// Send the requests queued for a shard in order, until the queue is closed
+/// and empty.
+async fn shard_sender(
+    client: HttpClient,
+    settings: Arc<Settings>,
+    stats: Arc<ShardStats>,
+    mut recv: mpsc::Receiver<Batch>,
+    _done: watch::Sender<()>,
+) {
+    while let Some(batch) = recv.recv().await {
+        let result = http_client::post_with_retries(
+            &client,
+            &settings.url,
+            &settings.headers,
+            batch.body,
+            &settings.retry,
+            &stats.request_retries,
+            &stats.errors,
+        )
+        .await;
+        match result {
+            Ok(()) => stats.samples_sent.inc_by(batch.samples as f64),
+            Err(e) => {
+                stats.errors.report(&e);
+                stats.samples_dropped.inc_by(batch.samples as f64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::timeout;
+
+    fn event(line: &'static [u8]) -> Event {
+        Event::Pdu(crate::statsd_proto::Pdu::parse(Bytes::from_static(line)).unwrap())
+    }
+
+    fn labels(series: &proto::TimeSeries) -> Vec<(&str, &str)> {
+        series
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize(b"foo.bar-baz:1", false), "foo_bar_baz:1");
+        assert_eq!(sanitize(b"foo:bar", true), "foo_bar");
+        assert_eq!(sanitize(b"5xx", false), "_5xx");
+    }
+
+    /// Serve remote write requests, failing the first `failures` of them,
+    /// and forwarding each decoded request which succeeded.
+    async fn remote_write_server(
+        failures: usize,
+    ) -> (String, mpsc::UnboundedReceiver<proto::WriteRequest>) {
+        let (sender, recv) = mpsc::unbounded_channel();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let make_svc = make_service_fn(move |_conn| {
+            let sender = sender.clone();
+            let attempts = attempts.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let sender = sender.clone();
+                    let attempts = attempts.clone();
+                    async move {
+                        assert_eq!(req.headers()[CONTENT_ENCODING], "snappy");
+                        if attempts.fetch_add(1, Ordering::AcqRel) < failures {
+                            return Ok::<_, Infallible>(
+                                Response::builder().status(503).body(Body::empty()).unwrap(),
+                            );
+                        }
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+                        sender
+                            .send(proto::WriteRequest::decode(body.as_slice()).unwrap())
+                            .unwrap();
+                        Ok(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}/api/v1/write", server.local_addr());
+        tokio::spawn(server);
+        (url, recv)
+    }
+
+    fn config(url: String) -> PrometheusBackendConfig {
+        PrometheusBackendConfig {
+            url,
+            flush_interval_ms: Some(3600 * 1000),
+            shards: Some(2),
+            max_queue: None,
+            max_samples_per_send: None,
+            max_retries: Some(3),
+            retry_initial_ms: Some(1),
+            retry_max_ms: Some(1),
+            timeout_ms: None,
+            headers: HashMap::new(),
+            input_filter: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_write() {
+        let (url, mut recv) = remote_write_server(2).await;
+        let scope = crate::stats::Collector::default().scope("test");
+        let backend = PrometheusBackend::new(scope.clone(), &config(url)).unwrap();
+        backend.provide_statsd(&event(b"foo.bar:1|c|#host:a"));
+        backend.provide_statsd(&event(b"foo.bar:1|c|@0.5|#host:a"));
+        backend.provide_statsd(&event(b"lat:10|ms"));
+        backend.provide_statsd(&event(b"lat:20|ms"));
+        backend.provide_statsd(&event(b"users:1|s"));
+        let finished = backend.finished();
+        drop(backend);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        let mut series = Vec::new();
+        while let Ok(request) = recv.try_recv() {
+            series.extend(request.timeseries);
+        }
+        series.sort_by(|a, b| a.labels[0].value.cmp(&b.labels[0].value));
+        assert_eq!(series.len(), 3);
+        assert_eq!(
+            labels(&series[0]),
+            vec![("__name__", "foo_bar"), ("host", "a")]
+        );
+        assert_eq!(series[0].samples[0].value, 3_f64);
+        assert_eq!(labels(&series[1]), vec![("__name__", "lat_count")]);
+        assert_eq!(series[1].samples[0].value, 2_f64);
+        assert_eq!(labels(&series[2]), vec![("__name__", "lat_sum")]);
+        assert_eq!(series[2].samples[0].value, 30_f64);
+        assert_eq!(scope.counter("samples_sent").unwrap().get(), 3_f64);
+        assert_eq!(scope.counter("request_retries").unwrap().get(), 2_f64);
+        assert_eq!(scope.counter("unsupported_types").unwrap().get(), 1_f64);
+    }
+
+    #[test]
+    fn test_flush_cumulative() {
+        let scope = crate::stats::Collector::default().scope("test");
+        let aggregator = Aggregator {
+            state: Mutex::new(State::default()),
+            shards: 1,
+            max_samples_per_send: 1,
+            series_gauge: scope.gauge("series").unwrap(),
+        };
+        let owned = |line| Owned::try_from(&event(line)).unwrap();
+        aggregator.record(&owned(b"a:1|c"));
+        aggregator.record(&owned(b"b:1|g"));
+        let now = SystemTime::now();
+        assert_eq!(aggregator.flush(now)[0].len(), 2);
+        aggregator.record(&owned(b"a:1|c"));
+        assert_eq!(
+            aggregator.state.lock().series[&SeriesKey::new(&owned(b"a:1|c"))].value,
+            Value::Counter(2_f64)
+        );
+
+        // Series not updated are still sent, until they go stale
+        for _ in 0..STALE_FLUSHES - 1 {
+            assert_eq!(aggregator.flush(now)[0].len(), 2);
+        }
+        assert_eq!(aggregator.flush(now)[0].len(), 1);
+        assert_eq!(scope.gauge("series").unwrap().get(), 1_f64);
+    }
+}
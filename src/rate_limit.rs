@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled continuously at `rate` tokens per second, holding
+/// at most `capacity` tokens. Tokens can either be taken only when available,
+/// for callers which drop excess work, or taken unconditionally with the
+/// bucket going into debt, for callers which instead delay until the debt is
+/// repaid.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket. The capacity is the largest burst allowed after
+    /// the bucket has been idle.
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Take `count` tokens if they are all available, returning whether they
+    /// were taken.
+    pub fn try_take(&mut self, count: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < count {
+            return false;
+        }
+        self.tokens -= count;
+        true
+    }
+
+    /// Take `count` tokens regardless of availability, returning how long
+    /// until the bucket is no longer in debt.
+    pub fn take(&mut self, count: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= count;
+        if self.tokens >= 0_f64 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn try_take_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10_f64, 10_f64, start);
+        assert!(bucket.try_take(10_f64, start));
+        assert!(!bucket.try_take(1_f64, start));
+        // Half a second refills half the rate
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(5_f64, later));
+        assert!(!bucket.try_take(1_f64, later));
+        // Refilling never exceeds the capacity
+        let much_later = later + Duration::from_secs(60);
+        assert!(!bucket.try_take(11_f64, much_later));
+        assert!(bucket.try_take(10_f64, much_later));
+    }
+
+    #[test]
+    fn take_into_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100_f64, 100_f64, start);
+        assert_eq!(bucket.take(50_f64, start), Duration::from_secs(0));
+        assert_eq!(bucket.take(100_f64, start), Duration::from_millis(500));
+        // Once the delay has passed the bucket is back to empty
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(0_f64, later), Duration::from_secs(0));
+        assert!(!bucket.try_take(1_f64, later));
+    }
+}
@@ -1,27 +1,155 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 
+use smallvec::SmallVec;
+
+use crate::config::ShardKey;
 use crate::statsd_proto::Pdu;
 
 // HASHLIB_SEED same as the legacy statsrelay code base
 const HASHLIB_SEED: u32 = 0xaccd3d34;
 
+// Seed for choosing a percentage of names, differing from HASHLIB_SEED so the
+// names chosen are spread across every shard
+const PERCENT_SEED: u32 = 0x5bd1e995;
+
 pub fn statsrelay_compat_hash(pdu: &Pdu) -> u32 {
     murmur3::murmur3_32(&mut Cursor::new(pdu.name()), HASHLIB_SEED).unwrap_or(0)
 }
 
+/// Value of the first DogStatsD tag with the given key, empty for a tag
+/// with no value
+fn tag_value<'a>(pdu: &'a Pdu, key: &str) -> Option<&'a [u8]> {
+    let key = key.as_bytes();
+    pdu.tags()?
+        .split(|c| *c == b',')
+        .find_map(|tag| match tag.strip_prefix(key) {
+            Some(b"") => Some(&b""[..]),
+            Some(rest) => rest.strip_prefix(b":"),
+            None => None,
+        })
+}
+
+/// Hash the part of a line a backend shards by. Lines without the tag a
+/// backend shards by fall back to being sharded by name, and tags are
+/// hashed as they were received, so the same tags in another order may be
+/// sent to another shard.
+pub fn shard_hash(pdu: &Pdu, key: &ShardKey) -> u32 {
+    match key {
+        ShardKey::Name => statsrelay_compat_hash(pdu),
+        ShardKey::NameAndTags => {
+            let mut id = Cursor::new(pdu.name()).chain(Cursor::new(pdu.tags().unwrap_or_default()));
+            murmur3::murmur3_32(&mut id, HASHLIB_SEED).unwrap_or(0)
+        }
+        ShardKey::Tag(key) => match tag_value(pdu, key) {
+            Some(value) => murmur3::murmur3_32(&mut Cursor::new(value), HASHLIB_SEED).unwrap_or(0),
+            None => statsrelay_compat_hash(pdu),
+        },
+    }
+}
+
+pub(crate) fn hash_in_percent(hash: u32, percent: f64) -> bool {
+    (hash as f64) < percent / 100_f64 * (u32::MAX as f64 + 1_f64)
+}
+
+/// Whether a metric's name is within a percentage of all names. The same
+/// names are always chosen, and raising the percentage only adds to them.
+pub fn name_in_percent(pdu: &Pdu, percent: f64) -> bool {
+    let hash = murmur3::murmur3_32(&mut Cursor::new(pdu.name()), PERCENT_SEED).unwrap_or(0);
+    hash_in_percent(hash, percent)
+}
+
+fn id_percent_hash(pdu: &Pdu) -> u32 {
+    let mut id = Cursor::new(pdu.name())
+        .chain(Cursor::new(pdu.pdu_type()))
+        .chain(Cursor::new(pdu.tags().unwrap_or_default()));
+    murmur3::murmur3_32(&mut id, PERCENT_SEED).unwrap_or(0)
+}
+
+/// Whether a metric's name, type and tags are within a percentage of all
+/// of them, as with [`name_in_percent`](name_in_percent). Tags are hashed as
+/// they were received, so the same tags in another order may not be chosen.
+pub fn id_in_percent(pdu: &Pdu, percent: f64) -> bool {
+    hash_in_percent(id_percent_hash(pdu), percent)
+}
+
+/// Position of a metric's name, type and tags within all of them, from 0 up
+/// to 100. A metric is within a percentage, as with
+/// [`id_in_percent`](id_in_percent), when its position is below it.
+pub fn id_percentile(pdu: &Pdu) -> f64 {
+    id_percent_hash(pdu) as f64 / (u32::MAX as f64 + 1_f64) * 100_f64
+}
+
+/// Whether the shard key hash of a metric is within a percentage of all
+/// keys. The keys chosen are independent of the member each is sharded to.
+pub fn code_in_percent(code: u32, percent: f64) -> bool {
+    let hash = murmur3::murmur3_32(&mut Cursor::new(code.to_le_bytes()), PERCENT_SEED).unwrap_or(0);
+    hash_in_percent(hash, percent)
+}
+
+/// Another hash derived from a hash, murmur3's finalizer of the hash offset
+/// so that zero is not a fixed point
+fn rehash(code: u32) -> u32 {
+    let mut h = code.wrapping_add(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// Members which keys are sharded over. By default a key's member is chosen
+/// by its hash modulo the number of members, as the legacy statsrelay code
+/// base does. A ring created [`with_vnodes`](Ring::with_vnodes) instead
+/// places each named member at that many points on a hash ring, and a key
+/// goes to the member of the first point at or after its hash. Adding or
+/// removing a member then only moves the keys of its own points.
 pub struct Ring<C: Send + Sync + 'static> {
     members: Vec<C>,
+    vnodes: Option<u32>,
+    /// Names of members, in the order they were pushed
+    names: Vec<String>,
+    /// Hash of each point on the ring and the index of its member, sorted
+    points: Vec<(u32, usize)>,
 }
 
 impl<C: Send + Sync + 'static> Ring<C> {
     pub fn new() -> Self {
         Ring {
             members: Vec::new(),
+            vnodes: None,
+            names: Vec::new(),
+            points: Vec::new(),
+        }
+    }
+
+    pub fn with_vnodes(vnodes: u32) -> Self {
+        Ring {
+            vnodes: Some(vnodes),
+            ..Ring::new()
         }
     }
 
     pub fn push(&mut self, c: C) {
+        self.push_named("", c);
+    }
+
+    /// Add a member, placing its points on the ring by its name. A name
+    /// pushed more than once gets distinct points for each member, so
+    /// repeating it weights it as repeating it would without virtual nodes.
+    pub fn push_named(&mut self, name: &str, c: C) {
+        let index = self.members.len();
         self.members.push(c);
+        if let Some(vnodes) = self.vnodes {
+            let repeats = self.names.iter().filter(|n| *n == name).count() as u32;
+            for vnode in (repeats * vnodes)..((repeats + 1) * vnodes) {
+                let key = format!("{}-{}", name, vnode);
+                let hash = murmur3::murmur3_32(&mut Cursor::new(key.as_bytes()), HASHLIB_SEED)
+                    .unwrap_or(0);
+                self.points.push((hash, index));
+            }
+            self.points.sort_unstable();
+        }
+        self.names.push(name.to_owned());
     }
 
     pub fn len(&self) -> usize {
@@ -32,23 +160,167 @@ impl<C: Send + Sync + 'static> Ring<C> {
         self.len() == 0
     }
 
+    /// Every member, in the order they were pushed
+    pub fn members(&self) -> impl Iterator<Item = &C> {
+        self.members.iter()
+    }
+
+    /// Index of the first point at or after a hash, wrapping around
+    fn point(&self, code: u32) -> usize {
+        let point = self.points.partition_point(|(hash, _)| *hash < code);
+        if point == self.points.len() {
+            0
+        } else {
+            point
+        }
+    }
+
+    fn index(&self, code: u32) -> usize {
+        if self.points.is_empty() {
+            code as usize % self.members.len()
+        } else {
+            self.points[self.point(code)].1
+        }
+    }
+
     pub fn pick_from(&self, code: u32) -> &C {
-        let l = self.members.len();
-        self.members.get(code as usize % l).unwrap()
+        self.members.get(self.index(code)).unwrap()
+    }
+
+    /// Pick a member as for `pick_from`, but only from the members passing
+    /// the predicate, so the keys of the excluded members are rehashed over
+    /// the rest. Returns None if no member passes.
+    pub fn pick_from_filtered<F>(&self, code: u32, filter: F) -> Option<&C>
+    where
+        F: FnMut(&C) -> bool,
+    {
+        self.index_filtered(code, filter).map(|i| &self.members[i])
+    }
+
+    /// Index of the member `pick_from_filtered` picks. Only the keys of
+    /// members which don't pass move: on a ring, to the next point of a
+    /// passing member, and otherwise to the first passing member of
+    /// successive rehashes of the key, so they are spread over the rest.
+    fn index_filtered<F>(&self, code: u32, mut filter: F) -> Option<usize>
+    where
+        F: FnMut(&C) -> bool,
+    {
+        if !self.points.is_empty() {
+            let start = self.point(code);
+            return (0..self.points.len())
+                .map(|i| self.points[(start + i) % self.points.len()].1)
+                .find(|i| filter(&self.members[*i]));
+        }
+        let len = self.members.len();
+        if len == 0 {
+            return None;
+        }
+        let index = code as usize % len;
+        if filter(&self.members[index]) {
+            return Some(index);
+        }
+        let mut probe = code;
+        for _ in 1..len {
+            probe = rehash(probe);
+            let i = probe as usize % len;
+            if filter(&self.members[i]) {
+                return Some(i);
+            }
+        }
+        // Rehashing may miss the few passing members of a large ring
+        (1..len)
+            .map(|i| (index + i) % len)
+            .find(|i| filter(&self.members[*i]))
+    }
+
+    /// Pick up to `n` distinct members passing the predicate, starting with
+    /// the member `pick_from_filtered` picks and continuing around the ring.
+    /// Members pushed with the same name are only picked once.
+    pub fn pick_n_filtered<F>(&self, code: u32, n: usize, mut filter: F) -> SmallVec<[&C; 4]>
+    where
+        F: FnMut(&C) -> bool,
+    {
+        // Walk the members, or the points of a ring with vnodes, from the
+        // first position until enough members are picked
+        let (start, steps) = if self.points.is_empty() {
+            match self.index_filtered(code, &mut filter) {
+                Some(first) => (first, self.members.len()),
+                None => return SmallVec::new(),
+            }
+        } else {
+            (self.point(code), self.points.len())
+        };
+        let mut picked: SmallVec<[usize; 4]> = SmallVec::new();
+        for step in 0..steps {
+            if picked.len() == n {
+                break;
+            }
+            let index = if self.points.is_empty() {
+                (start + step) % steps
+            } else {
+                self.points[(start + step) % steps].1
+            };
+            let name = &self.names[index];
+            let repeat = picked
+                .iter()
+                .any(|p| *p == index || (!name.is_empty() && self.names[*p] == *name));
+            if !repeat && filter(&self.members[index]) {
+                picked.push(index);
+            }
+        }
+        picked.into_iter().map(|i| &self.members[i]).collect()
     }
 
     pub fn act_on<F>(&mut self, code: u32, mut f: F)
     where
         F: FnMut(&mut C),
     {
-        let len = self.members.len();
-        let c = &mut self.members[code as usize % len];
-        f(c);
+        let index = self.index(code);
+        f(&mut self.members[index]);
     }
 
     pub fn swap(&mut self, other: Ring<C>) {
-        self.members = other.members;
+        *self = other;
     }
+
+    /// The share of all hashes, from 0 to 1, each member is picked for
+    pub fn shares(&self) -> Vec<f64> {
+        let mut shares = vec![0_f64; self.members.len()];
+        if self.points.is_empty() {
+            for share in shares.iter_mut() {
+                *share = 1_f64 / self.members.len() as f64;
+            }
+            return shares;
+        }
+        let space = u32::MAX as f64 + 1_f64;
+        let mut previous = self.points[self.points.len() - 1].0 as f64 - space;
+        for (hash, index) in self.points.iter() {
+            shares[*index] += (*hash as f64 - previous) / space;
+            previous = *hash as f64;
+        }
+        shares
+    }
+}
+
+/// The share of keys, from 0 to 1, each distinct endpoint of a shard map is
+/// expected to receive, in the order they first appear.
+pub fn expected_shares(shard_map: &[String], vnodes: Option<u32>) -> Vec<(String, f64)> {
+    let mut ring = match vnodes {
+        Some(vnodes) => Ring::with_vnodes(vnodes),
+        None => Ring::new(),
+    };
+    let endpoints: Vec<&String> = shard_map.iter().filter(|e| !e.is_empty()).collect();
+    for endpoint in endpoints.iter() {
+        ring.push_named(endpoint, ());
+    }
+    let mut shares: Vec<(String, f64)> = Vec::new();
+    for (endpoint, share) in endpoints.into_iter().zip(ring.shares()) {
+        match shares.iter_mut().find(|(e, _)| e == endpoint) {
+            Some((_, total)) => *total += share,
+            None => shares.push((endpoint.clone(), share)),
+        }
+    }
+    shares
 }
 
 impl<C: Send + Sync + 'static> Default for Ring<C> {
@@ -76,6 +348,195 @@ pub mod test {
         ring.swap(ring2);
         assert_eq!(ring.len(), 3);
     }
+    #[test]
+    fn test_pick_from_filtered() {
+        let mut ring = Ring::new();
+        for i in 0..4 {
+            ring.push(i);
+        }
+        assert_eq!(ring.pick_from_filtered(5, |_| true), Some(&1));
+        assert_eq!(ring.pick_from_filtered(1, |c| *c % 2 == 0).unwrap() % 2, 0);
+        assert_eq!(ring.pick_from_filtered(1, |_| false), None);
+
+        // Only the keys of the excluded member move, spread over the rest
+        let mut moved = [0; 4];
+        for code in (0..u32::MAX).step_by(65_537) {
+            let picked = *ring.pick_from_filtered(code, |c| *c != 1).unwrap();
+            if *ring.pick_from(code) == 1 {
+                moved[picked] += 1;
+            } else {
+                assert_eq!(picked, *ring.pick_from(code));
+            }
+        }
+        assert_eq!(moved[1], 0);
+        assert!(
+            moved.iter().filter(|m| **m > 2000).count() == 3,
+            "{:?}",
+            moved
+        );
+    }
+
+    #[test]
+    fn test_shard_hash() {
+        let parse = |line: &'static str| Pdu::parse(Bytes::from(line)).unwrap();
+        let host = ShardKey::Tag("host".to_owned());
+        let a = parse("cpu:1|g|#host:a,region:us");
+        assert_eq!(shard_hash(&a, &ShardKey::Name), statsrelay_compat_hash(&a));
+        // Every metric of a host shards together
+        assert_eq!(
+            shard_hash(&a, &host),
+            shard_hash(&parse("mem:5|g|#region:eu,host:a"), &host)
+        );
+        assert_ne!(
+            shard_hash(&a, &host),
+            shard_hash(&parse("cpu:1|g|#host:b,region:us"), &host)
+        );
+        let untagged = parse("cpu:1|g|#hostname:a");
+        assert_eq!(
+            shard_hash(&untagged, &host),
+            statsrelay_compat_hash(&untagged)
+        );
+        assert_ne!(
+            shard_hash(&a, &ShardKey::NameAndTags),
+            shard_hash(&parse("cpu:1|g|#host:b,region:us"), &ShardKey::NameAndTags)
+        );
+    }
+
+    #[test]
+    fn test_vnodes() {
+        let mut ring = Ring::with_vnodes(100);
+        for i in 0..4 {
+            ring.push_named(&format!("10.0.0.{}:8125", i), i);
+        }
+        let shares = ring.shares();
+        assert!((shares.iter().sum::<f64>() - 1_f64).abs() < 1e-9);
+        assert!(
+            shares.iter().all(|s| (0.15..0.35).contains(s)),
+            "{:?}",
+            shares
+        );
+
+        // Each key moves only if its member was removed
+        let mut smaller = Ring::with_vnodes(100);
+        for i in [0, 1, 3] {
+            smaller.push_named(&format!("10.0.0.{}:8125", i), i);
+        }
+        for code in (0..u32::MAX).step_by(1 << 20) {
+            let before = *ring.pick_from(code);
+            if before != 2 {
+                assert_eq!(*smaller.pick_from(code), before);
+            }
+            assert_eq!(
+                ring.pick_from_filtered(code, |c| *c != 2),
+                Some(smaller.pick_from(code))
+            );
+        }
+    }
+
+    #[test]
+    fn test_pick_n_filtered() {
+        let mut ring = Ring::new();
+        for (name, i) in [("a", 0), ("b", 1), ("a", 2), ("c", 3)] {
+            ring.push_named(name, i);
+        }
+        let picked = ring.pick_n_filtered(1, 2, |_| true);
+        assert_eq!(picked.as_slice(), &[&1, &2]);
+        // The second "a" is skipped as a repeat of the first
+        let picked = ring.pick_n_filtered(0, 3, |_| true);
+        assert_eq!(picked.as_slice(), &[&0, &1, &3]);
+        let picked = ring.pick_n_filtered(0, 3, |c| *c != 1);
+        assert_eq!(picked.as_slice(), &[&0, &3]);
+        assert_eq!(
+            ring.pick_n_filtered(5, 1, |c| *c != 1)[0],
+            ring.pick_from_filtered(5, |c| *c != 1).unwrap()
+        );
+
+        let mut ring = Ring::with_vnodes(10);
+        for i in 0..4 {
+            ring.push_named(&i.to_string(), i);
+        }
+        for code in (0..u32::MAX).step_by(1 << 24) {
+            let picked = ring.pick_n_filtered(code, 3, |_| true);
+            assert_eq!(picked.len(), 3);
+            assert_eq!(picked[0], ring.pick_from(code));
+        }
+        // The walk stops once enough members are picked
+        let mut checked = 0;
+        ring.pick_n_filtered(0, 1, |_| {
+            checked += 1;
+            true
+        });
+        assert_eq!(checked, 1);
+    }
+
+    #[test]
+    fn test_expected_shares() {
+        let shard_map: Vec<String> = vec!["a:1".into(), "b:1".into(), "a:1".into(), "".into()];
+        let shares = expected_shares(&shard_map, None);
+        assert_eq!(shares.len(), 2);
+        assert_eq!(shares[0].0, "a:1");
+        assert!((shares[0].1 - 2_f64 / 3_f64).abs() < 1e-9);
+        let shares = expected_shares(&shard_map, Some(50));
+        assert!((shares[0].1 + shares[1].1 - 1_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_name_in_percent() {
+        let pdus: Vec<Pdu> = (0..10000)
+            .map(|i| Pdu::parse(Bytes::from(format!("metric.{}:1|c", i))).unwrap())
+            .collect();
+        let chosen = |percent| {
+            pdus.iter()
+                .filter(|pdu| name_in_percent(pdu, percent))
+                .count()
+        };
+        assert_eq!(chosen(100_f64), pdus.len());
+        assert_eq!(chosen(0_f64), 0);
+        let quarter = chosen(25_f64);
+        assert!((2300..2700).contains(&quarter), "{} chosen", quarter);
+        // Names chosen at a lower percentage are still chosen at a higher one
+        assert!(pdus
+            .iter()
+            .filter(|pdu| name_in_percent(pdu, 10_f64))
+            .all(|pdu| name_in_percent(pdu, 25_f64)));
+    }
+
+    #[test]
+    fn test_id_in_percent() {
+        let pdus: Vec<Pdu> = (0..10000)
+            .map(|i| Pdu::parse(Bytes::from(format!("metric:1|c|#host:{}", i))).unwrap())
+            .collect();
+        let chosen = pdus.iter().filter(|pdu| id_in_percent(pdu, 25_f64)).count();
+        assert!((2300..2700).contains(&chosen), "{} chosen", chosen);
+        // Every line of a chosen metric is chosen, whatever its value
+        for pdu in pdus.iter().filter(|pdu| id_in_percent(pdu, 25_f64)) {
+            let line = [b"metric:5", &pdu.as_bytes()[8..]].concat();
+            assert!(id_in_percent(
+                &Pdu::parse(Bytes::from(line)).unwrap(),
+                25_f64
+            ));
+        }
+    }
+
+    #[test]
+    fn test_code_in_percent() {
+        let chosen = (0..10000_u32)
+            .filter(|c| code_in_percent(*c, 25_f64))
+            .count();
+        assert!((2300..2700).contains(&chosen), "{} chosen", chosen);
+        // Raising the percentage only adds keys, and keys are chosen evenly
+        // from those sharded to each member
+        for code in (0..10000_u32).filter(|c| code_in_percent(*c, 25_f64)) {
+            assert!(code_in_percent(code, 50_f64));
+        }
+        let member = (0..10000_u32)
+            .filter(|c| c % 4 == 0 && code_in_percent(*c, 50_f64))
+            .count();
+        assert!((1100..1400).contains(&member), "{} chosen", member);
+        assert!(!(0..1000_u32).any(|c| code_in_percent(c, 0_f64)));
+        assert!((0..1000_u32).all(|c| code_in_percent(c, 100_f64)));
+    }
+
     #[test]
     fn test_hash() {
         let mut ring = Ring::new();
@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use tokio::time::timeout;
+
+pub const LISTENER_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const PROCESSOR_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+pub const BACKEND_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const ADMIN_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Resolve a configured stage deadline in seconds, falling back to the given
+/// default.
+pub fn deadline(seconds: Option<u64>, default: Duration) -> Duration {
+    seconds.map(Duration::from_secs).unwrap_or(default)
+}
+
+/// Run a single named stage of the shutdown sequence, waiting at most
+/// `deadline` for it to complete before moving on. Stages are expected to be
+/// run in order, so a stage which times out does not block later stages from
+/// running. Returns true if the stage completed before its deadline.
+pub async fn stage<F: Future>(name: &str, deadline: Duration, stage: F) -> bool {
+    info!("shutdown: {}", name);
+    let start = Instant::now();
+    match timeout(deadline, stage).await {
+        Ok(_) => {
+            info!("shutdown: {} finished in {:?}", name, start.elapsed());
+            true
+        }
+        Err(_) => {
+            warn!(
+                "shutdown: {} did not finish within {:?}, continuing",
+                name, deadline
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn stage_deadline() {
+        assert!(stage("immediate", Duration::from_secs(1), async {}).await);
+        assert!(
+            !stage(
+                "pending",
+                Duration::from_millis(10),
+                futures::future::pending::<()>()
+            )
+            .await
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! A size capped on-disk ring of statsd lines, used to hold lines for a
+//! backend endpoint while its in-memory queue is full.
+//!
+//! Lines are appended to segment files in a directory, named by an
+//! increasing sequence number. Once the ring is over its size cap the oldest
+//! segments are deleted, so an extended outage keeps the most recent data.
+//! Segments left behind by a previous process are picked up again on open.
+//!
+//! A directory has one ring per process: opening a directory whose ring is
+//! already open returns that ring, so clients spilling for the same endpoint,
+//! such as the old and new clients of a reload, append to and replay from the
+//! same segments rather than overwriting each other's.
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+use parking_lot::{const_mutex, Mutex};
+
+const SEGMENT_EXTENSION: &str = "spill";
+/// Smallest segment written, regardless of the size cap
+const MIN_SEGMENT_BYTES: u64 = 64 * 1024;
+/// The size cap is split over roughly this many segments, which is the
+/// granularity of eviction
+const SEGMENTS: u64 = 8;
+
+/// Rings currently open, by directory
+static OPEN: Mutex<Option<HashMap<PathBuf, Weak<Spill>>>> = const_mutex(None);
+
+struct Segment {
+    path: PathBuf,
+    bytes: u64,
+}
+
+struct Writer {
+    segment: Segment,
+    file: BufWriter<File>,
+}
+
+struct State {
+    /// Completed segments, oldest first
+    segments: VecDeque<Segment>,
+    writer: Option<Writer>,
+    next: u64,
+    bytes: u64,
+}
+
+pub struct Spill {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    state: Mutex<State>,
+}
+
+impl Spill {
+    /// Open the spill ring of the given directory, creating it if needed and
+    /// adopting any segments already in it, or return the ring if it is
+    /// already open.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        let mut open = OPEN.lock();
+        let open = open.get_or_insert_with(HashMap::new);
+        open.retain(|_, spill| spill.strong_count() > 0);
+        if let Some(spill) = open.get(&dir).and_then(Weak::upgrade) {
+            return Ok(spill);
+        }
+        let spill = Arc::new(Self::adopt(&dir, max_bytes)?);
+        open.insert(dir, Arc::downgrade(&spill));
+        Ok(spill)
+    }
+
+    fn adopt(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let sequence = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok());
+            if let Some(sequence) = sequence {
+                let bytes = fs::metadata(&path)?.len();
+                found.push((sequence, Segment { path, bytes }));
+            }
+        }
+        found.sort_by_key(|(sequence, _)| *sequence);
+        let next = found.last().map(|(sequence, _)| sequence + 1).unwrap_or(0);
+        let segments: VecDeque<Segment> = found.into_iter().map(|(_, s)| s).collect();
+        let bytes = segments.iter().map(|s| s.bytes).sum();
+        Ok(Spill {
+            dir: dir.to_owned(),
+            max_bytes,
+            segment_bytes: (max_bytes / SEGMENTS).max(MIN_SEGMENT_BYTES),
+            state: Mutex::new(State {
+                segments,
+                writer: None,
+                next,
+                bytes,
+            }),
+        })
+    }
+
+    /// Append a line to the ring, returning the number of bytes of older
+    /// lines evicted to stay under the size cap.
+    pub fn push(&self, line: &[u8]) -> io::Result<u64> {
+        let mut state = self.state.lock();
+        if state.writer.is_none() {
+            let path = self
+                .dir
+                .join(format!("{:020}.{}", state.next, SEGMENT_EXTENSION));
+            state.next += 1;
+            let file = BufWriter::new(File::create(&path)?);
+            state.writer = Some(Writer {
+                segment: Segment { path, bytes: 0 },
+                file,
+            });
+        }
+        let writer = state.writer.as_mut().unwrap();
+        writer.file.write_all(line)?;
+        writer.file.write_all(b"\n")?;
+        let written = line.len() as u64 + 1;
+        writer.segment.bytes += written;
+        let full = writer.segment.bytes >= self.segment_bytes;
+        state.bytes += written;
+        if full {
+            Self::complete(&mut state)?;
+        }
+
+        let mut evicted = 0;
+        while state.bytes > self.max_bytes {
+            let oldest = match state.segments.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            fs::remove_file(&oldest.path)?;
+            state.bytes -= oldest.bytes;
+            evicted += oldest.bytes;
+        }
+        Ok(evicted)
+    }
+
+    /// Finish writing the current segment, making it available to pop.
+    fn complete(state: &mut State) -> io::Result<()> {
+        if let Some(mut writer) = state.writer.take() {
+            writer.file.flush()?;
+            state.segments.push_back(writer.segment);
+        }
+        Ok(())
+    }
+
+    /// Take the oldest segment out of the ring, returning its path. The
+    /// caller owns the file, and should delete it once its lines have been
+    /// replayed. Until then it is adopted again if the process restarts.
+    pub fn pop(&self) -> io::Result<Option<PathBuf>> {
+        let mut state = self.state.lock();
+        if state.segments.is_empty() {
+            Self::complete(&mut state)?;
+        }
+        Ok(state.segments.pop_front().map(|segment| {
+            state.bytes -= segment.bytes;
+            segment.path
+        }))
+    }
+
+    /// Number of bytes of lines held in the ring
+    pub fn bytes(&self) -> u64 {
+        self.state.lock().bytes
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = Self::complete(&mut self.state.lock());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_spill_ring() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = Spill::open(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(spill.pop().unwrap(), None);
+        spill.push(b"a:1|c").unwrap();
+        spill.push(b"b:1|c").unwrap();
+        assert_eq!(spill.bytes(), 12);
+
+        let first = spill.pop().unwrap().unwrap();
+        assert_eq!(lines(&first), vec!["a:1|c", "b:1|c"]);
+        assert_eq!(spill.bytes(), 0);
+        fs::remove_file(first).unwrap();
+
+        // Segments not yet replayed are adopted when reopened
+        spill.push(b"c:1|c").unwrap();
+        drop(spill);
+        let spill = Spill::open(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(spill.bytes(), 6);
+        spill.push(b"d:1|c").unwrap();
+        let second = spill.pop().unwrap().unwrap();
+        assert_eq!(lines(&second), vec!["c:1|c"]);
+        let third = spill.pop().unwrap().unwrap();
+        assert_eq!(lines(&third), vec!["d:1|c"]);
+        assert_eq!(spill.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn test_spill_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = Spill::open(dir.path(), 1024 * 1024).unwrap();
+        let again = Spill::open(&dir.path().join("."), 1024 * 1024).unwrap();
+        assert!(Arc::ptr_eq(&spill, &again));
+        spill.push(b"a:1|c").unwrap();
+        again.push(b"b:1|c").unwrap();
+        let segment = again.pop().unwrap().unwrap();
+        assert_eq!(lines(&segment), vec!["a:1|c", "b:1|c"]);
+        assert_eq!(spill.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn test_spill_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = Spill::open(dir.path(), 2 * MIN_SEGMENT_BYTES).unwrap();
+        let line = [b'x'; 1023];
+        let mut evicted = 0;
+        for _ in 0..256 {
+            evicted += spill.push(&line).unwrap();
+        }
+        // Four segments were written, of which the oldest two were evicted
+        assert_eq!(evicted, 2 * MIN_SEGMENT_BYTES);
+        assert_eq!(spill.bytes(), 2 * MIN_SEGMENT_BYTES);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
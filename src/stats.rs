@@ -1,6 +1,9 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use dashmap::DashMap;
+use prometheus::core::Collector as _;
+use prometheus::proto::{Metric, MetricFamily};
 use prometheus::{Encoder, Registry, TextEncoder};
 
 pub const SEP: &str = ":";
@@ -21,6 +24,9 @@ pub struct Collector {
     registry: Registry,
     counters: Arc<DashMap<String, Counter>>,
     gauges: Arc<DashMap<String, Gauge>>,
+    gauge_vecs: Arc<DashMap<String, GaugeVec>>,
+    counter_vecs: Arc<DashMap<String, CounterVec>>,
+    histograms: Arc<DashMap<String, Histogram>>,
 }
 
 impl Default for Collector {
@@ -29,6 +35,9 @@ impl Default for Collector {
             registry: Registry::new(),
             counters: Arc::new(DashMap::new()),
             gauges: Arc::new(DashMap::new()),
+            gauge_vecs: Arc::new(DashMap::new()),
+            counter_vecs: Arc::new(DashMap::new()),
+            histograms: Arc::new(DashMap::new()),
         }
     }
 }
@@ -52,6 +61,26 @@ impl Collector {
         Ok(buffer)
     }
 
+    /// Return the current value of every registered counter and gauge, keyed
+    /// by full metric name. Each series of a labeled counter or gauge is
+    /// keyed by its name and labels, as `name{label="value"}`.
+    pub fn snapshot(&self) -> HashMap<String, f64> {
+        let counters = self.counters.iter().map(|c| (c.key().clone(), c.get()));
+        let gauges = self.gauges.iter().map(|g| (g.key().clone(), g.get()));
+        let mut snapshot: HashMap<String, f64> = counters.chain(gauges).collect();
+        for counters in self.counter_vecs.iter() {
+            for family in counters.counters.collect() {
+                snapshot.extend(series(&family, |m| m.get_counter().get_value()));
+            }
+        }
+        for gauges in self.gauge_vecs.iter() {
+            for family in gauges.gauges.collect() {
+                snapshot.extend(series(&family, |m| m.get_gauge().get_value()));
+            }
+        }
+        snapshot
+    }
+
     /// Attempt to register a new counter. If the counter already exists, it
     /// will return the previously registered counter instead of the one passed
     /// in.
@@ -79,6 +108,58 @@ impl Collector {
         };
         Ok(gauge)
     }
+
+    fn register_gauge_vec(&self, g: GaugeVec) -> anyhow::Result<GaugeVec> {
+        let gauge = match self.gauge_vecs.get(&g.name) {
+            Some(gauge) => gauge.clone(),
+            None => {
+                self.registry.register(Box::new(g.clone().gauges))?;
+                self.gauge_vecs.insert(g.name.clone(), g.clone());
+                g
+            }
+        };
+        Ok(gauge)
+    }
+
+    fn register_counter_vec(&self, c: CounterVec) -> anyhow::Result<CounterVec> {
+        let counter = match self.counter_vecs.get(&c.name) {
+            Some(counter) => counter.clone(),
+            None => {
+                self.registry.register(Box::new(c.clone().counters))?;
+                self.counter_vecs.insert(c.name.clone(), c.clone());
+                c
+            }
+        };
+        Ok(counter)
+    }
+
+    fn register_histogram(&self, h: Histogram) -> anyhow::Result<Histogram> {
+        let histogram = match self.histograms.get(&h.name) {
+            Some(histogram) => histogram.clone(),
+            None => {
+                self.registry.register(Box::new(h.clone().histogram))?;
+                self.histograms.insert(h.name.clone(), h.clone());
+                h
+            }
+        };
+        Ok(histogram)
+    }
+}
+
+/// Each labeled series of a metric family, keyed by name and labels
+fn series<'a>(
+    family: &'a MetricFamily,
+    value: fn(&Metric) -> f64,
+) -> impl Iterator<Item = (String, f64)> + 'a {
+    family.get_metric().iter().map(move |metric| {
+        let labels: Vec<String> = metric
+            .get_label()
+            .iter()
+            .map(|label| format!("{}=\"{}\"", label.get_name(), label.get_value()))
+            .collect();
+        let name = format!("{}{{{}}}", family.get_name(), labels.join(","));
+        (name, value(metric))
+    })
 }
 
 #[derive(Clone, Debug)]
@@ -88,6 +169,11 @@ pub struct Scope {
 }
 
 impl Scope {
+    /// The collector this scope registers metrics with
+    pub fn collector(&self) -> &Collector {
+        &self.collector
+    }
+
     pub fn scope(&self, extend: &str) -> Scope {
         Scope {
             scope: format!("{}{}{}", self.scope, SEP, extend),
@@ -110,6 +196,30 @@ impl Scope {
         let gauge = Gauge::new(name.as_str())?;
         self.collector.register_gauge(gauge)
     }
+
+    /// Create a new gauge with a set of label names, or return the existing
+    /// labeled gauge with the same name
+    pub fn gauge_vec(&self, name: &str, labels: &[&str]) -> anyhow::Result<GaugeVec> {
+        let name = format!("{}{}{}", self.scope, SEP, name);
+        let gauge = GaugeVec::new(name.as_str(), labels)?;
+        self.collector.register_gauge_vec(gauge)
+    }
+
+    /// Create a new counter with a set of label names, or return the existing
+    /// labeled counter with the same name
+    pub fn counter_vec(&self, name: &str, labels: &[&str]) -> anyhow::Result<CounterVec> {
+        let name = format!("{}{}{}", self.scope, SEP, name);
+        let counter = CounterVec::new(name.as_str(), labels)?;
+        self.collector.register_counter_vec(counter)
+    }
+
+    /// Create a new histogram with the given bucket upper bounds, or return
+    /// the existing histogram with the same name
+    pub fn histogram(&self, name: &str, buckets: &[f64]) -> anyhow::Result<Histogram> {
+        let name = format!("{}{}{}", self.scope, SEP, name);
+        let histogram = Histogram::new(name.as_str(), buckets)?;
+        self.collector.register_histogram(histogram)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -131,11 +241,118 @@ impl Gauge {
         self.gauge.set(value)
     }
 
+    pub fn inc(&self) {
+        self.gauge.inc()
+    }
+
+    pub fn dec(&self) {
+        self.gauge.dec()
+    }
+
     pub fn get(&self) -> f64 {
         self.gauge.get()
     }
 }
 
+/// A gauge partitioned by label values. Each distinct set of label values is
+/// exported as its own series until the gauge is reset.
+#[derive(Clone, Debug)]
+pub struct GaugeVec {
+    name: String,
+    gauges: prometheus::GaugeVec,
+}
+
+impl GaugeVec {
+    fn new(name: &str, labels: &[&str]) -> anyhow::Result<Self> {
+        let opts = prometheus::Opts::new(name.to_owned(), "a labeled gauge");
+        let pg = prometheus::GaugeVec::new(opts, labels)?;
+        Ok(Self {
+            name: name.to_owned(),
+            gauges: pg,
+        })
+    }
+
+    /// Set the gauge for the given label values, which must match the
+    /// number of labels the gauge was created with
+    pub fn set(&self, label_values: &[&str], value: f64) {
+        self.gauges.with_label_values(label_values).set(value)
+    }
+
+    pub fn get(&self, label_values: &[&str]) -> f64 {
+        self.gauges.with_label_values(label_values).get()
+    }
+
+    /// Remove every labeled series
+    pub fn reset(&self) {
+        self.gauges.reset()
+    }
+}
+
+/// A counter partitioned by label values, with each distinct set of label
+/// values exported as its own series.
+#[derive(Clone, Debug)]
+pub struct CounterVec {
+    name: String,
+    counters: prometheus::CounterVec,
+}
+
+impl CounterVec {
+    fn new(name: &str, labels: &[&str]) -> anyhow::Result<Self> {
+        let opts = prometheus::Opts::new(name.to_owned(), "a labeled counter");
+        let pc = prometheus::CounterVec::new(opts, labels)?;
+        Ok(Self {
+            name: name.to_owned(),
+            counters: pc,
+        })
+    }
+
+    /// Increment the counter for the given label values, which must match
+    /// the number of labels the counter was created with
+    pub fn inc(&self, label_values: &[&str]) {
+        self.counters.with_label_values(label_values).inc()
+    }
+
+    pub fn inc_by(&self, label_values: &[&str], value: f64) {
+        self.counters.with_label_values(label_values).inc_by(value)
+    }
+
+    pub fn get(&self, label_values: &[&str]) -> f64 {
+        self.counters.with_label_values(label_values).get()
+    }
+}
+
+/// A distribution of observed values, counted into fixed buckets.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    name: String,
+    histogram: prometheus::Histogram,
+}
+
+impl Histogram {
+    fn new(name: &str, buckets: &[f64]) -> anyhow::Result<Self> {
+        let opts = prometheus::HistogramOpts::new(name.to_owned(), "a histogram")
+            .buckets(buckets.to_vec());
+        Ok(Self {
+            name: name.to_owned(),
+            histogram: prometheus::Histogram::with_opts(opts)?,
+        })
+    }
+
+    pub fn observe(&self, value: f64) {
+        self.histogram.observe(value)
+    }
+
+    /// Number of values observed
+    pub fn count(&self) -> u64 {
+        self.histogram.get_sample_count()
+    }
+
+    /// Sum of the values observed
+    pub fn sum(&self) -> f64 {
+        self.histogram.get_sample_sum()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Counter {
     name: String,
@@ -195,4 +412,66 @@ pub mod test {
         ctr2.set(13_f64);
         assert_eq!(ctr1.get(), 13_f64);
     }
+
+    #[test]
+    pub fn test_gauge_vec() {
+        let collector = Collector::default();
+        let scope = collector.scope("prefix");
+        let g1 = scope.gauge_vec("gauge", &["label"]).unwrap();
+        g1.set(&["a"], 1_f64);
+        let g2 = scope.gauge_vec("gauge", &["label"]).unwrap();
+        g2.set(&["b"], 2_f64);
+        assert_eq!(g1.get(&["b"]), 2_f64);
+        let output = String::from_utf8(collector.prometheus_output().unwrap()).unwrap();
+        assert!(output.contains("prefix:gauge{label=\"a\"} 1"));
+        g1.reset();
+        let output = String::from_utf8(collector.prometheus_output().unwrap()).unwrap();
+        assert!(!output.contains("label=\"a\""));
+    }
+
+    #[test]
+    pub fn test_counter_vec() {
+        let collector = Collector::default();
+        let scope = collector.scope("prefix");
+        let c1 = scope.counter_vec("counter", &["label"]).unwrap();
+        c1.inc(&["a"]);
+        let c2 = scope.counter_vec("counter", &["label"]).unwrap();
+        c2.inc(&["a"]);
+        assert_eq!(c1.get(&["a"]), 2_f64);
+        assert_eq!(c1.get(&["b"]), 0_f64);
+    }
+
+    #[test]
+    pub fn test_histogram() {
+        let collector = Collector::default();
+        let scope = collector.scope("prefix");
+        let h1 = scope.histogram("histogram", &[1_f64, 10_f64]).unwrap();
+        h1.observe(5_f64);
+        let h2 = scope.histogram("histogram", &[1_f64, 10_f64]).unwrap();
+        h2.observe(20_f64);
+        assert_eq!(h1.count(), 2);
+        assert_eq!(h1.sum(), 25_f64);
+        let output = String::from_utf8(collector.prometheus_output().unwrap()).unwrap();
+        assert!(output.contains("prefix:histogram_bucket{le=\"10\"} 1"));
+    }
+
+    #[test]
+    pub fn test_snapshot() {
+        let collector = Collector::default();
+        let scope = collector.scope("prefix");
+        scope.counter("counter").unwrap().inc_by(3_f64);
+        scope.gauge("gauge").unwrap().set(7_f64);
+        let counters = scope.counter_vec("counters", &["a", "b"]).unwrap();
+        counters.inc_by(&["x", "y"], 2_f64);
+        let gauges = scope.gauge_vec("gauges", &["label"]).unwrap();
+        gauges.set(&["x"], 4_f64);
+        gauges.set(&["y"], 5_f64);
+        let snapshot = collector.snapshot();
+        assert_eq!(snapshot.len(), 5);
+        assert_eq!(snapshot["prefix:counter"], 3_f64);
+        assert_eq!(snapshot["prefix:gauge"], 7_f64);
+        assert_eq!(snapshot["prefix:counters{a=\"x\",b=\"y\"}"], 2_f64);
+        assert_eq!(snapshot["prefix:gauges{label=\"x\"}"], 4_f64);
+        assert_eq!(snapshot["prefix:gauges{label=\"y\"}"], 5_f64);
+    }
 }
@@ -1,33 +1,182 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::path::Path;
 use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant, SystemTime};
 
+use bytes::Bytes;
 use regex::bytes::RegexSet;
+use smallvec::{smallvec, SmallVec};
 
+use crate::breaker::BreakerSettings;
 use crate::config;
 use crate::discovery;
-use crate::shard::{statsrelay_compat_hash, Ring};
+use crate::graphite;
+use crate::health::HealthCheck;
+use crate::shard::{
+    code_in_percent, hash_in_percent, id_in_percent, name_in_percent, shard_hash, Ring,
+};
 use crate::stats;
-use crate::statsd_client::StatsdClient;
+use crate::statsd_client::{
+    path_component, Backoff, Batching, ClientOptions, StatsdClient, Transport,
+};
 use crate::statsd_proto;
-use crate::statsd_proto::Event;
+use crate::statsd_proto::{convert, Event, Owned};
+use crate::tls::ClientTls;
 
 use log::warn;
 
+/// Default time a circuit stays open before probing the endpoint
+const BREAKER_OPEN_MS: u64 = 10000;
+
+/// Default datagram size limit for UDP backends, fitting a 1500 byte
+/// Ethernet MTU after IP and UDP headers.
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+/// A shard map being migrated to, which a share of keys is sent to instead
+/// of, or as well as, the backend's own shard map
+struct Migration {
+    next: Box<StatsdBackend>,
+    cutover_percent: f64,
+    dual_write: bool,
+    /// Lines sent by destination, `current` or `next`
+    lines: stats::CounterVec,
+}
+
+/// Endpoints recently added to a backend's ring, which are only sent a share
+/// of the keys hashing to them, growing over the slow start window. Keys are
+/// admitted by their hash, so each key moves to its new endpoint once.
+struct SlowStart {
+    window: Duration,
+    /// When each warming endpoint was added
+    added: HashMap<String, Instant>,
+    /// When the last endpoint finishes warming
+    until: Instant,
+}
+
+impl SlowStart {
+    /// Start warming the endpoints of a ring which were not in the previous
+    /// one, carrying over those still warming from it
+    fn new(
+        window: Duration,
+        ring: &Ring<StatsdClient>,
+        previous: &StatsdBackend,
+        now: Instant,
+    ) -> Option<Self> {
+        let existing = previous.clients();
+        let mut added = HashMap::new();
+        for client in ring.members() {
+            let endpoint = client.endpoint();
+            let since = match existing.get(endpoint) {
+                None => now,
+                Some(_) => match previous
+                    .slow_start
+                    .as_ref()
+                    .and_then(|s| s.added.get(endpoint))
+                {
+                    Some(since) if now.duration_since(*since) < window => *since,
+                    _ => continue,
+                },
+            };
+            added.insert(endpoint.to_owned(), since);
+        }
+        let until = added.values().max().map(|since| *since + window)?;
+        Some(SlowStart {
+            window,
+            added,
+            until,
+        })
+    }
+
+    /// Whether a key may be sent to an endpoint, rather than to another
+    fn admits(&self, code: u32, client: &StatsdClient, now: Instant) -> bool {
+        match self.added.get(client.endpoint()) {
+            None => true,
+            Some(since) => {
+                let ramp = now.duration_since(*since).as_secs_f64() / self.window.as_secs_f64();
+                ramp >= 1_f64 || hash_in_percent(code, ramp * 100_f64)
+            }
+        }
+    }
+}
+
+/// Host of an endpoint as used in prefix and suffix templates, with anything
+/// but letters, digits, `-` and `_` replaced by `_`. For unix sockets this is
+/// the name of the socket file, without any extension.
+fn endpoint_host(endpoint: &str) -> String {
+    let host = match endpoint.strip_prefix("unix://") {
+        Some(path) => Path::new(path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(path),
+        None => endpoint
+            .rsplit_once(':')
+            .map_or(endpoint, |(host, _port)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    host.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Expand the `{shard}` and `{endpoint_host}` variables of a prefix or
+/// suffix for an endpoint
+fn expand_affix(template: &str, shard: usize, endpoint: &str) -> Vec<u8> {
+    template
+        .replace("{shard}", &shard.to_string())
+        .replace("{endpoint_host}", &endpoint_host(endpoint))
+        .into_bytes()
+}
+
+/// The expanded prefix and suffix of an endpoint
+type Affixes = (Vec<u8>, Vec<u8>);
+
 pub struct StatsdBackend {
     conf: config::StatsdBackendConfig,
     ring: Ring<StatsdClient>,
+    slow_start: Option<SlowStart>,
+    migration: Option<Migration>,
+    /// Expanded prefix and suffix for each endpoint, if either is templated
+    affixes: Option<HashMap<String, Affixes>>,
     input_filter: Option<RegexSet>,
+    ejection: Option<config::Ejection>,
+    graphite_timestamp: config::GraphiteTimestamp,
     warning_log: AtomicU64,
     backend_sends: stats::Counter,
+    backend_replicated_sends: stats::Counter,
     backend_fails: stats::Counter,
+    ejected_drops: stats::Counter,
+    format_failures: stats::Counter,
+}
+
+/// Scale down the sample rate of counters and timers, which statsd servers
+/// scale back up by, to account for only a percentage being sent. Returns
+/// None if the existing rate can not be parsed.
+fn resample(pdu: &statsd_proto::Pdu, percent: f64) -> Option<statsd_proto::Pdu> {
+    if !matches!(pdu.pdu_type(), b"c" | b"ms") {
+        return Some(pdu.clone());
+    }
+    let rate = match pdu.sample_rate() {
+        Some(rate) => lexical::parse::<f64, _>(rate).ok()?,
+        None => 1_f64,
+    };
+    let rate = lexical::to_string(rate * percent / 100_f64);
+    Some(pdu.with_sample_rate(rate.as_bytes()))
 }
 
 impl StatsdBackend {
     pub fn new(
         stats: stats::Scope,
+        name: &str,
         conf: &config::StatsdBackendConfig,
         client_ref: Option<&StatsdBackend>,
         discovery_update: Option<&discovery::Update>,
+        migration_update: Option<&discovery::Update>,
     ) -> anyhow::Result<Self> {
         let mut filters: Vec<String> = Vec::new();
 
@@ -44,12 +193,86 @@ impl StatsdBackend {
             None
         };
 
-        let mut ring: Ring<StatsdClient> = Ring::new();
+        let mut ring: Ring<StatsdClient> = match conf.vnodes {
+            Some(vnodes) => Ring::with_vnodes(vnodes),
+            None => Ring::new(),
+        };
 
         // Use the same backend for the same endpoint address, caching the lookup locally
         let mut memoize: HashMap<String, StatsdClient> =
             client_ref.map_or_else(HashMap::new, |b| b.clients());
 
+        let transport = match conf.protocol {
+            config::BackendProtocol::Tcp => match conf.tls.as_ref() {
+                Some(tls) => Transport::Tls(ClientTls::new(tls)?),
+                None => Transport::Tcp,
+            },
+            config::BackendProtocol::Udp => Transport::Udp {
+                max_datagram_bytes: conf.max_datagram_bytes.unwrap_or(MAX_DATAGRAM_BYTES),
+            },
+        };
+        let defaults = Batching::default();
+        let batching = Batching {
+            max_bytes: conf.max_batch_bytes.unwrap_or(defaults.max_bytes),
+            flush_interval: conf
+                .flush_interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.flush_interval),
+            max_in_flight: conf.max_in_flight.unwrap_or(defaults.max_in_flight),
+            pipeline: conf.pipeline_batches.unwrap_or(defaults.pipeline),
+        };
+        let defaults = Backoff::default();
+        let backoff = Backoff {
+            initial: conf
+                .reconnect_initial_ms
+                .map_or(defaults.initial, Duration::from_millis),
+            max: conf
+                .reconnect_max_ms
+                .map_or(defaults.max, Duration::from_millis),
+        };
+        let options = ClientOptions {
+            channel_buffer: conf.max_queue.unwrap_or(100000) as usize,
+            transport,
+            batching,
+            backoff,
+            // Backends sharing an endpoint spill to their own directories
+            spill: conf.spill.as_ref().map(|spill| config::SpillConfig {
+                dir: Path::new(&spill.dir)
+                    .join(path_component(name))
+                    .to_string_lossy()
+                    .into_owned(),
+                ..spill.clone()
+            }),
+            breaker: conf.breaker_failures.map(|failures| BreakerSettings {
+                failures,
+                open_for: Duration::from_millis(conf.breaker_open_ms.unwrap_or(BREAKER_OPEN_MS)),
+            }),
+            health_check: conf.health_check.as_ref().map(|check| {
+                let defaults = HealthCheck::default();
+                HealthCheck {
+                    interval: check
+                        .interval_ms
+                        .map_or(defaults.interval, Duration::from_millis),
+                    timeout: check
+                        .timeout_ms
+                        .map_or(defaults.timeout, Duration::from_millis),
+                    unhealthy_threshold: check
+                        .unhealthy_threshold
+                        .unwrap_or(defaults.unhealthy_threshold),
+                    healthy_threshold: check
+                        .healthy_threshold
+                        .unwrap_or(defaults.healthy_threshold),
+                    ping: check.mode == config::HealthCheckMode::Ping,
+                    verify_new: check.verify_new_endpoints,
+                }
+            }),
+            rate_limit: conf.rate_limit.clone(),
+            compression: conf.compression,
+            drain_timeout: conf.drain_timeout_ms.map_or(
+                ClientOptions::default().drain_timeout,
+                Duration::from_millis,
+            ),
+        };
         let use_endpoints = discovery_update
             .map(|u| u.sources())
             .unwrap_or(&conf.shard_map);
@@ -57,26 +280,107 @@ impl StatsdBackend {
             if endpoint.is_empty() {
                 continue;
             }
-            if let Some(client) = memoize.get(endpoint) {
-                ring.push(client.clone())
+            if let Some(client) = memoize.get(endpoint).filter(|c| c.options() == &options) {
+                ring.push_named(endpoint, client.clone())
             } else {
-                let client = StatsdClient::new(
-                    stats.scope("statsd_client"),
-                    endpoint.as_str(),
-                    conf.max_queue.unwrap_or(100000) as usize,
-                );
+                // Endpoints of the first ring, or rebuilt with new options,
+                // are trusted, while those joining the ring may be verified
+                let client = if client_ref.is_none() || memoize.contains_key(endpoint) {
+                    StatsdClient::new(
+                        stats.scope("statsd_client"),
+                        endpoint.as_str(),
+                        options.clone(),
+                    )
+                } else {
+                    StatsdClient::joining(
+                        stats.scope("statsd_client"),
+                        endpoint.as_str(),
+                        options.clone(),
+                    )
+                };
                 memoize.insert(endpoint.clone(), client.clone());
-                ring.push(client);
+                ring.push_named(endpoint, client);
             }
         }
 
+        let templated = |affix: &Option<String>| affix.as_deref().is_some_and(|a| a.contains('{'));
+        let affixes = if templated(&conf.prefix) || templated(&conf.suffix) {
+            let mut affixes = HashMap::new();
+            // Shards are numbered by their first place in the shard map
+            for (shard, endpoint) in use_endpoints.iter().filter(|e| !e.is_empty()).enumerate() {
+                affixes.entry(endpoint.clone()).or_insert_with(|| {
+                    let expand = |affix: &Option<String>| {
+                        expand_affix(affix.as_deref().unwrap_or_default(), shard, endpoint)
+                    };
+                    (expand(&conf.prefix), expand(&conf.suffix))
+                });
+            }
+            Some(affixes)
+        } else {
+            None
+        };
+
+        let slow_start = match (conf.slow_start_ms, client_ref) {
+            (Some(window), Some(previous)) => SlowStart::new(
+                Duration::from_millis(window),
+                &ring,
+                previous,
+                Instant::now(),
+            ),
+            _ => None,
+        };
+
+        let migration = match &conf.migration {
+            None => None,
+            Some(migration) => {
+                let next_conf = config::StatsdBackendConfig {
+                    shard_map: migration.shard_map.clone(),
+                    shard_map_source: migration.shard_map_source.clone(),
+                    migration: None,
+                    ..conf.clone()
+                };
+                // Carry over the clients of the shard map previously being
+                // migrated to, or of the previous shard map if there was none
+                let previous = client_ref
+                    .and_then(|b| b.migration.as_ref())
+                    .map(|m| m.next.as_ref())
+                    .or(client_ref);
+                let next = StatsdBackend::new(
+                    stats.scope("migration_next"),
+                    name,
+                    &next_conf,
+                    previous,
+                    migration_update,
+                    None,
+                )?;
+                Some(Migration {
+                    next: Box::new(next),
+                    cutover_percent: migration.cutover_percent,
+                    dual_write: migration.dual_write,
+                    lines: stats.counter_vec("migration_lines", &["destination"])?,
+                })
+            }
+        };
+
         let backend = StatsdBackend {
             conf: conf.clone(),
             ring,
+            slow_start,
+            migration,
+            affixes,
             input_filter,
+            ejection: conf.health_check.as_ref().map(|check| check.ejection),
+            graphite_timestamp: conf
+                .graphite
+                .as_ref()
+                .map(|g| g.timestamp.clone())
+                .unwrap_or_default(),
             warning_log: AtomicU64::new(0),
             backend_fails: stats.counter("backend_fails").unwrap(),
             backend_sends: stats.counter("backend_sends").unwrap(),
+            backend_replicated_sends: stats.counter("backend_replicated_sends").unwrap(),
+            ejected_drops: stats.counter("ejected_drops").unwrap(),
+            format_failures: stats.counter("format_failures").unwrap(),
         };
 
         Ok(backend)
@@ -88,15 +392,54 @@ impl StatsdBackend {
     // old ring are both dropped.
     fn clients(&self) -> HashMap<String, StatsdClient> {
         let mut memoize: HashMap<String, StatsdClient> = HashMap::new();
-        for i in 0..self.ring.len() {
-            let client = self.ring.pick_from(i as u32);
+        for client in self.ring.members() {
             memoize.insert(String::from(client.endpoint()), client.clone());
         }
+        if let Some(migration) = &self.migration {
+            for (endpoint, client) in migration.next.clients() {
+                memoize.entry(endpoint).or_insert(client);
+            }
+        }
         memoize
     }
 
+    pub fn conf(&self) -> &config::StatsdBackendConfig {
+        &self.conf
+    }
+
+    /// Futures resolving once each distinct client of this backend has
+    /// finished sending and exited. Clients only exit once every handle to
+    /// them, including this backend, has been dropped.
+    pub fn finished(&self) -> Vec<impl Future<Output = ()>> {
+        self.clients().values().map(|c| c.finished()).collect()
+    }
+
+    /// The highest send queue occupancy of any client of this backend
+    pub fn queue_occupancy(&self) -> f64 {
+        let next = self.migration.as_ref().map(|m| m.next.queue_occupancy());
+        self.ring
+            .members()
+            .map(|c| c.queue_occupancy())
+            .chain(next)
+            .fold(0_f64, f64::max)
+    }
+
+    /// Number of lines queued across every distinct client of this backend
+    pub fn queue_depth(&self) -> usize {
+        self.clients().values().map(|c| c.queue_depth()).sum()
+    }
+
     pub fn provide_statsd(&self, input: &Event) {
-        let pdu: statsd_proto::Pdu = input.into();
+        let pdu: statsd_proto::Pdu = match self.conf.convert_tags {
+            None => input.into(),
+            Some(config::ConvertTags::Inline) => match Owned::try_from(input) {
+                Ok(owned) => convert::to_inline_tags(owned).into(),
+                Err(_) => {
+                    self.format_failures.inc();
+                    return;
+                }
+            },
+        };
         if !self
             .input_filter
             .as_ref()
@@ -104,50 +447,243 @@ impl StatsdBackend {
         {
             return;
         }
+        if let Some(percent) = self.conf.shadow_percent {
+            if !name_in_percent(&pdu, percent) {
+                return;
+            }
+        }
+        let pdu = match self.conf.sample_percent {
+            None => pdu,
+            Some(percent) => {
+                if !id_in_percent(&pdu, percent) {
+                    return;
+                }
+                match resample(&pdu, percent) {
+                    Some(pdu) => pdu,
+                    None => {
+                        self.format_failures.inc();
+                        return;
+                    }
+                }
+            }
+        };
+
+        match &self.migration {
+            None => self.send(&pdu),
+            Some(migration) => {
+                let key = shard_hash(&pdu, &self.conf.shard_key);
+                let cut_over = code_in_percent(key, migration.cutover_percent);
+                if cut_over {
+                    migration.lines.inc(&["next"]);
+                    migration.next.send(&pdu);
+                }
+                if !cut_over || migration.dual_write {
+                    migration.lines.inc(&["current"]);
+                    self.send(&pdu);
+                }
+            }
+        }
+    }
 
+    /// Send a line to the endpoints of this backend's own shard map
+    fn send(&self, pdu: &statsd_proto::Pdu) {
         let ring_read = &self.ring;
         let code = match ring_read.len() {
             0 => return, // In case of nothing to send, do nothing
             1 => 1_u32,
-            _ => statsrelay_compat_hash(&pdu),
+            _ => shard_hash(pdu, &self.conf.shard_key),
+        };
+        // Keys not yet admitted to a warming endpoint go to the next
+        // endpoint on the ring instead
+        let now = Instant::now();
+        let slow_start = self.slow_start.as_ref().filter(|s| now < s.until);
+        let warm = |c: &StatsdClient| slow_start.is_none_or(|s| s.admits(code, c, now));
+        let replication = self.conf.replication.unwrap_or(1) as usize;
+        let clients: SmallVec<[&StatsdClient; 4]> = if replication > 1 {
+            self.pick_replicas(code, replication, warm)
+        } else {
+            let pick = || match slow_start {
+                None => ring_read.pick_from(code),
+                Some(_) => ring_read
+                    .pick_from_filtered(code, warm)
+                    .unwrap_or_else(|| ring_read.pick_from(code)),
+            };
+            match self.ejection {
+                None => smallvec![pick()],
+                // With every endpoint unhealthy, sending to them anyway beats
+                // dropping everything
+                Some(config::Ejection::Rehash) => smallvec![ring_read
+                    .pick_from_filtered(code, |c| c.healthy() && warm(c))
+                    .unwrap_or_else(pick)],
+                Some(config::Ejection::Hole) => {
+                    let client = pick();
+                    if !client.healthy() {
+                        self.ejected_drops.inc();
+                        return;
+                    }
+                    smallvec![client]
+                }
+            }
         };
-        let client = ring_read.pick_from(code);
-        let sender = client.sender();
+        if clients.is_empty() {
+            return;
+        }
 
-        // Assign prefix and/or suffix
-        let pdu_clone = if self.conf.prefix.is_some() || self.conf.suffix.is_some() {
-            pdu.with_prefix_suffix(
-                self.conf
-                    .prefix
-                    .as_ref()
-                    .map(|p| p.as_bytes())
-                    .unwrap_or_default(),
-                self.conf
-                    .suffix
-                    .as_ref()
-                    .map(|s| s.as_bytes())
-                    .unwrap_or_default(),
-            )
+        // Templated prefixes and suffixes differ by endpoint, so each line
+        // is rendered for each endpoint it is sent to
+        let shared = match self.affixes {
+            None => match self.render(
+                pdu,
+                self.conf.prefix.as_deref().unwrap_or_default().as_bytes(),
+                self.conf.suffix.as_deref().unwrap_or_default().as_bytes(),
+            ) {
+                Some(line) => Some(line),
+                None => return,
+            },
+            Some(_) => None,
+        };
+        for (replica, client) in clients.iter().enumerate() {
+            let line = match (&shared, &self.affixes) {
+                (Some(line), _) => line.clone(),
+                (None, Some(affixes)) => {
+                    let (prefix, suffix) = &affixes[client.endpoint()];
+                    match self.render(pdu, prefix, suffix) {
+                        Some(line) => line,
+                        None => return,
+                    }
+                }
+                (None, None) => unreachable!("lines are shared unless templated"),
+            };
+            match client.try_send(line) {
+                Err(_e) => {
+                    self.backend_fails.inc();
+                    let count = self
+                        .warning_log
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    if count % 1000 == 0 {
+                        warn!(
+                            "error pushing to queue full (endpoint {}, total failures {})",
+                            client.endpoint(),
+                            count
+                        );
+                    }
+                }
+                Ok(_) if replica == 0 => {
+                    self.backend_sends.inc();
+                }
+                Ok(_) => {
+                    self.backend_replicated_sends.inc();
+                }
+            }
+        }
+    }
+
+    /// Render the line sent for a PDU, or None if it can not be formatted
+    fn render(&self, pdu: &statsd_proto::Pdu, prefix: &[u8], suffix: &[u8]) -> Option<Bytes> {
+        let pdu = if !prefix.is_empty() || !suffix.is_empty() {
+            pdu.with_prefix_suffix(prefix, suffix)
         } else {
-            pdu
-        };
-        match sender.try_send(pdu_clone) {
-            Err(_e) => {
-                self.backend_fails.inc();
-                let count = self
-                    .warning_log
-                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                if count % 1000 == 0 {
-                    warn!(
-                        "error pushing to queue full (endpoint {}, total failures {})",
-                        client.endpoint(),
-                        count
-                    );
+            pdu.clone()
+        };
+        let pdu = match self.conf.tag_format {
+            None => pdu,
+            Some(config::TagFormat::Datadog) => convert::pdu_datadog_tags(&pdu),
+            Some(config::TagFormat::Inline) => convert::pdu_inline_tags(&pdu),
+            Some(config::TagFormat::Strip) => convert::pdu_strip_tags(&pdu),
+        };
+        match self.conf.format {
+            config::BackendFormat::Statsd => Some(pdu.into_bytes()),
+            config::BackendFormat::Graphite => match Owned::try_from(&pdu) {
+                Ok(owned) => Some(graphite::to_plaintext(
+                    &owned,
+                    &self.graphite_timestamp,
+                    SystemTime::now(),
+                )),
+                Err(_) => {
+                    self.format_failures.inc();
+                    None
+                }
+            },
+        }
+    }
+
+    /// Pick the distinct endpoints a line is replicated to, handling
+    /// unhealthy and warming endpoints as for a single endpoint
+    fn pick_replicas<F>(
+        &self,
+        code: u32,
+        replication: usize,
+        warm: F,
+    ) -> SmallVec<[&StatsdClient; 4]>
+    where
+        F: Fn(&StatsdClient) -> bool,
+    {
+        let pick = || {
+            let clients = self.ring.pick_n_filtered(code, replication, &warm);
+            if clients.is_empty() {
+                self.ring.pick_n_filtered(code, replication, |_| true)
+            } else {
+                clients
+            }
+        };
+        match self.ejection {
+            None => pick(),
+            Some(config::Ejection::Rehash) => {
+                let healthy = self
+                    .ring
+                    .pick_n_filtered(code, replication, |c| c.healthy() && warm(c));
+                if healthy.is_empty() {
+                    pick()
+                } else {
+                    healthy
                 }
             }
-            Ok(_) => {
-                self.backend_sends.inc();
+            Some(config::Ejection::Hole) => {
+                let mut clients = pick();
+                let picked = clients.len();
+                clients.retain(|c| c.healthy());
+                self.ejected_drops.inc_by((picked - clients.len()) as f64);
+                clients
             }
         }
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand_affix() {
+        assert_eq!(endpoint_host("10.0.0.1:8125"), "10_0_0_1");
+        assert_eq!(endpoint_host("[::1]:8125"), "__1");
+        assert_eq!(endpoint_host("agg-1.example.com:8125"), "agg-1_example_com");
+        assert_eq!(endpoint_host("unix:///run/statsd.sock"), "statsd");
+        assert_eq!(
+            expand_affix("relay.{shard}.{endpoint_host}.", 2, "agg-1:8125"),
+            b"relay.2.agg-1."
+        );
+        assert_eq!(expand_affix(".plain", 0, "agg-1:8125"), b".plain");
+    }
+
+    #[tokio::test]
+    async fn test_verify_joining() {
+        let conf: config::StatsdBackendConfig = serde_json::from_value(serde_json::json!({
+            "shard_map": ["127.0.0.1:1"],
+            "health_check": {"interval_ms": 3_600_000, "verify_new_endpoints": true},
+        }))
+        .unwrap();
+        let scope = stats::Collector::default().scope("b");
+        let backend = StatsdBackend::new(scope.clone(), "b", &conf, None, None, None).unwrap();
+        assert!(backend.clients()["127.0.0.1:1"].healthy());
+
+        let conf = config::StatsdBackendConfig {
+            shard_map: vec!["127.0.0.1:1".to_owned(), "127.0.0.1:2".to_owned()],
+            ..conf
+        };
+        let backend = StatsdBackend::new(scope, "b", &conf, Some(&backend), None, None).unwrap();
+        let clients = backend.clients();
+        assert!(clients["127.0.0.1:1"].healthy());
+        assert!(!clients["127.0.0.1:2"].healthy());
+    }
+}
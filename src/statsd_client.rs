@@ -1,64 +1,517 @@
-use bytes::{BufMut, Bytes, BytesMut};
-use memchr::memchr;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memchr::{memchr, memrchr};
 use stream_cancel::{Trigger, Tripwire};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket, UnixDatagram, UnixStream};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
 
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::breaker::{BreakerSettings, CircuitBreaker};
+use crate::compression::compress;
+use crate::config::{BackendRateLimit, BackendRateLimitAction, Compression, SpillConfig};
+use crate::error::{Categorized, Category, ErrorCounters};
+use crate::health::{self, HealthCheck};
+use crate::rate_limit::TokenBucket;
+use crate::spill::Spill;
 use crate::stats;
-use crate::statsd_proto::Pdu;
+use crate::tls::ClientTls;
 
 use log::{info, warn};
+use parking_lot::Mutex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("connect to {0} timed out")]
+    ConnectTimeout(String),
+    #[error("connect to {endpoint} failed: {source}")]
+    Connect {
+        endpoint: String,
+        source: std::io::Error,
+    },
+    #[error("write to {0} made no progress, reforming connection")]
+    WriteZero(String),
+    #[error("write to {endpoint} failed, reforming connection: {source}")]
+    Write {
+        endpoint: String,
+        source: std::io::Error,
+    },
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        Category::Network
+    }
+}
+
+/// How a client delivers lines to its endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// A persistent TCP connection, reformed on failure
+    Tcp,
+    /// As for TCP, with the connection secured by TLS
+    Tls(ClientTls),
+    /// UDP datagrams, each packed with as many whole lines as fit in
+    /// `max_datagram_bytes`
+    Udp { max_datagram_bytes: usize },
+}
 
 pub struct StatsdClient {
-    sender: mpsc::Sender<Pdu>,
+    sender: mpsc::Sender<Bytes>,
     inner: Arc<StatsdClientInner>,
 }
 
 struct StatsdClientInner {
     endpoint: String,
-    sender: mpsc::Sender<Pdu>,
+    options: ClientOptions,
+    sender: mpsc::Sender<Bytes>,
+    done: watch::Receiver<()>,
+    queue_depth: stats::Gauge,
+    breaker: Arc<CircuitBreaker>,
+    breaker_rejected: stats::Counter,
+    healthy: Arc<AtomicBool>,
+    /// Only set when lines over the rate limit are dropped, as queued lines
+    /// are instead limited as they are sent
+    drop_limiter: Option<Mutex<Limiter>>,
+    rate_limited_lines: stats::Counter,
+    spill: Option<ClientSpill>,
     _trig: Trigger,
+    /// Stops attempts to connect, once the client has been dropped and had
+    /// its drain timeout to write out its queue
+    connect_trig: Option<Trigger>,
+}
+
+impl Drop for StatsdClientInner {
+    fn drop(&mut self) {
+        let trig = self.connect_trig.take();
+        let mut done = self.done.clone();
+        let drain_timeout = self.options.drain_timeout;
+        // Keep reconnecting to write out the queue, unless there is no
+        // runtime left to do so
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = timeout(drain_timeout, async {
+                    while done.changed().await.is_ok() {}
+                })
+                .await;
+                drop(trig);
+            });
+        }
+    }
+}
+
+/// Token buckets limiting the lines and bytes sent to an endpoint, each
+/// holding up to one second's worth of tokens as burst.
+struct Limiter {
+    lines: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Limiter {
+    fn new(config: &BackendRateLimit, now: Instant) -> Self {
+        let bucket = |rate: Option<f64>| rate.map(|r| TokenBucket::new(r, r, now));
+        Limiter {
+            lines: bucket(config.lines_per_second),
+            bytes: bucket(config.bytes_per_second),
+        }
+    }
+
+    /// Take tokens for a single line if available.
+    fn try_take(&mut self, bytes: usize, now: Instant) -> bool {
+        self.lines.as_mut().is_none_or(|b| b.try_take(1_f64, now))
+            && self
+                .bytes
+                .as_mut()
+                .is_none_or(|b| b.try_take(bytes as f64, now))
+    }
+
+    /// Take tokens for a batch, returning how long to wait before sending it.
+    fn take(&mut self, lines: usize, bytes: usize, now: Instant) -> Duration {
+        let lines = self
+            .lines
+            .as_mut()
+            .map(|b| b.take(lines as f64, now))
+            .unwrap_or_default();
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map(|b| b.take(bytes as f64, now))
+            .unwrap_or_default();
+        lines.max(bytes)
+    }
+}
+
+/// Lines spilled to disk while the queue is full. Lines are handed to a
+/// writer task, so the disk is never waited on while lines are ingested.
+struct ClientSpill {
+    spill: Arc<Spill>,
+    writer: mpsc::Sender<Bytes>,
 }
 
-const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+impl ClientSpill {
+    fn open(stats: &stats::Scope, endpoint: &str, config: &SpillConfig) -> std::io::Result<Self> {
+        let spill = Spill::open(&spill_dir(config, endpoint), config.max_bytes)?;
+        let bytes = stats.gauge_vec("spill_bytes", &["endpoint"]).unwrap();
+        bytes.set(&[endpoint], spill.bytes() as f64);
+        let (writer, lines) = mpsc::channel(SPILL_QUEUE);
+        tokio::spawn(spill_writer(
+            stats.clone(),
+            endpoint.to_owned(),
+            spill.clone(),
+            lines,
+        ));
+        Ok(ClientSpill { spill, writer })
+    }
+}
+
+/// Append lines handed over by a client to its spill ring, a batch at a time
+/// on the blocking pool. The task exits once the client is dropped.
+async fn spill_writer(
+    stats: stats::Scope,
+    endpoint: String,
+    spill: Arc<Spill>,
+    mut lines: mpsc::Receiver<Bytes>,
+) {
+    let spilled_lines = stats.counter("spilled_lines").unwrap();
+    let dropped_bytes = stats.counter("spill_dropped_bytes").unwrap();
+    let bytes = stats.gauge_vec("spill_bytes", &["endpoint"]).unwrap();
+    while let Some(line) = lines.recv().await {
+        let mut batch = vec![line];
+        while batch.len() < SPILL_BATCH {
+            match lines.try_recv() {
+                Ok(line) => batch.push(line),
+                Err(_) => break,
+            }
+        }
+        let ring = spill.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            let mut written = 0;
+            let mut evicted = 0;
+            for line in batch.iter() {
+                evicted += ring.push(line)?;
+                written += 1;
+            }
+            Ok::<_, std::io::Error>((written, evicted))
+        })
+        .await;
+        match written {
+            Ok(Ok((written, evicted))) => {
+                spilled_lines.inc_by(written as f64);
+                dropped_bytes.inc_by(evicted as f64);
+            }
+            Ok(Err(e)) => warn!("could not spill lines for {}: {}", endpoint, e),
+            Err(e) => warn!("spilling lines for {} failed: {}", endpoint, e),
+        }
+        bytes.set(&[endpoint.as_str()], spill.bytes() as f64);
+    }
+}
+
+const RECONNECT_INITIAL: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
 const SEND_DELAY: Duration = Duration::from_millis(500);
 const SEND_THRESHOLD: usize = 10 * 1024;
-const INITIAL_BUF_CAPACITY: usize = SEND_THRESHOLD + 1024;
+const CHANNEL_BUFFER: usize = 100000;
+const MAX_IN_FLIGHT: usize = 10;
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of lines which can wait to be spilled before spilling fails
+const SPILL_QUEUE: usize = 10000;
+/// Most lines spilled in one go on the blocking pool
+const SPILL_BATCH: usize = 1024;
+/// How often spilled lines are checked for replay
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+/// Spilled lines are only replayed while the queue is less full than this,
+/// leaving room for live lines
+const REPLAY_OCCUPANCY: f64 = 0.5;
+/// Bucket upper bounds for the size of batches in bytes
+const BATCH_BYTES_BUCKETS: &[f64] = &[
+    64_f64,
+    256_f64,
+    1024_f64,
+    4096_f64,
+    16384_f64,
+    65536_f64,
+    262144_f64,
+    1048576_f64,
+];
+/// Bucket upper bounds for the number of lines in batches
+const BATCH_LINES_BUCKETS: &[f64] = &[
+    1_f64, 4_f64, 16_f64, 64_f64, 256_f64, 1024_f64, 4096_f64, 16384_f64,
+];
+
+/// Delays between attempts to reconnect to an endpoint. The delay doubles
+/// after each failed attempt up to `max`, and a random jitter of up to half
+/// the delay is taken off so clients of the same dead endpoint spread out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: RECONNECT_INITIAL,
+            max: RECONNECT_MAX,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the next attempt, after `failures` consecutive
+    /// failed attempts. `jitter` is a random value from 0 to 1.
+    pub(crate) fn delay(&self, failures: u32, jitter: f64) -> Duration {
+        let delay = self
+            .initial
+            .checked_mul(
+                1_u32
+                    .checked_shl(failures.saturating_sub(1))
+                    .unwrap_or(u32::MAX),
+            )
+            .map_or(self.max, |d| d.min(self.max));
+        delay.mul_f64(1_f64 - jitter / 2_f64)
+    }
+}
+
+/// How a client coalesces lines into writes. Lines are held until a batch
+/// reaches `max_bytes`, or for at most around `flush_interval`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batching {
+    pub max_bytes: usize,
+    pub flush_interval: Duration,
+    /// Number of complete batches held waiting to be written, bounding the
+    /// memory used beyond the line queue
+    pub max_in_flight: usize,
+    /// Number of waiting batches written to a stream before it is flushed
+    pub pipeline: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Batching {
+            max_bytes: SEND_THRESHOLD,
+            flush_interval: SEND_DELAY,
+            max_in_flight: MAX_IN_FLIGHT,
+            pipeline: 1,
+        }
+    }
+}
+
+/// Everything about how a client queues and delivers lines to its endpoint.
+/// Clients are only reused across backend reloads if these are unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientOptions {
+    /// Number of lines which can be queued before sends fail
+    pub channel_buffer: usize,
+    pub transport: Transport,
+    pub batching: Batching,
+    pub backoff: Backoff,
+    /// Where lines go while the queue is full, instead of being dropped
+    pub spill: Option<SpillConfig>,
+    /// When to stop queueing lines for a failing endpoint
+    pub breaker: Option<BreakerSettings>,
+    /// How to actively check the endpoint is healthy
+    pub health_check: Option<HealthCheck>,
+    pub rate_limit: Option<BackendRateLimit>,
+    /// Compress streams to the endpoint, for endpoints which are statsrelay
+    /// servers configured with the same compression
+    pub compression: Option<Compression>,
+    /// How long a dropped client keeps trying to write out its queue
+    pub drain_timeout: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            channel_buffer: CHANNEL_BUFFER,
+            transport: Transport::Tcp,
+            batching: Batching::default(),
+            backoff: Backoff::default(),
+            spill: None,
+            breaker: None,
+            health_check: None,
+            rate_limit: None,
+            compression: None,
+            drain_timeout: DRAIN_TIMEOUT,
+        }
+    }
+}
 
 impl StatsdClient {
-    pub fn new(stats: stats::Scope, endpoint: &str, channel_buffer: usize) -> Self {
+    pub fn new(stats: stats::Scope, endpoint: &str, options: ClientOptions) -> Self {
+        Self::start(stats, endpoint, options, false)
+    }
+
+    /// A client for an endpoint joining a running ring, which starts
+    /// unhealthy until its first health check passes if the check verifies
+    /// new endpoints
+    pub fn joining(stats: stats::Scope, endpoint: &str, options: ClientOptions) -> Self {
+        let verify = options.health_check.as_ref().is_some_and(|c| c.verify_new);
+        Self::start(stats, endpoint, options, verify)
+    }
+
+    fn start(stats: stats::Scope, endpoint: &str, options: ClientOptions, verify: bool) -> Self {
         // Currently, we need this tripwire to abort connection looping. This can probably be refactored
         let (trig, trip) = Tripwire::new();
-        let (sender, recv) = mpsc::channel::<Pdu>(channel_buffer);
+        let (connect_trig, connect_trip) = Tripwire::new();
+        let (sender, recv) = mpsc::channel::<Bytes>(options.channel_buffer);
+        // The sender half is held by the sending task, and dropped once it
+        // exits, to signal the client has finished.
+        let (done_sender, done) = watch::channel(());
+        let queue_depth = stats.gauge("queue_depth").unwrap();
+        // A client without its spill still delivers lines, so failing to
+        // open one is not fatal
+        let spill = options.spill.as_ref().and_then(|config| {
+            ClientSpill::open(&stats, endpoint, config)
+                .map_err(|e| warn!("could not open spill for {}: {}", endpoint, e))
+                .ok()
+        });
+        let breaker = Arc::new(CircuitBreaker::new(
+            options.breaker.clone(),
+            &stats,
+            endpoint,
+        ));
+        let eps = String::from(endpoint);
+        // Endpoints to be verified start unhealthy, until their first check
+        let healthy = Arc::new(AtomicBool::new(!verify));
+        if let Some(check) = &options.health_check {
+            let tls = match &options.transport {
+                Transport::Tls(tls) => Some(tls.clone()),
+                _ => None,
+            };
+            tokio::spawn(health::checker(
+                stats.clone(),
+                eps.clone(),
+                check.clone(),
+                tls,
+                healthy.clone(),
+                trip.clone(),
+            ));
+        }
+        if let Some(spill) = &spill {
+            tokio::spawn(replay(
+                stats.clone(),
+                eps.clone(),
+                spill.spill.clone(),
+                sender.clone(),
+                trip.clone(),
+            ));
+        }
         let inner = StatsdClientInner {
             endpoint: endpoint.to_string(),
+            options: options.clone(),
             sender: sender.clone(),
+            done,
+            queue_depth,
+            breaker: breaker.clone(),
+            breaker_rejected: stats.counter("breaker_rejected").unwrap(),
+            healthy,
+            drop_limiter: options
+                .rate_limit
+                .as_ref()
+                .filter(|limit| limit.action == BackendRateLimitAction::Drop)
+                .map(|limit| Mutex::new(Limiter::new(limit, Instant::now()))),
+            rate_limited_lines: stats.counter("rate_limited_lines").unwrap(),
+            spill,
             _trig: trig,
+            connect_trig: Some(connect_trig),
         };
-        let eps = String::from(endpoint);
-        let (ticker_sender, ticker_recv) = mpsc::channel::<bool>(1);
-        tokio::spawn(ticker(eps.clone(), ticker_sender));
-        tokio::spawn(client_task(stats, eps, trip, recv, ticker_recv));
+        tokio::spawn(client_task(
+            stats,
+            eps,
+            options,
+            breaker,
+            connect_trip,
+            recv,
+            done_sender,
+        ));
         StatsdClient {
             inner: Arc::new(inner),
             sender,
         }
     }
 
-    pub fn sender(&self) -> mpsc::Sender<Pdu> {
+    pub fn sender(&self) -> mpsc::Sender<Bytes> {
         self.sender.clone()
     }
 
+    /// Queue a line, without its trailing newline, to be sent without
+    /// waiting, failing if the queue is full or the circuit for the endpoint
+    /// is open. If the client spills to disk, the line is instead handed over
+    /// to be spilled, only failing if too many lines are already waiting to
+    /// be. Lines over a dropping rate limit are
+    /// counted and dropped. Lines queued this way are tracked in the
+    /// queue_depth gauge.
+    pub fn try_send(&self, line: Bytes) -> Result<(), mpsc::error::TrySendError<Bytes>> {
+        if let Some(limiter) = &self.inner.drop_limiter {
+            if !limiter.lock().try_take(line.len() + 1, Instant::now()) {
+                self.inner.rate_limited_lines.inc();
+                return Ok(());
+            }
+        }
+        let result = if self.inner.breaker.allow(Instant::now()) {
+            self.inner.queue_depth.inc();
+            let result = self.sender.try_send(line);
+            if result.is_err() {
+                self.inner.queue_depth.dec();
+            }
+            result
+        } else {
+            self.inner.breaker_rejected.inc();
+            Err(mpsc::error::TrySendError::Full(line))
+        };
+        match (result, &self.inner.spill) {
+            (Err(mpsc::error::TrySendError::Full(line)), Some(spill)) => {
+                spill.writer.try_send(line)
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Number of lines queued to send
+    pub fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Fraction of the send queue currently in use, from 0 (empty) to 1
+    /// (full).
+    pub fn queue_occupancy(&self) -> f64 {
+        self.queue_depth() as f64 / self.sender.max_capacity() as f64
+    }
+
     pub fn endpoint(&self) -> &str {
         self.inner.endpoint.as_str()
     }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.inner.options
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.inner.breaker
+    }
+
+    /// Whether the endpoint is passing its health checks. Endpoints without
+    /// health checks are always healthy.
+    pub fn healthy(&self) -> bool {
+        self.inner.healthy.load(Ordering::Acquire)
+    }
+
+    /// Returns a future which resolves once the client has written out
+    /// everything queued to it and its tasks have exited. This only happens
+    /// once all clones of this client have been dropped.
+    pub fn finished(&self) -> impl Future<Output = ()> {
+        let mut done = self.inner.done.clone();
+        async move { while done.changed().await.is_ok() {} }
+    }
 }
 
 impl Clone for StatsdClient {
@@ -70,17 +523,88 @@ impl Clone for StatsdClient {
     }
 }
 
+/// Endpoints of this form name a unix socket path rather than a network
+/// address.
+const UNIX_SCHEME: &str = "unix://";
+
+fn unix_path(endpoint: &str) -> Option<&str> {
+    endpoint.strip_prefix(UNIX_SCHEME)
+}
+
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+pub(crate) type Connection = Box<dyn Stream>;
+
+/// A connection as written to by a sender, compressing lines if configured.
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+fn writer(connection: Connection, compression: Option<Compression>) -> Writer {
+    match compression {
+        Some(compression) => compress(connection, compression),
+        None => Box::new(connection),
+    }
+}
+
+/// Connect a stream to the endpoint, which is either a TCP address or a unix
+/// socket path, and secure it with TLS if configured.
+pub(crate) async fn connect(
+    endpoint: &str,
+    tls: Option<&ClientTls>,
+) -> std::io::Result<Connection> {
+    match (unix_path(endpoint), tls) {
+        (Some(path), None) => Ok(Box::new(UnixStream::connect(path).await?)),
+        (None, None) => Ok(Box::new(TcpStream::connect(endpoint).await?)),
+        (Some(path), Some(tls)) => {
+            let stream = UnixStream::connect(path).await?;
+            let connector = TlsConnector::from(tls.config());
+            Ok(Box::new(
+                connector.connect(tls.server_name(path)?, stream).await?,
+            ))
+        }
+        (None, Some(tls)) => {
+            let stream = TcpStream::connect(endpoint).await?;
+            let connector = TlsConnector::from(tls.config());
+            Ok(Box::new(
+                connector
+                    .connect(tls.server_name(endpoint)?, stream)
+                    .await?,
+            ))
+        }
+    }
+}
+
 /// Repeatedly try to form a connection to and endpoint with backoff. If the
 /// tripwire is set, this function will then abort and return none.
 async fn form_connection(
     stats: stats::Scope,
+    errors: &ErrorCounters,
     endpoint: &str,
+    tls: Option<&ClientTls>,
+    backoff: &Backoff,
+    breaker: &CircuitBreaker,
     mut connect_tripwire: Tripwire,
-) -> Option<TcpStream> {
+) -> Option<Connection> {
     let connections_made = stats.counter("connections_made").unwrap();
     let connections_failed = stats.counter("connections_failed").unwrap();
+    let endpoint_failures = stats
+        .counter_vec("endpoint_connect_failures", &["endpoint"])
+        .unwrap();
+    let endpoint_backoff = stats
+        .gauge_vec("endpoint_reconnect_backoff_seconds", &["endpoint"])
+        .unwrap();
+    let mut failures = 0_u32;
     loop {
-        let connect_attempt = timeout(CONNECT_TIMEOUT, TcpStream::connect(endpoint));
+        if failures > 0 {
+            let delay = backoff.delay(failures, fastrand::f64());
+            endpoint_backoff.set(&[endpoint], delay.as_secs_f64());
+            select! {
+                _ = sleep(delay) => {},
+                _ = (&mut connect_tripwire) => return None,
+            }
+        }
+        let connect_attempt = timeout(CONNECT_TIMEOUT, connect(endpoint, tls));
 
         let stream = match select!(
             connect = connect_attempt => connect,
@@ -89,15 +613,22 @@ async fn form_connection(
             },
         ) {
             Err(_e) => {
-                warn!("connect timeout to {:?}", endpoint);
+                errors.report(&Error::ConnectTimeout(endpoint.to_owned()));
                 connections_failed.inc();
-                tokio::time::sleep(RECONNECT_DELAY).await;
+                endpoint_failures.inc(&[endpoint]);
+                breaker.failure(Instant::now());
+                failures = failures.saturating_add(1);
                 continue;
             }
             Ok(Err(e)) => {
-                warn!("connect error to {:?} error {:?}", endpoint, e);
+                errors.report(&Error::Connect {
+                    endpoint: endpoint.to_owned(),
+                    source: e,
+                });
                 connections_failed.inc();
-                tokio::time::sleep(RECONNECT_DELAY).await;
+                endpoint_failures.inc(&[endpoint]);
+                breaker.failure(Instant::now());
+                failures = failures.saturating_add(1);
                 continue;
             }
             Ok(Ok(s)) => {
@@ -106,6 +637,7 @@ async fn form_connection(
             }
         };
         connections_made.inc();
+        endpoint_backoff.set(&[endpoint], 0_f64);
         return Some(stream);
     }
 }
@@ -125,19 +657,64 @@ fn trim_to_next_newline(buf: &mut Bytes) {
 async fn client_sender(
     stats: stats::Scope,
     endpoint: String,
+    options: ClientOptions,
+    breaker: Arc<CircuitBreaker>,
     connect_tripwire: Tripwire,
     mut recv: mpsc::Receiver<bytes::Bytes>,
+    _done: watch::Sender<()>,
 ) {
     let bytes_sent = stats.counter("bytes_sent").unwrap();
     let connections_aborted = stats.counter("connections_aborted").unwrap();
+    let pipelined_batches = stats.counter("pipelined_batches").unwrap();
+    let errors = ErrorCounters::new(&stats, "statsd_client");
+    let tls = match &options.transport {
+        Transport::Tls(tls) => Some(tls),
+        _ => None,
+    };
 
-    let first_connect_tripwire = connect_tripwire.clone();
-    let mut lazy_connect: Option<TcpStream> =
-        form_connection(stats.clone(), endpoint.as_str(), first_connect_tripwire).await;
+    // A batch already waiting when the last one was written, to write before
+    // flushing, and the number of batches written since the last flush
+    let mut next: Option<Bytes> = None;
+    let mut unflushed = 0_usize;
+
+    let first_connect = form_connection(
+        stats.clone(),
+        &errors,
+        endpoint.as_str(),
+        tls,
+        &options.backoff,
+        &breaker,
+        connect_tripwire.clone(),
+    );
+    tokio::pin!(first_connect);
+    // The queue is watched while first connecting, so a client closed with
+    // nothing queued exits straight away, even if the endpoint is down
+    let connected = select! {
+        connected = &mut first_connect => connected,
+        received = recv.recv() => match received {
+            None => {
+                info!("sender task {} exiting", endpoint);
+                return;
+            }
+            Some(buf) => {
+                next = Some(buf);
+                first_connect.await
+            }
+        },
+    };
+    let mut lazy_connect: Option<Writer> = connected.map(|c| writer(c, options.compression));
 
     loop {
-        let mut buf = match recv.recv().await {
+        let received = match next.take() {
+            Some(buf) => Some(buf),
+            None => recv.recv().await,
+        };
+        let mut buf = match received {
             None => {
+                // Finish any compressed stream or TLS session cleanly
+                if let Some(connect) = lazy_connect.as_mut() {
+                    let _ = connect.shutdown().await;
+                }
                 info!("sender task {} exiting", endpoint);
                 return;
             }
@@ -150,8 +727,17 @@ async fn client_sender(
             let connect = match lazy_connect.as_mut() {
                 None => {
                     let reconnect_tripwire = connect_tripwire.clone();
-                    lazy_connect =
-                        form_connection(stats.clone(), endpoint.as_str(), reconnect_tripwire).await;
+                    lazy_connect = form_connection(
+                        stats.clone(),
+                        &errors,
+                        endpoint.as_str(),
+                        tls,
+                        &options.backoff,
+                        &breaker,
+                        reconnect_tripwire,
+                    )
+                    .await
+                    .map(|c| writer(c, options.compression));
                     if lazy_connect.is_none() {
                         // Early check to see if the tripwire is set and bail
                         info!("sender task {} exiting", endpoint);
@@ -161,18 +747,39 @@ async fn client_sender(
                 }
                 Some(c) => c,
             };
-            // Write the buffer until success
-            let result = connect.write_buf(&mut buf).await;
+            // Write the buffer until success, flushing anything held back
+            // by TLS or compression once it is all written, unless another
+            // batch is waiting to be pipelined behind it
+            let result = match connect.write_buf(&mut buf).await {
+                Ok(bytes) if buf.is_empty() => {
+                    unflushed += 1;
+                    if unflushed < options.batching.pipeline {
+                        next = recv.try_recv().ok();
+                    }
+                    if next.is_some() {
+                        pipelined_batches.inc();
+                        Ok(bytes)
+                    } else {
+                        unflushed = 0;
+                        connect.flush().await.map(|_| bytes)
+                    }
+                }
+                result => result,
+            };
             match result {
                 Ok(0) if !buf.is_empty() => {
                     // Write 0 error, abort the connection and try again
+                    errors.report(&Error::WriteZero(endpoint.clone()));
+                    breaker.failure(Instant::now());
                     lazy_connect = None;
+                    unflushed = 0;
                     trim_to_next_newline(&mut buf);
                     connections_aborted.inc();
                     continue;
                 }
                 Ok(bytes) if buf.is_empty() => {
                     bytes_sent.inc_by(bytes as f64);
+                    breaker.success();
                     drop(buf);
                     break;
                 }
@@ -181,12 +788,14 @@ async fn client_sender(
                     continue;
                 }
                 Err(e) => {
-                    warn!(
-                        "write error {} - {:?}, reforming a connection with this buffer",
-                        endpoint, e
-                    );
+                    errors.report(&Error::Write {
+                        endpoint: endpoint.clone(),
+                        source: e,
+                    });
+                    breaker.failure(Instant::now());
                     trim_to_next_newline(&mut buf);
                     lazy_connect = None;
+                    unflushed = 0;
                     connections_aborted.inc();
                     continue;
                 }
@@ -195,6 +804,119 @@ async fn client_sender(
     }
 }
 
+/// Split the next datagram off the front of a buffer of newline terminated
+/// lines, holding as many whole lines as fit in `max` bytes. A single line
+/// longer than `max` is returned alone.
+fn next_datagram(buf: &mut Bytes, max: usize) -> Bytes {
+    if buf.len() <= max {
+        return buf.split_off(0);
+    }
+    match memrchr(b'\n', &buf[..max]) {
+        Some(pos) => buf.split_to(pos + 1),
+        None => match memchr(b'\n', buf) {
+            Some(pos) => buf.split_to(pos + 1),
+            None => buf.split_off(0),
+        },
+    }
+}
+
+/// A connected datagram socket, to either a UDP address or a unix datagram
+/// socket path.
+enum Datagram {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl Datagram {
+    async fn connect(endpoint: &str) -> std::io::Result<Self> {
+        if let Some(path) = unix_path(endpoint) {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            return Ok(Datagram::Unix(socket));
+        }
+        let addr = tokio::net::lookup_host(endpoint)
+            .await?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "no address found")
+            })?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Datagram::Udp(socket))
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Datagram::Udp(socket) => socket.send(buf).await,
+            Datagram::Unix(socket) => socket.send(buf).await,
+        }
+    }
+}
+
+async fn udp_sender(
+    stats: stats::Scope,
+    endpoint: String,
+    max_datagram_bytes: usize,
+    breaker: Arc<CircuitBreaker>,
+    mut recv: mpsc::Receiver<bytes::Bytes>,
+    _done: watch::Sender<()>,
+) {
+    let bytes_sent = stats.counter("bytes_sent").unwrap();
+    let datagrams_sent = stats.counter("datagrams_sent").unwrap();
+    let datagrams_failed = stats.counter("datagrams_failed").unwrap();
+    let errors = ErrorCounters::new(&stats, "statsd_client");
+    let mut lazy_socket: Option<Datagram> = None;
+
+    while let Some(mut buf) = recv.recv().await {
+        if lazy_socket.is_none() {
+            match Datagram::connect(endpoint.as_str()).await {
+                Ok(socket) => {
+                    info!("statsd client datagram {:?}", endpoint);
+                    lazy_socket = Some(socket);
+                }
+                Err(e) => {
+                    // Datagrams are best effort, so the buffer is dropped
+                    // rather than held until the endpoint resolves
+                    errors.report(&Error::Connect {
+                        endpoint: endpoint.clone(),
+                        source: e,
+                    });
+                    breaker.failure(Instant::now());
+                    datagrams_failed.inc();
+                    continue;
+                }
+            }
+        }
+        let socket = lazy_socket.as_ref().unwrap();
+        while !buf.is_empty() {
+            let datagram = next_datagram(&mut buf, max_datagram_bytes);
+            match socket.send(&datagram).await {
+                Ok(bytes) => {
+                    bytes_sent.inc_by(bytes as f64);
+                    datagrams_sent.inc();
+                    breaker.success();
+                }
+                // Errors such as an ICMP port unreachable from an earlier
+                // datagram are reported, but do not need a new socket
+                Err(e) => {
+                    errors.report(&Error::Write {
+                        endpoint: endpoint.clone(),
+                        source: e,
+                    });
+                    breaker.failure(Instant::now());
+                    datagrams_failed.inc();
+                }
+            }
+        }
+    }
+    info!("sender task {} exiting", endpoint);
+}
+
 ///
 /// Ticker is responsible for making sure the statsd channel emits a payload at
 /// a particular rate (allowing for write combining). Due to an issue with
@@ -202,9 +924,9 @@ async fn client_sender(
 /// ticker is needed as opposed to a timeout() wrapper over a queue.recv, which
 /// does not reliably get woken by try_send. The upside of this we also form one
 /// less short lived timer, not that its really a major advantage.
-async fn ticker(endpoint: String, sender: mpsc::Sender<bool>) {
+async fn ticker(endpoint: String, interval: Duration, sender: mpsc::Sender<bool>) {
     loop {
-        sleep(SEND_DELAY).await;
+        sleep(interval).await;
         if sender.send(true).await.is_err() {
             info!("ticker task {} exiting", endpoint);
             return;
@@ -212,42 +934,175 @@ async fn ticker(endpoint: String, sender: mpsc::Sender<bool>) {
     }
 }
 
+/// Split a spilled segment into lines. A final line without a newline was
+/// cut short by a crash, so is skipped.
+fn spilled_lines(mut contents: Bytes) -> Vec<Bytes> {
+    let mut lines = Vec::new();
+    while let Some(pos) = memchr(b'\n', &contents) {
+        let line = contents.split_to(pos);
+        contents.advance(1);
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Replay spilled lines back into the queue of a client, a segment at a time,
+/// while the queue has room. A full queue means the endpoint is still not
+/// keeping up, so replay waits for it to drain. The task exits once the
+/// tripwire is set, leaving anything not yet replayed on disk.
+async fn replay(
+    stats: stats::Scope,
+    endpoint: String,
+    spill: Arc<Spill>,
+    sender: mpsc::Sender<Bytes>,
+    mut tripwire: Tripwire,
+) {
+    let replayed_lines = stats.counter("replayed_lines").unwrap();
+    let queue_depth = stats.gauge("queue_depth").unwrap();
+    let spill_bytes = stats.gauge_vec("spill_bytes", &["endpoint"]).unwrap();
+    let occupancy = |s: &mpsc::Sender<Bytes>| 1_f64 - s.capacity() as f64 / s.max_capacity() as f64;
+    loop {
+        select! {
+            _ = sleep(REPLAY_INTERVAL) => {}
+            _ = &mut tripwire => return,
+        }
+        while occupancy(&sender) < REPLAY_OCCUPANCY {
+            let popped = {
+                let spill = spill.clone();
+                tokio::task::spawn_blocking(move || spill.pop()).await
+            };
+            let path = match popped {
+                Ok(Ok(Some(path))) => path,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    warn!("could not replay spill for {}: {}", endpoint, e);
+                    break;
+                }
+                Err(e) => {
+                    warn!("could not replay spill for {}: {}", endpoint, e);
+                    break;
+                }
+            };
+            spill_bytes.set(&[endpoint.as_str()], spill.bytes() as f64);
+            let contents = match tokio::fs::read(&path).await {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!("could not read spilled {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            for line in spilled_lines(Bytes::from(contents)) {
+                while occupancy(&sender) >= REPLAY_OCCUPANCY {
+                    select! {
+                        _ = sleep(REPLAY_INTERVAL / 10) => {}
+                        _ = &mut tripwire => return,
+                    }
+                }
+                queue_depth.inc();
+                if sender.send(line).await.is_err() {
+                    queue_depth.dec();
+                    return;
+                }
+                replayed_lines.inc();
+            }
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("could not remove spilled {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Directory for the lines spilled for an endpoint, named after the endpoint
+fn spill_dir(config: &SpillConfig, endpoint: &str) -> PathBuf {
+    Path::new(&config.dir).join(path_component(endpoint))
+}
+
+/// A name usable as a path component, with anything other than
+/// alphanumerics, dots and dashes replaced
+pub(crate) fn path_component(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
 async fn client_task(
     stats: stats::Scope,
     endpoint: String,
+    options: ClientOptions,
+    breaker: Arc<CircuitBreaker>,
     connect_tripwire: Tripwire,
-    mut recv: mpsc::Receiver<Pdu>,
-    mut ticker_recv: mpsc::Receiver<bool>,
+    mut recv: mpsc::Receiver<Bytes>,
+    done: watch::Sender<()>,
 ) {
     let backoff_send = stats.counter("send_backoff").unwrap();
     let delayed_sends = stats.counter("delayed_sends").unwrap();
     let messages_queued = stats.counter("messages_queued").unwrap();
+    let queue_depth = stats.gauge("queue_depth").unwrap();
+    let batch_bytes = stats.histogram("batch_bytes", BATCH_BYTES_BUCKETS).unwrap();
+    let batch_lines = stats.histogram("batch_lines", BATCH_LINES_BUCKETS).unwrap();
+    let rate_limited_delays = stats.counter("rate_limited_delays").unwrap();
+    let mut limiter = options
+        .rate_limit
+        .as_ref()
+        .filter(|limit| limit.action == BackendRateLimitAction::Queue)
+        .map(|limit| Limiter::new(limit, Instant::now()));
 
-    let mut buf = BytesMut::with_capacity(INITIAL_BUF_CAPACITY);
-    let (buf_sender, buf_recv) = mpsc::channel(10);
-    tokio::spawn(client_sender(
-        stats,
+    let max_batch_bytes = options.batching.max_bytes;
+    let buf_capacity = max_batch_bytes + 1024;
+    let mut buf = BytesMut::with_capacity(buf_capacity);
+    let mut lines = 0_usize;
+    let (ticker_sender, mut ticker_recv) = mpsc::channel::<bool>(1);
+    tokio::spawn(ticker(
         endpoint.clone(),
-        connect_tripwire,
-        buf_recv,
+        options.batching.flush_interval,
+        ticker_sender,
     ));
+    let (buf_sender, buf_recv) = mpsc::channel(options.batching.max_in_flight);
+    match options.transport {
+        Transport::Udp { max_datagram_bytes } => tokio::spawn(udp_sender(
+            stats,
+            endpoint.clone(),
+            max_datagram_bytes,
+            breaker,
+            buf_recv,
+            done,
+        )),
+        _ => tokio::spawn(client_sender(
+            stats,
+            endpoint.clone(),
+            options,
+            breaker,
+            connect_tripwire,
+            buf_recv,
+            done,
+        )),
+    };
 
     loop {
-        let (pdu, timeout) = select! {
-            p = recv.recv() => (p, false),
+        let (line, timeout) = select! {
+            l = recv.recv() => (l, false),
             _ = ticker_recv.recv() => (None, true),
         };
 
-        match (pdu, timeout) {
-            (Some(pdu), _) => {
-                let pdu_bytes = pdu.as_bytes();
-                if buf.remaining_mut() < pdu_bytes.len() {
-                    buf.reserve(pdu_bytes.len() + 10);
+        match (line, timeout) {
+            (Some(line), _) => {
+                queue_depth.dec();
+                if buf.remaining_mut() < line.len() {
+                    buf.reserve(line.len() + 10);
                 }
-                buf.put(pdu_bytes);
+                buf.put(line);
                 buf.put(b"\n".as_ref());
+                lines += 1;
                 messages_queued.inc();
-                if buf.len() < SEND_THRESHOLD {
+                if buf.len() < max_batch_bytes {
                     backoff_send.inc();
                     // Do not send now
                     continue;
@@ -268,10 +1123,606 @@ async fn client_task(
                 // Timeout! Just go ahead and send whats in the buf now
             }
         };
+        batch_bytes.observe(buf.len() as f64);
+        batch_lines.observe(lines as f64);
+        // Holding the batch back leaves further lines waiting in the queue
+        if let Some(limiter) = limiter.as_mut() {
+            let delay = limiter.take(lines, buf.len(), Instant::now());
+            if delay > Duration::from_secs(0) {
+                rate_limited_delays.inc();
+                sleep(delay).await;
+            }
+        }
+        lines = 0;
         if buf_sender.send(buf.freeze()).await.is_err() {
             info!("client task {} exiting", endpoint);
             return;
         }
-        buf = BytesMut::with_capacity(INITIAL_BUF_CAPACITY);
+        buf = BytesMut::with_capacity(buf_capacity);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn drain_on_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let scope = crate::stats::Collector::default().scope("test");
+
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 100,
+                ..Default::default()
+            },
+        );
+        let (mut socket, _) = listener.accept().await.unwrap();
+        // Wait for the client side to see the connection, so the lines are
+        // written to this one.
+        let connections_made = scope.counter("connections_made").unwrap();
+        while connections_made.get() < 1_f64 {
+            sleep(Duration::from_millis(5)).await;
+        }
+        for _ in 0..10 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+
+        // Queued lines are below the send threshold, but dropping the last
+        // handle to the client must still write them out before finishing.
+        let finished = client.finished();
+        drop(client);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"foo:1|c\n".repeat(10));
+    }
+
+    #[tokio::test]
+    async fn drain_after_drop_reconnects() {
+        // Reserve a port, with nothing listening on it yet
+        let endpoint = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let scope = crate::stats::Collector::default().scope("test");
+
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 100,
+                backoff: Backoff {
+                    initial: Duration::from_millis(5),
+                    max: Duration::from_millis(20),
+                },
+                ..Default::default()
+            },
+        );
+        for _ in 0..10 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        let finished = client.finished();
+        drop(client);
+
+        // The dropped client keeps connecting until it has written out its
+        // queue, once the endpoint comes up
+        let listener = TcpListener::bind(endpoint.as_str()).await.unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"foo:1|c\n".repeat(10));
+    }
+
+    #[tokio::test]
+    async fn finish_empty_while_connecting() {
+        let scope = crate::stats::Collector::default().scope("test");
+        // Nothing is listening, so the client never connects
+        let client = StatsdClient::new(
+            scope.clone(),
+            "127.0.0.1:1",
+            ClientOptions {
+                backoff: Backoff {
+                    initial: Duration::from_millis(5),
+                    max: Duration::from_millis(20),
+                },
+                ..Default::default()
+            },
+        );
+        // With nothing queued there is nothing to wait to connect for
+        let finished = client.finished();
+        drop(client);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queue_occupancy() {
+        let scope = crate::stats::Collector::default().scope("test");
+        // Nothing is listening, but the client tasks don't get to run until
+        // this test yields, so the queue is left as filled.
+        let client = StatsdClient::new(
+            scope.clone(),
+            "127.0.0.1:1",
+            ClientOptions {
+                channel_buffer: 4,
+                ..Default::default()
+            },
+        );
+        assert_eq!(client.queue_occupancy(), 0_f64);
+        for _ in 0..2 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        assert_eq!(client.queue_occupancy(), 0.5_f64);
+        assert_eq!(client.queue_depth(), 2);
+        assert_eq!(scope.gauge("queue_depth").unwrap().get(), 2_f64);
+    }
+
+    #[test]
+    fn test_next_datagram() {
+        let mut buf = Bytes::from_static(b"a:1|c\nb:1|c\nlong.line:1|c\nc:1|c\n");
+        assert_eq!(
+            next_datagram(&mut buf, 14),
+            Bytes::from_static(b"a:1|c\nb:1|c\n")
+        );
+        assert_eq!(
+            next_datagram(&mut buf, 8),
+            Bytes::from_static(b"long.line:1|c\n")
+        );
+        assert_eq!(next_datagram(&mut buf, 8), Bytes::from_static(b"c:1|c\n"));
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn udp_transport() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let endpoint = socket.local_addr().unwrap().to_string();
+        let scope = crate::stats::Collector::default().scope("test");
+        let transport = Transport::Udp {
+            max_datagram_bytes: 16,
+        };
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 100,
+                transport,
+                ..Default::default()
+            },
+        );
+        for _ in 0..3 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        let finished = client.finished();
+        drop(client);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        // Two lines fit in each datagram
+        let mut buf = [0_u8; 64];
+        let size = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"foo:1|c\nfoo:1|c\n");
+        let size = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"foo:1|c\n");
+        assert_eq!(scope.counter("datagrams_sent").unwrap().get(), 2_f64);
+    }
+
+    #[tokio::test]
+    async fn unix_transports() {
+        let dir = tempfile::tempdir().unwrap();
+        let stream_path = dir.path().join("stream.sock");
+        let datagram_path = dir.path().join("datagram.sock");
+        let listener = tokio::net::UnixListener::bind(&stream_path).unwrap();
+        let datagram = std::os::unix::net::UnixDatagram::bind(&datagram_path).unwrap();
+        let scope = crate::stats::Collector::default().scope("test");
+
+        let endpoint = |path: &std::path::Path| format!("unix://{}", path.display());
+        let stream_client = StatsdClient::new(
+            scope.scope("stream"),
+            &endpoint(&stream_path),
+            ClientOptions {
+                channel_buffer: 100,
+                ..Default::default()
+            },
+        );
+        let datagram_client = StatsdClient::new(
+            scope.scope("datagram"),
+            &endpoint(&datagram_path),
+            ClientOptions {
+                channel_buffer: 100,
+                transport: Transport::Udp {
+                    max_datagram_bytes: 1432,
+                },
+                ..Default::default()
+            },
+        );
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let connections_made = scope.scope("stream").counter("connections_made").unwrap();
+        while connections_made.get() < 1_f64 {
+            sleep(Duration::from_millis(5)).await;
+        }
+        for client in [&stream_client, &datagram_client].iter() {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        let finished = futures::future::join(stream_client.finished(), datagram_client.finished());
+        drop(stream_client);
+        drop(datagram_client);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"foo:1|c\n");
+        let mut buf = [0_u8; 64];
+        let size = datagram.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"foo:1|c\n");
+    }
+
+    #[tokio::test]
+    async fn pipelined_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.sock");
+        let scope = crate::stats::Collector::default().scope("test");
+        let client = StatsdClient::new(
+            scope.clone(),
+            &format!("unix://{}", path.display()),
+            ClientOptions {
+                channel_buffer: 100,
+                batching: Batching {
+                    max_bytes: 1,
+                    max_in_flight: 16,
+                    pipeline: 4,
+                    ..Default::default()
+                },
+                backoff: Backoff {
+                    initial: Duration::from_millis(10),
+                    max: Duration::from_millis(20),
+                },
+                ..Default::default()
+            },
+        );
+        // Every line is its own batch, all waiting until the endpoint is up
+        for _ in 0..8 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        sleep(Duration::from_millis(50)).await;
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = [0_u8; 64];
+        timeout(Duration::from_secs(5), socket.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        // Two flushes of four batches each
+        assert_eq!(scope.counter("pipelined_batches").unwrap().get(), 6_f64);
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn tls_transport() {
+        use crate::tls::test::{ca, leaf, pem_file};
+
+        let trusted = ca("trusted");
+        let server = leaf(&["localhost"], &trusted);
+        let (cert, key) = (
+            pem_file(&server.cert.pem()),
+            pem_file(&server.key.serialize_pem()),
+        );
+        let acceptor = tokio_rustls::TlsAcceptor::from(
+            crate::tls::server_config(&crate::config::TlsServerConfig {
+                cert: cert.path().to_str().unwrap().to_owned(),
+                key: key.path().to_str().unwrap().to_owned(),
+                client_ca: None,
+                client_san_allowlist: None,
+                identity_tag: None,
+                handshake_timeout_seconds: None,
+            })
+            .unwrap(),
+        );
+        let ca_file = pem_file(&trusted.cert.pem());
+        let tls = ClientTls::new(&crate::config::TlsClientConfig {
+            ca: ca_file.path().to_str().unwrap().to_owned(),
+            cert: None,
+            key: None,
+            server_name: Some("localhost".to_owned()),
+        })
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let scope = crate::stats::Collector::default().scope("test");
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 100,
+                transport: Transport::Tls(tls),
+                ..Default::default()
+            },
+        );
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut stream = acceptor.accept(socket).await.unwrap();
+        client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        let finished = client.finished();
+        drop(client);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+
+        let mut received = vec![0_u8; 8];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, b"foo:1|c\n");
+    }
+
+    #[tokio::test]
+    async fn batching() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let endpoint = socket.local_addr().unwrap().to_string();
+        let scope = crate::stats::Collector::default().scope("test");
+        let batching = Batching {
+            max_bytes: 16,
+            flush_interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let transport = Transport::Udp {
+            max_datagram_bytes: 1432,
+        };
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 100,
+                transport,
+                batching,
+                ..Default::default()
+            },
+        );
+        for _ in 0..2 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+
+        // The batch is sent as soon as it reaches max_bytes, well before the
+        // flush interval
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let received = tokio::task::spawn_blocking(move || {
+            let mut buf = [0_u8; 64];
+            let size = socket.recv(&mut buf).unwrap();
+            buf[..size].to_vec()
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, b"foo:1|c\nfoo:1|c\n");
+        let batch_lines = scope.histogram("batch_lines", BATCH_LINES_BUCKETS).unwrap();
+        assert_eq!(batch_lines.count(), 1);
+        assert_eq!(batch_lines.sum(), 2_f64);
+    }
+
+    #[test]
+    fn backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        };
+        assert_eq!(backoff.delay(1, 0_f64), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3, 0_f64), Duration::from_secs(4));
+        assert_eq!(backoff.delay(3, 1_f64), Duration::from_secs(2));
+        assert_eq!(backoff.delay(5, 0_f64), Duration::from_secs(10));
+        assert_eq!(backoff.delay(u32::MAX, 0_f64), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn reconnect_backoff() {
+        // Reserve a port with nothing listening on it
+        let endpoint = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+        };
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 100,
+                backoff,
+                drain_timeout: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+        let failures = scope
+            .counter_vec("endpoint_connect_failures", &["endpoint"])
+            .unwrap();
+        timeout(Duration::from_secs(5), async {
+            while failures.get(&[endpoint.as_str()]) < 3_f64 {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // Reconnect attempts stop once the drain timeout passes
+        let finished = client.finished();
+        drop(client);
+        timeout(Duration::from_secs(5), finished).await.unwrap();
+    }
+
+    #[test]
+    fn test_spilled_lines() {
+        let lines = spilled_lines(Bytes::from_static(b"a:1|c\n\nb:1|c\nc:1"));
+        assert_eq!(lines, vec![&b"a:1|c"[..], &b"b:1|c"[..]]);
+    }
+
+    #[tokio::test]
+    async fn spill_replay() {
+        // Reserve a port with nothing listening on it, so the queue fills
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let endpoint = addr.to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let scope = crate::stats::Collector::default().scope("test");
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                channel_buffer: 4,
+                batching: Batching {
+                    max_bytes: 1,
+                    flush_interval: Duration::from_millis(10),
+                    ..Default::default()
+                },
+                backoff: Backoff {
+                    initial: Duration::from_millis(10),
+                    max: Duration::from_millis(20),
+                },
+                spill: Some(SpillConfig {
+                    dir: dir.path().to_str().unwrap().to_owned(),
+                    max_bytes: 1024 * 1024,
+                }),
+                ..Default::default()
+            },
+        );
+        for _ in 0..100 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        let spilled_lines = scope.counter("spilled_lines").unwrap();
+        timeout(Duration::from_secs(10), async {
+            while spilled_lines.get() == 0_f64 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Once the endpoint is back, everything including the spilled lines
+        // is delivered
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(10), async {
+            while received.iter().filter(|b| **b == b'\n').count() < 100 {
+                let mut buf = [0_u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            scope.counter("replayed_lines").unwrap().get(),
+            spilled_lines.get()
+        );
+        let spill_bytes = scope.gauge_vec("spill_bytes", &["endpoint"]).unwrap();
+        assert_eq!(spill_bytes.get(&[endpoint.as_str()]), 0_f64);
+    }
+
+    #[tokio::test]
+    async fn breaker_opens() {
+        // Reserve a port with nothing listening on it
+        let endpoint = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let scope = crate::stats::Collector::default().scope("test");
+        let client = StatsdClient::new(
+            scope.clone(),
+            endpoint.as_str(),
+            ClientOptions {
+                backoff: Backoff {
+                    initial: Duration::from_millis(10),
+                    max: Duration::from_millis(20),
+                },
+                breaker: Some(BreakerSettings {
+                    failures: 2,
+                    open_for: Duration::from_secs(60),
+                }),
+                ..Default::default()
+            },
+        );
+        timeout(Duration::from_secs(5), async {
+            while client.breaker().state() != crate::breaker::State::Open {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(client.try_send(Bytes::from_static(b"foo:1|c")).is_err());
+        assert_eq!(client.queue_depth(), 0);
+        assert_eq!(scope.counter("breaker_rejected").unwrap().get(), 1_f64);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_drop() {
+        let scope = crate::stats::Collector::default().scope("test");
+        let client = StatsdClient::new(
+            scope.clone(),
+            "127.0.0.1:1",
+            ClientOptions {
+                rate_limit: Some(BackendRateLimit {
+                    lines_per_second: Some(5_f64),
+                    bytes_per_second: None,
+                    action: BackendRateLimitAction::Drop,
+                }),
+                ..Default::default()
+            },
+        );
+        for _ in 0..20 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        assert_eq!(scope.counter("rate_limited_lines").unwrap().get(), 15_f64);
+    }
+
+    #[test]
+    fn test_limiter() {
+        let start = Instant::now();
+        let config = BackendRateLimit {
+            lines_per_second: Some(100_f64),
+            bytes_per_second: Some(1000_f64),
+            action: BackendRateLimitAction::Queue,
+        };
+        let mut limiter = Limiter::new(&config, start);
+        assert_eq!(limiter.take(50, 500, start), Duration::from_secs(0));
+        // The slower of the two limits sets the delay
+        assert_eq!(limiter.take(60, 1000, start), Duration::from_millis(500));
+        assert!(!limiter.try_take(1, start));
+    }
+
+    #[tokio::test]
+    async fn compressed_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let scope = crate::stats::Collector::default().scope("test");
+        let client = StatsdClient::new(
+            scope,
+            endpoint.as_str(),
+            ClientOptions {
+                compression: Some(Compression::Zstd),
+                ..Default::default()
+            },
+        );
+        for _ in 0..3 {
+            client.try_send(Bytes::from_static(b"foo:1|c")).unwrap();
+        }
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut stream = crate::compression::Decompress::new(socket, Some(Compression::Zstd));
+        // Lines are readable once flushed, without waiting for the client
+        // to finish the stream
+        let mut received = [0_u8; 24];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&received, b"foo:1|c\nfoo:1|c\nfoo:1|c\n");
+        drop(client);
     }
 }
@@ -1,8 +1,10 @@
 use bytes::BufMut;
 use bytes::Bytes;
-use memchr::memchr;
+use memchr::{memchr, memchr_iter};
 use thiserror::Error;
 
+use crate::error::{Categorized, Category};
+
 use std::{
     cmp::Ordering,
     convert::{TryFrom, TryInto},
@@ -51,7 +53,16 @@ impl Hash for Id {
 
 impl PartialEq for Id {
     fn eq(&self, other: &Id) -> bool {
-        self.name == other.name && self.mtype == other.mtype && self.tags == other.tags
+        // Tags compare equal by name alone, so values are compared here to
+        // agree with hashing
+        self.name == other.name
+            && self.mtype == other.mtype
+            && self.tags.len() == other.tags.len()
+            && self
+                .tags
+                .iter()
+                .zip(other.tags.iter())
+                .all(|(a, b)| a.name == b.name && a.value == b.value)
     }
 }
 
@@ -117,21 +128,34 @@ impl PartialEq for Type {
     }
 }
 
-impl fmt::Display for Type {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Type {
+    /// A lowercase name for the type, such as `counter`
+    pub fn name(&self) -> &'static str {
         use Type::*;
 
-        write!(
-            f,
-            "({})",
-            match self {
-                Counter => "counter",
-                Timer => "timer",
-                Gauge => "gauge",
-                DirectGauge => "directgauge",
-                Set => "set",
-            }
-        )
+        match self {
+            Counter => "counter",
+            Timer => "timer",
+            Gauge => "gauge",
+            DirectGauge => "directgauge",
+            Set => "set",
+        }
+    }
+
+    /// The type with a [`name`](Type::name), such as `counter`
+    pub fn from_name(name: &str) -> Option<Type> {
+        use Type::*;
+
+        [Counter, Timer, Gauge, DirectGauge, Set]
+            .iter()
+            .find(|mtype| mtype.name() == name)
+            .copied()
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({})", self.name())
     }
 }
 
@@ -153,6 +177,40 @@ pub enum ParseError {
     RepeatedTags,
     #[error("unsupported extension field")]
     UnsupportedExtensionField,
+    #[error("line longer than {0} bytes")]
+    LineTooLong(usize),
+    #[error("more than {0} fields in line")]
+    TooManySegments(usize),
+    #[error("more than {0} tags in line")]
+    TooManyTags(usize),
+}
+
+/// Bounds enforced by `Pdu::parse_strict`, for listeners which accept input
+/// from untrusted clients.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    /// Longest line accepted, in bytes
+    pub max_length: usize,
+    /// Most `|` separated fields accepted, counting `name:value` as the first
+    pub max_segments: usize,
+    /// Most `,` separated tags accepted
+    pub max_tags: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_length: 4096,
+            max_segments: 8,
+            max_tags: 64,
+        }
+    }
+}
+
+impl Categorized for ParseError {
+    fn category(&self) -> Category {
+        Category::Protocol
+    }
 }
 
 /// Set of key/value fields for a tag.
@@ -340,6 +398,21 @@ impl TryFrom<Pdu> for Owned {
     }
 }
 
+/// The Id of a line, without parsing its value, such as the member of a set
+impl TryFrom<&Pdu> for Id {
+    type Error = ParseError;
+
+    fn try_from(pdu: &Pdu) -> Result<Self, Self::Error> {
+        let mtype: Type = pdu.pdu_type().try_into()?;
+        let tags = pdu.tags().map(|v| parse_tags(v)).transpose()?;
+        Ok(Id {
+            name: pdu.name().to_vec(),
+            mtype,
+            tags: tags.unwrap_or_default(),
+        })
+    }
+}
+
 impl TryFrom<&Pdu> for Owned {
     type Error = ParseError;
 
@@ -355,13 +428,7 @@ impl TryFrom<&Pdu> for Owned {
                 _ => Err(ParseError::InvalidSampleRate),
             })
             .transpose()?;
-        let mtype: Type = pdu.pdu_type().try_into()?;
-        let tags = pdu.tags().map(|v| parse_tags(v)).transpose()?;
-        let id = Id {
-            name: pdu.name().to_vec(),
-            mtype,
-            tags: tags.unwrap_or_default(),
-        };
+        let id = pdu.try_into()?;
         Ok(Owned {
             id,
             value,
@@ -468,6 +535,82 @@ pub mod convert {
             sample_rate: input.sample_rate,
         }
     }
+
+    /// Split a name into the name without any inline tags (`.__key=value`
+    /// components), and those inline tags.
+    fn split_inline_tags(name: &[u8]) -> (Vec<u8>, Vec<Tag>) {
+        let mut base = Vec::with_capacity(name.len());
+        let mut tags = Vec::new();
+        let mut first = true;
+        for component in name.split(|c| *c == b'.') {
+            let tag = component
+                .strip_prefix(b"__")
+                .and_then(|tag| memchr(b'=', tag).map(|i| (tag, i)));
+            match tag {
+                Some((tag, i)) => tags.push(Tag {
+                    name: tag[..i].to_vec(),
+                    value: tag[i + 1..].to_vec(),
+                }),
+                None => {
+                    if !first {
+                        base.push(b'.');
+                    }
+                    base.extend_from_slice(component);
+                    first = false;
+                }
+            }
+        }
+        (base, tags)
+    }
+
+    /// Convert a PDU's DogStatsD tags to inline tags, alongside any inline
+    /// tags it already has. Unlike [`to_inline_tags`](to_inline_tags),
+    /// values are passed through without being parsed.
+    pub fn pdu_inline_tags(pdu: &Pdu) -> Pdu {
+        let tags = pdu.tags().unwrap_or_default();
+        if tags.is_empty() {
+            return pdu.clone();
+        }
+        let (base, mut all) = split_inline_tags(pdu.name());
+        all.extend(parse_tags(tags).unwrap_or_default());
+        all.sort();
+        let mut name = base;
+        for tag in all {
+            name.extend_from_slice(b".__");
+            name.extend(inline_sanitize(tag.name));
+            name.extend_from_slice(b"=");
+            name.extend(inline_sanitize(tag.value));
+        }
+        pdu.with_name_and_tags(&name, b"")
+    }
+
+    /// Convert a PDU's inline tags to DogStatsD tags, after any it already
+    /// has.
+    pub fn pdu_datadog_tags(pdu: &Pdu) -> Pdu {
+        let (name, inline) = split_inline_tags(pdu.name());
+        if inline.is_empty() {
+            return pdu.clone();
+        }
+        let mut tags = pdu.tags().unwrap_or_default().to_vec();
+        for tag in inline {
+            if !tags.is_empty() {
+                tags.push(b',');
+            }
+            tags.extend(tag.name);
+            tags.push(b':');
+            tags.extend(tag.value);
+        }
+        pdu.with_name_and_tags(&name, &tags)
+    }
+
+    /// Remove both the inline and DogStatsD tags of a PDU
+    pub fn pdu_strip_tags(pdu: &Pdu) -> Pdu {
+        let (name, inline) = split_inline_tags(pdu.name());
+        if inline.is_empty() && pdu.tags().is_none() {
+            return pdu.clone();
+        }
+        pdu.with_name_and_tags(&name, b"")
+    }
 }
 
 fn parse_tags(input: &[u8]) -> Result<Vec<Tag>, ParseError> {
@@ -546,6 +689,12 @@ impl Pdu {
         self.sample_rate_index.map(|v| &self.underlying[v.0..v.1])
     }
 
+    /// Whether the PDU is a gauge written with a leading `+` or `-`, which
+    /// changes the gauge's value by its own rather than setting it
+    pub fn is_gauge_delta(&self) -> bool {
+        self.pdu_type() == b"g" && matches!(self.value().first(), Some(b'+') | Some(b'-'))
+    }
+
     pub fn len(&self) -> usize {
         self.underlying.len()
     }
@@ -558,6 +707,11 @@ impl Pdu {
         self.underlying.as_ref()
     }
 
+    /// Consume the PDU, returning the line it was parsed from
+    pub fn into_bytes(self) -> Bytes {
+        self.underlying
+    }
+
     /// Return a clone of the PDU with a prefix and suffix attached to the statsd name
     pub fn with_prefix_suffix(&self, prefix: &[u8], suffix: &[u8]) -> Self {
         let offset = suffix.len() + prefix.len();
@@ -580,6 +734,116 @@ impl Pdu {
         }
     }
 
+    /// Return a clone of the PDU with the given DogStatsD tags (such as
+    /// `key:value,other:value`) appended to any existing tags
+    pub fn with_tags(&self, tags: &[u8]) -> Self {
+        let mut buf = bytes::BytesMut::with_capacity(self.len() + tags.len() + 2);
+        match self.tags_index {
+            Some((begin, end)) => {
+                let separator: &[u8] = if begin == end { b"" } else { b"," };
+                let offset = separator.len() + tags.len();
+                buf.put(&self.underlying[..end]);
+                buf.put(separator);
+                buf.put(tags);
+                buf.put(&self.underlying[end..]);
+                Pdu {
+                    underlying: buf.freeze(),
+                    tags_index: Some((begin, end + offset)),
+                    sample_rate_index: self.sample_rate_index.map(|(b, e)| {
+                        if b > end {
+                            (b + offset, e + offset)
+                        } else {
+                            (b, e)
+                        }
+                    }),
+                    ..self.clone()
+                }
+            }
+            None => {
+                buf.put(self.as_bytes());
+                buf.put(b"|#".as_ref());
+                buf.put(tags);
+                Pdu {
+                    underlying: buf.freeze(),
+                    tags_index: Some((self.len() + 2, self.len() + 2 + tags.len())),
+                    ..self.clone()
+                }
+            }
+        }
+    }
+
+    /// Return a clone of the PDU with its name replaced, and its DogStatsD
+    /// tags replaced, or removed if `tags` is empty. Any other fields are
+    /// kept as they are.
+    pub fn with_name_and_tags(&self, name: &[u8], tags: &[u8]) -> Self {
+        let mut buf = bytes::BytesMut::with_capacity(self.len() + name.len() + tags.len() + 2);
+        buf.put(name);
+        let rest = match self.tags_index {
+            // Drop the existing tags along with their |# marker
+            Some((begin, end)) => {
+                buf.put(&self.underlying[self.value_index - 1..begin - 2]);
+                &self.underlying[end..]
+            }
+            None => &self.underlying[self.value_index - 1..],
+        };
+        buf.put(rest);
+        if !tags.is_empty() {
+            buf.put(b"|#".as_ref());
+            buf.put(tags);
+        }
+        Pdu::parse(buf.freeze()).expect("rebuilt from a parsed PDU")
+    }
+
+    /// Return a clone of the PDU with its name replaced. Any other fields
+    /// are kept as they are.
+    pub fn with_name(&self, name: &[u8]) -> Self {
+        let mut buf = bytes::BytesMut::with_capacity(self.len() + name.len());
+        buf.put(name);
+        buf.put(&self.underlying[self.value_index - 1..]);
+        Pdu::parse(buf.freeze()).expect("rebuilt from a parsed PDU")
+    }
+
+    /// Return a clone of the PDU with its sample rate replaced, or added if
+    /// it had none. Any other fields are kept as they are.
+    pub fn with_sample_rate(&self, rate: &[u8]) -> Self {
+        let mut buf = bytes::BytesMut::with_capacity(self.len() + rate.len() + 2);
+        match self.sample_rate_index {
+            // Drop the existing rate along with its |@ marker
+            Some((begin, end)) => {
+                buf.put(&self.underlying[..begin - 2]);
+                buf.put(&self.underlying[end..]);
+            }
+            None => buf.put(self.as_bytes()),
+        }
+        buf.put(b"|@".as_ref());
+        buf.put(rate);
+        Pdu::parse(buf.freeze()).expect("rebuilt from a parsed PDU")
+    }
+
+    /// Return a clone of the PDU with its value replaced. Any other fields
+    /// are kept as they are.
+    pub fn with_value(&self, value: &[u8]) -> Self {
+        let mut buf = bytes::BytesMut::with_capacity(self.len() + value.len());
+        buf.put(&self.underlying[..self.value_index]);
+        buf.put(value);
+        buf.put(&self.underlying[self.type_index - 1..]);
+        Pdu::parse(buf.freeze()).expect("rebuilt from a parsed PDU")
+    }
+
+    /// Return a clone of the PDU without its sample rate. Any other fields
+    /// are kept as they are.
+    pub fn without_sample_rate(&self) -> Self {
+        let (begin, end) = match self.sample_rate_index {
+            Some(index) => index,
+            None => return self.clone(),
+        };
+        let mut buf = bytes::BytesMut::with_capacity(self.len());
+        // Drop the rate along with its |@ marker
+        buf.put(&self.underlying[..begin - 2]);
+        buf.put(&self.underlying[end..]);
+        Pdu::parse(buf.freeze()).expect("rebuilt from a parsed PDU")
+    }
+
     /// Parse an incoming single protocol unit and capture internal field
     /// offsets for the positions and lengths of various protocol fields for
     /// later access. No parsing or validation of values is done, so at a low
@@ -642,6 +906,29 @@ impl Pdu {
             tags_index,
         })
     }
+
+    /// Parse a protocol unit as with `parse`, but first enforce the given
+    /// limits on its size and number of fields, and reject lines with an
+    /// empty name, value or type. The limits bound the work done by later
+    /// stages, such as tag parsing, on hostile input.
+    pub fn parse_strict(line: Bytes, limits: &ParseLimits) -> Result<Self, ParseError> {
+        if line.len() > limits.max_length {
+            return Err(ParseError::LineTooLong(limits.max_length));
+        }
+        if memchr_iter(b'|', &line).count() >= limits.max_segments {
+            return Err(ParseError::TooManySegments(limits.max_segments));
+        }
+        let pdu = Pdu::parse(line)?;
+        if pdu.name().is_empty() || pdu.value().is_empty() || pdu.pdu_type().is_empty() {
+            return Err(ParseError::InvalidLine);
+        }
+        if let Some(tags) = pdu.tags() {
+            if memchr_iter(b',', tags).count() >= limits.max_tags {
+                return Err(ParseError::TooManyTags(limits.max_tags));
+            }
+        }
+        Ok(pdu)
+    }
 }
 
 #[cfg(test)]
@@ -672,6 +959,35 @@ pub mod test {
         assert_eq!(pdu.pdu_type(), b"c")
     }
 
+    #[test]
+    fn parse_strict_limits() {
+        let limits = ParseLimits {
+            max_length: 32,
+            max_segments: 4,
+            max_tags: 2,
+        };
+        let parse = |line: &'static [u8]| Pdu::parse_strict(Bytes::from_static(line), &limits);
+
+        assert!(parse(b"foo.bar:3|c|@1.0|#a:b,c:d").is_ok());
+        assert!(matches!(
+            parse(b"foo.bar.baz.qux.quux.corge.grault:3|c"),
+            Err(ParseError::LineTooLong(32))
+        ));
+        assert!(matches!(
+            parse(b"foo:3|c|||||"),
+            Err(ParseError::TooManySegments(4))
+        ));
+        assert!(matches!(
+            parse(b"foo:3|c|#a,b,c"),
+            Err(ParseError::TooManyTags(2))
+        ));
+        // Accepted by the lenient parser, but missing required fields
+        for line in [b":3|c".as_ref(), b"foo:|c", b"foo:3|"] {
+            assert!(Pdu::parse(Bytes::from_static(line)).is_ok());
+            assert!(matches!(parse(line), Err(ParseError::InvalidLine)));
+        }
+    }
+
     #[test]
     fn tagged_pdu() {
         let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|c|@1.0|#tags")).unwrap();
@@ -703,6 +1019,72 @@ pub mod test {
         assert_eq!(pdu.sample_rate().unwrap(), b"1.0");
     }
 
+    #[test]
+    fn with_tags_test() {
+        let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|c|#a:b|@0.5"))
+            .unwrap()
+            .with_tags(b"c:d");
+        assert_eq!(pdu.as_bytes(), b"foo.bar:3|c|#a:b,c:d|@0.5");
+        assert_eq!(pdu.tags().unwrap(), b"a:b,c:d");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+
+        let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|c|@0.5|#a:b"))
+            .unwrap()
+            .with_tags(b"c:d");
+        assert_eq!(pdu.tags().unwrap(), b"a:b,c:d");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+
+        let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|c|@0.5"))
+            .unwrap()
+            .with_tags(b"c:d");
+        assert_eq!(pdu.as_bytes(), b"foo.bar:3|c|@0.5|#c:d");
+        assert_eq!(pdu.tags().unwrap(), b"c:d");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+        assert_eq!(pdu.pdu_type(), b"c");
+    }
+
+    #[test]
+    fn with_sample_rate_test() {
+        let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|c|@0.5|#a:b"))
+            .unwrap()
+            .with_sample_rate(b"0.25");
+        assert_eq!(pdu.as_bytes(), b"foo.bar:3|c|#a:b|@0.25");
+        assert_eq!(pdu.tags().unwrap(), b"a:b");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.25");
+
+        let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|ms"))
+            .unwrap()
+            .with_sample_rate(b"0.1");
+        assert_eq!(pdu.as_bytes(), b"foo.bar:3|ms|@0.1");
+        assert_eq!(pdu.pdu_type(), b"ms");
+
+        let pdu = pdu.without_sample_rate();
+        assert_eq!(pdu.as_bytes(), b"foo.bar:3|ms");
+        assert_eq!(pdu.sample_rate(), None);
+        let pdu = Pdu::parse(Bytes::from_static(b"foo.bar:3|c|@x|#a:b"))
+            .unwrap()
+            .without_sample_rate();
+        assert_eq!(pdu.as_bytes(), b"foo.bar:3|c|#a:b");
+        assert_eq!(pdu.tags().unwrap(), b"a:b");
+    }
+
+    #[test]
+    fn gauge_deltas() {
+        let parse = |line: &'static [u8]| Pdu::parse(Bytes::from_static(line)).unwrap();
+        assert!(parse(b"temp:+2|g").is_gauge_delta());
+        assert!(parse(b"temp:-2|g|#a:b").is_gauge_delta());
+        assert!(!parse(b"temp:2|g").is_gauge_delta());
+        assert!(!parse(b"temp:-2|G").is_gauge_delta());
+        assert!(!parse(b"requests:-2|c").is_gauge_delta());
+
+        let owned: Owned = parse(b"temp:+2.5|g").try_into().unwrap();
+        assert_eq!(owned.value(), 2.5);
+
+        let pdu = parse(b"temp:2|g|#a:b").with_value(b"+12.5");
+        assert_eq!(pdu.as_bytes(), b"temp:+12.5|g|#a:b");
+        assert_eq!(pdu.tags().unwrap(), b"a:b");
+    }
+
     #[test]
     fn test_parse_tag() {
         let tag_v = b"name:value";
@@ -827,6 +1209,20 @@ pub mod test {
         assert_eq!(map.get(&owned.id), Some(&true));
     }
 
+    #[test]
+    fn test_key_tag_values() {
+        let id = |value: &[u8]| Id {
+            name: b"hello".to_vec(),
+            mtype: Type::Counter,
+            tags: vec![Tag {
+                name: b"host".to_vec(),
+                value: value.to_vec(),
+            }],
+        };
+        assert_eq!(id(b"a"), id(b"a"));
+        assert_ne!(id(b"a"), id(b"b"));
+    }
+
     #[test]
     fn test_fmt_id() {
         let id1 = Id {
@@ -870,6 +1266,27 @@ pub mod test {
             );
         }
 
+        #[test]
+        fn convert_pdu_tags() {
+            use super::super::convert::{pdu_datadog_tags, pdu_inline_tags, pdu_strip_tags};
+            let pdu = Pdu::parse(Bytes::from_static(b"foo.__b=2.bar:3|c|#a:1|@0.5")).unwrap();
+            assert_eq!(
+                pdu_inline_tags(&pdu).as_bytes(),
+                b"foo.bar.__a=1.__b=2:3|c|@0.5"
+            );
+            assert_eq!(
+                pdu_datadog_tags(&pdu).as_bytes(),
+                b"foo.bar:3|c|@0.5|#a:1,b:2"
+            );
+            assert_eq!(pdu_strip_tags(&pdu).as_bytes(), b"foo.bar:3|c|@0.5");
+
+            // Set members are passed through without being parsed
+            let pdu = Pdu::parse(Bytes::from_static(b"users:bob|s|#a:1")).unwrap();
+            assert_eq!(pdu_inline_tags(&pdu).as_bytes(), b"users.__a=1:bob|s");
+            let pdu = Pdu::parse(Bytes::from_static(b"foo:1|c")).unwrap();
+            assert_eq!(pdu_datadog_tags(&pdu).as_bytes(), b"foo:1|c");
+        }
+
         #[test]
         fn convert_tags_dirty() {
             let pdu = Pdu::parse(Bytes::from_static(
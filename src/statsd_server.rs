@@ -1,30 +1,92 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use memchr::memchr;
 use stream_cancel::Tripwire;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::unix;
-use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::select;
-use tokio::time::timeout;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
+use std::collections::HashMap;
 use std::io::ErrorKind;
-use std::net::UdpSocket;
+use std::net::{IpAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{debug, info, warn};
+use log::{debug, info, warn, Level};
+use parking_lot::Mutex;
+use thiserror::Error;
 
 use crate::backends::Backends;
+use crate::compression::Decompress;
 use crate::config;
-use crate::config::StatsdServerConfig;
+use crate::config::{
+    Backpressure, BackpressurePolicy, ConnectionRateLimit, RateLimitAction, StatsdServerConfig,
+    TlsServerConfig,
+};
+use crate::error::{Categorized, Category, ErrorCounters};
+use crate::net;
+use crate::rate_limit::TokenBucket;
 use crate::stats;
-use crate::statsd_proto::{Event, Pdu};
+use crate::statsd_proto::{Event, ParseError, ParseLimits, Pdu};
+use crate::systemd;
+use crate::tls;
 
 const TCP_READ_TIMEOUT: Duration = Duration::from_secs(62);
 const READ_BUFFER: usize = 8192;
+const MAX_LINE_LENGTH: usize = 65536;
+const BACKPRESSURE_WATERMARK: f64 = 0.8;
+const BACKPRESSURE_POLL: Duration = Duration::from_millis(50);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not bind {protocol} listener on {addr}: {source}")]
+    Bind {
+        protocol: &'static str,
+        addr: String,
+        source: std::io::Error,
+    },
+    #[error("{protocol} accept failed: {source}")]
+    Accept {
+        protocol: &'static str,
+        source: std::io::Error,
+    },
+    #[error("read from {peer} failed: {source}")]
+    Read {
+        peer: String,
+        source: std::io::Error,
+    },
+    #[error("read from {0} timed out")]
+    ReadTimeout(String),
+    #[error("udp receive failed: {0}")]
+    Receive(std::io::Error),
+    #[error(transparent)]
+    Tls(#[from] tls::Error),
+    #[error("tls handshake with {peer} failed: {source}")]
+    Handshake {
+        peer: String,
+        source: std::io::Error,
+    },
+    #[error("client {peer} is not allowed to connect")]
+    Unauthorized { peer: String },
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        match self {
+            Error::Bind { .. } | Error::Tls(_) => Category::Config,
+            Error::Unauthorized { .. } => Category::Protocol,
+            _ => Category::Network,
+        }
+    }
+}
 
 struct UdpServer {
     shutdown_gate: Arc<AtomicBool>,
@@ -43,103 +105,808 @@ impl UdpServer {
         }
     }
 
-    fn udp_worker(
+    /// Spawn the reader threads for a UDP listener. Workers either share one
+    /// socket, or with `udp_reuseport` each bind their own socket and the
+    /// kernel balances datagrams between them. With more than one worker,
+    /// each worker counts its traffic in its own `workerN` scope.
+    fn udp_workers(
         &mut self,
         stats: stats::Scope,
-        bind: String,
+        errors: ErrorCounters,
+        backends: Backends,
+        config: &StatsdServerConfig,
+    ) -> Result<Vec<std::thread::JoinHandle<()>>, Error> {
+        let bind = config
+            .udp_bind
+            .clone()
+            .unwrap_or_else(|| config.bind.clone());
+        let workers = config.udp_workers.unwrap_or(1);
+        let bind_error = |source| Error::Bind {
+            protocol: "udp",
+            addr: bind.clone(),
+            source,
+        };
+        let sockets = match systemd::take_udp(bind.as_str()) {
+            Some(socket) => {
+                if config.udp_reuseport && workers > 1 {
+                    info!(
+                        "udp socket for {} is inherited, so its workers share it",
+                        bind
+                    );
+                }
+                vec![socket]
+            }
+            None if config.udp_reuseport && workers > 1 => {
+                net::bind_udp(bind.as_str(), config.ip_family, workers).map_err(bind_error)?
+            }
+            None if config.ip_family.is_some() => {
+                net::bind_udp(bind.as_str(), config.ip_family, 1).map_err(bind_error)?
+            }
+            None => vec![UdpSocket::bind(bind.as_str()).map_err(bind_error)?],
+        };
+        for socket in sockets.iter() {
+            let sock_ref = socket2::SockRef::from(socket);
+            if let Some(requested) = config.udp_recv_buffer_bytes {
+                sock_ref
+                    .set_recv_buffer_size(requested)
+                    .map_err(bind_error)?;
+            }
+            // The kernel may adjust the requested size, such as capping it to
+            // net.core.rmem_max, so report what is actually in effect
+            let effective = sock_ref.recv_buffer_size().map_err(bind_error)?;
+            stats
+                .gauge("recv_buffer_bytes")
+                .unwrap()
+                .set(effective as f64);
+            if let Some(requested) = config.udp_recv_buffer_bytes.filter(|r| effective < *r) {
+                warn!(
+                    "udp receive buffer on {} is {} bytes, less than the {} requested",
+                    bind, effective, requested
+                );
+            }
+            // We set a small timeout to allow aborting the UDP server if there
+            // is no incoming traffic.
+            socket
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .map_err(bind_error)?;
+        }
+        let mut handles = Vec::with_capacity(workers);
+        for worker in 0..workers {
+            let socket = sockets[worker % sockets.len()]
+                .try_clone()
+                .map_err(bind_error)?;
+            let stats = if workers == 1 {
+                stats.clone()
+            } else {
+                stats.scope(&format!("worker{}", worker))
+            };
+            handles.push(self.datagram_worker(
+                socket,
+                stats,
+                errors.clone(),
+                backends.clone(),
+                config.route.clone(),
+                config.strict_parsing.then(ParseLimits::default),
+                Rewrite::new(config),
+                config.source_ip_tag.clone(),
+            ));
+        }
+        info!(
+            "statsd udp server running on {} with {} workers",
+            bind, workers
+        );
+        Ok(handles)
+    }
+
+    /// Spawn a reader for a unix datagram socket. Returns the reader, and
+    /// whether the socket was inherited from systemd.
+    #[allow(clippy::too_many_arguments)]
+    fn unix_datagram_worker(
+        &mut self,
+        stats: stats::Scope,
+        errors: ErrorCounters,
+        path: String,
         backends: Backends,
         route: Vec<config::Route>,
-    ) -> std::thread::JoinHandle<()> {
-        let socket = UdpSocket::bind(bind.as_str()).unwrap();
+        limits: Option<ParseLimits>,
+        rewrite: Rewrite,
+    ) -> Result<(std::thread::JoinHandle<()>, bool), Error> {
+        let bind_error = |source| Error::Bind {
+            protocol: "unix_datagram",
+            addr: path.clone(),
+            source,
+        };
+        let (socket, inherited) = match systemd::take_unix_datagram(path.as_str()) {
+            Some(socket) => (socket, true),
+            None => {
+                let addr = unix_socket_addr(path.as_str()).map_err(bind_error)?;
+                (UnixDatagram::bind_addr(&addr).map_err(bind_error)?, false)
+            }
+        };
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .map_err(bind_error)?;
+        info!("statsd unix datagram server running on {}", path);
+        let worker = self.datagram_worker(
+            socket, stats, errors, backends, route, limits, rewrite, None,
+        );
+        Ok((worker, inherited))
+    }
 
+    #[allow(clippy::too_many_arguments)]
+    fn datagram_worker<S: DatagramSocket>(
+        &mut self,
+        socket: S,
+        stats: stats::Scope,
+        errors: ErrorCounters,
+        backends: Backends,
+        route: Vec<config::Route>,
+        limits: Option<ParseLimits>,
+        rewrite: Rewrite,
+        source_ip_tag: Option<String>,
+    ) -> std::thread::JoinHandle<()> {
         let processed_lines = stats.counter("processed_lines").unwrap();
         let incoming_bytes = stats.counter("incoming_bytes").unwrap();
-        // We set a small timeout to allow aborting the UDP server if there is no
-        // incoming traffic.
-        socket
-            .set_read_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
-        info!("statsd udp server running on {}", bind);
         let gate = self.shutdown_gate.clone();
         std::thread::spawn(move || {
-            info!("started udp reader thread");
+            info!("started datagram reader thread");
             let mut buf = BytesMut::with_capacity(65535);
             loop {
                 if gate.load(Relaxed) {
                     break;
                 }
                 buf.resize(65535, 0_u8);
-                match socket.recv_from(buf.as_mut()) {
-                    Ok((size, _remote)) => {
+                match socket.recv_datagram(buf.as_mut()) {
+                    Ok((size, remote)) => {
                         buf.truncate(size);
                         incoming_bytes.inc_by(size as f64);
-                        let r = process_buffer_newlines(&mut buf);
+                        let mut r = process_buffer_newlines(&mut buf, &errors, limits.as_ref());
                         processed_lines.inc_by(r.len() as f64);
-                        backends.provide_statsd_slice(&r, &route);
 
-                        if let Ok(p) = Pdu::parse(buf.clone().freeze()) {
-                            backends.provide_statsd(&Event::Pdu(p), &route);
+                        if !buf.is_empty() {
+                            match parse_line(buf.clone().freeze(), limits.as_ref()) {
+                                Ok(p) => r.push(Event::Pdu(p)),
+                                Err(e) => errors.record(&e),
+                            }
                         }
+                        match source_ip_tag.as_ref().zip(remote) {
+                            Some((key, ip)) => rewrite
+                                .with_tags(Some(format_tag(key, &ip.to_string())))
+                                .apply(&mut r),
+                            None => rewrite.apply(&mut r),
+                        }
+                        backends.provide_statsd_slice(&r, &route);
                     }
                     Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
-                    Err(e) => warn!("udp receiver error {:?}", e),
+                    Err(e) => errors.report(&Error::Receive(e)),
+                }
+            }
+            info!("terminating datagram reader");
+        })
+    }
+}
+
+/// A blocking datagram socket which can be read by a server thread.
+trait DatagramSocket: Send + 'static {
+    /// Receive one datagram, along with the sender's address if it has one.
+    fn recv_datagram(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<IpAddr>)>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn recv_datagram(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<IpAddr>)> {
+        self.recv_from(buf)
+            .map(|(size, remote)| (size, Some(remote.ip())))
+    }
+}
+
+impl DatagramSocket for UnixDatagram {
+    fn recv_datagram(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<IpAddr>)> {
+        self.recv(buf).map(|size| (size, None))
+    }
+}
+
+/// Names starting with `@` are in the Linux abstract socket namespace, which
+/// has no file on disk to clean up.
+fn is_abstract(path: &str) -> bool {
+    path.starts_with('@')
+}
+
+/// Build the address for a unix socket path or abstract name.
+fn unix_socket_addr(path: &str) -> std::io::Result<std::os::unix::net::SocketAddr> {
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "abstract unix sockets are only supported on linux",
+        )),
+        None => std::os::unix::net::SocketAddr::from_pathname(path),
+    }
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Tracks the open stream connections of a server, enforcing the optional
+/// total and per remote address connection limits. Each accepted connection
+/// holds a ConnectionGuard, which releases its slot when dropped.
+#[derive(Clone)]
+struct ConnectionTracker {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    counts: Arc<Mutex<ConnectionCounts>>,
+    active: stats::Gauge,
+    active_sender: Arc<watch::Sender<usize>>,
+    active_receiver: watch::Receiver<usize>,
+}
+
+struct ConnectionGuard {
+    tracker: ConnectionTracker,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.release(self.ip);
+    }
+}
+
+impl ConnectionTracker {
+    fn new(stats: &stats::Scope, config: &StatsdServerConfig) -> Self {
+        Self::with_limits(stats, config.max_connections, config.max_connections_per_ip)
+    }
+
+    fn with_limits(
+        stats: &stats::Scope,
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+    ) -> Self {
+        let (active_sender, active_receiver) = watch::channel(0);
+        ConnectionTracker {
+            max_connections,
+            max_connections_per_ip,
+            counts: Arc::new(Mutex::new(ConnectionCounts::default())),
+            active: stats.gauge("connections_active").unwrap(),
+            active_sender: Arc::new(active_sender),
+            active_receiver,
+        }
+    }
+
+    fn set_active(&self, total: usize) {
+        self.active.set(total as f64);
+        let _ = self.active_sender.send(total);
+    }
+
+    /// Wait until there are no open connections.
+    async fn wait_idle(&self) {
+        let mut receiver = self.active_receiver.clone();
+        while *receiver.borrow() != 0 {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Attempt to reserve a connection slot, optionally for a given remote
+    /// address. Returns None if any limit would be exceeded.
+    fn acquire(&self, ip: Option<IpAddr>) -> Option<ConnectionGuard> {
+        let mut counts = self.counts.lock();
+        if let Some(max) = self.max_connections {
+            if counts.total >= max {
+                return None;
+            }
+        }
+        if let Some(ip) = ip {
+            // Only addresses with open connections are kept, so a rejected
+            // address leaves no entry behind
+            let open = counts.per_ip.get(&ip).copied().unwrap_or(0);
+            if let Some(max) = self.max_connections_per_ip {
+                if open >= max {
+                    return None;
                 }
             }
-            info!("terminating statsd udp");
+            counts.per_ip.insert(ip, open + 1);
+        }
+        counts.total += 1;
+        self.set_active(counts.total);
+        Some(ConnectionGuard {
+            tracker: self.clone(),
+            ip,
         })
     }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut counts = self.counts.lock();
+        counts.total -= 1;
+        if let Some(ip) = ip {
+            if let Some(per_ip) = counts.per_ip.get_mut(&ip) {
+                *per_ip -= 1;
+                if *per_ip == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+        self.set_active(counts.total);
+    }
 }
 
-fn process_buffer_newlines(buf: &mut BytesMut) -> Vec<Event> {
+pub(crate) fn parse_line(line: Bytes, limits: Option<&ParseLimits>) -> Result<Pdu, ParseError> {
+    match limits {
+        Some(limits) => Pdu::parse_strict(line, limits),
+        None => Pdu::parse(line),
+    }
+}
+
+/// Inline commands accepted on stream connections in place of a statsd line.
+/// Each is answered on the connection. On datagram sockets, which have no way
+/// to reply, commands are consumed and ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Legacy statsrelay liveness check, answered with `ok`
+    Status,
+    /// Whether backend queues have room, answered with `ok` or `degraded`
+    Health,
+    /// Queue depth of each backend
+    Stats,
+    /// Running statsrelay version
+    Version,
+}
+
+impl Command {
+    fn parse(line: &[u8]) -> Option<Self> {
+        match line {
+            b"status" => Some(Command::Status),
+            b"health" => Some(Command::Health),
+            b"stats" => Some(Command::Stats),
+            b"version" => Some(Command::Version),
+            _ => None,
+        }
+    }
+
+    /// Build the reply to the command, ending in a newline. The multi-line
+    /// `stats` reply is terminated by an `end` line.
+    pub fn reply(&self, backends: &Backends) -> String {
+        match self {
+            Command::Status => "ok\n".to_owned(),
+            Command::Health if backends.queue_occupancy() >= 1_f64 => "degraded\n".to_owned(),
+            Command::Health => "ok\n".to_owned(),
+            Command::Stats => {
+                let mut depths = backends.queue_depths();
+                depths.sort();
+                let mut reply: String = depths
+                    .iter()
+                    .map(|(name, depth)| format!("queue_depth.{} {}\n", name, depth))
+                    .collect();
+                reply.push_str(&format!(
+                    "queue_occupancy {:.3}\nend\n",
+                    backends.queue_occupancy()
+                ));
+                reply
+            }
+            Command::Version => format!("statsrelay {}\n", crate::built_info::PKG_VERSION),
+        }
+    }
+}
+
+/// Split every complete line off the front of the buffer and parse it, leaving
+/// any trailing partial line in place. Lines which fail to parse are counted
+/// in `errors` and skipped, and inline commands are skipped.
+pub fn process_buffer_newlines(
+    buf: &mut BytesMut,
+    errors: &ErrorCounters,
+    limits: Option<&ParseLimits>,
+) -> Vec<Event> {
+    process_buffer_commands(buf, errors, limits, usize::MAX, &mut Vec::new()).0
+}
+
+/// As [`process_buffer_newlines`], collecting any inline commands found into
+/// `commands` and skipping lines longer than `max_line_length`. Returns the
+/// events along with how many lines were skipped as too long.
+pub fn process_buffer_commands(
+    buf: &mut BytesMut,
+    errors: &ErrorCounters,
+    limits: Option<&ParseLimits>,
+    max_line_length: usize,
+    commands: &mut Vec<Command>,
+) -> (Vec<Event>, usize) {
     let mut ret: Vec<Event> = Vec::new();
+    let mut oversized = 0;
     loop {
         match memchr(b'\n', &buf) {
             None => break,
             Some(newline) => {
                 let mut incoming = buf.split_to(newline + 1);
-                if incoming[incoming.len() - 2] == b'\r' {
+                if incoming.len() > 1 && incoming[incoming.len() - 2] == b'\r' {
                     incoming.truncate(incoming.len() - 2);
                 } else {
                     incoming.truncate(incoming.len() - 1);
                 }
+                if incoming.len() > max_line_length {
+                    oversized += 1;
+                    continue;
+                }
                 let frozen = incoming.freeze();
-                if frozen == "status" {
-                    // Consume a line consisting of just the word status, and do not produce a PDU
+                if let Some(command) = Command::parse(&frozen) {
+                    // Commands are not statsd lines, and do not produce a PDU
+                    commands.push(command);
                     continue;
                 }
-                if let Ok(pdu) = Pdu::parse(frozen) {
-                    ret.push(Event::Pdu(pdu));
+                match parse_line(frozen, limits) {
+                    Ok(pdu) => ret.push(Event::Pdu(pdu)),
+                    Err(e) => errors.record(&e),
                 }
             }
         };
     }
-    ret
+    (ret, oversized)
 }
 
+/// Tracks whether a stream connection is in the middle of discarding a line
+/// which grew past the maximum line length.
+#[derive(Debug, Default)]
+struct LineGuard {
+    discarding: bool,
+}
+
+impl LineGuard {
+    /// Drop the remainder of a previously oversized line from the front of
+    /// the buffer. Once a newline is seen the buffer is positioned at the
+    /// start of the next line and normal framing can resume.
+    fn resync(&mut self, buf: &mut BytesMut) {
+        if !self.discarding {
+            return;
+        }
+        match memchr(b'\n', buf) {
+            Some(newline) => {
+                buf.advance(newline + 1);
+                self.discarding = false;
+            }
+            None => buf.clear(),
+        }
+    }
+
+    /// Called with the unframed remainder of the buffer. Returns true if the
+    /// partial line is longer than max_line_length, in which case it is
+    /// dropped and the rest of the line will be skipped by resync.
+    fn check(&mut self, buf: &mut BytesMut, max_line_length: usize) -> bool {
+        if buf.len() <= max_line_length {
+            return false;
+        }
+        buf.clear();
+        self.discarding = true;
+        true
+    }
+}
+
+/// Rate limiting state for a single stream connection, so one runaway client
+/// can't starve the rest of the pipeline.
+struct ConnectionLimiter {
+    lines: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    action: RateLimitAction,
+    dropped_lines: stats::Counter,
+    delays: stats::Counter,
+}
+
+impl ConnectionLimiter {
+    fn new(stats: &stats::Scope, config: &ConnectionRateLimit, now: Instant) -> Self {
+        // Buckets hold up to one second's worth of tokens as burst
+        let bucket = |rate: Option<f64>| rate.map(|r| TokenBucket::new(r, r, now));
+        ConnectionLimiter {
+            lines: bucket(config.lines_per_second),
+            bytes: bucket(config.bytes_per_second),
+            action: config.action,
+            dropped_lines: stats.counter("rate_limited_lines").unwrap(),
+            delays: stats.counter("rate_limited_delays").unwrap(),
+        }
+    }
+
+    /// Apply the limit to a batch of events which were read in `read_bytes`
+    /// bytes. When dropping, events over the limit are removed from the batch.
+    /// When delaying, returns how long to wait before reading again.
+    fn limit(&mut self, events: &mut Vec<Event>, read_bytes: usize, now: Instant) -> Duration {
+        match self.action {
+            RateLimitAction::Drop => {
+                let before = events.len();
+                let lines = &mut self.lines;
+                let bytes = &mut self.bytes;
+                events.retain(|event| {
+                    let len = match event {
+                        Event::Pdu(pdu) => pdu.len() + 1,
+                        Event::Parsed(_) => 0,
+                    };
+                    lines.as_mut().is_none_or(|b| b.try_take(1_f64, now))
+                        && bytes.as_mut().is_none_or(|b| b.try_take(len as f64, now))
+                });
+                self.dropped_lines.inc_by((before - events.len()) as f64);
+                Duration::from_secs(0)
+            }
+            RateLimitAction::Delay => {
+                let lines = self
+                    .lines
+                    .as_mut()
+                    .map(|b| b.take(events.len() as f64, now))
+                    .unwrap_or_default();
+                let bytes = self
+                    .bytes
+                    .as_mut()
+                    .map(|b| b.take(read_bytes as f64, now))
+                    .unwrap_or_default();
+                let delay = lines.max(bytes);
+                if delay > Duration::from_secs(0) {
+                    self.delays.inc();
+                }
+                delay
+            }
+        }
+    }
+}
+
+/// Applies a server's backpressure policy to stream connections, based on how
+/// full downstream backend queues are.
+struct BackpressureGate {
+    policy: BackpressurePolicy,
+    watermark: f64,
+    shed_lines: stats::Counter,
+    blocks: stats::Counter,
+    pauses: stats::Counter,
+}
+
+impl BackpressureGate {
+    fn new(stats: &stats::Scope, config: &Backpressure) -> Self {
+        BackpressureGate {
+            policy: config.policy,
+            watermark: config.watermark.unwrap_or(BACKPRESSURE_WATERMARK),
+            shed_lines: stats.counter("backpressure_shed_lines").unwrap(),
+            blocks: stats.counter("backpressure_blocks").unwrap(),
+            pauses: stats.counter("backpressure_pauses").unwrap(),
+        }
+    }
+
+    fn exceeded(&self, backends: &Backends) -> bool {
+        backends.queue_occupancy() > self.watermark
+    }
+
+    async fn wait(&self, backends: &Backends) {
+        while self.exceeded(backends) {
+            sleep(BACKPRESSURE_POLL).await;
+        }
+    }
+
+    /// Apply the policy to lines about to be routed, if backend queues are
+    /// over the watermark. Returns whether to pause before the next read.
+    async fn apply(
+        &self,
+        backends: &Backends,
+        lines: &mut Vec<Event>,
+        tripwire: &Tripwire,
+    ) -> bool {
+        if !self.exceeded(backends) {
+            return false;
+        }
+        match self.policy {
+            BackpressurePolicy::Shed => {
+                self.shed_lines.inc_by(lines.len() as f64);
+                lines.clear();
+                false
+            }
+            BackpressurePolicy::Block => {
+                self.blocks.inc();
+                select! {
+                    _ = self.wait(backends) => {},
+                    _ = tripwire.clone() => {},
+                }
+                false
+            }
+            BackpressurePolicy::PauseReads => {
+                self.pauses.inc();
+                true
+            }
+        }
+    }
+}
+
+/// Format a DogStatsD tag, replacing characters which would break the
+/// statsd line protocol.
+fn format_tag(key: &str, value: &str) -> Vec<u8> {
+    format!("{}:{}", key, value)
+        .bytes()
+        .map(|b| match b {
+            b',' | b'|' | b'#' | b'\n' | b'\r' => b'_',
+            b => b,
+        })
+        .collect()
+}
+
+/// Combine two optional sets of tags into one.
+fn join_tags(a: Option<Vec<u8>>, b: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    match (a, b) {
+        (Some(mut a), Some(b)) => {
+            a.push(b',');
+            a.extend(b);
+            Some(a)
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+/// Rewrites applied to every line a server receives before it is routed:
+/// the server's prefix, suffix and tags, along with any tags identifying the
+/// connection or sender.
+#[derive(Clone, Debug, Default)]
+struct Rewrite {
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+    tags: Option<Vec<u8>>,
+}
+
+impl Rewrite {
+    fn new(config: &StatsdServerConfig) -> Self {
+        Rewrite {
+            prefix: config.prefix.clone().unwrap_or_default().into_bytes(),
+            suffix: config.suffix.clone().unwrap_or_default().into_bytes(),
+            tags: config
+                .tags
+                .as_ref()
+                .filter(|tags| !tags.is_empty())
+                .map(|tags| tags.join(",").into_bytes()),
+        }
+    }
+
+    /// Extend the rewrite with tags specific to a connection or sender
+    fn with_tags(&self, tags: Option<Vec<u8>>) -> Self {
+        Rewrite {
+            tags: join_tags(self.tags.clone(), tags),
+            ..self.clone()
+        }
+    }
+
+    fn apply(&self, events: &mut [Event]) {
+        let rename = !self.prefix.is_empty() || !self.suffix.is_empty();
+        if !rename && self.tags.is_none() {
+            return;
+        }
+        for event in events.iter_mut() {
+            if let Event::Pdu(pdu) = event {
+                if rename {
+                    *pdu = pdu.with_prefix_suffix(&self.prefix, &self.suffix);
+                }
+                if let Some(tags) = self.tags.as_ref() {
+                    *pdu = pdu.with_tags(tags);
+                }
+            }
+        }
+    }
+}
+
+/// Complete the TLS handshake on an accepted connection and authorize the
+/// client, returning the stream along with the tag identifying the client if
+/// one is configured.
+async fn tls_accept(
+    acceptor: TlsAcceptor,
+    config: &TlsServerConfig,
+    socket: TcpStream,
+    peer: &str,
+) -> Result<(TlsStream<TcpStream>, Option<Vec<u8>>), Error> {
+    let handshake_error = |source| Error::Handshake {
+        peer: peer.to_owned(),
+        source,
+    };
+    let handshake_timeout = config
+        .handshake_timeout_seconds
+        .map(Duration::from_secs)
+        .unwrap_or(TLS_HANDSHAKE_TIMEOUT);
+    let stream = timeout(handshake_timeout, acceptor.accept(socket))
+        .await
+        .map_err(|_| handshake_error(std::io::Error::new(ErrorKind::TimedOut, "timed out")))?
+        .map_err(handshake_error)?;
+    // Without a client CA there is no verified certificate to look at
+    if config.client_ca.is_none() {
+        return Ok((stream, None));
+    }
+    let names = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(tls::certificate_names)
+        .unwrap_or_default();
+    let identity =
+        tls::authorize(&names, config.client_san_allowlist.as_deref()).ok_or_else(|| {
+            Error::Unauthorized {
+                peer: peer.to_owned(),
+            }
+        })?;
+    debug!("authenticated {} as {}", peer, identity);
+    let tags = config
+        .identity_tag
+        .as_ref()
+        .map(|key| format_tag(key, &identity));
+    Ok((stream, tags))
+}
+
+/// Answer any inline commands read from a connection. Failing to write a
+/// reply is left for the next read to notice.
+async fn reply<T>(socket: &mut T, commands: &mut Vec<Command>, backends: &Backends, peer: &str)
+where
+    T: AsyncWrite + Unpin,
+{
+    for command in commands.drain(..) {
+        let reply = command.reply(backends);
+        if let Err(e) = socket.write_all(reply.as_bytes()).await {
+            debug!("failed to reply to {:?} from {}: {}", command, peer, e);
+            break;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn client_handler<T>(
     stats: stats::Scope,
+    errors: ErrorCounters,
     peer: String,
     mut tripwire: Tripwire,
-    mut socket: T,
+    socket: T,
     backends: Backends,
     route: Vec<config::Route>,
     config: config::StatsdServerConfig,
+    rewrite: Rewrite,
 ) where
     T: AsyncRead + AsyncWrite + Unpin,
 {
+    let mut socket = Decompress::new(socket, config.compression);
     let incoming_bytes = stats.counter("incoming_bytes").unwrap();
     let disconnects = stats.counter("disconnects").unwrap();
     let processed_lines = stats.counter("lines").unwrap();
+    let oversized_lines = stats.counter("oversized_lines").unwrap();
 
+    let max_line_length = config.max_line_length.unwrap_or(MAX_LINE_LENGTH);
+    let limits = config.strict_parsing.then(ParseLimits::default);
+    let mut guard = LineGuard::default();
     let read_buffer = config.read_buffer.unwrap_or(READ_BUFFER);
+    let read_timeout = config
+        .read_timeout_seconds
+        .map(Duration::from_secs)
+        .unwrap_or(TCP_READ_TIMEOUT);
+    let mut limiter = config
+        .connection_rate_limit
+        .as_ref()
+        .map(|limit| ConnectionLimiter::new(&stats, limit, Instant::now()));
+    let backpressure = config
+        .backpressure
+        .as_ref()
+        .map(|bp| BackpressureGate::new(&stats, bp));
+    let mut paused = false;
+    // Time to wait before the next read when rate limited. Not reading lets
+    // the socket buffers fill, pushing back on the client.
+    let mut delay = Duration::from_secs(0);
     let mut buf = BytesMut::with_capacity(read_buffer);
+    let mut commands = Vec::new();
 
     loop {
         if buf.remaining_mut() < read_buffer {
             buf.reserve(read_buffer);
         }
+        let pause = backpressure.as_ref().filter(|_| paused);
+        paused = false;
+        let read = async {
+            if delay > Duration::from_secs(0) {
+                sleep(delay).await;
+            }
+            if let Some(gate) = pause {
+                gate.wait(&backends).await;
+            }
+            timeout(read_timeout, socket.read_buf(&mut buf)).await
+        };
         let result = select! {
-            r = timeout(TCP_READ_TIMEOUT, socket.read_buf(&mut buf)) => {
+            r = read => {
                 match r {
                     Err(_e)  => Err(std::io::Error::new(ErrorKind::TimedOut, "read timeout")),
                     Ok(Err(e)) => Err(e),
@@ -155,14 +922,36 @@ async fn client_handler<T>(
                 break;
             }
             Ok(bytes) if bytes == 0 => {
-                let r = process_buffer_newlines(&mut buf);
+                guard.resync(&mut buf);
+                let (mut r, oversized) = process_buffer_commands(
+                    &mut buf,
+                    &errors,
+                    limits.as_ref(),
+                    max_line_length,
+                    &mut commands,
+                );
+                oversized_lines.inc_by(oversized as f64);
+                if guard.check(&mut buf, max_line_length) {
+                    oversized_lines.inc();
+                }
+                // The last line needs no newline once the connection is closed
+                if !buf.is_empty() {
+                    match parse_line(buf.clone().freeze(), limits.as_ref()) {
+                        Ok(p) => r.push(Event::Pdu(p)),
+                        Err(e) => errors.record(&e),
+                    }
+                }
                 processed_lines.inc_by(r.len() as f64);
-
+                rewrite.apply(&mut r);
+                if let Some(limiter) = limiter.as_mut() {
+                    // Nothing further will be read, so any delay is moot
+                    limiter.limit(&mut r, 0, Instant::now());
+                }
+                if let Some(gate) = backpressure.as_ref() {
+                    gate.apply(&backends, &mut r, &tripwire).await;
+                }
                 backends.provide_statsd_slice(&r, &route);
-                let remaining = buf.clone().freeze();
-                if let Ok(p) = Pdu::parse(remaining) {
-                    backends.provide_statsd(&Event::Pdu(p), &route);
-                };
+                reply(&mut socket, &mut commands, &backends, &peer).await;
                 debug!("remaining {:?}", buf);
                 debug!("closing reader {}", peer);
                 break;
@@ -170,9 +959,30 @@ async fn client_handler<T>(
             Ok(bytes) => {
                 incoming_bytes.inc_by(bytes as f64);
 
-                let r = process_buffer_newlines(&mut buf);
+                guard.resync(&mut buf);
+                let (mut r, oversized) = process_buffer_commands(
+                    &mut buf,
+                    &errors,
+                    limits.as_ref(),
+                    max_line_length,
+                    &mut commands,
+                );
+                oversized_lines.inc_by(oversized as f64);
                 processed_lines.inc_by(r.len() as f64);
+                rewrite.apply(&mut r);
+                reply(&mut socket, &mut commands, &backends, &peer).await;
+                delay = limiter
+                    .as_mut()
+                    .map(|limiter| limiter.limit(&mut r, bytes, Instant::now()))
+                    .unwrap_or_default();
+                if let Some(gate) = backpressure.as_ref() {
+                    paused = gate.apply(&backends, &mut r, &tripwire).await;
+                }
                 backends.provide_statsd_slice(&r, &route);
+                if guard.check(&mut buf, max_line_length) {
+                    oversized_lines.inc();
+                    debug!("discarding oversized line from {}", peer);
+                }
             }
             Err(e) if e.kind() == ErrorKind::Other => {
                 // Ignoring the results of the write call here
@@ -184,11 +994,17 @@ async fn client_handler<T>(
                 break;
             }
             Err(e) if e.kind() == ErrorKind::TimedOut => {
-                debug!("read timeout, closing {}", peer);
+                errors.report_at(Level::Debug, &Error::ReadTimeout(peer.clone()));
                 break;
             }
             Err(e) => {
-                debug!("socket error {:?} {}", e, peer);
+                errors.report_at(
+                    Level::Debug,
+                    &Error::Read {
+                        peer: peer.clone(),
+                        source: e,
+                    },
+                );
                 break;
             }
         }
@@ -215,32 +1031,111 @@ pub async fn run(
     tripwire: Tripwire,
     config: StatsdServerConfig,
     backends: Backends,
-) {
-    let tcp_listener = TcpListener::bind(config.bind.as_str()).await.unwrap();
+) -> Result<(), Error> {
+    let errors = ErrorCounters::new(&stats, "statsd_server");
+    let tcp_bind_error = |source| Error::Bind {
+        protocol: "tcp",
+        addr: config.bind.clone(),
+        source,
+    };
+    let tcp_listener = match systemd::take_tcp(config.bind.as_str()) {
+        Some(listener) => TcpListener::from_std(listener).map_err(tcp_bind_error)?,
+        None if config.ip_family.is_some() => {
+            let listener =
+                net::bind_tcp(config.bind.as_str(), config.ip_family).map_err(tcp_bind_error)?;
+            TcpListener::from_std(listener).map_err(tcp_bind_error)?
+        }
+        None => TcpListener::bind(config.bind.as_str())
+            .await
+            .map_err(tcp_bind_error)?,
+    };
     info!("statsd tcp server running on {}", config.bind);
 
-    let unix_listener = config.socket.as_ref().map(|socket| {
-        let unix = UnixListener::bind(socket.as_str()).unwrap();
-        info!("statsd unix server running on {}", socket);
-        unix
-    });
+    // An inherited unix socket belongs to systemd, and is left in place on
+    // shutdown so the next instance can take it over
+    let mut unix_inherited = false;
+    let unix_listener = config
+        .socket
+        .as_ref()
+        .map(|socket| {
+            let unix_bind_error = |source| Error::Bind {
+                protocol: "unix",
+                addr: socket.clone(),
+                source,
+            };
+            let unix = match systemd::take_unix(socket.as_str()) {
+                Some(listener) => {
+                    unix_inherited = true;
+                    UnixListener::from_std(listener).map_err(unix_bind_error)?
+                }
+                None => {
+                    let addr = unix_socket_addr(socket.as_str()).map_err(unix_bind_error)?;
+                    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)
+                        .map_err(unix_bind_error)?;
+                    listener.set_nonblocking(true).map_err(unix_bind_error)?;
+                    UnixListener::from_std(listener).map_err(unix_bind_error)?
+                }
+            };
+            info!("statsd unix server running on {}", socket);
+            Ok::<_, Error>(unix)
+        })
+        .transpose()?;
 
     // Spawn the threaded, non-async blocking UDP server
-    let mut udp = UdpServer::new();
-    let udp_join = udp.udp_worker(
-        stats.scope("udp"),
-        config.bind.clone(),
-        backends.clone(),
-        config.route.clone(),
-    );
+    let udp = if config.udp_enabled {
+        let mut udp = UdpServer::new();
+        let udp_join = udp.udp_workers(
+            stats.scope("udp"),
+            errors.clone(),
+            backends.clone(),
+            &config,
+        )?;
+        Some((udp, udp_join))
+    } else {
+        info!("statsd udp server disabled for {}", config.bind);
+        None
+    };
+
+    let mut unix_datagram_inherited = false;
+    let unix_datagram = config
+        .datagram_socket
+        .as_ref()
+        .map(|path| {
+            let mut server = UdpServer::new();
+            let (join, inherited) = server.unix_datagram_worker(
+                stats.scope("unix_datagram"),
+                errors.clone(),
+                path.clone(),
+                backends.clone(),
+                config.route.clone(),
+                config.strict_parsing.then(ParseLimits::default),
+                Rewrite::new(&config),
+            )?;
+            unix_datagram_inherited = inherited;
+            Ok::<_, Error>((server, vec![join]))
+        })
+        .transpose()?;
 
     let accept_connections = stats.counter("accepts").unwrap();
     let accept_connections_unix = stats.counter("accepts_unix").unwrap();
     let accept_failures = stats.counter("accept_failures").unwrap();
     let accept_failures_unix = stats.counter("accept_failures_unix").unwrap();
+    let rejected_connections = stats.counter("rejected_connections").unwrap();
+    let rejected_connections_unix = stats.counter("rejected_connections_unix").unwrap();
+    let tracker = ConnectionTracker::new(&stats, &config);
+    let connections = tracker.clone();
+    let tls_acceptor = config
+        .tls
+        .as_ref()
+        .map(tls::server_config)
+        .transpose()?
+        .map(TlsAcceptor::from);
+    let tls_handshake_failures = stats.counter("tls_handshake_failures").unwrap();
+    let tls_rejected_clients = stats.counter("tls_rejected_clients").unwrap();
 
     let routes = config.route.clone();
     let server_config = config.clone();
+    let rewrite = Rewrite::new(&config);
     async move {
         loop {
             select! {
@@ -253,28 +1148,80 @@ pub async fn run(
                     match unix_res {
                         Ok((socket,_)) => {
                             let peer_addr = format!("{:?}", socket.peer_addr());
+                            let guard = match tracker.acquire(None) {
+                                Some(guard) => guard,
+                                None => {
+                                    rejected_connections_unix.inc();
+                                    debug!("rejected unix connection from {} due to connection limits", peer_addr);
+                                    continue;
+                                }
+                            };
                             debug!("accepted unix connection from {:?}", socket.peer_addr());
                             accept_connections_unix.inc();
-                            tokio::spawn(client_handler(stats.scope("connections_unix"), peer_addr, tripwire.clone(), socket, backends.clone(), routes.clone(), server_config.clone()));
+                            let handler = client_handler(stats.scope("connections_unix"), errors.clone(), peer_addr, tripwire.clone(), socket, backends.clone(), routes.clone(), server_config.clone(), rewrite.clone());
+                            tokio::spawn(async move {
+                                handler.await;
+                                drop(guard);
+                            });
                         }
                         Err(err) => {
                             accept_failures_unix.inc();
-                            info!("unix accept error = {:?}", err);
+                            errors.report_at(Level::Info, &Error::Accept { protocol: "unix", source: err });
                         }
                     }
                 }
                 socket_res = tcp_listener.accept() => {
 
                     match socket_res {
-                        Ok((socket,_)) => {
+                        Ok((socket, remote)) => {
                             let peer_addr = format!("{:?}", socket.peer_addr());
+                            let guard = match tracker.acquire(Some(remote.ip())) {
+                                Some(guard) => guard,
+                                None => {
+                                    rejected_connections.inc();
+                                    debug!("rejected connection from {} due to connection limits", peer_addr);
+                                    continue;
+                                }
+                            };
                             debug!("accepted connection from {:?}", socket.peer_addr());
                             accept_connections.inc();
-                            tokio::spawn(client_handler(stats.scope("connections"), peer_addr, tripwire.clone(), socket, backends.clone(), routes.clone(), server_config.clone()));
+                            let source_tag = server_config.source_ip_tag.as_ref().map(|key| format_tag(key, &remote.ip().to_string()));
+                            let acceptor = match tls_acceptor.clone() {
+                                Some(acceptor) => acceptor,
+                                None => {
+                                    let handler = client_handler(stats.scope("connections"), errors.clone(), peer_addr, tripwire.clone(), socket, backends.clone(), routes.clone(), server_config.clone(), rewrite.with_tags(source_tag));
+                                    tokio::spawn(async move {
+                                        handler.await;
+                                        drop(guard);
+                                    });
+                                    continue;
+                                }
+                            };
+                            // Handshake off the accept loop, so a slow client
+                            // can't hold up other connections
+                            let (stats, errors, tripwire, backends, routes, server_config, rewrite) = (stats.scope("connections"), errors.clone(), tripwire.clone(), backends.clone(), routes.clone(), server_config.clone(), rewrite.clone());
+                            let (tls_handshake_failures, tls_rejected_clients) = (tls_handshake_failures.clone(), tls_rejected_clients.clone());
+                            tokio::spawn(async move {
+                                let tls_config = server_config.tls.as_ref().unwrap();
+                                match tls_accept(acceptor, tls_config, socket, &peer_addr).await {
+                                    Ok((stream, identity_tag)) => {
+                                        let rewrite = rewrite.with_tags(join_tags(source_tag, identity_tag));
+                                        client_handler(stats, errors, peer_addr, tripwire, stream, backends, routes, server_config.clone(), rewrite).await
+                                    }
+                                    Err(e) => {
+                                        match e {
+                                            Error::Unauthorized { .. } => tls_rejected_clients.inc(),
+                                            _ => tls_handshake_failures.inc(),
+                                        }
+                                        errors.report_at(Level::Info, &e);
+                                    }
+                                }
+                                drop(guard);
+                            });
                         }
                         Err(err) => {
                             accept_failures.inc();
-                            info!("accept error = {:?}", err);
+                            errors.report_at(Level::Info, &Error::Accept { protocol: "tcp", source: err });
                         }
                     }
                 }
@@ -282,37 +1229,376 @@ pub async fn run(
         }
     }
     .await;
-    drop(udp);
-    // The socket file descriptor is not removed on teardown. Lets remove it if enabled.
-    if let Some(socket) = config.socket.as_ref() {
-        let _ = std::fs::remove_file(socket);
+    // Connections see the same tripwire and close, wait for them to finish
+    // handing off anything they have read.
+    connections.wait_idle().await;
+    debug!("all connections closed");
+    // The socket file descriptor is not removed on teardown. Lets remove it if
+    // enabled, and not an abstract socket which has no file.
+    let sockets = [
+        (config.socket.as_ref(), unix_inherited),
+        (config.datagram_socket.as_ref(), unix_datagram_inherited),
+    ];
+    for (socket, inherited) in sockets.iter() {
+        if let Some(socket) = socket.filter(|s| !inherited && !is_abstract(s)) {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+    for (server, joins) in udp.into_iter().chain(unix_datagram) {
+        drop(server);
+        tokio::task::spawn_blocking(move || {
+            for join in joins {
+                join.join().unwrap();
+            }
+        })
+        .await
+        .unwrap();
     }
-    tokio::task::spawn_blocking(move || {
-        udp_join.join().unwrap();
-    })
-    .await
-    .unwrap();
+    Ok(())
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    fn test_errors() -> ErrorCounters {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        ErrorCounters::new(&scope, "test")
+    }
+
+    fn tracker_with_limits(
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+    ) -> ConnectionTracker {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        ConnectionTracker::with_limits(&scope, max_connections, max_connections_per_ip)
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let tracker = tracker_with_limits(Some(3), Some(2));
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+
+        let g1 = tracker.acquire(Some(ip1)).unwrap();
+        let _g2 = tracker.acquire(Some(ip1)).unwrap();
+        // Per ip limit reached for ip1, but not for ip2
+        assert!(tracker.acquire(Some(ip1)).is_none());
+        let _g3 = tracker.acquire(Some(ip2)).unwrap();
+        // Total limit reached
+        assert!(tracker.acquire(Some(ip2)).is_none());
+        assert!(tracker.acquire(None).is_none());
+        assert_eq!(tracker.active.get(), 3_f64);
+
+        // Releasing a connection frees both the total and per ip slots
+        drop(g1);
+        assert_eq!(tracker.active.get(), 2_f64);
+        let _g4 = tracker.acquire(Some(ip1)).unwrap();
+
+        // Rejected addresses are not tracked
+        let tracker = tracker_with_limits(None, Some(0));
+        assert!(tracker.acquire(Some(ip1)).is_none());
+        assert!(tracker.counts.lock().per_ip.is_empty());
+    }
+
+    #[test]
+    fn test_connection_unlimited() {
+        let tracker = tracker_with_limits(None, None);
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let guards: Vec<_> = (0..100)
+            .map(|_| tracker.acquire(Some(ip)).unwrap())
+            .collect();
+        assert_eq!(tracker.active.get(), 100_f64);
+        drop(guards);
+        assert_eq!(tracker.active.get(), 0_f64);
+        assert!(tracker.counts.lock().per_ip.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_wait_idle() {
+        let tracker = tracker_with_limits(None, None);
+        // No connections, returns immediately
+        tracker.wait_idle().await;
+
+        let guard = tracker.acquire(None).unwrap();
+        assert!(timeout(Duration::from_millis(50), tracker.wait_idle())
+            .await
+            .is_err());
+        let waiter = tracker.clone();
+        let idle = tokio::spawn(async move { waiter.wait_idle().await });
+        drop(guard);
+        timeout(Duration::from_secs(1), idle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_process_buffer_empty_lines() {
+        let mut b = BytesMut::new();
+        // Bare newlines must not underflow when checking for a carriage return
+        b.put_slice(b"\n\r\nfoo:1|c\n\n");
+        let errors = test_errors();
+        let r = process_buffer_newlines(&mut b, &errors, None);
+        assert_eq!(r.len(), 1);
+        assert!(b.is_empty());
+        assert_eq!(errors.counter(Category::Protocol).get(), 3_f64);
+    }
+
+    #[test]
+    fn test_process_buffer_strict() {
+        let mut b = BytesMut::new();
+        b.put_slice(b"foo:1|c\nfoo:1|c|||||||||\n:1|c\n");
+        let errors = test_errors();
+        let r = process_buffer_newlines(&mut b, &errors, Some(&ParseLimits::default()));
+        assert_eq!(r.len(), 1);
+        assert_eq!(errors.counter(Category::Protocol).get(), 2_f64);
+    }
+
+    #[test]
+    fn test_rewrite_tags() {
+        let tag = format_tag("client", "a,b|c#d");
+        assert_eq!(tag, b"client:a_b_c_d");
+        let mut buf = BytesMut::from("a:1|c\nb:1|c|#x:y\n");
+        let mut events = process_buffer_newlines(&mut buf, &test_errors(), None);
+        Rewrite::default().with_tags(Some(tag)).apply(&mut events);
+        let lines: Vec<Pdu> = events.into_iter().map(Pdu::from).collect();
+        assert_eq!(lines[0].as_bytes(), b"a:1|c|#client:a_b_c_d");
+        assert_eq!(lines[1].as_bytes(), b"b:1|c|#x:y,client:a_b_c_d");
+    }
+
+    #[test]
+    fn test_rewrite_server_config() {
+        let config: StatsdServerConfig = serde_json::from_value(serde_json::json!({
+            "bind": "127.0.0.1:0",
+            "prefix": "edge.",
+            "suffix": ".total",
+            "tags": ["tier:edge", "region:us"],
+            "route": [],
+        }))
+        .unwrap();
+        let mut buf = BytesMut::from("a:1|c|@0.5\n");
+        let mut events = process_buffer_newlines(&mut buf, &test_errors(), None);
+        Rewrite::new(&config)
+            .with_tags(Some(b"source:10.0.0.1".to_vec()))
+            .apply(&mut events);
+        let line = Pdu::from(&events[0]);
+        assert_eq!(
+            line.as_bytes(),
+            b"edge.a.total:1|c|@0.5|#tier:edge,region:us,source:10.0.0.1"
+        );
+        assert_eq!(line.sample_rate().unwrap(), b"0.5");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_unix_datagram_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let scope = crate::stats::Collector::default().scope("prefix");
+        let name = format!("statsrelay-test-{}", std::process::id());
+        let config: StatsdServerConfig = serde_json::from_value(serde_json::json!({
+            "bind": "127.0.0.1:0",
+            "udp_enabled": false,
+            "datagram_socket": format!("@{}", name),
+            "route": [],
+        }))
+        .unwrap();
+        let (trigger, tripwire) = Tripwire::new();
+        let server = tokio::spawn(run(
+            scope.scope("server"),
+            tripwire,
+            config,
+            Backends::new(scope.scope("backends")),
+        ));
+
+        let processed = scope
+            .scope("server")
+            .scope("unix_datagram")
+            .counter("processed_lines")
+            .unwrap();
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let client = UnixDatagram::unbound().unwrap();
+        timeout(Duration::from_secs(5), async {
+            while client.send_to_addr(b"a:1|c\nb:1|c\n", &addr).is_err() {
+                sleep(Duration::from_millis(10)).await;
+            }
+            while processed.get() < 2_f64 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        trigger.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_workers() {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        let sockets = net::bind_udp("127.0.0.1:0", None, 2).unwrap();
+        let addr = sockets[0].local_addr().unwrap();
+        // The sockets only reserve a free port, the server binds its own
+        drop(sockets);
+        let config: StatsdServerConfig = serde_json::from_value(serde_json::json!({
+            "bind": addr.to_string(),
+            "udp_workers": 2,
+            "udp_reuseport": true,
+            "route": [],
+        }))
+        .unwrap();
+        let (trigger, tripwire) = Tripwire::new();
+        let server = tokio::spawn(run(
+            scope.scope("server"),
+            tripwire,
+            config,
+            Backends::new(scope.scope("backends")),
+        ));
+
+        let processed = |worker: &str| {
+            scope
+                .scope("server")
+                .scope("udp")
+                .scope(worker)
+                .counter("processed_lines")
+                .unwrap()
+        };
+        let (worker0, worker1) = (processed("worker0"), processed("worker1"));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        timeout(Duration::from_secs(5), async {
+            while worker0.get() + worker1.get() < 2_f64 {
+                client.send_to(b"a:1|c\n", addr).unwrap();
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        trigger.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_join_tags() {
+        let tag = |t: &str| Some(t.as_bytes().to_vec());
+        assert_eq!(join_tags(tag("a:b"), tag("c:d")), tag("a:b,c:d"));
+        assert_eq!(join_tags(None, tag("c:d")), tag("c:d"));
+        assert_eq!(join_tags(tag("a:b"), None), tag("a:b"));
+        assert_eq!(join_tags(None, None), None);
+    }
+
     #[test]
     fn test_process_buffer_no_newlines() {
         let mut b = BytesMut::new();
         // Validate we don't consume non-newlines
         b.put_slice(b"hello");
-        let r = process_buffer_newlines(&mut b);
+        let r = process_buffer_newlines(&mut b, &test_errors(), None);
         assert!(r.is_empty());
         assert!(b.split().as_ref() == b"hello");
     }
 
+    fn limiter(action: RateLimitAction) -> ConnectionLimiter {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        let config = ConnectionRateLimit {
+            lines_per_second: Some(2_f64),
+            bytes_per_second: None,
+            action,
+        };
+        ConnectionLimiter::new(&scope, &config, Instant::now())
+    }
+
+    fn lines(count: usize) -> Vec<Event> {
+        let mut b = BytesMut::new();
+        for _ in 0..count {
+            b.put_slice(b"foo:1|c\n");
+        }
+        process_buffer_newlines(&mut b, &test_errors(), None)
+    }
+
+    #[test]
+    fn test_rate_limit_drop() {
+        let mut limiter = limiter(RateLimitAction::Drop);
+        let now = Instant::now();
+        let mut r = lines(3);
+        assert_eq!(limiter.limit(&mut r, 24, now), Duration::from_secs(0));
+        assert_eq!(r.len(), 2);
+        assert_eq!(limiter.dropped_lines.get(), 1_f64);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_shed() {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        let backends = Backends::new(scope.clone());
+        let (_trigger, tripwire) = Tripwire::new();
+        let mut r = lines(3);
+
+        let gate = BackpressureGate::new(
+            &scope,
+            &Backpressure {
+                policy: BackpressurePolicy::Shed,
+                watermark: None,
+            },
+        );
+        assert!(!gate.apply(&backends, &mut r, &tripwire).await);
+        assert_eq!(r.len(), 3);
+
+        // Any occupancy is over a negative watermark
+        let gate = BackpressureGate::new(
+            &scope,
+            &Backpressure {
+                policy: BackpressurePolicy::Shed,
+                watermark: Some(-1_f64),
+            },
+        );
+        assert!(!gate.apply(&backends, &mut r, &tripwire).await);
+        assert!(r.is_empty());
+        assert_eq!(gate.shed_lines.get(), 3_f64);
+    }
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let mut limiter = limiter(RateLimitAction::Delay);
+        let now = Instant::now();
+        let mut r = lines(3);
+        let delay = limiter.limit(&mut r, 24, now);
+        // Nothing is dropped, but reading is held off until back under limit
+        assert_eq!(r.len(), 3);
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+        assert_eq!(limiter.delays.get(), 1_f64);
+    }
+
+    #[test]
+    fn test_line_guard_resync() {
+        let mut guard = LineGuard::default();
+        let mut b = BytesMut::new();
+        b.put_slice(b"foo:1|c\nbar");
+        let r = process_buffer_newlines(&mut b, &test_errors(), None);
+        assert_eq!(r.len(), 1);
+        assert!(!guard.check(&mut b, 8));
+
+        // The partial line grows past the limit and is dropped
+        b.put_slice(b"barbarbar");
+        assert!(guard.check(&mut b, 8));
+        assert!(b.is_empty());
+
+        // Further data without a newline is still part of the oversized line
+        b.put_slice(b"barbarbarbar");
+        guard.resync(&mut b);
+        assert!(b.is_empty());
+
+        // Framing resumes after the next newline
+        b.put_slice(b"bar\nfoo:1|c\n");
+        guard.resync(&mut b);
+        let r = process_buffer_newlines(&mut b, &test_errors(), None);
+        assert_eq!(r.len(), 1);
+        assert!(b.is_empty());
+        assert!(!guard.check(&mut b, 8));
+    }
+
     #[test]
     fn test_process_buffer_newlines() {
         let mut b = BytesMut::new();
         // Validate we don't consume newlines, but not a remnant
         b.put_slice(b"hello:1|c\nhello:1|c\nhello2");
-        let r = process_buffer_newlines(&mut b);
+        let r = process_buffer_newlines(&mut b, &test_errors(), None);
         assert!(r.len() == 2);
         assert!(b.split().as_ref() == b"hello2");
     }
@@ -323,7 +1609,7 @@ pub mod test {
         let mut b = BytesMut::new();
         // Validate we don't consume newlines, but not a remnant
         b.put_slice(b"hello:1|c\r\nhello:1|c\nhello2");
-        let r = process_buffer_newlines(&mut b);
+        let r = process_buffer_newlines(&mut b, &test_errors(), None);
         for w in r {
             let pdu: Pdu = w.into();
             assert!(pdu.pdu_type() == b"c");
@@ -334,13 +1620,56 @@ pub mod test {
         assert!(b.split().as_ref() == b"hello2");
     }
 
+    #[test]
+    fn test_process_buffer_commands() {
+        let mut commands = Vec::new();
+        let mut b = BytesMut::from("health\nhello:1|c\nstats\r\nversion\nstatus\n");
+        let (r, oversized) =
+            process_buffer_commands(&mut b, &test_errors(), None, 16, &mut commands);
+        assert_eq!(r.len(), 1);
+        assert_eq!(oversized, 0);
+        assert_eq!(
+            commands,
+            vec![
+                Command::Health,
+                Command::Stats,
+                Command::Version,
+                Command::Status
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_buffer_oversized() {
+        // Complete lines over the limit are skipped, even when read at once
+        let mut b = BytesMut::from("foo:1|c\nfoo.bar.baz:1|c\nbar:1|c\r\n");
+        let (r, oversized) =
+            process_buffer_commands(&mut b, &test_errors(), None, 8, &mut Vec::new());
+        assert_eq!(r.len(), 2);
+        assert_eq!(oversized, 1);
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn test_command_reply() {
+        let scope = crate::stats::Collector::default().scope("prefix");
+        let backends = Backends::new(scope);
+        assert_eq!(Command::Status.reply(&backends), "ok\n");
+        assert_eq!(Command::Health.reply(&backends), "ok\n");
+        assert_eq!(
+            Command::Stats.reply(&backends),
+            "queue_occupancy 0.000\nend\n"
+        );
+        assert!(Command::Version.reply(&backends).starts_with("statsrelay "));
+    }
+
     #[test]
     fn test_process_buffer_status() {
         let mut found = 0;
         let mut b = BytesMut::new();
         // Validate we don't consume newlines, but not a remnant
         b.put_slice(b"status\r\nhello:1|c\nhello2");
-        let r = process_buffer_newlines(&mut b);
+        let r = process_buffer_newlines(&mut b, &test_errors(), None);
         for w in r {
             let pdu: Pdu = w.into();
             assert!(pdu.pdu_type() == b"c");
@@ -350,4 +1679,15 @@ pub mod test {
         assert_eq!(1, found);
         assert!(b.split().as_ref() == b"hello2");
     }
+
+    #[test]
+    fn test_process_buffer_counts_protocol_errors() {
+        let errors = test_errors();
+        let mut b = BytesMut::new();
+        b.put_slice(b"garbage\nhello:1|c\nmore garbage\n");
+        let r = process_buffer_newlines(&mut b, &errors, None);
+        assert_eq!(1, r.len());
+        assert_eq!(errors.counter(Category::Protocol).get(), 2_f64);
+        assert_eq!(errors.counter(Category::Network).get(), 0_f64);
+    }
 }
@@ -0,0 +1,152 @@
+//! Support for systemd socket activation, where listening sockets are created
+//! by systemd and passed in as file descriptors described by the `LISTEN_PID`
+//! and `LISTEN_FDS` environment variables. Since the socket outlives the
+//! process, statsrelay can be restarted without refusing connections or
+//! losing datagrams.
+//!
+//! Inherited sockets are claimed by servers by matching their configured bind
+//! address or socket path, so a configuration works the same whether or not
+//! it is socket activated.
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::PathBuf;
+
+use log::{info, warn};
+use parking_lot::{const_mutex, Mutex};
+use socket2::{Socket, Type};
+
+/// The first file descriptor passed by systemd, following stdin, stdout and
+/// stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Address {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+struct Inherited {
+    address: Address,
+    stream: bool,
+    socket: Socket,
+}
+
+static INHERITED: Mutex<Option<Vec<Inherited>>> = const_mutex(None);
+
+/// Parse the number of file descriptors passed to this process, which is
+/// only valid if `LISTEN_PID` names this process.
+fn listen_fds(pid: Option<&str>, fds: Option<&str>) -> usize {
+    match (pid.and_then(|p| p.parse::<u32>().ok()), fds) {
+        (Some(pid), Some(fds)) if pid == std::process::id() => fds.parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn inherit() -> Vec<Inherited> {
+    let count = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+    );
+    // Child processes should not see the sockets as theirs
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let mut inherited = Vec::with_capacity(count);
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd {
+        // Safety: systemd hands over ownership of these descriptors, and they
+        // are only ever taken once as INHERITED is populated once.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let stream = match socket.r#type() {
+            Ok(t) => t == Type::STREAM,
+            Err(e) => {
+                warn!("ignoring inherited fd {} which is not a socket: {}", fd, e);
+                continue;
+            }
+        };
+        let address = match socket.local_addr() {
+            Ok(addr) => match (addr.as_socket(), addr.as_pathname()) {
+                (Some(inet), _) => Address::Inet(inet),
+                (None, Some(path)) => Address::Unix(path.to_owned()),
+                #[cfg(target_os = "linux")]
+                (None, None) if addr.as_abstract_namespace().is_some() => {
+                    let name = addr.as_abstract_namespace().unwrap();
+                    Address::Unix(PathBuf::from(format!("@{}", String::from_utf8_lossy(name))))
+                }
+                (None, None) => {
+                    warn!("ignoring inherited fd {} with an unsupported address", fd);
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("ignoring inherited fd {}: {}", fd, e);
+                continue;
+            }
+        };
+        info!("inherited socket {:?} from systemd", address);
+        inherited.push(Inherited {
+            address,
+            stream,
+            socket,
+        });
+    }
+    inherited
+}
+
+fn take(address: &Address, stream: bool) -> Option<Socket> {
+    let mut guard = INHERITED.lock();
+    let inherited = guard.get_or_insert_with(inherit);
+    let position = inherited
+        .iter()
+        .position(|i| i.stream == stream && &i.address == address)?;
+    let socket = inherited.swap_remove(position).socket;
+    // Stream listeners are handed to tokio, which expects non-blocking
+    // sockets, while datagram sockets are read from a blocking thread
+    if let Err(e) = socket.set_nonblocking(stream) {
+        warn!("could not use inherited socket {:?}: {}", address, e);
+        return None;
+    }
+    Some(socket)
+}
+
+fn inet(bind: &str) -> Option<Address> {
+    bind.to_socket_addrs().ok()?.next().map(Address::Inet)
+}
+
+/// Take the inherited TCP listener bound to the given address, if any.
+pub fn take_tcp(bind: &str) -> Option<TcpListener> {
+    take(&inet(bind)?, true).map(TcpListener::from)
+}
+
+/// Take the inherited UDP socket bound to the given address, if any.
+pub fn take_udp(bind: &str) -> Option<UdpSocket> {
+    take(&inet(bind)?, false).map(UdpSocket::from)
+}
+
+/// Take the inherited unix stream listener bound to the given path or
+/// abstract name, if any.
+pub fn take_unix(path: &str) -> Option<UnixListener> {
+    take(&Address::Unix(PathBuf::from(path)), true).map(UnixListener::from)
+}
+
+/// Take the inherited unix datagram socket bound to the given path or
+/// abstract name, if any.
+pub fn take_unix_datagram(path: &str) -> Option<UnixDatagram> {
+    take(&Address::Unix(PathBuf::from(path)), false).map(UnixDatagram::from)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        let pid = std::process::id().to_string();
+        assert_eq!(listen_fds(Some(&pid), Some("3")), 3);
+        assert_eq!(listen_fds(Some("1"), Some("3")), 0);
+        assert_eq!(listen_fds(None, Some("3")), 0);
+        assert_eq!(listen_fds(Some(&pid), None), 0);
+        assert_eq!(listen_fds(Some(&pid), Some("x")), 0);
+    }
+}
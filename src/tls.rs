@@ -0,0 +1,360 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use thiserror::Error;
+use x509_parser::extensions::GeneralName;
+
+use crate::config::{TlsClientConfig, TlsServerConfig};
+use crate::error::{Categorized, Category};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("no certificates found in {0}")]
+    NoCertificates(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("invalid client CA bundle: {0}")]
+    ClientCa(#[from] rustls::server::VerifierBuilderError),
+    #[error("invalid tls configuration: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        Category::Config
+    }
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let read_error = |source| Error::Read {
+        path: path.to_owned(),
+        source,
+    };
+    let mut reader = BufReader::new(File::open(path).map_err(read_error)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(read_error)?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificates(path.to_owned()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let read_error = |source| Error::Read {
+        path: path.to_owned(),
+        source,
+    };
+    let mut reader = BufReader::new(File::open(path).map_err(read_error)?);
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(read_error)?
+        .ok_or_else(|| Error::NoPrivateKey(path.to_owned()))
+}
+
+/// Build the rustls configuration for a TLS listener. When a client CA
+/// bundle is configured, clients must present a certificate signed by one of
+/// those CAs to complete the handshake.
+pub fn server_config(config: &TlsServerConfig) -> Result<Arc<ServerConfig>, Error> {
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match config.client_ca.as_ref() {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider()).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server = builder.with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)?;
+    Ok(Arc::new(server))
+}
+
+/// Client side TLS for connections to a backend, built from its
+/// configuration. Two are equal when built from the same configuration, so
+/// connections can be kept across reloads which leave it unchanged.
+#[derive(Clone)]
+pub struct ClientTls {
+    settings: TlsClientConfig,
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    pub fn new(settings: &TlsClientConfig) -> Result<Self, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&settings.ca)? {
+            roots.add(cert)?;
+        }
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match (settings.cert.as_ref(), settings.key.as_ref()) {
+            (Some(cert), Some(key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        Ok(ClientTls {
+            settings: settings.clone(),
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+
+    /// The name to verify an endpoint's certificate against: the configured
+    /// server name, or else the host part of the endpoint address.
+    pub fn server_name(&self, endpoint: &str) -> Result<ServerName<'static>, std::io::Error> {
+        let name = match self.settings.server_name.as_deref() {
+            Some(name) => name,
+            None if endpoint.starts_with("unix://") => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "unix socket endpoints need a tls server_name to verify",
+                ))
+            }
+            None => endpoint
+                .rsplit_once(':')
+                .map_or(endpoint, |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        };
+        ServerName::try_from(name.to_owned())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    }
+}
+
+impl PartialEq for ClientTls {
+    fn eq(&self, other: &Self) -> bool {
+        self.settings == other.settings
+    }
+}
+
+impl std::fmt::Debug for ClientTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.settings.fmt(f)
+    }
+}
+
+/// Names a certificate identifies its holder by: the DNS, URI and email
+/// subject alternative names, followed by the subject common name.
+pub fn certificate_names(cert: &CertificateDer<'_>) -> Vec<String> {
+    let cert = match x509_parser::parse_x509_certificate(cert.as_ref()) {
+        Ok((_, cert)) => cert,
+        Err(_) => return Vec::new(),
+    };
+    let mut names: Vec<String> = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            match name {
+                GeneralName::DNSName(n) | GeneralName::URI(n) | GeneralName::RFC822Name(n) => {
+                    names.push((*n).to_owned())
+                }
+                _ => (),
+            }
+        }
+    }
+    names.extend(
+        cert.subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(String::from),
+    );
+    names
+}
+
+/// Check a name against an allowlist entry, which is either an exact name or
+/// a `*.` prefixed wildcard matching exactly one leading label.
+fn name_matches(allowed: &str, name: &str) -> bool {
+    match allowed.strip_prefix("*.") {
+        Some(domain) => name
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == domain),
+        None => allowed == name,
+    }
+}
+
+/// Return the first of a peer's certificate names permitted by the allowlist.
+/// Without an allowlist any verified peer is permitted, and is identified by
+/// its first name.
+pub fn authorize(names: &[String], allowlist: Option<&[String]>) -> Option<String> {
+    match allowlist {
+        Some(allowlist) => names
+            .iter()
+            .find(|name| allowlist.iter().any(|a| name_matches(a, name)))
+            .cloned(),
+        None => Some(names.first().cloned().unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    pub fn pem_file(pem: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(pem.as_bytes()).unwrap();
+        file
+    }
+
+    pub struct Issued {
+        pub cert: rcgen::Certificate,
+        pub key: rcgen::KeyPair,
+    }
+
+    pub fn ca(name: &str) -> Issued {
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        Issued { cert, key }
+    }
+
+    pub fn leaf(names: &[&str], issuer: &Issued) -> Issued {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let params = rcgen::CertificateParams::new(names).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &issuer.cert, &issuer.key).unwrap();
+        Issued { cert, key }
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches("a.example.com", "a.example.com"));
+        assert!(!name_matches("a.example.com", "b.example.com"));
+        assert!(name_matches("*.example.com", "b.example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.b.example.com"));
+    }
+
+    #[test]
+    fn test_client_server_name() {
+        let trusted = ca("trusted");
+        let ca_file = pem_file(&trusted.cert.pem());
+        let mut settings = TlsClientConfig {
+            ca: ca_file.path().to_str().unwrap().to_owned(),
+            cert: None,
+            key: None,
+            server_name: None,
+        };
+        let tls = ClientTls::new(&settings).unwrap();
+        let name = |endpoint| tls.server_name(endpoint).unwrap().to_str().into_owned();
+        tls.server_name("unix:///run/statsd.sock").unwrap_err();
+        assert_eq!(name("statsd.example.com:8125"), "statsd.example.com");
+        assert_eq!(name("[::1]:8125"), "::1");
+        settings.server_name = Some("relay.example.com".to_owned());
+        let tls = ClientTls::new(&settings).unwrap();
+        assert_eq!(
+            tls.server_name("10.0.0.1:8125").unwrap().to_str(),
+            "relay.example.com"
+        );
+    }
+
+    #[test]
+    fn test_authorize() {
+        let names = vec!["a.example.com".to_owned(), "spiffe://a".to_owned()];
+        assert_eq!(authorize(&names, None), Some("a.example.com".to_owned()));
+        let allowlist = vec!["spiffe://a".to_owned()];
+        assert_eq!(
+            authorize(&names, Some(&allowlist)),
+            Some("spiffe://a".to_owned())
+        );
+        let allowlist = vec!["*.other.com".to_owned()];
+        assert_eq!(authorize(&names, Some(&allowlist)), None);
+    }
+
+    async fn handshake(trusted: &Issued, client_issuer: &Issued) -> std::io::Result<Vec<String>> {
+        let server = leaf(&["localhost"], trusted);
+        let client = leaf(&["client.example.com"], client_issuer);
+        let cert = pem_file(&server.cert.pem());
+        let key = pem_file(&server.key.serialize_pem());
+        let client_ca = pem_file(&trusted.cert.pem());
+        let config = server_config(&TlsServerConfig {
+            cert: cert.path().to_str().unwrap().to_owned(),
+            key: key.path().to_str().unwrap().to_owned(),
+            client_ca: Some(client_ca.path().to_str().unwrap().to_owned()),
+            client_san_allowlist: None,
+            identity_tag: None,
+            handshake_timeout_seconds: None,
+        })
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.cert.der().clone()).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(
+                vec![client.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(client.key.serialize_der().into()),
+            )
+            .unwrap();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let connect = async move {
+            let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+            let mut stream = TlsConnector::from(Arc::new(client_config))
+                .connect(name, client_io)
+                .await?;
+            stream.write_all(b"a:1|c\n").await?;
+            stream.shutdown().await?;
+            // Hold the connection open until the server is done with it
+            stream.read_to_end(&mut Vec::new()).await
+        };
+        let accept = async move {
+            let mut stream = TlsAcceptor::from(config).accept(server_io).await?;
+            let names = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(certificate_names)
+                .unwrap_or_default();
+            let mut line = Vec::new();
+            stream.read_to_end(&mut line).await?;
+            assert_eq!(line, b"a:1|c\n");
+            stream.shutdown().await?;
+            Ok(names)
+        };
+        let (_, accepted) = tokio::join!(connect, accept);
+        accepted
+    }
+
+    #[tokio::test]
+    async fn test_client_verification() {
+        let trusted = ca("trusted");
+        let names = handshake(&trusted, &trusted).await.unwrap();
+        assert_eq!(names[0], "client.example.com");
+
+        let untrusted = ca("untrusted");
+        assert!(handshake(&trusted, &untrusted).await.is_err());
+    }
+}